@@ -30,8 +30,14 @@ async fn main() {
         .build()
         .expect("Failed to create configuration");
 
-    iothub_service
+    let result = iothub_service
         .apply_modules_configuration(device_id, &modules_content)
         .await
         .expect("Failed to apply configuration");
+    for module in result.modules() {
+        println!("Applied {}", module);
+    }
+    for warning in result.warnings() {
+        println!("Warning: {}", warning);
+    }
 }
@@ -13,8 +13,9 @@ async fn main() {
 
     let iothub_service = IoTHubService::from_private_key(iot_hub_name, private_key, 3600)
         .expect("Failed to create IoTHubService");
-    let module_method =
-        iothub_service.create_module_method(device_id, "$edgeAgent", "ping", 10, 20);
+    let module_method = iothub_service
+        .create_module_method(device_id, "$edgeAgent", "ping", 10, 20)
+        .expect("Invalid time outs");
     let response = module_method
         .invoke::<serde_json::Value>(json!({}))
         .await
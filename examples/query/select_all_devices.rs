@@ -22,7 +22,7 @@ async fn main() {
     let query_result = query.execute().await.expect("Failed to execute the query");
     println!(
         "{}",
-        serde_json::to_string_pretty(&query_result)
+        serde_json::to_string_pretty(&*query_result)
             .expect("Failed to convert JSON to pretty string")
     );
 }
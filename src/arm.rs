@@ -0,0 +1,586 @@
+//! The arm module is used for managing the message routes and enrichments of an
+//! IoT Hub through the Azure Resource Manager (ARM) management plane, and for
+//! getting the details of its built-in Event Hub compatible endpoint.
+//!
+//! Unlike the rest of the crate, which uses the service API of the IoT Hub, the
+//! management plane requires an Azure Active Directory access token for the
+//! `https://management.azure.com/` resource. Getting the access token is left to
+//! the application, such as with the Azure CLI or a managed identity.
+use std::fmt;
+use std::sync::Arc;
+
+use http::{Method, Request};
+use serde::{Deserialize, Serialize};
+
+use crate::error::IoTHubError;
+use crate::redact::{secret, RevealSecrets};
+use crate::response::{Response, ResponseMetadata};
+use crate::tier::{HubLimits, HubTier};
+use crate::transport::{default_transport, HttpRequest, HttpResponse, Transport};
+
+/// The API version used for requests to the management plane
+pub const ARM_API_VERSION: &str = "2021-07-02";
+
+/// The endpoint of the management plane in the global Azure cloud
+pub const ARM_ENDPOINT: &str = "https://management.azure.com";
+
+/// A route that sends messages matching a condition to one or more endpoints
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageRoute {
+    /// The unique name of the route
+    pub name: String,
+    /// The source of the messages, such as `DeviceMessages` or `TwinChangeEvents`
+    pub source: String,
+    /// The condition the messages must match, `true` routes all messages
+    #[serde(default = "default_condition")]
+    pub condition: String,
+    /// The names of the endpoints the messages are sent to
+    pub endpoint_names: Vec<String>,
+    /// Whether the route is enabled
+    pub is_enabled: bool,
+}
+
+impl MessageRoute {
+    /// Create a new enabled route that sends all device messages to an endpoint
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::arm::MessageRoute;
+    ///
+    /// let mut route = MessageRoute::new("alerts", "alerts-queue");
+    /// route.condition = "$body.temperature > 50".to_string();
+    /// ```
+    pub fn new<S, T>(name: S, endpoint_name: T) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        MessageRoute {
+            name: name.into(),
+            source: DEVICE_MESSAGES_SOURCE.to_string(),
+            condition: default_condition(),
+            endpoint_names: vec![endpoint_name.into()],
+            is_enabled: true,
+        }
+    }
+}
+
+/// The source of the messages sent by devices
+const DEVICE_MESSAGES_SOURCE: &str = "DeviceMessages";
+
+fn default_condition() -> String {
+    "true".to_string()
+}
+
+/// An enrichment that adds an application property to the messages sent to endpoints
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageEnrichment {
+    /// The name of the application property
+    pub key: String,
+    /// The value of the property, such as a static value or `$twin.tags.location`
+    pub value: String,
+    /// The names of the endpoints of the messages the property is added to
+    pub endpoint_names: Vec<String>,
+}
+
+impl MessageEnrichment {
+    /// Create a new enrichment for the given endpoints
+    pub fn new<S, T>(key: S, value: T, endpoint_names: Vec<String>) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        MessageEnrichment {
+            key: key.into(),
+            value: value.into(),
+            endpoint_names,
+        }
+    }
+}
+
+/// The routing properties of an IoT Hub
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutingProperties {
+    /// The custom endpoints of the IoT Hub, such as Event Hubs and storage containers
+    #[serde(default)]
+    pub endpoints: serde_json::Value,
+    /// The routes of the IoT Hub
+    #[serde(default)]
+    pub routes: Vec<MessageRoute>,
+    /// The route used for messages that match no other route
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_route: Option<serde_json::Value>,
+    /// The enrichments of the messages
+    #[serde(default)]
+    pub enrichments: Vec<MessageEnrichment>,
+}
+
+/// The name of the built-in endpoint that receives the device messages
+const EVENTS_ENDPOINT_NAME: &str = "events";
+
+/// The details of the built-in Event Hub compatible endpoint of an IoT Hub
+///
+/// These details are needed for consuming the device messages with an Event Hub client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EventHubEndpoint {
+    /// The Event Hub compatible name
+    pub path: String,
+    /// The Event Hub compatible endpoint, such as `sb://iothub-ns-cool-iot-1234.servicebus.windows.net/`
+    pub endpoint: String,
+    /// The number of partitions
+    pub partition_count: u32,
+    /// The ids of the partitions
+    #[serde(default)]
+    pub partition_ids: Vec<String>,
+    /// The number of days the messages are retained
+    #[serde(default)]
+    pub retention_time_in_days: u32,
+}
+
+impl EventHubEndpoint {
+    /// Get the Event Hub compatible connection string for a shared access policy of the IoT Hub
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::arm::EventHubEndpoint;
+    ///
+    /// let endpoint = EventHubEndpoint {
+    ///     path: "cool-iot-hub".to_string(),
+    ///     endpoint: "sb://iothub-ns-cool-iot-1234.servicebus.windows.net/".to_string(),
+    ///     partition_count: 4,
+    ///     partition_ids: vec![],
+    ///     retention_time_in_days: 1,
+    /// };
+    /// assert_eq!(
+    ///     endpoint.connection_string("service", "c2VjcmV0"),
+    ///     "Endpoint=sb://iothub-ns-cool-iot-1234.servicebus.windows.net/;SharedAccessKeyName=service;SharedAccessKey=c2VjcmV0;EntityPath=cool-iot-hub"
+    /// );
+    /// ```
+    pub fn connection_string(&self, key_name: &str, key: &str) -> String {
+        format!(
+            "Endpoint={};SharedAccessKeyName={};SharedAccessKey={};EntityPath={}",
+            self.endpoint, key_name, key, self.path
+        )
+    }
+}
+
+/// The pricing tier and number of units of an IoT Hub
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HubSku {
+    /// The tier of the IoT Hub, such as `S1`
+    pub name: HubTier,
+    /// The number of units of the IoT Hub
+    pub capacity: u32,
+}
+
+impl HubSku {
+    /// Get the limits of the IoT Hub
+    pub fn limits(&self) -> HubLimits {
+        self.name.limits(self.capacity)
+    }
+}
+
+/// The ArmClient is used for managing an IoT Hub through the management plane
+pub struct ArmClient {
+    endpoint: String,
+    subscription_id: String,
+    resource_group: String,
+    iothub_name: String,
+    access_token: String,
+    transport: Arc<dyn Transport>,
+}
+
+impl RevealSecrets for ArmClient {
+    fn fmt_secrets(&self, f: &mut fmt::Formatter<'_>, reveal: bool) -> fmt::Result {
+        f.debug_struct("ArmClient")
+            .field("endpoint", &self.endpoint)
+            .field("subscription_id", &self.subscription_id)
+            .field("resource_group", &self.resource_group)
+            .field("iothub_name", &self.iothub_name)
+            .field("access_token", &secret(&self.access_token, reveal))
+            .finish()
+    }
+}
+
+impl fmt::Debug for ArmClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_secrets(f, false)
+    }
+}
+
+impl ArmClient {
+    /// Create a new ArmClient for an IoT Hub in a resource group
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::arm::ArmClient;
+    ///
+    /// let arm_client = ArmClient::new(
+    ///     "00000000-0000-0000-0000-000000000000",
+    ///     "some-resource-group",
+    ///     "cool-iot-hub",
+    ///     "<an access token for https://management.azure.com/>",
+    /// );
+    /// ```
+    pub fn new<S, T, U, V>(
+        subscription_id: S,
+        resource_group: T,
+        iothub_name: U,
+        access_token: V,
+    ) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+        U: Into<String>,
+        V: Into<String>,
+    {
+        ArmClient {
+            endpoint: ARM_ENDPOINT.to_string(),
+            subscription_id: subscription_id.into(),
+            resource_group: resource_group.into(),
+            iothub_name: iothub_name.into(),
+            access_token: access_token.into(),
+            transport: default_transport(),
+        }
+    }
+
+    /// Set the endpoint of the management plane, such as `https://management.usgovcloudapi.net`
+    pub fn with_endpoint<S>(mut self, endpoint: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.endpoint = endpoint.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Send all requests to the management plane with the given transport
+    pub fn with_transport<T>(mut self, transport: T) -> Self
+    where
+        T: Transport + 'static,
+    {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    /// Get the uri of the IoT Hub resource
+    fn resource_uri(&self) -> String {
+        format!(
+            "{}/subscriptions/{}/resourceGroups/{}/providers/Microsoft.Devices/IotHubs/{}?api-version={}",
+            self.endpoint,
+            self.subscription_id,
+            self.resource_group,
+            self.iothub_name,
+            ARM_API_VERSION
+        )
+    }
+
+    /// Send a request and fail on an unsuccessful status code
+    async fn send(
+        &self,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.transport.send(request).await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
+        }
+        Ok(response)
+    }
+
+    /// Get the IoT Hub resource, which contains the etag and the routing properties
+    async fn get_resource(
+        &self,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+        let request = Request::builder()
+            .uri(self.resource_uri())
+            .method(Method::GET)
+            .header("Authorization", format!("Bearer {}", self.access_token))
+            .body(Vec::new())?;
+
+        let response = self.send(request).await?;
+        Ok(serde_json::from_slice(response.body())?)
+    }
+
+    /// Get the routing properties of the IoT Hub
+    pub async fn get_routing(
+        &self,
+    ) -> Result<RoutingProperties, Box<dyn std::error::Error + Send + Sync>> {
+        let resource = self.get_resource().await?;
+        Ok(serde_json::from_value(
+            resource["properties"]["routing"].clone(),
+        )?)
+    }
+
+    /// Get the pricing tier and number of units of the IoT Hub
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::arm::ArmClient;
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let access_token = "";
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let arm_client = ArmClient::new("subscription", "resource-group", "cool-iot-hub", access_token);
+    /// let sku = arm_client.get_sku().await?;
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?
+    ///     .with_tier(sku.name, sku.capacity);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_sku(&self) -> Result<HubSku, Box<dyn std::error::Error + Send + Sync>> {
+        let resource = self.get_resource().await?;
+        Ok(serde_json::from_value(resource["sku"].clone())?)
+    }
+
+    /// Get the details of the built-in Event Hub compatible endpoint of the IoT Hub
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::arm::ArmClient;
+    ///
+    /// # let access_token = "";
+    /// let arm_client = ArmClient::new("subscription", "resource-group", "cool-iot-hub", access_token);
+    /// let endpoint = arm_client.get_events_endpoint().await?;
+    /// println!("{} partitions at {}", endpoint.partition_count, endpoint.endpoint);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_events_endpoint(
+        &self,
+    ) -> Result<EventHubEndpoint, Box<dyn std::error::Error + Send + Sync>> {
+        let resource = self.get_resource().await?;
+        Ok(serde_json::from_value(
+            resource["properties"]["eventHubEndpoints"][EVENTS_ENDPOINT_NAME].clone(),
+        )?)
+    }
+
+    /// Read, modify and write the routing properties of the IoT Hub
+    ///
+    /// The IoT Hub resource is written with the etag it was read with,
+    /// so concurrent changes to the IoT Hub are not overwritten.
+    pub async fn modify_routing<F>(
+        &self,
+        modify: F,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        F: FnOnce(&mut RoutingProperties),
+    {
+        let mut resource = self.get_resource().await?;
+        let mut routing: RoutingProperties =
+            serde_json::from_value(resource["properties"]["routing"].clone())?;
+        modify(&mut routing);
+        resource["properties"]["routing"] = serde_json::to_value(&routing)?;
+
+        let mut request = Request::builder()
+            .uri(self.resource_uri())
+            .method(Method::PUT)
+            .header("Authorization", format!("Bearer {}", self.access_token))
+            .header("Content-Type", "application/json");
+        if let Some(etag) = resource["etag"].as_str() {
+            request = request.header("If-Match", etag);
+        }
+        let request = request.body(serde_json::to_vec(&resource)?)?;
+
+        let response = self.send(request).await?;
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            (),
+        ))
+    }
+
+    /// Add a route, replacing the route with the same name
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::arm::{ArmClient, MessageRoute};
+    ///
+    /// # let access_token = "";
+    /// let arm_client = ArmClient::new("subscription", "resource-group", "cool-iot-hub", access_token);
+    /// arm_client.set_route(MessageRoute::new("alerts", "alerts-queue")).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn set_route(
+        &self,
+        route: MessageRoute,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>> {
+        self.modify_routing(|routing| {
+            routing
+                .routes
+                .retain(|existing| existing.name != route.name);
+            routing.routes.push(route);
+        })
+        .await
+    }
+
+    /// Remove the route with the given name
+    pub async fn remove_route<S>(
+        &self,
+        name: S,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let name = name.into();
+        self.modify_routing(|routing| routing.routes.retain(|route| route.name != name))
+            .await
+    }
+
+    /// Add an enrichment, replacing the enrichment with the same key
+    pub async fn set_enrichment(
+        &self,
+        enrichment: MessageEnrichment,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>> {
+        self.modify_routing(|routing| {
+            routing
+                .enrichments
+                .retain(|existing| existing.key != enrichment.key);
+            routing.enrichments.push(enrichment);
+        })
+        .await
+    }
+
+    /// Remove the enrichment with the given key
+    pub async fn remove_enrichment<S>(
+        &self,
+        key: S,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let key = key.into();
+        self.modify_routing(|routing| {
+            routing
+                .enrichments
+                .retain(|enrichment| enrichment.key != key)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::arm::{ArmClient, MessageEnrichment, MessageRoute};
+    use crate::testing::mocktransport::{json_response, MockTransport};
+
+    /// Create a transport that returns the resource of the IoT Hub and accepts its updates
+    fn resource_transport() -> MockTransport {
+        MockTransport::new(|request, _| {
+            assert_eq!(request.headers()["Authorization"], "Bearer some-token");
+            assert!(request.uri().to_string().starts_with(
+                "https://management.azure.com/subscriptions/some-subscription/resourceGroups/some-group/providers/Microsoft.Devices/IotHubs/cool-iot-hub?"
+            ));
+
+            if request.method() == http::Method::PUT {
+                return json_response(&json!({}));
+            }
+            json_response(&json!({
+                "name": "cool-iot-hub",
+                "etag": "AAAAAAFD6M4=",
+                "sku": { "name": "S2", "tier": "Standard", "capacity": 4 },
+                "properties": {
+                    "eventHubEndpoints": {
+                        "events": {
+                            "retentionTimeInDays": 1,
+                            "partitionCount": 2,
+                            "partitionIds": ["0", "1"],
+                            "path": "cool-iot-hub",
+                            "endpoint": "sb://iothub-ns-cool-iot-1234.servicebus.windows.net/"
+                        }
+                    },
+                    "routing": {
+                        "endpoints": { "eventHubs": [] },
+                        "routes": [
+                            { "name": "alerts", "source": "DeviceMessages", "condition": "false", "endpointNames": ["old"], "isEnabled": false },
+                            { "name": "telemetry", "source": "DeviceMessages", "condition": "true", "endpointNames": ["events"], "isEnabled": true }
+                        ],
+                        "enrichments": []
+                    }
+                }
+            }))
+        })
+    }
+
+    #[test]
+    fn set_route_should_replace_route_with_same_name(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let transport = resource_transport();
+        let arm_client = ArmClient::new(
+            "some-subscription",
+            "some-group",
+            "cool-iot-hub",
+            "some-token",
+        )
+        .with_transport(transport.clone());
+
+        futures::executor::block_on(arm_client.set_route(MessageRoute::new("alerts", "queue")))?;
+        futures::executor::block_on(arm_client.set_enrichment(MessageEnrichment::new(
+            "site",
+            "$twin.tags.site",
+            vec!["queue".to_string()],
+        )))?;
+
+        let put_requests = transport
+            .requests()
+            .iter()
+            .filter(|request| request.method() == http::Method::PUT)
+            .map(|request| {
+                let if_match = request.headers()["If-Match"].to_str().unwrap().to_string();
+                let resource: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+                (if_match, resource)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(put_requests.len(), 2);
+        let (if_match, resource) = &put_requests[0];
+        assert_eq!(if_match, "AAAAAAFD6M4=");
+        assert_eq!(resource["name"], "cool-iot-hub");
+        assert_eq!(
+            resource["properties"]["routing"]["routes"],
+            json!([
+                { "name": "telemetry", "source": "DeviceMessages", "condition": "true", "endpointNames": ["events"], "isEnabled": true },
+                { "name": "alerts", "source": "DeviceMessages", "condition": "true", "endpointNames": ["queue"], "isEnabled": true }
+            ])
+        );
+        assert_eq!(
+            put_requests[1].1["properties"]["routing"]["enrichments"],
+            json!([{ "key": "site", "value": "$twin.tags.site", "endpointNames": ["queue"] }])
+        );
+        assert!(!format!("{:?}", arm_client).contains("some-token"));
+        Ok(())
+    }
+
+    #[test]
+    fn get_events_endpoint_and_sku_should_parse_resource(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let arm_client = ArmClient::new(
+            "some-subscription",
+            "some-group",
+            "cool-iot-hub",
+            "some-token",
+        )
+        .with_transport(resource_transport());
+
+        let sku = futures::executor::block_on(arm_client.get_sku())?;
+        assert_eq!(sku.name, crate::tier::HubTier::Standard2);
+        assert_eq!(sku.limits().twin_operations_per_second(), 480);
+
+        let endpoint = futures::executor::block_on(arm_client.get_events_endpoint())?;
+        assert_eq!(endpoint.path, "cool-iot-hub");
+        assert_eq!(endpoint.partition_count, 2);
+        assert_eq!(endpoint.partition_ids, vec!["0", "1"]);
+        assert_eq!(
+            endpoint.endpoint,
+            "sb://iothub-ns-cool-iot-1234.servicebus.windows.net/"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,747 @@
+//! The blocking module contains synchronous wrappers around the
+//! asynchronous IoT Hub API. The wrappers drive the asynchronous
+//! implementation on an internal runtime, so no async runtime is
+//! needed by the caller.
+//!
+//! The blocking managers wrap the operations on a single twin, identity,
+//! configuration or feedback batch. Streams and operations on many devices at
+//! the same time, such as watching twins or provisioning from a directory, are
+//! left out, because they are built on running futures concurrently. They can
+//! be used through the asynchronous API of [`IoTHubServiceBlocking::iothub_service`].
+//!
+//! This module is only available when the `blocking` feature is enabled.
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::configuration::{ApplyResult, Configuration, ConvergenceReport};
+use crate::directmethod::{DirectMethod, DirectMethodResponse};
+use crate::error::BuilderError;
+use crate::messaging::FeedbackBatch;
+use crate::query::{Query, QueryBuilder};
+use crate::registry::{DeviceIdentity, IfMatch, ModuleIdentity};
+use crate::response::Response;
+use crate::twin::{DesiredTwin, DeviceTwin, FullTwinContent, ModuleTwin, Status};
+use crate::{IoTHubService, ModulesContent};
+
+/// The IoTHubServiceBlocking is the synchronous counterpart of the IoTHubService
+pub struct IoTHubServiceBlocking {
+    iothub_service: IoTHubService,
+    runtime: Mutex<Runtime>,
+}
+
+impl IoTHubServiceBlocking {
+    /// Create a new IoTHubServiceBlocking from an existing IoTHubService
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::blocking::IoTHubServiceBlocking;
+    ///
+    /// let iothub_service = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>");
+    /// let iothub = IoTHubServiceBlocking::new(iothub_service).expect("Failed to create the runtime");
+    /// ```
+    pub fn new(
+        iothub_service: IoTHubService,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let runtime = Builder::new().basic_scheduler().enable_all().build()?;
+        Ok(Self {
+            iothub_service,
+            runtime: Mutex::new(runtime),
+        })
+    }
+
+    /// Create a new IoTHubServiceBlocking based on a given connection string
+    ///
+    /// See [`IoTHubService::from_connection_string`] for more information.
+    pub fn from_connection_string<S>(
+        connection_string: S,
+        expires_in_seconds: i64,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsRef<str>,
+    {
+        Self::new(IoTHubService::from_connection_string(
+            connection_string,
+            expires_in_seconds,
+        )?)
+    }
+
+    /// Create a new IoTHubServiceBlocking based on a given IoT Hub name and a private key
+    ///
+    /// See [`IoTHubService::from_private_key`] for more information.
+    pub fn from_private_key<S, T>(
+        iothub_name: S,
+        private_key: T,
+        expires_in_seconds: i64,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: AsRef<str>,
+    {
+        Self::new(IoTHubService::from_private_key(
+            iothub_name,
+            private_key,
+            expires_in_seconds,
+        )?)
+    }
+
+    /// Get the wrapped IoTHubService
+    pub fn iothub_service(&self) -> &IoTHubService {
+        &self.iothub_service
+    }
+
+    /// Run a future to completion on the internal runtime
+    fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        let mut runtime = match self.runtime.lock() {
+            Ok(runtime) => runtime,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        runtime.block_on(future)
+    }
+
+    /// Get a blocking twin manager
+    pub fn twin_manager(&self) -> TwinManagerBlocking<'_> {
+        TwinManagerBlocking { iothub: self }
+    }
+
+    /// Get a blocking registry manager
+    pub fn registry_manager(&self) -> RegistryManagerBlocking<'_> {
+        RegistryManagerBlocking { iothub: self }
+    }
+
+    /// Get a blocking configuration manager
+    pub fn configuration_manager(&self) -> ConfigurationManagerBlocking<'_> {
+        ConfigurationManagerBlocking { iothub: self }
+    }
+
+    /// Get a blocking messaging manager
+    pub fn messaging_manager(&self) -> MessagingManagerBlocking<'_> {
+        MessagingManagerBlocking { iothub: self }
+    }
+
+    /// Create a new blocking device method
+    pub fn create_device_method<S, T>(
+        &self,
+        device_id: S,
+        method_name: T,
+        response_time_out: u64,
+        connect_time_out: u64,
+    ) -> Result<DirectMethodBlocking<'_>, BuilderError>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        Ok(DirectMethodBlocking {
+            iothub: self,
+            direct_method: self.iothub_service.create_device_method(
+                device_id,
+                method_name,
+                response_time_out,
+                connect_time_out,
+            )?,
+        })
+    }
+
+    /// Create a new blocking module method
+    pub fn create_module_method<S, T, U>(
+        &self,
+        device_id: S,
+        module_id: T,
+        method_name: U,
+        response_time_out: u64,
+        connect_time_out: u64,
+    ) -> Result<DirectMethodBlocking<'_>, BuilderError>
+    where
+        S: Into<String>,
+        T: Into<String>,
+        U: Into<String>,
+    {
+        Ok(DirectMethodBlocking {
+            iothub: self,
+            direct_method: self.iothub_service.create_module_method(
+                device_id,
+                module_id,
+                method_name,
+                response_time_out,
+                connect_time_out,
+            )?,
+        })
+    }
+
+    /// Create a new blocking device method with the default response and connect time outs
+    pub fn create_device_method_with_defaults<S, T>(
+        &self,
+        device_id: S,
+        method_name: T,
+    ) -> Result<DirectMethodBlocking<'_>, BuilderError>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        Ok(DirectMethodBlocking {
+            iothub: self,
+            direct_method: self
+                .iothub_service
+                .create_device_method_with_defaults(device_id, method_name)?,
+        })
+    }
+
+    /// Create a new blocking module method with the default response and connect time outs
+    pub fn create_module_method_with_defaults<S, T, U>(
+        &self,
+        device_id: S,
+        module_id: T,
+        method_name: U,
+    ) -> Result<DirectMethodBlocking<'_>, BuilderError>
+    where
+        S: Into<String>,
+        T: Into<String>,
+        U: Into<String>,
+    {
+        Ok(DirectMethodBlocking {
+            iothub: self,
+            direct_method: self.iothub_service.create_module_method_with_defaults(
+                device_id,
+                module_id,
+                method_name,
+            )?,
+        })
+    }
+
+    /// Create a new IoT Hub query, which can be executed with `execute_query`
+    ///
+    /// # Example
+    /// ```no_run
+    /// use azure_iothub_service::blocking::IoTHubServiceBlocking;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubServiceBlocking::from_connection_string(connection_string, 3600)
+    ///     .expect("Failed to create the IoTHubServiceBlocking!");
+    /// let query = iothub.build_query()
+    ///     .select("*")
+    ///     .from("devices")
+    ///     .build()
+    ///     .expect("Failed to build the query");
+    /// let result = iothub.execute_query(query).expect("Failed to execute the query");
+    /// ```
+    pub fn build_query(&self) -> QueryBuilder {
+        self.iothub_service.build_query()
+    }
+
+    /// Execute an IoT Hub query
+    pub fn execute_query(
+        &self,
+        query: Query,
+    ) -> Result<Response<serde_json::Value>, Box<dyn std::error::Error + Send + Sync>> {
+        self.block_on(query.execute())
+    }
+
+    /// Apply a new modules configuration on a given edge device
+    pub fn apply_modules_configuration<S>(
+        &self,
+        device_id: S,
+        modules_content: &ModulesContent,
+    ) -> Result<Response<ApplyResult>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        self.block_on(
+            self.iothub_service
+                .apply_modules_configuration(device_id, modules_content),
+        )
+    }
+
+    /// Apply a new modules configuration on a given edge device and wait until the deployment converged
+    pub fn apply_modules_configuration_and_wait<S>(
+        &self,
+        device_id: S,
+        modules_content: &ModulesContent,
+        timeout: Duration,
+    ) -> Result<Response<ConvergenceReport>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        self.block_on(self.iothub_service.apply_modules_configuration_and_wait(
+            device_id,
+            modules_content,
+            timeout,
+        ))
+    }
+}
+
+/// The TwinManagerBlocking is the synchronous counterpart of the TwinManager
+pub struct TwinManagerBlocking<'a> {
+    iothub: &'a IoTHubServiceBlocking,
+}
+
+impl<'a> TwinManagerBlocking<'a> {
+    /// Get the twin of a device
+    pub fn get_device_twin<T>(
+        &self,
+        device_id: T,
+    ) -> Result<Response<DeviceTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: Into<String>,
+    {
+        let twin_manager = self.iothub.iothub_service.twin_manager();
+        self.iothub
+            .block_on(twin_manager.get_device_twin(device_id))
+    }
+
+    /// Get the twin of a module
+    pub fn get_module_twin<S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+    ) -> Result<Response<ModuleTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let twin_manager = self.iothub.iothub_service.twin_manager();
+        self.iothub
+            .block_on(twin_manager.get_module_twin(device_id, module_id))
+    }
+
+    /// Get the twin of a device with its desired and reported properties deserialized
+    /// into the given types
+    pub fn get_typed_device_twin<D, R, S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<DeviceTwin<D, R>>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        D: DeserializeOwned + Default,
+        R: DeserializeOwned + Default,
+        S: Into<String>,
+    {
+        let twin_manager = self.iothub.iothub_service.twin_manager();
+        self.iothub
+            .block_on(twin_manager.get_typed_device_twin(device_id))
+    }
+
+    /// Get the twin of a module with its desired and reported properties deserialized
+    /// into the given types
+    pub fn get_typed_module_twin<D, R, S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+    ) -> Result<Response<ModuleTwin<D, R>>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        D: DeserializeOwned + Default,
+        R: DeserializeOwned + Default,
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let twin_manager = self.iothub.iothub_service.twin_manager();
+        self.iothub
+            .block_on(twin_manager.get_typed_module_twin(device_id, module_id))
+    }
+
+    /// Update the twin of a device
+    pub fn update_device_twin<T>(
+        &self,
+        device_id: T,
+        desired_twin: DesiredTwin,
+    ) -> Result<Response<DeviceTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: Into<String>,
+    {
+        let twin_manager = self.iothub.iothub_service.twin_manager();
+        self.iothub
+            .block_on(twin_manager.update_device_twin(device_id, desired_twin))
+    }
+
+    /// Update the twin of a module
+    pub fn update_module_twin<S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+        desired_twin: DesiredTwin,
+    ) -> Result<Response<ModuleTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let twin_manager = self.iothub.iothub_service.twin_manager();
+        self.iothub
+            .block_on(twin_manager.update_module_twin(device_id, module_id, desired_twin))
+    }
+
+    /// Replace the twin of a device
+    pub fn replace_device_twin<T>(
+        &self,
+        device_id: T,
+        twin_content: &FullTwinContent,
+    ) -> Result<Response<DeviceTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: Into<String>,
+    {
+        let twin_manager = self.iothub.iothub_service.twin_manager();
+        self.iothub
+            .block_on(twin_manager.replace_device_twin(device_id, twin_content))
+    }
+
+    /// Replace the twin of a module
+    pub fn replace_module_twin<S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+        twin_content: &FullTwinContent,
+    ) -> Result<Response<ModuleTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let twin_manager = self.iothub.iothub_service.twin_manager();
+        self.iothub
+            .block_on(twin_manager.replace_module_twin(device_id, module_id, twin_content))
+    }
+}
+
+/// The RegistryManagerBlocking is the synchronous counterpart of the RegistryManager
+pub struct RegistryManagerBlocking<'a> {
+    iothub: &'a IoTHubServiceBlocking,
+}
+
+impl<'a> RegistryManagerBlocking<'a> {
+    /// Get the identity of a device
+    pub fn get_device_identity<S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub
+            .block_on(registry_manager.get_device_identity(device_id))
+    }
+
+    /// Create the identity of a new device
+    pub fn create_device_identity(
+        &self,
+        device_identity: &DeviceIdentity,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>> {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub
+            .block_on(registry_manager.create_device_identity(device_identity))
+    }
+
+    /// Update the identity of a device
+    pub fn update_device_identity(
+        &self,
+        device_identity: &DeviceIdentity,
+        if_match: IfMatch,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>> {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub
+            .block_on(registry_manager.update_device_identity(device_identity, if_match))
+    }
+
+    /// Delete the identity of a device
+    pub fn delete_device_identity<S>(
+        &self,
+        device_id: S,
+        if_match: IfMatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub
+            .block_on(registry_manager.delete_device_identity(device_id, if_match))
+    }
+
+    /// Get the identities of all modules of a device
+    pub fn get_module_identities<S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<Vec<ModuleIdentity>>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub
+            .block_on(registry_manager.get_module_identities(device_id))
+    }
+
+    /// Get the identity of a module
+    pub fn get_module_identity<S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+    ) -> Result<Response<ModuleIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub
+            .block_on(registry_manager.get_module_identity(device_id, module_id))
+    }
+
+    /// Update the identity of a module
+    pub fn update_module_identity(
+        &self,
+        module_identity: &ModuleIdentity,
+        if_match: IfMatch,
+    ) -> Result<Response<ModuleIdentity>, Box<dyn std::error::Error + Send + Sync>> {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub
+            .block_on(registry_manager.update_module_identity(module_identity, if_match))
+    }
+
+    /// Delete the identity of a module
+    pub fn delete_module_identity<S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+        if_match: IfMatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub
+            .block_on(registry_manager.delete_module_identity(device_id, module_id, if_match))
+    }
+
+    /// List the twins of all edge devices
+    pub fn list_edge_devices(
+        &self,
+    ) -> Result<Response<Vec<DeviceTwin>>, Box<dyn std::error::Error + Send + Sync>> {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub.block_on(registry_manager.list_edge_devices())
+    }
+
+    /// List the twins of all devices with the given status
+    pub fn list_devices_by_status(
+        &self,
+        status: Status,
+    ) -> Result<Response<Vec<DeviceTwin>>, Box<dyn std::error::Error + Send + Sync>> {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub
+            .block_on(registry_manager.list_devices_by_status(status))
+    }
+
+    /// Disable a device with the given status reason
+    pub fn disable_device<S, T>(
+        &self,
+        device_id: S,
+        reason: T,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub
+            .block_on(registry_manager.disable_device(device_id, reason))
+    }
+
+    /// Enable a device and clear its status reason
+    pub fn enable_device<S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let registry_manager = self.iothub.iothub_service.registry_manager();
+        self.iothub
+            .block_on(registry_manager.enable_device(device_id))
+    }
+}
+
+/// The ConfigurationManagerBlocking is the synchronous counterpart of the ConfigurationManager
+pub struct ConfigurationManagerBlocking<'a> {
+    iothub: &'a IoTHubServiceBlocking,
+}
+
+impl<'a> ConfigurationManagerBlocking<'a> {
+    /// Get a configuration
+    pub fn get_configuration<S>(
+        &self,
+        configuration_id: S,
+    ) -> Result<Response<Configuration>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let configuration_manager = self.iothub.iothub_service.configuration_manager();
+        self.iothub
+            .block_on(configuration_manager.get_configuration(configuration_id))
+    }
+
+    /// Get at most `max_count` configurations
+    pub fn get_configurations(
+        &self,
+        max_count: u32,
+    ) -> Result<Response<Vec<Configuration>>, Box<dyn std::error::Error + Send + Sync>> {
+        let configuration_manager = self.iothub.iothub_service.configuration_manager();
+        self.iothub
+            .block_on(configuration_manager.get_configurations(max_count))
+    }
+
+    /// Create a new configuration
+    pub fn create_configuration(
+        &self,
+        configuration: &Configuration,
+    ) -> Result<Response<Configuration>, Box<dyn std::error::Error + Send + Sync>> {
+        let configuration_manager = self.iothub.iothub_service.configuration_manager();
+        self.iothub
+            .block_on(configuration_manager.create_configuration(configuration))
+    }
+
+    /// Update an existing configuration
+    pub fn update_configuration(
+        &self,
+        configuration: &Configuration,
+    ) -> Result<Response<Configuration>, Box<dyn std::error::Error + Send + Sync>> {
+        let configuration_manager = self.iothub.iothub_service.configuration_manager();
+        self.iothub
+            .block_on(configuration_manager.update_configuration(configuration))
+    }
+
+    /// Delete a configuration
+    pub fn delete_configuration<S>(
+        &self,
+        configuration_id: S,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let configuration_manager = self.iothub.iothub_service.configuration_manager();
+        self.iothub
+            .block_on(configuration_manager.delete_configuration(configuration_id))
+    }
+}
+
+/// The MessagingManagerBlocking is the synchronous counterpart of the MessagingManager
+pub struct MessagingManagerBlocking<'a> {
+    iothub: &'a IoTHubServiceBlocking,
+}
+
+impl<'a> MessagingManagerBlocking<'a> {
+    /// Receive the next batch of feedback records, if there is any
+    pub fn receive_feedback(
+        &self,
+    ) -> Result<Response<Option<FeedbackBatch>>, Box<dyn std::error::Error + Send + Sync>> {
+        let messaging_manager = self.iothub.iothub_service.messaging_manager();
+        self.iothub.block_on(messaging_manager.receive_feedback())
+    }
+
+    /// Complete a batch of feedback records, removing it from the feedback queue
+    pub fn complete_feedback(
+        &self,
+        batch: &FeedbackBatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>> {
+        let messaging_manager = self.iothub.iothub_service.messaging_manager();
+        self.iothub
+            .block_on(messaging_manager.complete_feedback(batch))
+    }
+
+    /// Abandon a batch of feedback records, so it is delivered again
+    pub fn abandon_feedback(
+        &self,
+        batch: &FeedbackBatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>> {
+        let messaging_manager = self.iothub.iothub_service.messaging_manager();
+        self.iothub
+            .block_on(messaging_manager.abandon_feedback(batch))
+    }
+
+    /// Wait for the next batch of feedback records
+    ///
+    /// See [`MessagingManager::wait_for_feedback`](crate::messaging::MessagingManager::wait_for_feedback)
+    /// for more information.
+    pub fn wait_for_feedback(
+        &self,
+        timeout: Duration,
+    ) -> Result<Response<FeedbackBatch>, Box<dyn std::error::Error + Send + Sync>> {
+        let messaging_manager = self.iothub.iothub_service.messaging_manager();
+        self.iothub
+            .block_on(messaging_manager.wait_for_feedback(timeout))
+    }
+}
+
+/// The DirectMethodBlocking is the synchronous counterpart of the DirectMethod
+pub struct DirectMethodBlocking<'a> {
+    iothub: &'a IoTHubServiceBlocking,
+    direct_method: DirectMethod,
+}
+
+impl<'a> DirectMethodBlocking<'a> {
+    /// Invoke the DirectMethod
+    ///
+    /// See [`DirectMethod::invoke`] for more information.
+    pub fn invoke<T: DeserializeOwned>(
+        &self,
+        payload: serde_json::Value,
+    ) -> Result<Response<DirectMethodResponse<T>>, Box<dyn std::error::Error + Send + Sync>> {
+        self.iothub.block_on(self.direct_method.invoke(payload))
+    }
+
+    /// Invoke the DirectMethod without converting the payload of the response
+    ///
+    /// See [`DirectMethod::invoke_raw`] for more information.
+    pub fn invoke_raw(
+        &self,
+        payload: serde_json::Value,
+    ) -> Result<
+        Response<DirectMethodResponse<serde_json::Value>>,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        self.iothub.block_on(self.direct_method.invoke_raw(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::blocking::IoTHubServiceBlocking;
+    use crate::testing::mocktransport::{json_response, MockTransport};
+    use crate::IoTHubService;
+
+    #[test]
+    fn blocking_service_should_wrap_iothub_service(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+        let iothub = IoTHubServiceBlocking::from_connection_string(connection_string, 3600)?;
+        assert_eq!(iothub.iothub_service().iothub_name(), "cool-iot-hub");
+
+        let query = iothub.build_query().select("*").from("devices").build()?;
+        drop(query);
+        Ok(())
+    }
+
+    #[test]
+    fn blocking_registry_manager_should_wait_for_response(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // The transport returns the identity of the requested device
+        let transport = MockTransport::new(|request, _| {
+            json_response(&json!({
+                "deviceId": request.uri().path().trim_start_matches("/devices/"),
+                "etag": "AAAAAAAAAAE=",
+                "status": "enabled"
+            }))
+        });
+        let iothub = IoTHubServiceBlocking::new(
+            IoTHubService::from_sas_token("test", "test").with_transport(transport),
+        )?;
+        let device_identity = iothub
+            .registry_manager()
+            .get_device_identity("some-device")?;
+        assert_eq!(device_identity.device_id, "some-device");
+        assert_eq!(device_identity.etag, "AAAAAAAAAAE=");
+        Ok(())
+    }
+}
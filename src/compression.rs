@@ -0,0 +1,126 @@
+//! The compression module decompresses the responses of the IoT Hub, which
+//! are compressed when the request accepts a compressed encoding. This is only
+//! used for queries and twins, as their responses can be large.
+use std::io::Read;
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use http::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH};
+
+use crate::transport::{HttpRequest, HttpResponse};
+
+/// The encodings that are accepted for compressed responses
+const ACCEPTED_ENCODINGS: &str = "gzip, deflate";
+
+/// Accept a compressed response to the request
+pub(crate) fn accept_compressed(request: &mut HttpRequest) {
+    request.headers_mut().insert(
+        ACCEPT_ENCODING,
+        HeaderValue::from_static(ACCEPTED_ENCODINGS),
+    );
+}
+
+/// Decompress the body of a response according to its `Content-Encoding`
+pub(crate) fn decompress(
+    mut response: HttpResponse,
+) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let encoding = match response.headers().get(CONTENT_ENCODING) {
+        Some(encoding) => encoding.to_str()?.trim().to_ascii_lowercase(),
+        None => return Ok(response),
+    };
+
+    let body = response.body();
+    let mut decompressed = Vec::with_capacity(body.len() * 4);
+    match encoding.as_str() {
+        "identity" => return Ok(response),
+        "gzip" | "x-gzip" => {
+            GzDecoder::new(&body[..]).read_to_end(&mut decompressed)?;
+        }
+        // The deflate encoding should be wrapped in zlib, but some servers send raw deflate
+        "deflate" => {
+            if ZlibDecoder::new(&body[..])
+                .read_to_end(&mut decompressed)
+                .is_err()
+            {
+                decompressed.clear();
+                DeflateDecoder::new(&body[..]).read_to_end(&mut decompressed)?;
+            }
+        }
+        _ => {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unsupported content encoding {}", encoding),
+            )))
+        }
+    }
+
+    response.headers_mut().remove(CONTENT_ENCODING);
+    response.headers_mut().remove(CONTENT_LENGTH);
+    *response.body_mut() = decompressed;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    use crate::testing::mocktransport::MockTransport;
+    use crate::IoTHubService;
+
+    fn load_json_file(file_name: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/");
+        d.push(file_name);
+
+        Ok(std::fs::read_to_string(d)?)
+    }
+
+    #[test]
+    fn compressed_responses_should_be_decompressed(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Queries are answered with deflate and other requests with gzip
+        let twin = load_json_file("twin/devicetwin_deserialization.json")?;
+        let transport = MockTransport::new(move |request, _| {
+            if request.uri().path() == "/devices/query" {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(format!("[{}]", twin).as_bytes())?;
+                Ok(http::Response::builder()
+                    .header("Content-Encoding", "deflate")
+                    .body(encoder.finish()?)?)
+            } else {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(twin.as_bytes())?;
+                Ok(http::Response::builder()
+                    .header("Content-Encoding", "gzip")
+                    .body(encoder.finish()?)?)
+            }
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let device_twin =
+            futures::executor::block_on(iothub.twin_manager().get_device_twin("some-device"))?;
+        assert_eq!(device_twin.device_id, "some-device");
+
+        let query = iothub.build_query().select("*").from("devices").build()?;
+        let result = futures::executor::block_on(query.execute())?;
+        assert_eq!(result[0]["deviceId"], "some-device");
+
+        for request in transport.requests().iter() {
+            assert_eq!(request.headers()["Accept-Encoding"], "gzip, deflate");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn decompress_should_reject_unknown_encodings() {
+        let response = http::Response::builder()
+            .header("Content-Encoding", "br")
+            .body(b"{}".to_vec())
+            .unwrap();
+        assert!(super::decompress(response).is_err());
+    }
+}
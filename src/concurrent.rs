@@ -0,0 +1,235 @@
+//! The concurrent module contains helpers for running a bounded number of
+//! futures at the same time, or a few different futures at the same time,
+//! without depending on a specific async runtime.
+use std::collections::HashSet;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A future that completes with the output of the first of the running futures that completes
+///
+/// The completed future is removed from the running futures. When there are no running
+/// futures the output is `None`.
+pub(crate) struct SelectNext<'a, F> {
+    running: &'a mut Vec<Pin<Box<F>>>,
+}
+
+/// Wait for the first of the running futures to complete
+pub(crate) fn select_next<F>(running: &mut Vec<Pin<Box<F>>>) -> SelectNext<'_, F> {
+    SelectNext { running }
+}
+
+impl<'a, F> Future for SelectNext<'a, F>
+where
+    F: Future,
+{
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.running.is_empty() {
+            return Poll::Ready(None);
+        }
+
+        for index in 0..self.running.len() {
+            if let Poll::Ready(output) = self.running[index].as_mut().poll(cx) {
+                self.running.swap_remove(index);
+                return Poll::Ready(Some(output));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Run a future for every item, with at most `max_concurrency` futures running at the same time
+///
+/// The futures complete with a key and a result, which are returned in the order the
+/// futures complete. Every item gets its own result, also when items share a key.
+pub(crate) async fn for_each_bounded<I, F, Fut, K, R>(
+    items: I,
+    max_concurrency: usize,
+    f: F,
+) -> Vec<(K, R)>
+where
+    I: IntoIterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: Future<Output = (K, R)>,
+{
+    for_each_bounded_with_progress(items, max_concurrency, f, |_, _, _| {}).await
+}
+
+/// Run a future for every item like [`for_each_bounded`], calling `progress` with the
+/// key, the result and the number of completed futures whenever a future completes
+pub(crate) async fn for_each_bounded_with_progress<I, F, Fut, K, R, P>(
+    items: I,
+    max_concurrency: usize,
+    mut f: F,
+    mut progress: P,
+) -> Vec<(K, R)>
+where
+    I: IntoIterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: Future<Output = (K, R)>,
+    P: FnMut(&K, &R, usize),
+{
+    let mut items = items.into_iter();
+    let mut running = Vec::new();
+    let mut results = Vec::new();
+    loop {
+        while running.len() < max_concurrency.max(1) {
+            match items.next() {
+                Some(item) => running.push(Box::pin(f(item))),
+                None => break,
+            }
+        }
+
+        match select_next(&mut running).await {
+            Some((key, result)) => {
+                progress(&key, &result, results.len() + 1);
+                results.push((key, result));
+            }
+            None => break,
+        }
+    }
+    results
+}
+
+/// Remove the duplicates from the items, keeping the first occurrence of every item
+pub(crate) fn unique<I, T>(items: I) -> Vec<T>
+where
+    I: IntoIterator<Item = T>,
+    T: Eq + Hash + Clone,
+{
+    let mut seen = HashSet::new();
+    items
+        .into_iter()
+        .filter(|item| seen.insert(item.clone()))
+        .collect()
+}
+
+/// A future that completes with the outputs of three futures, which run at the same time
+pub(crate) struct Join3<A, B, C>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+{
+    a: Pin<Box<A>>,
+    b: Pin<Box<B>>,
+    c: Pin<Box<C>>,
+    a_output: Option<A::Output>,
+    b_output: Option<B::Output>,
+    c_output: Option<C::Output>,
+}
+
+/// Run three futures at the same time and wait for all of them to complete
+pub(crate) fn join3<A, B, C>(a: A, b: B, c: C) -> Join3<A, B, C>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+{
+    Join3 {
+        a: Box::pin(a),
+        b: Box::pin(b),
+        c: Box::pin(c),
+        a_output: None,
+        b_output: None,
+        c_output: None,
+    }
+}
+
+// The outputs are never pinned, so the join can be moved regardless of its outputs
+impl<A, B, C> Unpin for Join3<A, B, C>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+{
+}
+
+impl<A, B, C> Future for Join3<A, B, C>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+{
+    type Output = (A::Output, B::Output, C::Output);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if this.a_output.is_none() {
+            if let Poll::Ready(output) = this.a.as_mut().poll(cx) {
+                this.a_output = Some(output);
+            }
+        }
+        if this.b_output.is_none() {
+            if let Poll::Ready(output) = this.b.as_mut().poll(cx) {
+                this.b_output = Some(output);
+            }
+        }
+        if this.c_output.is_none() {
+            if let Poll::Ready(output) = this.c.as_mut().poll(cx) {
+                this.c_output = Some(output);
+            }
+        }
+
+        match (
+            this.a_output.take(),
+            this.b_output.take(),
+            this.c_output.take(),
+        ) {
+            (Some(a), Some(b), Some(c)) => Poll::Ready((a, b, c)),
+            (a, b, c) => {
+                this.a_output = a;
+                this.b_output = b;
+                this.c_output = c;
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use crate::concurrent::{for_each_bounded_with_progress, unique};
+    use crate::delay::delay_for;
+
+    #[test]
+    fn for_each_bounded_should_limit_running_futures() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let mut completed = Vec::new();
+        let results = futures::executor::block_on(for_each_bounded_with_progress(
+            1..=10,
+            3,
+            |index: u64| {
+                let running = &running;
+                let max_running = &max_running;
+                async move {
+                    let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now_running, Ordering::SeqCst);
+                    delay_for(Duration::from_millis(index)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    // Every key is shared by two items
+                    (index % 5, index * 2)
+                }
+            },
+            |_, _, count| completed.push(count),
+        ));
+
+        assert_eq!(results.len(), 10);
+        assert!(results.contains(&(2, 4)));
+        assert!(results.contains(&(2, 14)));
+        assert_eq!(max_running.load(Ordering::SeqCst), 3);
+        assert_eq!(completed, (1..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unique_should_keep_first_occurrence() {
+        assert_eq!(unique(vec!["b", "a", "b", "c", "a"]), vec!["b", "a", "c"]);
+    }
+}
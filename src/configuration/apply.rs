@@ -0,0 +1,206 @@
+//! The apply module contains the result of applying a modules configuration to an
+//! edge device, which tells what was sent and what the IoT Hub warned about.
+use crate::configuration::ModulesContent;
+
+/// The names of the fields of a response body that can contain validation warnings
+const WARNINGS_FIELDS: [&str; 2] = ["warnings", "Warnings"];
+
+/// The names of the fields of a warning object that contain its description
+const MESSAGE_FIELDS: [&str; 2] = ["message", "Message"];
+
+/// A module of the modules configuration that was applied to an edge device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedModule {
+    module_id: String,
+    image: String,
+    version: Option<String>,
+}
+
+impl AppliedModule {
+    /// Get the id of the module, the system modules are named `$edgeAgent` and `$edgeHub`
+    pub fn module_id(&self) -> &String {
+        &self.module_id
+    }
+
+    /// Get the image of the module
+    pub fn image(&self) -> &String {
+        &self.image
+    }
+
+    /// Get the version of the module, which the system modules do not have
+    pub fn version(&self) -> &Option<String> {
+        &self.version
+    }
+}
+
+impl std::fmt::Display for AppliedModule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.module_id, self.image)
+    }
+}
+
+/// The result of applying a modules configuration to an edge device
+///
+/// # Example
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// use azure_iothub_service::{IoTHubService, ModulesContentBuilder};
+///
+/// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+/// # let modules_content = ModulesContentBuilder::new().build()?;
+/// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+/// let result = iothub
+///     .apply_modules_configuration("some-device", &modules_content)
+///     .await?;
+/// for module in result.modules() {
+///     println!("Applied {}", module);
+/// }
+/// for warning in result.warnings() {
+///     println!("Warning: {}", warning);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApplyResult {
+    modules: Vec<AppliedModule>,
+    warnings: Vec<String>,
+}
+
+impl ApplyResult {
+    /// Create the result of applying a modules configuration from the body of the response
+    pub(crate) fn new(modules_content: &ModulesContent, body: &[u8]) -> Self {
+        let edge_agent = modules_content.edge_agent();
+        let system_modules = edge_agent.system_modules();
+        let mut modules = vec![
+            AppliedModule {
+                module_id: "$edgeAgent".to_string(),
+                image: system_modules.edge_agent().settings().image().clone(),
+                version: None,
+            },
+            AppliedModule {
+                module_id: "$edgeHub".to_string(),
+                image: system_modules.edge_hub().settings().image().clone(),
+                version: None,
+            },
+        ];
+
+        let mut custom_modules: Vec<AppliedModule> = edge_agent
+            .modules()
+            .iter()
+            .map(|(module_id, module)| AppliedModule {
+                module_id: module_id.clone(),
+                image: module.settings.image().clone(),
+                version: Some(module.version.clone()),
+            })
+            .collect();
+        custom_modules.sort_by(|a, b| a.module_id.cmp(&b.module_id));
+        modules.extend(custom_modules);
+
+        ApplyResult {
+            modules,
+            warnings: parse_warnings(body),
+        }
+    }
+
+    /// Get the modules that were sent, the system modules first and the other modules ordered by id
+    pub fn modules(&self) -> &Vec<AppliedModule> {
+        &self.modules
+    }
+
+    /// Get the validation warnings the IoT Hub returned for the configuration
+    pub fn warnings(&self) -> &Vec<String> {
+        &self.warnings
+    }
+
+    /// Check whether the IoT Hub returned validation warnings
+    pub fn has_warnings(&self) -> bool {
+        !self.warnings.is_empty()
+    }
+}
+
+/// Get the validation warnings from the body of a successful response
+///
+/// The IoT Hub usually returns an empty body, so bodies without warnings,
+/// including bodies that are not JSON, result in no warnings.
+fn parse_warnings(body: &[u8]) -> Vec<String> {
+    let value: serde_json::Value = match serde_json::from_slice(body) {
+        Ok(value) => value,
+        Err(_) => return Vec::new(),
+    };
+
+    let warnings = match &value {
+        serde_json::Value::Array(warnings) => warnings,
+        serde_json::Value::Object(object) => {
+            match WARNINGS_FIELDS
+                .iter()
+                .find_map(|field| object.get(*field).and_then(serde_json::Value::as_array))
+            {
+                Some(warnings) => warnings,
+                None => return Vec::new(),
+            }
+        }
+        _ => return Vec::new(),
+    };
+
+    warnings
+        .iter()
+        .map(|warning| match warning {
+            serde_json::Value::String(warning) => warning.clone(),
+            warning => MESSAGE_FIELDS
+                .iter()
+                .find_map(|field| warning.get(*field).and_then(serde_json::Value::as_str))
+                .map_or_else(|| warning.to_string(), String::from),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_warnings, ApplyResult};
+    use crate::configuration::{EdgeModuleBuilder, ModulesContentBuilder, RestartPolicy, Status};
+
+    #[test]
+    fn apply_result_should_list_modules_and_warnings(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let module = |module_id: &str| {
+            EdgeModuleBuilder::new()
+                .module_id(module_id)
+                .version("1.0")
+                .status(Status::Running)
+                .restart_policy(RestartPolicy::Always)
+                .image(format!("someacr.azurecr.io/{}:1.0", module_id))
+                .build()
+        };
+        let modules_content = ModulesContentBuilder::new()
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.2")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.2")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(7200)
+            .edge_module(module("sensor")?)
+            .edge_module(module("filter")?)
+            .build()?;
+
+        let result = ApplyResult::new(&modules_content, b"");
+        let modules: Vec<String> = result.modules().iter().map(|m| m.to_string()).collect();
+        assert_eq!(
+            modules,
+            vec![
+                "$edgeAgent (mcr.microsoft.com/azureiotedge-agent:1.2)",
+                "$edgeHub (mcr.microsoft.com/azureiotedge-hub:1.2)",
+                "filter (someacr.azurecr.io/filter:1.0)",
+                "sensor (someacr.azurecr.io/sensor:1.0)",
+            ]
+        );
+        assert_eq!(result.modules()[2].version().as_deref(), Some("1.0"));
+        assert!(!result.has_warnings());
+
+        assert_eq!(
+            parse_warnings(br#"{"warnings": ["unused route", {"message": "large twin"}]}"#),
+            vec!["unused route", "large twin"]
+        );
+        assert!(parse_warnings(b"OK").is_empty());
+        assert!(parse_warnings(br#"{"status": "ok"}"#).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! The conflict module is used for finding configurations with the same
+//! priority that can target the same devices, in which case the IoT Hub
+//! applies only one of them without telling which.
+use std::collections::HashMap;
+
+use crate::configuration::Configuration;
+
+/// The target condition that targets all devices
+const ALL_DEVICES_CONDITION: &str = "*";
+
+/// Two configurations with the same priority that can apply to the same devices
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriorityConflict {
+    first_configuration_id: String,
+    second_configuration_id: String,
+    priority: i32,
+    overlapping_devices: Option<u64>,
+}
+
+impl PriorityConflict {
+    /// Get the id of the first configuration of the conflict
+    pub fn first_configuration_id(&self) -> &String {
+        &self.first_configuration_id
+    }
+
+    /// Get the id of the second configuration of the conflict
+    pub fn second_configuration_id(&self) -> &String {
+        &self.second_configuration_id
+    }
+
+    /// Get the priority both configurations have
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Get the number of devices matching both target conditions, if they were counted
+    pub fn overlapping_devices(&self) -> Option<u64> {
+        self.overlapping_devices
+    }
+
+    /// Set the number of devices matching both target conditions
+    pub(crate) fn set_overlapping_devices(&mut self, overlapping_devices: u64) {
+        self.overlapping_devices = Some(overlapping_devices);
+    }
+}
+
+/// Find the pairs of configurations that have the same priority and set the same content
+///
+/// Edge deployments conflict with other edge deployments, while device configurations
+/// only conflict when they set overlapping twin paths. Whether the target conditions
+/// actually overlap depends on the devices in the IoT Hub, so the overlapping devices
+/// are not counted. Configurations without a target condition target no devices and
+/// are skipped.
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::configuration::conflict::find_potential_conflicts;
+/// use azure_iothub_service::configuration::DeviceConfigurationBuilder;
+/// use serde_json::json;
+///
+/// let configuration = |id: &str, target_condition: &str| {
+///     DeviceConfigurationBuilder::new()
+///         .id(id)
+///         .priority(10)
+///         .target_condition(target_condition)
+///         .desired_properties("telemetry", json!({ "interval": 30 }))
+///         .build()
+///         .expect("Failed to build the configuration")
+/// };
+///
+/// let conflicts = find_potential_conflicts(&[
+///     configuration("production", "tags.environment='production'"),
+///     configuration("building-43", "tags.location.building='43'"),
+/// ]);
+/// assert_eq!(conflicts.len(), 1);
+/// ```
+pub fn find_potential_conflicts(configurations: &[Configuration]) -> Vec<PriorityConflict> {
+    let mut conflicts = Vec::new();
+    for (index, first) in configurations.iter().enumerate() {
+        for second in &configurations[index + 1..] {
+            if first.priority == second.priority
+                && !first.target_condition.trim().is_empty()
+                && !second.target_condition.trim().is_empty()
+                && contents_overlap(first, second)
+            {
+                conflicts.push(PriorityConflict {
+                    first_configuration_id: first.id.clone(),
+                    second_configuration_id: second.id.clone(),
+                    priority: first.priority,
+                    overlapping_devices: None,
+                });
+            }
+        }
+    }
+    conflicts
+}
+
+/// Get the condition matching the devices targeted by both configurations
+///
+/// Returns `None` when both configurations target all devices.
+pub(crate) fn overlap_condition(first: &Configuration, second: &Configuration) -> Option<String> {
+    let conditions: Vec<String> = [&first.target_condition, &second.target_condition]
+        .iter()
+        .map(|condition| condition.trim())
+        .filter(|condition| *condition != ALL_DEVICES_CONDITION)
+        .map(|condition| format!("({})", condition))
+        .collect();
+
+    if conditions.is_empty() {
+        None
+    } else {
+        Some(conditions.join(" AND "))
+    }
+}
+
+/// Check whether two configurations set the same content
+fn contents_overlap(first: &Configuration, second: &Configuration) -> bool {
+    if first.is_edge_deployment() || second.is_edge_deployment() {
+        return first.is_edge_deployment() && second.is_edge_deployment();
+    }
+
+    paths_overlap(
+        &first.content.device_content,
+        &second.content.device_content,
+    ) || paths_overlap(
+        &first.content.module_content,
+        &second.content.module_content,
+    )
+}
+
+/// Check whether any of the twin paths is equal to or nested in one of the other paths
+fn paths_overlap(
+    first: &Option<HashMap<String, serde_json::Value>>,
+    second: &Option<HashMap<String, serde_json::Value>>,
+) -> bool {
+    let (first, second) = match (first, second) {
+        (Some(first), Some(second)) => (first, second),
+        _ => return false,
+    };
+
+    let is_nested =
+        |path: &str, parent: &str| path == parent || path.starts_with(&format!("{}.", parent));
+    first.keys().any(|first_path| {
+        second.keys().any(|second_path| {
+            is_nested(first_path, second_path) || is_nested(second_path, first_path)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::configuration::conflict::{find_potential_conflicts, overlap_condition};
+    use crate::configuration::{Configuration, DeviceConfigurationBuilder};
+
+    fn configuration(id: &str, priority: i32, target_condition: &str, path: &str) -> Configuration {
+        DeviceConfigurationBuilder::new()
+            .id(id)
+            .priority(priority)
+            .target_condition(target_condition)
+            .desired_properties(path, json!({ "interval": 30 }))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn find_potential_conflicts_should_match_priority_and_paths() {
+        let configurations = vec![
+            configuration("a", 10, "tags.environment='production'", "telemetry"),
+            configuration("b", 10, "*", "telemetry.interval"),
+            configuration("c", 10, "tags.environment='test'", "logging"),
+            configuration("d", 20, "tags.environment='production'", "telemetry"),
+        ];
+
+        let conflicts = find_potential_conflicts(&configurations);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].first_configuration_id(), "a");
+        assert_eq!(conflicts[0].second_configuration_id(), "b");
+        assert_eq!(conflicts[0].priority(), 10);
+        assert_eq!(conflicts[0].overlapping_devices(), None);
+
+        assert_eq!(
+            overlap_condition(&configurations[0], &configurations[1]).as_deref(),
+            Some("(tags.environment='production')")
+        );
+        assert_eq!(
+            overlap_condition(&configurations[0], &configurations[2]).as_deref(),
+            Some("(tags.environment='production') AND (tags.environment='test')")
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! The convergence module contains the report of an edge deployment,
+//! which compares the desired modules of the `$edgeAgent` twin with the
+//! modules the edge agent reports to be running.
+use std::time::Duration;
+
+use crate::twin::ModuleTwin;
+
+/// The runtime status of a module when no status is given in the deployment
+const DEFAULT_MODULE_STATUS: &str = "running";
+
+/// The status code the edge agent reports when it applied the desired properties
+const DESIRED_STATUS_OK: i64 = 200;
+
+/// The convergence of a single module of an edge deployment
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleConvergence {
+    module_name: String,
+    desired_status: String,
+    runtime_status: Option<String>,
+}
+
+impl ModuleConvergence {
+    /// Get the name of the module in the deployment, such as `edgeHub` or `SimulatedTemperatureSensor`
+    pub fn module_name(&self) -> &String {
+        &self.module_name
+    }
+
+    /// Get the desired status of the module, such as `running` or `stopped`
+    pub fn desired_status(&self) -> &String {
+        &self.desired_status
+    }
+
+    /// Get the runtime status the edge agent reports for the module, if it reports the module at all
+    pub fn runtime_status(&self) -> &Option<String> {
+        &self.runtime_status
+    }
+
+    /// Check whether the module reports its desired status
+    pub fn is_converged(&self) -> bool {
+        self.runtime_status.as_deref() == Some(self.desired_status.as_str())
+    }
+}
+
+/// The ConvergenceReport describes how far an edge device has applied its deployment
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConvergenceReport {
+    desired_version: Option<i64>,
+    reported_version: Option<i64>,
+    reported_status_code: Option<i64>,
+    modules: Vec<ModuleConvergence>,
+    elapsed: Duration,
+}
+
+impl ConvergenceReport {
+    /// Create the ConvergenceReport from the twin of the `$edgeAgent` module
+    pub(crate) fn from_edge_agent_twin(edge_agent_twin: &ModuleTwin, elapsed: Duration) -> Self {
+        let desired = &edge_agent_twin.properties.desired;
+        let reported = &edge_agent_twin.properties.reported;
+
+        let mut modules = Vec::new();
+        for (section, required_status) in &[("systemModules", true), ("modules", false)] {
+            let desired_modules = match desired[*section].as_object() {
+                Some(desired_modules) => desired_modules,
+                None => continue,
+            };
+
+            for (module_name, module) in desired_modules {
+                let desired_status = match module["status"].as_str() {
+                    Some(status) => status,
+                    // The edge agent itself has no status in the deployment
+                    None if *required_status => continue,
+                    None => DEFAULT_MODULE_STATUS,
+                };
+                modules.push(ModuleConvergence {
+                    module_name: module_name.clone(),
+                    desired_status: desired_status.to_string(),
+                    runtime_status: reported[*section][module_name]["runtimeStatus"]
+                        .as_str()
+                        .map(String::from),
+                });
+            }
+        }
+
+        ConvergenceReport {
+            desired_version: desired["$version"].as_i64(),
+            reported_version: reported["lastDesiredVersion"].as_i64(),
+            reported_status_code: reported["lastDesiredStatus"]["code"].as_i64(),
+            modules,
+            elapsed,
+        }
+    }
+
+    /// Get the version of the desired properties of the `$edgeAgent` twin
+    pub fn desired_version(&self) -> Option<i64> {
+        self.desired_version
+    }
+
+    /// Get the version of the desired properties the edge agent last applied
+    pub fn reported_version(&self) -> Option<i64> {
+        self.reported_version
+    }
+
+    /// Get the status code the edge agent reported for the last desired properties
+    pub fn reported_status_code(&self) -> Option<i64> {
+        self.reported_status_code
+    }
+
+    /// Get the convergence of each module in the deployment
+    pub fn modules(&self) -> &Vec<ModuleConvergence> {
+        &self.modules
+    }
+
+    /// Get the modules that do not report their desired status yet
+    pub fn pending_modules(&self) -> Vec<&ModuleConvergence> {
+        self.modules
+            .iter()
+            .filter(|module| !module.is_converged())
+            .collect()
+    }
+
+    /// Get the time between applying the deployment and creating the report
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Check whether the edge agent applied the latest desired properties
+    /// and all modules report their desired status
+    pub fn is_converged(&self) -> bool {
+        self.desired_version.is_some()
+            && self.desired_version == self.reported_version
+            && self.reported_status_code.unwrap_or(DESIRED_STATUS_OK) == DESIRED_STATUS_OK
+            && self.modules.iter().all(ModuleConvergence::is_converged)
+    }
+}
@@ -0,0 +1,712 @@
+//! The deviceconfiguration module contains the automatic device management (ADM)
+//! configurations, which apply desired twin properties to all devices matching
+//! a target condition.
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::ModulesContent;
+use crate::error::{BuilderError, BuilderErrorType};
+
+/// The schema version of configurations
+pub const CONFIGURATION_SCHEMA_VERSION: &str = "1.0";
+
+/// The prefix of the desired properties within the device content
+const DESIRED_PROPERTIES_PREFIX: &str = "properties.desired";
+
+/// The maximum length of the id of a configuration
+pub const MAX_CONFIGURATION_ID_LENGTH: usize = 128;
+
+/// The characters the id of a configuration can contain besides lowercase letters and digits
+const CONFIGURATION_ID_SPECIAL_CHARACTERS: &str = "-:+%_#*?!(),=@;$'";
+
+/// The content that is applied by a configuration
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationContent {
+    /// The desired properties of the device twins, keyed by their path such as `properties.desired.settings`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_content: Option<HashMap<String, serde_json::Value>>,
+    /// The modules content of an edge deployment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modules_content: Option<serde_json::Value>,
+    /// The desired properties of the module twins, keyed by their path such as `properties.desired.settings`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module_content: Option<HashMap<String, serde_json::Value>>,
+}
+
+/// The metrics of a configuration
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ConfigurationMetrics {
+    /// The queries of the metrics, keyed by the name of the metric
+    #[serde(default)]
+    pub queries: HashMap<String, String>,
+    /// The results of the metrics, keyed by the name of the metric
+    #[serde(default)]
+    pub results: HashMap<String, i64>,
+}
+
+/// An automatic device management configuration of the IoT Hub
+///
+/// Fields that are unknown to this crate are collected in `extra`, so they
+/// are sent back unchanged when the configuration is updated.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Configuration {
+    pub id: String,
+    #[serde(default = "default_schema_version")]
+    pub schema_version: String,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub content: ConfigurationContent,
+    #[serde(default)]
+    pub target_condition: String,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub metrics: ConfigurationMetrics,
+    #[serde(default, skip_serializing)]
+    pub system_metrics: ConfigurationMetrics,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub etag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_time_utc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_updated_time_utc: Option<String>,
+    /// The fields of the configuration that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+fn default_schema_version() -> String {
+    CONFIGURATION_SCHEMA_VERSION.to_string()
+}
+
+impl Configuration {
+    /// Check whether the configuration is an edge deployment
+    pub fn is_edge_deployment(&self) -> bool {
+        self.content.modules_content.is_some()
+    }
+
+    /// Get a copy of the configuration without the fields that are set by the IoT Hub
+    ///
+    /// The etag, the creation and update times and the results of the metrics are
+    /// removed, so the configuration can be version-controlled and created in another IoT Hub.
+    pub fn to_portable(&self) -> Configuration {
+        Configuration {
+            metrics: ConfigurationMetrics {
+                queries: self.metrics.queries.clone(),
+                results: HashMap::new(),
+            },
+            system_metrics: ConfigurationMetrics::default(),
+            etag: String::new(),
+            created_time_utc: None,
+            last_updated_time_utc: None,
+            ..self.clone()
+        }
+    }
+
+    /// Write the portable configuration to a file as JSON
+    ///
+    /// The fields are written in alphabetical order, so exporting the same
+    /// configuration again results in the same file.
+    pub fn write_to_file<P>(&self, path: P) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let configuration = serde_json::to_value(self.to_portable())?;
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), &configuration)?;
+        Ok(())
+    }
+
+    /// Read a configuration from a JSON file
+    pub fn read_from_file<P>(path: P) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+}
+
+/// The DeviceConfigurationBuilder is used for building configurations
+/// that apply desired properties to the twins of plain (non-edge) devices.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceConfigurationBuilder {
+    id: Option<String>,
+    priority: Option<i32>,
+    target_condition: Option<String>,
+    labels: HashMap<String, String>,
+    desired_properties: HashMap<String, serde_json::Value>,
+    metrics: HashMap<String, String>,
+}
+
+impl DeviceConfigurationBuilder {
+    /// Create a new DeviceConfigurationBuilder
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::configuration::DeviceConfigurationBuilder;
+    /// use serde_json::json;
+    ///
+    /// let configuration = DeviceConfigurationBuilder::new()
+    ///     .id("telemetry-interval")
+    ///     .priority(10)
+    ///     .target_condition("tags.environment='production'")
+    ///     .label("team", "sensors")
+    ///     .desired_properties("telemetry", json!({ "interval": 30 }))
+    ///     .metric("applied", "SELECT deviceId FROM devices WHERE properties.reported.telemetry.interval = 30")
+    ///     .build()
+    ///     .expect("Failed to build the configuration");
+    /// ```
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the id of the configuration
+    pub fn id<S>(mut self, id: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Set the priority of the configuration, higher priorities win when configurations overlap
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Set the condition that selects the devices the configuration applies to
+    pub fn target_condition<S>(mut self, target_condition: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.target_condition = Some(target_condition.into());
+        self
+    }
+
+    /// Add a label to the configuration
+    pub fn label<S, T>(mut self, key: S, value: T) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Set the value of a desired property on the targeted device twins
+    ///
+    /// The path is relative to the desired properties, so `telemetry` results
+    /// in `properties.desired.telemetry`.
+    pub fn desired_properties<S>(mut self, path: S, value: serde_json::Value) -> Self
+    where
+        S: Into<String>,
+    {
+        let key = desired_properties_key(&path.into());
+        self.desired_properties.insert(key, value);
+        self
+    }
+
+    /// Add a custom metric to the configuration, the query must select `deviceId`
+    pub fn metric<S, T>(mut self, name: S, query: T) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.metrics.insert(name.into(), query.into());
+        self
+    }
+
+    /// Build the configuration
+    pub fn build(self) -> Result<Configuration, BuilderError> {
+        let id = self
+            .id
+            .ok_or(BuilderError::new(BuilderErrorType::MissingValue("id")))?;
+        if !is_configuration_id(&id) {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue("id")));
+        }
+
+        let priority = self.priority.unwrap_or_default();
+        if priority < 0 {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                "priority",
+            )));
+        }
+
+        if self.desired_properties.is_empty() {
+            return Err(BuilderError::new(BuilderErrorType::MissingValue(
+                "desired_properties",
+            )));
+        }
+
+        Ok(Configuration {
+            id,
+            schema_version: default_schema_version(),
+            labels: self.labels,
+            content: ConfigurationContent {
+                device_content: Some(self.desired_properties),
+                modules_content: None,
+                module_content: None,
+            },
+            target_condition: self.target_condition.unwrap_or_default(),
+            priority,
+            metrics: ConfigurationMetrics {
+                queries: self.metrics,
+                results: HashMap::new(),
+            },
+            system_metrics: ConfigurationMetrics::default(),
+            etag: String::new(),
+            created_time_utc: None,
+            last_updated_time_utc: None,
+            extra: serde_json::Map::new(),
+        })
+    }
+}
+
+/// Get the key of a desired property within the device or module content
+pub(crate) fn desired_properties_key(path: &str) -> String {
+    let path = path.trim_start_matches(DESIRED_PROPERTIES_PREFIX);
+    let path = path.trim_start_matches('.');
+    if path.is_empty() {
+        DESIRED_PROPERTIES_PREFIX.to_string()
+    } else {
+        format!("{}.{}", DESIRED_PROPERTIES_PREFIX, path)
+    }
+}
+
+/// Check whether an id is accepted by the IoT Hub as the id of a configuration
+fn is_configuration_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_CONFIGURATION_ID_LENGTH
+        && id.chars().all(|c| {
+            c.is_ascii_lowercase()
+                || c.is_ascii_digit()
+                || CONFIGURATION_ID_SPECIAL_CHARACTERS.contains(c)
+        })
+}
+
+/// The content a ConfigurationBuilder applies
+#[derive(Debug, Clone)]
+enum BuilderContent {
+    Device(HashMap<String, serde_json::Value>),
+    Module(HashMap<String, serde_json::Value>),
+    Modules(Box<ModulesContent>),
+}
+
+/// The ConfigurationBuilder is used for building any automatic device management
+/// configuration, which applies desired properties to device twins or module twins,
+/// or deploys modules to edge devices.
+///
+/// A configuration applies a single kind of content, so the content methods of
+/// different kinds cannot be combined. The id and priority are validated when the
+/// configuration is built, before it is sent to the IoT Hub.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigurationBuilder {
+    id: Option<String>,
+    priority: Option<i32>,
+    target_condition: Option<String>,
+    labels: HashMap<String, String>,
+    metrics: HashMap<String, String>,
+    content: Option<BuilderContent>,
+    mixed_content: bool,
+}
+
+impl ConfigurationBuilder {
+    /// Create a new ConfigurationBuilder
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::configuration::ConfigurationBuilder;
+    /// use serde_json::json;
+    ///
+    /// let configuration = ConfigurationBuilder::new()
+    ///     .id("sensor-settings")
+    ///     .priority(10)
+    ///     .target_condition("FROM devices.modules WHERE moduleId = 'sensor'")
+    ///     .label("team", "sensors")
+    ///     .module_content("settings", json!({ "interval": 30 }))
+    ///     .metric("applied", "SELECT deviceId FROM devices.modules WHERE properties.reported.settings.interval = 30")
+    ///     .build()
+    ///     .expect("Failed to build the configuration");
+    /// assert!(configuration.content.module_content.is_some());
+    /// ```
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the id of the configuration
+    ///
+    /// The id can contain up to 128 lowercase letters, digits and the characters `-:+%_#*?!(),=@;$'`.
+    pub fn id<S>(mut self, id: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Set the priority of the configuration, higher priorities win when configurations overlap
+    ///
+    /// The priority cannot be negative.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Set the condition that selects the devices or modules the configuration applies to
+    pub fn target_condition<S>(mut self, target_condition: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.target_condition = Some(target_condition.into());
+        self
+    }
+
+    /// Add a label to the configuration
+    pub fn label<S, T>(mut self, key: S, value: T) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Add labels to the configuration
+    pub fn labels<I, S, T>(mut self, labels: I) -> Self
+    where
+        I: IntoIterator<Item = (S, T)>,
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.labels.extend(
+            labels
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    /// Add a custom metric to the configuration, the query must select `deviceId`
+    pub fn metric<S, T>(mut self, name: S, query: T) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.metrics.insert(name.into(), query.into());
+        self
+    }
+
+    /// Set the value of a desired property on the targeted device twins
+    ///
+    /// The path is relative to the desired properties, so `telemetry` results
+    /// in `properties.desired.telemetry`.
+    pub fn device_content<S>(mut self, path: S, value: serde_json::Value) -> Self
+    where
+        S: Into<String>,
+    {
+        let key = desired_properties_key(&path.into());
+        match &mut self.content {
+            Some(BuilderContent::Device(content)) => {
+                content.insert(key, value);
+            }
+            Some(_) => self.mixed_content = true,
+            None => {
+                self.content = Some(BuilderContent::Device(
+                    std::iter::once((key, value)).collect(),
+                ))
+            }
+        }
+        self
+    }
+
+    /// Set the value of a desired property on the targeted module twins
+    ///
+    /// The path is relative to the desired properties, like in `device_content`.
+    pub fn module_content<S>(mut self, path: S, value: serde_json::Value) -> Self
+    where
+        S: Into<String>,
+    {
+        let key = desired_properties_key(&path.into());
+        match &mut self.content {
+            Some(BuilderContent::Module(content)) => {
+                content.insert(key, value);
+            }
+            Some(_) => self.mixed_content = true,
+            None => {
+                self.content = Some(BuilderContent::Module(
+                    std::iter::once((key, value)).collect(),
+                ))
+            }
+        }
+        self
+    }
+
+    /// Deploy the modules content to the targeted edge devices
+    pub fn modules_content(mut self, modules_content: ModulesContent) -> Self {
+        match self.content {
+            Some(BuilderContent::Modules(_)) | None => {
+                self.content = Some(BuilderContent::Modules(Box::new(modules_content)))
+            }
+            Some(_) => self.mixed_content = true,
+        }
+        self
+    }
+
+    /// Build the configuration
+    pub fn build(self) -> Result<Configuration, BuilderError> {
+        let id = self
+            .id
+            .ok_or(BuilderError::new(BuilderErrorType::MissingValue("id")))?;
+        if !is_configuration_id(&id) {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue("id")));
+        }
+
+        let priority = self.priority.unwrap_or_default();
+        if priority < 0 {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                "priority",
+            )));
+        }
+
+        let target_condition = self
+            .target_condition
+            .filter(|target_condition| !target_condition.trim().is_empty())
+            .ok_or(BuilderError::new(BuilderErrorType::MissingValue(
+                "target_condition",
+            )))?;
+
+        if self
+            .metrics
+            .iter()
+            .any(|(name, query)| name.trim().is_empty() || query.trim().is_empty())
+        {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                "metric",
+            )));
+        }
+
+        if self.mixed_content {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                "content",
+            )));
+        }
+        let content = match self.content {
+            Some(BuilderContent::Device(device_content)) => ConfigurationContent {
+                device_content: Some(device_content),
+                ..ConfigurationContent::default()
+            },
+            Some(BuilderContent::Module(module_content)) => ConfigurationContent {
+                module_content: Some(module_content),
+                ..ConfigurationContent::default()
+            },
+            Some(BuilderContent::Modules(modules_content)) => ConfigurationContent {
+                modules_content: Some(serde_json::to_value(modules_content).map_err(|_| {
+                    BuilderError::new(BuilderErrorType::IncorrectValue("modules_content"))
+                })?),
+                ..ConfigurationContent::default()
+            },
+            None => return Err(BuilderError::new(BuilderErrorType::MissingValue("content"))),
+        };
+
+        Ok(Configuration {
+            id,
+            schema_version: default_schema_version(),
+            labels: self.labels,
+            content,
+            target_condition,
+            priority,
+            metrics: ConfigurationMetrics {
+                queries: self.metrics,
+                results: HashMap::new(),
+            },
+            system_metrics: ConfigurationMetrics::default(),
+            etag: String::new(),
+            created_time_utc: None,
+            last_updated_time_utc: None,
+            extra: serde_json::Map::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::configuration::deviceconfiguration::{
+        Configuration, ConfigurationBuilder, DeviceConfigurationBuilder,
+    };
+
+    #[test]
+    fn device_configuration_builder_should_succeed(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let configuration = DeviceConfigurationBuilder::new()
+            .id("telemetry-interval")
+            .priority(10)
+            .target_condition("tags.environment='production'")
+            .label("team", "sensors")
+            .desired_properties("telemetry", json!({ "interval": 30 }))
+            .metric("applied", "SELECT deviceId FROM devices")
+            .build()?;
+
+        assert!(!configuration.is_edge_deployment());
+        assert_eq!(
+            serde_json::to_value(&configuration)?,
+            json!({
+                "id": "telemetry-interval",
+                "schemaVersion": "1.0",
+                "labels": { "team": "sensors" },
+                "content": {
+                    "deviceContent": {
+                        "properties.desired.telemetry": { "interval": 30 }
+                    }
+                },
+                "targetCondition": "tags.environment='production'",
+                "priority": 10,
+                "metrics": {
+                    "queries": { "applied": "SELECT deviceId FROM devices" },
+                    "results": {}
+                }
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn configuration_builder_should_build_edge_deployments(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::ModulesContentBuilder;
+
+        let modules_content = ModulesContentBuilder::new()
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.2")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.2")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(7200)
+            .build()?;
+        let configuration = ConfigurationBuilder::new()
+            .id("edge-base:v1")
+            .priority(5)
+            .target_condition("tags.environment='production'")
+            .labels(vec![("team", "edge"), ("release", "1.2")])
+            .metric(
+                "running",
+                "SELECT deviceId FROM devices.modules WHERE moduleId = '$edgeHub'",
+            )
+            .modules_content(modules_content.clone())
+            .build()?;
+
+        assert!(configuration.is_edge_deployment());
+        assert_eq!(configuration.labels["release"], "1.2");
+        assert_eq!(
+            configuration.content.modules_content,
+            Some(serde_json::to_value(&modules_content)?)
+        );
+        assert!(configuration.content.device_content.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn configuration_builder_should_validate_before_building() {
+        let builder = || {
+            ConfigurationBuilder::new()
+                .id("telemetry-interval")
+                .target_condition("*")
+                .device_content("telemetry", json!({ "interval": 30 }))
+        };
+        assert!(builder().build().is_ok());
+        assert!(builder().id("Telemetry Interval").build().is_err());
+        assert!(builder().id("a".repeat(129)).build().is_err());
+        assert!(builder().priority(-1).build().is_err());
+        assert!(builder().target_condition(" ").build().is_err());
+        assert!(builder().metric("applied", "").build().is_err());
+        assert!(builder()
+            .module_content("telemetry", json!({ "interval": 30 }))
+            .build()
+            .is_err());
+        assert!(ConfigurationBuilder::new()
+            .id("empty")
+            .target_condition("*")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn device_configuration_builder_should_fail_without_content() {
+        let result = DeviceConfigurationBuilder::new().id("empty").build();
+        assert!(result.is_err());
+
+        let result = DeviceConfigurationBuilder::new()
+            .desired_properties("telemetry", json!({}))
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn configuration_should_deserialize() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let configuration: Configuration = serde_json::from_value(json!({
+            "id": "telemetry-interval",
+            "schemaVersion": "1.0",
+            "labels": {},
+            "content": {
+                "deviceContent": {
+                    "properties.desired.telemetry": { "interval": 30 }
+                }
+            },
+            "targetCondition": "*",
+            "createdTimeUtc": "2020-06-21T16:38:35.6713234Z",
+            "lastUpdatedTimeUtc": "2020-06-21T16:38:35.6713234Z",
+            "priority": 0,
+            "systemMetrics": {
+                "results": { "appliedCount": 3, "targetedCount": 4 },
+                "queries": {}
+            },
+            "metrics": { "results": {}, "queries": {} },
+            "etag": "MQ=="
+        }))?;
+
+        assert_eq!(configuration.system_metrics.results["appliedCount"], 3);
+        assert_eq!(configuration.etag, "MQ==");
+        Ok(())
+    }
+
+    #[test]
+    fn configuration_should_roundtrip_through_file(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut configuration = DeviceConfigurationBuilder::new()
+            .id("telemetry-interval")
+            .target_condition("tags.environment='production'")
+            .desired_properties("telemetry", json!({ "interval": 30 }))
+            .metric("applied", "SELECT deviceId FROM devices")
+            .build()?;
+        configuration.etag = "MQ==".to_string();
+        configuration.created_time_utc = Some("2020-06-21T16:38:35.6713234Z".to_string());
+        configuration
+            .metrics
+            .results
+            .insert("applied".to_string(), 3);
+
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "azure-iothub-service-configuration-{}.json",
+            std::process::id()
+        ));
+        configuration.write_to_file(&path)?;
+        let read_configuration = Configuration::read_from_file(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(read_configuration, configuration.to_portable());
+        assert!(read_configuration.etag.is_empty());
+        assert!(read_configuration.created_time_utc.is_none());
+        assert!(read_configuration.metrics.results.is_empty());
+        assert_eq!(
+            read_configuration.metrics.queries["applied"],
+            "SELECT deviceId FROM devices"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+//! The lint module checks a modules configuration for common mistakes, which
+//! the IoT Hub accepts but which lead to deployments that behave unexpectedly
+//! on the edge devices.
+use crate::configuration::modulescontent::ModulesContent;
+use crate::configuration::{RestartPolicy, Status};
+
+/// The tag Docker uses when an image has no tag
+const LATEST_TAG: &str = "latest";
+
+/// The registries that serve public images, which can be pulled without credentials
+const PUBLIC_REGISTRIES: [&str; 4] = [
+    "mcr.microsoft.com",
+    "docker.io",
+    "index.docker.io",
+    "registry-1.docker.io",
+];
+
+/// The endpoint of a route that sends messages to the IoT Hub
+const UPSTREAM_ENDPOINT: &str = "$upstream";
+
+/// The severity of a lint warning
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LintSeverity {
+    /// The configuration works, but is probably not what was intended
+    Info,
+    /// The configuration works, but is fragile or loses data
+    Warning,
+    /// The configuration will fail on the edge devices
+    Error,
+}
+
+/// The rule that raised a lint warning
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintRule {
+    /// An image uses the `latest` tag or no tag, so devices can run different versions
+    LatestImageTag,
+    /// An image is pulled from a private registry without registry credentials
+    MissingRegistryCredentials,
+    /// No route sends messages to the IoT Hub
+    MissingUpstreamRoute,
+    /// The time to live of messages is zero, so messages are dropped while the device is offline
+    ZeroTimeToLive,
+    /// A running module is never restarted, so it stays down after it exits
+    NeverRestarted,
+}
+
+/// A warning about a modules configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintWarning {
+    rule: LintRule,
+    severity: LintSeverity,
+    module_id: Option<String>,
+    message: String,
+}
+
+impl LintWarning {
+    fn new(
+        rule: LintRule,
+        severity: LintSeverity,
+        module_id: Option<&str>,
+        message: String,
+    ) -> Self {
+        LintWarning {
+            rule,
+            severity,
+            module_id: module_id.map(String::from),
+            message,
+        }
+    }
+
+    /// Get the rule that raised the warning
+    pub fn rule(&self) -> LintRule {
+        self.rule
+    }
+
+    /// Get the severity of the warning
+    pub fn severity(&self) -> LintSeverity {
+        self.severity
+    }
+
+    /// Get the module the warning is about, if it is about a single module
+    pub fn module_id(&self) -> &Option<String> {
+        &self.module_id
+    }
+
+    /// Get a description of the warning
+    pub fn message(&self) -> &String {
+        &self.message
+    }
+}
+
+impl std::fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.severity, self.message)
+    }
+}
+
+/// Check a modules configuration for common mistakes
+///
+/// The warnings are ordered by severity, with the most severe warnings first, and
+/// by module within the same severity.
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::configuration::lint::{lint_modules_content, LintRule};
+/// use azure_iothub_service::configuration::ModulesContentBuilder;
+///
+/// let modules_content = ModulesContentBuilder::new()
+///     .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.0")
+///     .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:latest")
+///     .minimum_docker_version("v1.25")
+///     .time_to_live_secs(7200)
+///     .route("upstream", "FROM /messages/* INTO $upstream")
+///     .build()
+///     .expect("Failed to build the ModulesContent");
+///
+/// let warnings = lint_modules_content(&modules_content);
+/// assert_eq!(warnings.len(), 1);
+/// assert_eq!(warnings[0].rule(), LintRule::LatestImageTag);
+/// ```
+pub fn lint_modules_content(modules_content: &ModulesContent) -> Vec<LintWarning> {
+    let edge_agent = modules_content.edge_agent();
+    let edge_hub = modules_content.edge_hub();
+    let system_modules = edge_agent.system_modules();
+
+    let mut images = vec![
+        ("$edgeAgent", system_modules.edge_agent().settings().image()),
+        ("$edgeHub", system_modules.edge_hub().settings().image()),
+    ];
+    let mut modules: Vec<_> = edge_agent.modules().iter().collect();
+    modules.sort_by(|a, b| a.0.cmp(b.0));
+    images.extend(
+        modules
+            .iter()
+            .map(|(module_id, module)| (module_id.as_str(), module.settings.image())),
+    );
+
+    let registry_credentials = edge_agent.runtime().settings().registry_credentials();
+    let mut warnings = Vec::new();
+    for (module_id, image) in images {
+        if uses_latest_tag(image) {
+            warnings.push(LintWarning::new(
+                LintRule::LatestImageTag,
+                LintSeverity::Warning,
+                Some(module_id),
+                format!(
+                    "The image {} of {} is not pinned to a version, pin it to a version or digest",
+                    image, module_id
+                ),
+            ));
+        }
+
+        if let Some(registry) = private_registry(image) {
+            let has_credentials = registry_credentials.values().any(|credential| {
+                credential
+                    .address()
+                    .trim_end_matches('/')
+                    .eq_ignore_ascii_case(registry)
+            });
+            if !has_credentials {
+                warnings.push(LintWarning::new(
+                    LintRule::MissingRegistryCredentials,
+                    LintSeverity::Error,
+                    Some(module_id),
+                    format!(
+                        "The image {} of {} is pulled from {} without registry credentials",
+                        image, module_id, registry
+                    ),
+                ));
+            }
+        }
+    }
+
+    for (module_id, module) in &modules {
+        if module.status == Status::Running && module.restart_policy == RestartPolicy::Never {
+            warnings.push(LintWarning::new(
+                LintRule::NeverRestarted,
+                LintSeverity::Warning,
+                Some(module_id),
+                format!(
+                    "The module {} should be running but is never restarted when it exits",
+                    module_id
+                ),
+            ));
+        }
+    }
+
+    if !edge_hub
+        .routes()
+        .values()
+        .any(|route| route.contains(UPSTREAM_ENDPOINT))
+    {
+        warnings.push(LintWarning::new(
+            LintRule::MissingUpstreamRoute,
+            LintSeverity::Info,
+            None,
+            "No route sends messages to the IoT Hub with $upstream".to_string(),
+        ));
+    }
+
+    if edge_hub
+        .store_and_forward_configuration()
+        .time_to_live_secs()
+        == 0
+    {
+        warnings.push(LintWarning::new(
+            LintRule::ZeroTimeToLive,
+            LintSeverity::Warning,
+            None,
+            "The time to live of messages is 0, so messages are dropped while the device is offline"
+                .to_string(),
+        ));
+    }
+
+    // The sort is stable, so warnings of the same severity stay ordered by module
+    warnings.sort_by_key(|warning| std::cmp::Reverse(warning.severity));
+    warnings
+}
+
+/// Check whether an image uses the `latest` tag, either explicitly or by having no tag
+fn uses_latest_tag(image: &str) -> bool {
+    if image.contains('@') {
+        return false;
+    }
+
+    // The registry can contain a port, so the tag is only looked for in the last part
+    let name = image.rsplit('/').next().unwrap_or(image);
+    match name.rfind(':') {
+        Some(index) => name[index + 1..].eq_ignore_ascii_case(LATEST_TAG),
+        None => true,
+    }
+}
+
+/// Get the registry of an image, if it is not a public registry
+///
+/// Like Docker, the first part of the image is only considered a registry when it
+/// contains a `.` or `:` or is `localhost`, otherwise the image is on Docker Hub.
+fn private_registry(image: &str) -> Option<&str> {
+    let index = image.find('/')?;
+    let registry = &image[..index];
+    let is_registry = registry.contains('.') || registry.contains(':') || registry == "localhost";
+    if !is_registry
+        || PUBLIC_REGISTRIES
+            .iter()
+            .any(|public| registry.eq_ignore_ascii_case(public))
+    {
+        return None;
+    }
+    Some(registry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint_modules_content, private_registry, uses_latest_tag, LintRule, LintSeverity};
+    use crate::configuration::{EdgeModuleBuilder, ModulesContentBuilder, RestartPolicy, Status};
+
+    #[test]
+    fn images_should_be_parsed() {
+        assert!(uses_latest_tag("sensor"));
+        assert!(uses_latest_tag("someacr.azurecr.io/sensor:latest"));
+        assert!(uses_latest_tag("localhost:5000/sensor"));
+        assert!(!uses_latest_tag("localhost:5000/sensor:1.0"));
+        assert!(!uses_latest_tag(
+            "someacr.azurecr.io/sensor@sha256:0123456789abcdef"
+        ));
+
+        assert_eq!(private_registry("sensor:1.0"), None);
+        assert_eq!(private_registry("library/sensor:1.0"), None);
+        assert_eq!(
+            private_registry("mcr.microsoft.com/azureiotedge-hub:1.0"),
+            None
+        );
+        assert_eq!(
+            private_registry("someacr.azurecr.io/sensor:1.0"),
+            Some("someacr.azurecr.io")
+        );
+        assert_eq!(
+            private_registry("localhost:5000/sensor:1.0"),
+            Some("localhost:5000")
+        );
+    }
+
+    #[test]
+    fn lint_should_flag_common_mistakes() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let module = |module_id: &str, image: &str, restart_policy| {
+            EdgeModuleBuilder::new()
+                .module_id(module_id)
+                .version("1.0")
+                .status(Status::Running)
+                .restart_policy(restart_policy)
+                .image(image)
+                .build()
+        };
+
+        let modules_content = ModulesContentBuilder::new()
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.0")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.0")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(0)
+            .registry_credential("someacr", "someacr", "secret", "someacr.azurecr.io")
+            .edge_module(module(
+                "sensor",
+                "someacr.azurecr.io/sensor:1.0",
+                RestartPolicy::Always,
+            )?)
+            .edge_module(module(
+                "filter",
+                "otheracr.azurecr.io/filter",
+                RestartPolicy::Never,
+            )?)
+            .route(
+                "sensorToFilter",
+                "FROM /messages/modules/sensor/outputs/* INTO BrokeredEndpoint(\"/modules/filter/inputs/input1\")",
+            )
+            .build()?;
+
+        let warnings = lint_modules_content(&modules_content);
+        let rules: Vec<(LintRule, LintSeverity, Option<&str>)> = warnings
+            .iter()
+            .map(|warning| {
+                (
+                    warning.rule(),
+                    warning.severity(),
+                    warning.module_id().as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                (
+                    LintRule::MissingRegistryCredentials,
+                    LintSeverity::Error,
+                    Some("filter")
+                ),
+                (
+                    LintRule::LatestImageTag,
+                    LintSeverity::Warning,
+                    Some("filter")
+                ),
+                (
+                    LintRule::NeverRestarted,
+                    LintSeverity::Warning,
+                    Some("filter")
+                ),
+                (LintRule::ZeroTimeToLive, LintSeverity::Warning, None),
+                (LintRule::MissingUpstreamRoute, LintSeverity::Info, None),
+            ]
+        );
+        Ok(())
+    }
+}
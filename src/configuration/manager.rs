@@ -0,0 +1,437 @@
+//! The manager module is used for managing the automatic device management
+//! configurations of the IoT Hub.
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use http::{Method, Request};
+
+use crate::configuration::conflict::{
+    find_potential_conflicts, overlap_condition, PriorityConflict,
+};
+use crate::configuration::Configuration;
+use crate::error::IoTHubError;
+use crate::response::{Response, ResponseMetadata};
+use crate::transport::HttpRequest;
+use crate::IoTHubService;
+
+/// The maximum number of configurations in an IoT Hub
+pub const MAX_CONFIGURATIONS: u32 = 100;
+
+/// How a configuration was applied to the IoT Hub when it was imported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportAction {
+    /// The configuration did not exist and was created
+    Created,
+    /// The configuration already existed and was updated
+    Updated,
+}
+
+/// The ConfigurationManager is used for creating, reading, updating
+/// and deleting the configurations of the IoT Hub.
+pub struct ConfigurationManager {
+    iothub_service: IoTHubService,
+}
+
+impl ConfigurationManager {
+    /// Create a new ConfigurationManager
+    pub fn new(iothub_service: &IoTHubService) -> Self {
+        ConfigurationManager {
+            iothub_service: iothub_service.clone(),
+        }
+    }
+
+    /// Get the uri of a configuration
+    fn configuration_uri(&self, configuration_id: &str) -> String {
+        format!(
+            "https://{}/configurations/{}?api-version={}",
+            self.iothub_service.hostname(),
+            configuration_id,
+            self.iothub_service.api_version()
+        )
+    }
+
+    /// Send a request and parse the body of the response
+    async fn send<T>(
+        &self,
+        operation: &'static str,
+        request: HttpRequest,
+    ) -> Result<Response<T>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        for<'de> T: serde::Deserialize<'de>,
+    {
+        let response = self.iothub_service.send(operation, None, request).await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
+        }
+
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            serde_json::from_slice(response.body())?,
+        ))
+    }
+
+    /// Get a configuration
+    pub async fn get_configuration<S>(
+        &self,
+        configuration_id: S,
+    ) -> Result<Response<Configuration>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let request = Request::builder()
+            .uri(self.configuration_uri(&configuration_id.into()))
+            .method(Method::GET)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json")
+            .body(Vec::new())?;
+
+        self.send("get_configuration", request).await
+    }
+
+    /// Get the configurations of the IoT Hub, limited to the given number of configurations
+    pub async fn get_configurations(
+        &self,
+        max_count: u32,
+    ) -> Result<Response<Vec<Configuration>>, Box<dyn std::error::Error + Send + Sync>> {
+        let uri = format!(
+            "https://{}/configurations?top={}&api-version={}",
+            self.iothub_service.hostname(),
+            max_count,
+            self.iothub_service.api_version()
+        );
+
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::GET)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json")
+            .body(Vec::new())?;
+
+        self.send("get_configurations", request).await
+    }
+
+    /// Find the configurations with the same priority that apply to the same devices
+    ///
+    /// The configurations are compared with [`find_potential_conflicts`], after which the
+    /// devices matching both target conditions are counted. Only the conflicts with
+    /// overlapping devices are returned.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// for conflict in iothub.configuration_manager().find_priority_conflicts(100).await? {
+    ///     println!(
+    ///         "{} and {} both have priority {}",
+    ///         conflict.first_configuration_id(),
+    ///         conflict.second_configuration_id(),
+    ///         conflict.priority()
+    ///     );
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn find_priority_conflicts(
+        &self,
+        max_count: u32,
+    ) -> Result<Vec<PriorityConflict>, Box<dyn std::error::Error + Send + Sync>> {
+        let configurations = self.get_configurations(max_count).await?.into_inner();
+        let by_id: HashMap<&str, &Configuration> = configurations
+            .iter()
+            .map(|configuration| (configuration.id.as_str(), configuration))
+            .collect();
+
+        let mut conflicts = Vec::new();
+        for mut conflict in find_potential_conflicts(&configurations) {
+            let first = by_id[conflict.first_configuration_id().as_str()];
+            let second = by_id[conflict.second_configuration_id().as_str()];
+
+            let mut query = self.iothub_service.build_query().from("devices");
+            if let Some(condition) = overlap_condition(first, second) {
+                query = query.and_where(condition);
+            }
+            let overlapping_devices = *query.count().await?;
+            if overlapping_devices > 0 {
+                conflict.set_overlapping_devices(overlapping_devices);
+                conflicts.push(conflict);
+            }
+        }
+        Ok(conflicts)
+    }
+
+    /// Create a new configuration
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::configuration::DeviceConfigurationBuilder;
+    /// use serde_json::json;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let configuration = DeviceConfigurationBuilder::new()
+    ///     .id("telemetry-interval")
+    ///     .target_condition("tags.environment='production'")
+    ///     .desired_properties("telemetry", json!({ "interval": 30 }))
+    ///     .build()?;
+    /// iothub.configuration_manager().create_configuration(&configuration).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn create_configuration(
+        &self,
+        configuration: &Configuration,
+    ) -> Result<Response<Configuration>, Box<dyn std::error::Error + Send + Sync>> {
+        let request = Request::builder()
+            .uri(self.configuration_uri(&configuration.id))
+            .method(Method::PUT)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(configuration)?)?;
+
+        self.send("create_configuration", request).await
+    }
+
+    /// Update an existing configuration
+    ///
+    /// The update only succeeds when the etag of the configuration matches the etag in
+    /// the IoT Hub. When the configuration has no etag the update is forced.
+    pub async fn update_configuration(
+        &self,
+        configuration: &Configuration,
+    ) -> Result<Response<Configuration>, Box<dyn std::error::Error + Send + Sync>> {
+        let etag = if configuration.etag.is_empty() {
+            "*".to_string()
+        } else {
+            format!("\"{}\"", configuration.etag.trim_matches('"'))
+        };
+
+        let request = Request::builder()
+            .uri(self.configuration_uri(&configuration.id))
+            .method(Method::PUT)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json")
+            .header("If-Match", etag)
+            .body(serde_json::to_vec(configuration)?)?;
+
+        self.send("update_configuration", request).await
+    }
+
+    /// Delete a configuration
+    pub async fn delete_configuration<S>(
+        &self,
+        configuration_id: S,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let request = Request::builder()
+            .uri(self.configuration_uri(&configuration_id.into()))
+            .method(Method::DELETE)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("If-Match", "*")
+            .body(Vec::new())?;
+
+        let response = self
+            .iothub_service
+            .send("delete_configuration", None, request)
+            .await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
+        }
+
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            (),
+        ))
+    }
+
+    /// Export all configurations of the IoT Hub to a directory
+    ///
+    /// Every configuration is written to `{id}.json` with [`Configuration::write_to_file`],
+    /// so the fields set by the IoT Hub are left out. The paths of the files are returned.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let files = iothub.configuration_manager().export_all("deployments").await?;
+    /// println!("Exported {} configurations", files.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn export_all<P>(
+        &self,
+        directory: P,
+    ) -> Result<Vec<PathBuf>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        P: AsRef<Path>,
+    {
+        let configurations = self
+            .get_configurations(MAX_CONFIGURATIONS)
+            .await?
+            .into_inner();
+
+        std::fs::create_dir_all(directory.as_ref())?;
+        let mut paths = Vec::with_capacity(configurations.len());
+        for configuration in configurations {
+            let path = directory
+                .as_ref()
+                .join(format!("{}.json", configuration.id));
+            configuration.write_to_file(&path)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    /// Import the configurations in the JSON files of a directory into the IoT Hub
+    ///
+    /// All files are read before the IoT Hub is changed, so an invalid file fails the
+    /// whole import. Configurations that already exist are updated regardless of their
+    /// etag, other configurations are created. The results are returned per configuration id.
+    ///
+    /// The IoT Hub does not allow changing the content of an existing configuration,
+    /// so promoting changed content requires a configuration with a new id.
+    pub async fn import_all<P>(
+        &self,
+        directory: P,
+    ) -> Result<
+        HashMap<String, Result<ImportAction, Box<dyn std::error::Error + Send + Sync>>>,
+        Box<dyn std::error::Error + Send + Sync>,
+    >
+    where
+        P: AsRef<Path>,
+    {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension() == Some(std::ffi::OsStr::new("json")) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let configurations = paths
+            .iter()
+            .map(Configuration::read_from_file)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let existing_ids: HashSet<String> = self
+            .get_configurations(MAX_CONFIGURATIONS)
+            .await?
+            .into_inner()
+            .into_iter()
+            .map(|configuration| configuration.id)
+            .collect();
+
+        let mut results = HashMap::new();
+        for configuration in configurations {
+            let configuration = configuration.to_portable();
+            let result = if existing_ids.contains(&configuration.id) {
+                self.update_configuration(&configuration)
+                    .await
+                    .map(|_| ImportAction::Updated)
+            } else {
+                self.create_configuration(&configuration)
+                    .await
+                    .map(|_| ImportAction::Created)
+            };
+            results.insert(configuration.id, result);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+
+    use crate::configuration::manager::ImportAction;
+    use crate::testing::mocktransport::{json_response, MockTransport};
+    use crate::IoTHubService;
+
+    #[test]
+    fn configurations_should_be_exported_and_imported(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let configuration = |id: &str| {
+            json!({
+                "id": id,
+                "schemaVersion": "1.0",
+                "content": { "deviceContent": { "properties.desired.telemetry": { "interval": 30 } } },
+                "targetCondition": "*",
+                "priority": 1,
+                "etag": "MQ==",
+                "createdTimeUtc": "2020-06-21T16:38:35.6713234Z",
+                "metrics": { "queries": {}, "results": {} }
+            })
+        };
+        let configurations = Arc::new(Mutex::new(HashMap::new()));
+        for id in &["first", "second"] {
+            configurations
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), configuration(id));
+        }
+        let stored = configurations.clone();
+        let transport = MockTransport::new(move |request, _| {
+            let mut configurations = stored.lock().unwrap();
+            if request.method() == http::Method::GET {
+                let mut list: Vec<_> = configurations.values().cloned().collect();
+                list.sort_by_key(|configuration| configuration["id"].to_string());
+                return json_response(&json!(list));
+            }
+
+            let mut configuration: serde_json::Value = serde_json::from_slice(request.body())?;
+            assert!(configuration.get("etag").is_none());
+            configuration["etag"] = json!("Mg==");
+            let id = configuration["id"].as_str().unwrap().to_string();
+            configurations.insert(id, configuration.clone());
+            json_response(&configuration)
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let configuration_manager = iothub.configuration_manager();
+
+        let mut directory = std::env::temp_dir();
+        directory.push(format!(
+            "azure-iothub-service-configurations-{}",
+            std::process::id()
+        ));
+        let paths = futures::executor::block_on(configuration_manager.export_all(&directory))?;
+        assert_eq!(paths.len(), 2);
+        assert!(!std::fs::read_to_string(&paths[0])?.contains("etag"));
+
+        configurations.lock().unwrap().remove("second");
+        let results = futures::executor::block_on(configuration_manager.import_all(&directory));
+        std::fs::remove_dir_all(&directory)?;
+        let results = results?;
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(*results["first"].as_ref().unwrap(), ImportAction::Updated);
+        assert_eq!(*results["second"].as_ref().unwrap(), ImportAction::Created);
+        let mut if_match = transport
+            .requests()
+            .iter()
+            .filter(|request| request.method() == http::Method::PUT)
+            .map(|request| {
+                request
+                    .headers()
+                    .get("If-Match")
+                    .map(|if_match| if_match.to_str().unwrap().to_string())
+            })
+            .collect::<Vec<_>>();
+        if_match.sort();
+        assert_eq!(if_match, vec![None, Some("*".to_string())]);
+        Ok(())
+    }
+}
@@ -1,6 +1,37 @@
+pub mod apply;
+pub mod conflict;
+pub mod convergence;
+pub mod deviceconfiguration;
+pub mod lint;
+pub mod manager;
+pub mod modulecontent;
 pub mod modulescontent;
+pub mod progress;
+pub mod reported;
+pub mod rollout;
+pub mod routing;
+pub mod template;
 
+pub use apply::{AppliedModule, ApplyResult};
+pub use conflict::PriorityConflict;
+pub use convergence::{ConvergenceReport, ModuleConvergence};
+pub use deviceconfiguration::{
+    Configuration, ConfigurationBuilder, ConfigurationContent, ConfigurationMetrics,
+    DeviceConfigurationBuilder,
+};
+pub use lint::{LintRule, LintSeverity, LintWarning};
+pub use manager::{ConfigurationManager, ImportAction};
+pub use modulecontent::ModuleContent;
 pub use modulescontent::{
-    EdgeModuleBuilder, ImagePullPolicy, ModulesContent, ModulesContentBuilder, RestartPolicy,
-    Status,
+    EdgeModuleBuilder, EnvironmentValue, EnvironmentVariable, ImagePullPolicy, ManifestIntegrity,
+    ManifestSigner, ModulesContent, ModulesContentBuilder, RegistryCredentialBuilder,
+    RegistryCredentialProvider, RestartPolicy, Status,
+};
+pub use progress::ApplyProgress;
+pub use reported::{
+    DesiredStatus, EdgeAgentReported, EdgeHubReported, ReportedClient, ReportedModule,
+    RuntimeStatus,
 };
+pub use rollout::{RolloutOrchestrator, RolloutOrchestratorBuilder};
+pub use routing::{Route, RouteReport, RouteValidation};
+pub use template::ManifestTemplate;
@@ -0,0 +1,175 @@
+//! The modulecontent module contains the content that sets desired properties on
+//! module twins, for modules that are not deployed by the IoT Edge runtime.
+use std::collections::{BTreeMap, HashMap};
+
+use serde::de;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::configuration::deviceconfiguration::desired_properties_key;
+
+/// The prefix of the keys of the module content
+const DESIRED_PROPERTIES_PREFIX: &str = "properties.desired";
+
+/// The desired properties to set on module twins, which is the `moduleContent`
+/// of a configuration
+///
+/// The properties are given by their path within the desired properties, so
+/// `telemetry.interval` sets `properties.desired.telemetry.interval`. Properties
+/// that are not given are left untouched on the module twins.
+///
+/// The IoT Hub applies module content through a configuration whose target condition
+/// selects module twins, like `FROM devices.modules WHERE moduleId = 'sensor'`. The
+/// applyConfigurationContent endpoint of a device takes no module id, so module content
+/// cannot be applied to a single module twin directly.
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::configuration::ModuleContent;
+/// use serde_json::json;
+///
+/// let module_content = ModuleContent::new()
+///     .desired_property("telemetry", json!({ "interval": 30 }))
+///     .desired_property("logLevel", json!("debug"));
+/// assert_eq!(
+///     serde_json::to_value(&module_content).unwrap(),
+///     json!({
+///         "properties.desired.logLevel": "debug",
+///         "properties.desired.telemetry": { "interval": 30 }
+///     })
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ModuleContent {
+    desired_properties: BTreeMap<String, serde_json::Value>,
+}
+
+impl ModuleContent {
+    /// Create an empty ModuleContent
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a desired property by its path within the desired properties
+    pub fn desired_property<S>(mut self, path: S, value: serde_json::Value) -> Self
+    where
+        S: Into<String>,
+    {
+        let key = desired_properties_key(&path.into());
+        let path = desired_property_path(&key).unwrap_or_default();
+        self.desired_properties.insert(path.to_string(), value);
+        self
+    }
+
+    /// Create a ModuleContent that sets every property of a JSON object
+    pub fn from_desired_properties(
+        properties: serde_json::Value,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        match properties {
+            serde_json::Value::Object(properties) => Ok(properties
+                .into_iter()
+                .fold(ModuleContent::new(), |content, (path, value)| {
+                    content.desired_property(path, value)
+                })),
+            _ => Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The desired properties must be a JSON object",
+            ))),
+        }
+    }
+
+    /// Get the desired properties by their path within the desired properties
+    pub fn desired_properties(&self) -> &BTreeMap<String, serde_json::Value> {
+        &self.desired_properties
+    }
+
+    /// Check whether no desired properties are set
+    pub fn is_empty(&self) -> bool {
+        self.desired_properties.is_empty()
+    }
+}
+
+impl From<ModuleContent> for HashMap<String, serde_json::Value> {
+    /// Convert the ModuleContent into the `module_content` of a [`ConfigurationContent`](crate::configuration::ConfigurationContent)
+    fn from(module_content: ModuleContent) -> Self {
+        module_content
+            .desired_properties
+            .into_iter()
+            .map(|(path, value)| (desired_properties_key(&path), value))
+            .collect()
+    }
+}
+
+impl Serialize for ModuleContent {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(
+            self.desired_properties
+                .iter()
+                .map(|(path, value)| (desired_properties_key(path), value)),
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for ModuleContent {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let content = BTreeMap::<String, serde_json::Value>::deserialize(deserializer)?;
+        let mut desired_properties = BTreeMap::new();
+        for (key, value) in content {
+            let path = desired_property_path(&key).ok_or_else(|| {
+                de::Error::custom(format!(
+                    "Expected the keys of the module content to start with {} but received: {}",
+                    DESIRED_PROPERTIES_PREFIX, key
+                ))
+            })?;
+            desired_properties.insert(path.to_string(), value);
+        }
+        Ok(ModuleContent { desired_properties })
+    }
+}
+
+/// Get the path within the desired properties of a key of the module content
+fn desired_property_path(key: &str) -> Option<&str> {
+    let path = key.strip_prefix(DESIRED_PROPERTIES_PREFIX)?;
+    if path.is_empty() {
+        Some(path)
+    } else {
+        path.strip_prefix('.')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::ModuleContent;
+
+    #[test]
+    fn module_content_should_roundtrip() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let module_content = ModuleContent::from_desired_properties(json!({
+            "telemetry": { "interval": 30 },
+            "properties.desired.logLevel": "debug"
+        }))?;
+        assert_eq!(
+            module_content
+                .desired_properties()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["logLevel", "telemetry"]
+        );
+
+        let value = serde_json::to_value(&module_content)?;
+        assert_eq!(value["properties.desired.telemetry"]["interval"], 30);
+        assert_eq!(
+            serde_json::from_value::<ModuleContent>(value)?,
+            module_content
+        );
+        assert!(serde_json::from_value::<ModuleContent>(json!({ "tags.site": "north" })).is_err());
+        assert!(ModuleContent::from_desired_properties(json!([1, 2])).is_err());
+        Ok(())
+    }
+}
@@ -1,18 +1,28 @@
+use serde::de::DeserializeOwned;
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use serde::Deserialize;
 use serde_json::json;
 use std::collections::HashMap;
+use std::sync::Arc;
 
+use crate::configuration::lint::{lint_modules_content, LintWarning};
 use crate::error::{BuilderError, BuilderErrorType};
+use crate::redact::{optional_secret, secret, RevealSecrets, REDACTED};
 
 /// The schema version of the modulescontent
 const SCHEMA_VERSION: &str = "1.0";
 
-/// The runtime type for the containers
+/// The schema version of signed modulescontent, which supports the `integrity` section
+const SIGNED_SCHEMA_VERSION: &str = "1.2";
+
+/// The default runtime type for the containers
 const RUNTIME_TYPE: &str = "docker";
 
+/// The prefix of the environment variables used for enabling experimental features on the EdgeAgent
+const EXPERIMENTAL_FEATURES_PREFIX: &str = "ExperimentalFeatures__";
+
 /// The status of a module, either Running or Stopped
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
     #[serde(rename = "running")]
     Running,
@@ -21,7 +31,7 @@ pub enum Status {
 }
 
 /// The restart policy of a module
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RestartPolicy {
     #[serde(rename = "never")]
     Never,
@@ -34,7 +44,7 @@ pub enum RestartPolicy {
 }
 
 /// The image pull policy of a module
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ImagePullPolicy {
     #[serde(rename = "on-create")]
     OnCreate,
@@ -42,13 +52,142 @@ pub enum ImagePullPolicy {
     Never,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The value of an environment variable
+///
+/// The EdgeAgent expects string values, but tolerates numbers and booleans as well.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum EnvironmentValue {
+    String(String),
+    Number(serde_json::Number),
+    Bool(bool),
+}
+
+impl EnvironmentValue {
+    /// Get the value as a string slice if it is a string value
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            EnvironmentValue::String(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Get the value as a 64 bit integer if it is an integer value
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            EnvironmentValue::Number(val) => val.as_i64(),
+            _ => None,
+        }
+    }
+
+    /// Get the value as a 64 bit float if it is a number value
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            EnvironmentValue::Number(val) => val.as_f64(),
+            _ => None,
+        }
+    }
+
+    /// Get the value as a boolean if it is a boolean value
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            EnvironmentValue::Bool(val) => Some(*val),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for EnvironmentValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EnvironmentValue::String(val) => write!(f, "{}", val),
+            EnvironmentValue::Number(val) => write!(f, "{}", val),
+            EnvironmentValue::Bool(val) => write!(f, "{}", val),
+        }
+    }
+}
+
+impl From<String> for EnvironmentValue {
+    fn from(value: String) -> Self {
+        EnvironmentValue::String(value)
+    }
+}
+
+impl From<&str> for EnvironmentValue {
+    fn from(value: &str) -> Self {
+        EnvironmentValue::String(value.to_string())
+    }
+}
+
+impl From<&String> for EnvironmentValue {
+    fn from(value: &String) -> Self {
+        EnvironmentValue::String(value.clone())
+    }
+}
+
+impl From<bool> for EnvironmentValue {
+    fn from(value: bool) -> Self {
+        EnvironmentValue::Bool(value)
+    }
+}
+
+impl From<i64> for EnvironmentValue {
+    fn from(value: i64) -> Self {
+        EnvironmentValue::Number(value.into())
+    }
+}
+
+impl From<u64> for EnvironmentValue {
+    fn from(value: u64) -> Self {
+        EnvironmentValue::Number(value.into())
+    }
+}
+
+impl PartialEq<str> for EnvironmentValue {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == Some(other)
+    }
+}
+
+impl PartialEq<&str> for EnvironmentValue {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == Some(*other)
+    }
+}
+
+/// An environment variable of a module
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct EnvironmentVariable {
-    value: String,
+    value: EnvironmentValue,
+}
+
+impl EnvironmentVariable {
+    /// Create a new EnvironmentVariable
+    pub fn new<V>(value: V) -> Self
+    where
+        V: Into<EnvironmentValue>,
+    {
+        Self {
+            value: value.into(),
+        }
+    }
+
+    /// Get the value of the EnvironmentVariable
+    pub fn value(&self) -> &EnvironmentValue {
+        &self.value
+    }
+
+    /// Set the value of the EnvironmentVariable
+    pub fn set_value<V>(&mut self, value: V)
+    where
+        V: Into<EnvironmentValue>,
+    {
+        self.value = value.into();
+    }
 }
 
 /// EdgeModule is an abstraction for the configuration of a custom module for IoT Edge
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct EdgeModule {
     #[serde(skip)]
@@ -62,9 +201,37 @@ pub struct EdgeModule {
     #[serde(default)]
     pub env: HashMap<String, EnvironmentVariable>,
     pub settings: ModuleSettings,
+    /// The order in which the edge agent starts the module, modules without an order start last
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub startup_order: Option<u32>,
 }
 
 /// The EdgeModuleBuilder can be used to build EdgeModules when creating a modules configuration
+///
+/// A partially filled builder can be cloned to build many similar modules.
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::configuration::{EdgeModuleBuilder, RestartPolicy, Status};
+///
+/// let template = EdgeModuleBuilder::new()
+///     .version("1.0")
+///     .status(Status::Running)
+///     .restart_policy(RestartPolicy::Always)
+///     .image("someacr.azurecr.io/sensor:1.0");
+/// let modules = ["north", "south"]
+///     .iter()
+///     .map(|site| {
+///         template
+///             .clone()
+///             .module_id(format!("sensor-{}", site))
+///             .environment_variable("SITE", *site)
+///             .build()
+///     })
+///     .collect::<Result<Vec<_>, _>>()
+///     .expect("Failed to build the modules");
+/// ```
+#[derive(Debug, Clone, Default)]
 pub struct EdgeModuleBuilder {
     module_id: Option<String>,
     version: Option<String>,
@@ -85,16 +252,7 @@ impl EdgeModuleBuilder {
     /// let edge_module_builder = EdgeModuleBuilder::new();
     /// ```
     pub fn new() -> Self {
-        Self {
-            module_id: None,
-            version: None,
-            status: None,
-            restart_policy: None,
-            image_pull_policy: None,
-            env: HashMap::new(),
-            image: None,
-            create_options: None,
-        }
+        Self::default()
     }
 
     /// Set the module id for the EdgeModule
@@ -175,19 +333,15 @@ impl EdgeModuleBuilder {
     /// use azure_iothub_service::configuration::{EdgeModuleBuilder};
     /// let edge_module_builder = EdgeModuleBuilder::new()
     ///     .environment_variable("variableOne", "someValue")
-    ///     .environment_variable("variableTwo", "someValue");
+    ///     .environment_variable("variableTwo", "someValue")
+    ///     .environment_variable("variableThree", 42u64);
     /// ```
     pub fn environment_variable<S, T>(mut self, key: S, value: T) -> Self
     where
         S: Into<String>,
-        T: Into<String>,
+        T: Into<EnvironmentValue>,
     {
-        self.env.insert(
-            key.into(),
-            EnvironmentVariable {
-                value: value.into(),
-            },
-        );
+        self.env.insert(key.into(), EnvironmentVariable::new(value));
         self
     }
 
@@ -204,9 +358,12 @@ impl EdgeModuleBuilder {
     /// let edge_module_builder = EdgeModuleBuilder::new()
     ///     .environment_variables(env_map);
     /// ```
-    pub fn environment_variables(mut self, variables: HashMap<String, String>) -> Self {
+    pub fn environment_variables<T>(mut self, variables: HashMap<String, T>) -> Self
+    where
+        T: Into<EnvironmentValue>,
+    {
         for (key, value) in variables {
-            self.env.insert(key, EnvironmentVariable { value });
+            self.env.insert(key, EnvironmentVariable::new(value));
         }
         self
     }
@@ -307,7 +464,7 @@ impl EdgeModuleBuilder {
         Ok(EdgeModule {
             module_id,
             version,
-            module_type: "docker".to_string(),
+            module_type: RUNTIME_TYPE.to_string(),
             status,
             restart_policy,
             image_pull_policy: self.image_pull_policy,
@@ -316,12 +473,16 @@ impl EdgeModuleBuilder {
                 image,
                 create_options: module_create_options,
             },
+            startup_order: None,
         })
     }
 }
 
 /// The registry credentials for modules configuration
-#[derive(Serialize, Deserialize)]
+///
+/// The password is redacted in the Debug output, unless it is revealed with
+/// [`RevealSecrets::reveal`].
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct RegistryCredential {
     username: String,
     password: String,
@@ -365,10 +526,294 @@ impl RegistryCredential {
     {
         self.username = username.into();
     }
+
+    /// Set the password of the RegistryCredential
+    pub fn set_password<S>(&mut self, password: S)
+    where
+        S: Into<String>,
+    {
+        self.password = password.into();
+    }
+
+    /// Set the address of the RegistryCredential
+    pub fn set_address<S>(&mut self, address: S)
+    where
+        S: Into<String>,
+    {
+        self.address = address.into();
+    }
+}
+
+impl RevealSecrets for RegistryCredential {
+    fn fmt_secrets(&self, f: &mut std::fmt::Formatter<'_>, reveal: bool) -> std::fmt::Result {
+        f.debug_struct("RegistryCredential")
+            .field("username", &self.username)
+            .field("password", &secret(&self.password, reveal))
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for RegistryCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_secrets(f, false)
+    }
+}
+
+/// The RegistryCredentialBuilder builds a RegistryCredential with a validated address
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::configuration::RegistryCredentialBuilder;
+///
+/// let credential = RegistryCredentialBuilder::new()
+///     .username("someacr")
+///     .password("secret")
+///     .address("someacr.azurecr.io")
+///     .build()
+///     .expect("Failed to build the registry credential");
+/// assert_eq!(credential.address(), "someacr.azurecr.io");
+///
+/// assert!(RegistryCredentialBuilder::new()
+///     .username("someacr")
+///     .password("secret")
+///     .address("https://someacr.azurecr.io/")
+///     .build()
+///     .is_err());
+/// ```
+#[derive(Clone, Default)]
+pub struct RegistryCredentialBuilder {
+    username: Option<String>,
+    password: Option<String>,
+    address: Option<String>,
+}
+
+impl RegistryCredentialBuilder {
+    /// Create a new RegistryCredentialBuilder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the username of the registry credential
+    pub fn username<S>(mut self, username: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.username = Some(username.into());
+        self
+    }
+
+    /// Set the password of the registry credential
+    pub fn password<S>(mut self, password: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Set the address of the registry, such as `someacr.azurecr.io` or `localhost:5000`
+    ///
+    /// The address is the host of the registry with an optional port, without a scheme or path.
+    pub fn address<S>(mut self, address: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.address = Some(address.into());
+        self
+    }
+
+    /// Build the RegistryCredential
+    pub fn build(self) -> Result<RegistryCredential, BuilderError> {
+        let username = match self.username {
+            Some(val) => val,
+            None => {
+                return Err(BuilderError::new(BuilderErrorType::MissingValue(
+                    "username",
+                )))
+            }
+        };
+
+        let password = match self.password {
+            Some(val) => val,
+            None => {
+                return Err(BuilderError::new(BuilderErrorType::MissingValue(
+                    "password",
+                )))
+            }
+        };
+
+        let address = match self.address {
+            Some(val) if is_valid_registry_address(&val) => val,
+            Some(_) => {
+                return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                    "address",
+                )))
+            }
+            None => return Err(BuilderError::new(BuilderErrorType::MissingValue("address"))),
+        };
+
+        Ok(RegistryCredential {
+            username,
+            password,
+            address,
+        })
+    }
+}
+
+impl std::fmt::Debug for RegistryCredentialBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegistryCredentialBuilder")
+            .field("username", &self.username)
+            .field("password", &optional_secret(&self.password, false))
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+/// The part of the host of an Azure Container Registry after the name of the registry
+const ACR_DOMAIN: &str = ".azurecr.";
+
+/// The minimum length of the name of an Azure Container Registry
+const ACR_NAME_MIN_LENGTH: usize = 5;
+
+/// The maximum length of the name of an Azure Container Registry
+const ACR_NAME_MAX_LENGTH: usize = 50;
+
+/// Check whether an address is a registry host with an optional port
+///
+/// The names of Azure Container Registries must be alphanumeric with 5 to 50 characters.
+fn is_valid_registry_address(address: &str) -> bool {
+    let (host, port) = match address.rfind(':') {
+        Some(index) => (&address[..index], Some(&address[index + 1..])),
+        None => (address, None),
+    };
+    if let Some(port) = port {
+        if port.parse::<u16>().is_err() {
+            return false;
+        }
+    }
+
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if !host.split('.').all(valid_label) {
+        return false;
+    }
+
+    match host.to_ascii_lowercase().find(ACR_DOMAIN) {
+        Some(index) => {
+            let name = &host[..index];
+            (ACR_NAME_MIN_LENGTH..=ACR_NAME_MAX_LENGTH).contains(&name.len())
+                && name.chars().all(|c| c.is_ascii_alphanumeric())
+        }
+        None => true,
+    }
+}
+
+/// The username used for authenticating to an Azure Container Registry with an access token
+pub const ACR_TOKEN_USERNAME: &str = "00000000-0000-0000-0000-000000000000";
+
+/// A callback that resolves the username and password for a registry address
+pub type RegistryCredentialCallback = dyn Fn(&str) -> Result<(String, String), Box<dyn std::error::Error + Send + Sync>>
+    + Send
+    + Sync;
+
+/// The RegistryCredentialProvider provides the username and password of a registry credential
+///
+/// The credentials are resolved when the modules content is built, so passwords can be taken
+/// from a secret store instead of being embedded in code. The secrets are redacted in the
+/// Debug output.
+#[derive(Clone)]
+pub enum RegistryCredentialProvider {
+    /// A plain username and password
+    Inline { username: String, password: String },
+    /// An access token of an Azure Container Registry, such as returned by `az acr login --expose-token`
+    AcrToken(String),
+    /// A callback that is called with the address of the registry and returns the username and password
+    Callback(Arc<RegistryCredentialCallback>),
+}
+
+impl RegistryCredentialProvider {
+    /// Create a provider for a plain username and password
+    pub fn inline<S, T>(username: S, password: T) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        RegistryCredentialProvider::Inline {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Create a provider for an access token of an Azure Container Registry
+    pub fn acr_token<S>(token: S) -> Self
+    where
+        S: Into<String>,
+    {
+        RegistryCredentialProvider::AcrToken(token.into())
+    }
+
+    /// Create a provider that resolves the username and password with a callback
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::configuration::RegistryCredentialProvider;
+    ///
+    /// let provider = RegistryCredentialProvider::callback(|address| {
+    ///     let password = std::env::var("REGISTRY_PASSWORD")?;
+    ///     Ok((format!("pull-{}", address), password))
+    /// });
+    /// ```
+    pub fn callback<F>(callback: F) -> Self
+    where
+        F: Fn(&str) -> Result<(String, String), Box<dyn std::error::Error + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        RegistryCredentialProvider::Callback(Arc::new(callback))
+    }
+
+    /// Resolve the username and password for the registry at the given address
+    pub fn resolve(
+        &self,
+        address: &str,
+    ) -> Result<(String, String), Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            RegistryCredentialProvider::Inline { username, password } => {
+                Ok((username.clone(), password.clone()))
+            }
+            RegistryCredentialProvider::AcrToken(token) => {
+                Ok((ACR_TOKEN_USERNAME.to_string(), token.clone()))
+            }
+            RegistryCredentialProvider::Callback(callback) => callback(address),
+        }
+    }
+}
+
+impl std::fmt::Debug for RegistryCredentialProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryCredentialProvider::Inline { username, .. } => f
+                .debug_struct("Inline")
+                .field("username", username)
+                .field("password", &REDACTED)
+                .finish(),
+            RegistryCredentialProvider::AcrToken(_) => {
+                f.debug_tuple("AcrToken").field(&REDACTED).finish()
+            }
+            RegistryCredentialProvider::Callback(_) => f.write_str("Callback"),
+        }
+    }
 }
 
 /// The runtime settings for the Edge Agent
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct RuntimeSettings {
     min_docker_version: String,
@@ -419,7 +864,7 @@ impl RuntimeSettings {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Runtime {
     settings: RuntimeSettings,
@@ -433,11 +878,19 @@ impl Runtime {
         &self.settings
     }
 
-    /// Get the runtime type (always "docker")
+    /// Get the runtime type
     pub fn runtime_type(&self) -> &String {
         &self.runtime_type
     }
 
+    /// Set the runtime type
+    pub fn set_runtime_type<S>(&mut self, runtime_type: S)
+    where
+        S: Into<String>,
+    {
+        self.runtime_type = runtime_type.into();
+    }
+
     /// Get a mutable reference to the RuntimeSettings
     pub fn settings_mut(&mut self) -> &mut RuntimeSettings {
         &mut self.settings
@@ -445,7 +898,7 @@ impl Runtime {
 }
 
 /// The settings of a module
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct ModuleSettings {
     image: String,
@@ -464,6 +917,46 @@ impl ModuleSettings {
         &self.create_options
     }
 
+    /// Get the create options parsed as JSON
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::configuration::{RestartPolicy, Status};
+    /// use azure_iothub_service::EdgeModuleBuilder;
+    /// use serde_json::json;
+    ///
+    /// let edge_module = EdgeModuleBuilder::new()
+    ///     .module_id("SomeModule")
+    ///     .version("1.0")
+    ///     .status(Status::Running)
+    ///     .restart_policy(RestartPolicy::Always)
+    ///     .image("some-image.containerregistry.url")
+    ///     .create_options(json!({ "HostConfig": { "Privileged": true } }))
+    ///     .build()
+    ///     .expect("Failed to build the module");
+    /// let create_options = edge_module.settings.create_options_json().unwrap();
+    /// assert_eq!(create_options, Some(json!({ "HostConfig": { "Privileged": true } })));
+    /// ```
+    pub fn create_options_json(
+        &self,
+    ) -> Result<Option<serde_json::Value>, Box<dyn std::error::Error + Send + Sync>> {
+        self.create_options_as()
+    }
+
+    /// Get the create options deserialized into a type, such as a struct with the
+    /// parts of the Docker create options that are needed
+    pub fn create_options_as<T>(
+        &self,
+    ) -> Result<Option<T>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: DeserializeOwned,
+    {
+        match &self.create_options {
+            Some(create_options) => Ok(Some(serde_json::from_str(create_options)?)),
+            None => Ok(None),
+        }
+    }
+
     /// Set the image
     pub fn set_image<S>(&mut self, image: S)
     where
@@ -476,7 +969,7 @@ impl ModuleSettings {
     pub fn set_create_options(
         &mut self,
         create_options: Option<serde_json::Value>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         match create_options {
             Some(val) => self.create_options = Some(serde_json::to_string(&val)?),
             None => self.create_options = None,
@@ -486,7 +979,7 @@ impl ModuleSettings {
 }
 
 /// The settings for the EdgeAgent
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct EdgeAgentSettings {
     #[serde(rename = "type")]
@@ -497,7 +990,7 @@ pub struct EdgeAgentSettings {
 }
 
 impl EdgeAgentSettings {
-    /// Get the runtime type (always "docker")
+    /// Get the runtime type
     pub fn runtime_type(&self) -> &String {
         &self.runtime_type
     }
@@ -524,7 +1017,7 @@ impl EdgeAgentSettings {
 }
 
 /// The settings for the EdgeHub module
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct EdgeHubSettings {
     #[serde(rename = "type")]
@@ -534,6 +1027,8 @@ pub struct EdgeHubSettings {
     settings: ModuleSettings,
     #[serde(skip_serializing_if = "HashMap::is_empty", default)]
     env: HashMap<String, EnvironmentVariable>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    startup_order: Option<u32>,
 }
 
 impl EdgeHubSettings {
@@ -562,6 +1057,11 @@ impl EdgeHubSettings {
         &self.env
     }
 
+    /// Get the order in which the edge agent starts the edge hub
+    pub fn startup_order(&self) -> Option<u32> {
+        self.startup_order
+    }
+
     /// Get a mutable reference to the settings
     pub fn settings_mut(&mut self) -> &mut ModuleSettings {
         &mut self.settings
@@ -574,7 +1074,7 @@ impl EdgeHubSettings {
 }
 
 /// The systemmodules of the EdgeAgent properties
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SystemModules {
     edge_hub: EdgeHubSettings,
@@ -604,13 +1104,15 @@ impl SystemModules {
 }
 
 /// The EdgeAgent module
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct EdgeAgent {
     schema_version: String,
     runtime: Runtime,
     system_modules: SystemModules,
     modules: HashMap<String, EdgeModule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    integrity: Option<ManifestIntegrity>,
 }
 
 impl EdgeAgent {
@@ -634,6 +1136,11 @@ impl EdgeAgent {
         &self.modules
     }
 
+    /// Get the signature of the properties, if the modules content is signed
+    pub fn integrity(&self) -> &Option<ManifestIntegrity> {
+        &self.integrity
+    }
+
     /// Get a mutable reference to the runtime
     pub fn runtime_mut(&mut self) -> &mut Runtime {
         &mut self.runtime
@@ -651,7 +1158,7 @@ impl EdgeAgent {
 }
 
 /// The store and forward configuration settings for the EdgeHub module
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct StoreAndForwardConfiguration {
     time_to_live_secs: u64,
@@ -670,12 +1177,14 @@ impl StoreAndForwardConfiguration {
 }
 
 /// The EdgeHub module
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct EdgeHub {
     schema_version: String,
     routes: HashMap<String, String>,
     store_and_forward_configuration: StoreAndForwardConfiguration,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    integrity: Option<ManifestIntegrity>,
 }
 
 impl EdgeHub {
@@ -694,6 +1203,11 @@ impl EdgeHub {
         &self.store_and_forward_configuration
     }
 
+    /// Get the signature of the properties, if the modules content is signed
+    pub fn integrity(&self) -> &Option<ManifestIntegrity> {
+        &self.integrity
+    }
+
     /// Get a mutable reference to the routes
     pub fn routes_mut(&mut self) -> &mut HashMap<String, String> {
         &mut self.routes
@@ -705,7 +1219,157 @@ impl EdgeHub {
     }
 }
 
+/// The certificates of the signer of a manifest
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct IntegrityHeader {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    signercert: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    intermediatecacert: Vec<String>,
+}
+
+/// The signature of a manifest
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IntegritySignature {
+    bytes: String,
+    algorithm: String,
+}
+
+/// The `integrity` section of signed desired properties, which the edge agent uses
+/// for verifying that the manifest was not altered
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestIntegrity {
+    header: IntegrityHeader,
+    signature: IntegritySignature,
+}
+
+impl ManifestIntegrity {
+    /// Create the integrity section for a signature made with an algorithm such as `ES256`
+    pub fn new<S>(algorithm: S, signature: &[u8]) -> Self
+    where
+        S: Into<String>,
+    {
+        ManifestIntegrity {
+            header: IntegrityHeader::default(),
+            signature: IntegritySignature {
+                bytes: base64::encode(signature),
+                algorithm: algorithm.into(),
+            },
+        }
+    }
+
+    /// Set the certificate of the signer, as base64 encoded parts of the PEM certificate
+    pub fn with_signer_certificate(mut self, certificate: Vec<String>) -> Self {
+        self.header.signercert = certificate;
+        self
+    }
+
+    /// Set the intermediate CA certificate, as base64 encoded parts of the PEM certificate
+    pub fn with_intermediate_ca_certificate(mut self, certificate: Vec<String>) -> Self {
+        self.header.intermediatecacert = certificate;
+        self
+    }
+
+    /// Get the algorithm of the signature
+    pub fn algorithm(&self) -> &String {
+        &self.signature.algorithm
+    }
+
+    /// Get the base64 encoded signature
+    pub fn signature(&self) -> &String {
+        &self.signature.bytes
+    }
+
+    /// Get the base64 encoded parts of the certificate of the signer
+    pub fn signer_certificate(&self) -> &Vec<String> {
+        &self.header.signercert
+    }
+
+    /// Get the base64 encoded parts of the intermediate CA certificate
+    pub fn intermediate_ca_certificate(&self) -> &Vec<String> {
+        &self.header.intermediatecacert
+    }
+}
+
+/// A callback that signs the canonicalized desired properties of `$edgeAgent` or `$edgeHub`
+pub type ManifestSignerCallback = dyn Fn(&[u8]) -> Result<ManifestIntegrity, Box<dyn std::error::Error + Send + Sync>>
+    + Send
+    + Sync;
+
+/// The ManifestSigner signs the desired properties of a modules content
+///
+/// The desired properties are canonicalized as JSON without whitespace and with the
+/// fields of every object ordered by name, without the `integrity` section.
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::configuration::{ManifestIntegrity, ManifestSigner};
+///
+/// let signer = ManifestSigner::new(|content| {
+///     // Sign the content with the private key of the signer certificate
+///     let signature = content.iter().rev().cloned().collect::<Vec<u8>>();
+///     Ok(ManifestIntegrity::new("ES256", &signature))
+/// });
+/// ```
+#[derive(Clone)]
+pub struct ManifestSigner(Arc<ManifestSignerCallback>);
+
+impl ManifestSigner {
+    /// Create a ManifestSigner that signs with a callback
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(&[u8]) -> Result<ManifestIntegrity, Box<dyn std::error::Error + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        ManifestSigner(Arc::new(callback))
+    }
+
+    /// Sign the desired properties, ignoring their current `integrity` section
+    fn sign<T>(
+        &self,
+        desired_properties: &T,
+    ) -> Result<ManifestIntegrity, Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: Serialize,
+    {
+        let mut value = serde_json::to_value(desired_properties)?;
+        if let Some(object) = value.as_object_mut() {
+            object.remove("integrity");
+        }
+        (self.0)(&serde_json::to_vec(&canonicalize(value))?)
+    }
+}
+
+impl std::fmt::Debug for ManifestSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ManifestSigner")
+    }
+}
+
+/// Order the fields of every object in a JSON value by name
+fn canonicalize(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(object) => {
+            let mut fields: Vec<(String, serde_json::Value)> = object.into_iter().collect();
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            serde_json::Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| (key, canonicalize(value)))
+                    .collect(),
+            )
+        }
+        serde_json::Value::Array(values) => {
+            serde_json::Value::Array(values.into_iter().map(canonicalize).collect())
+        }
+        value => value,
+    }
+}
+
 /// The module configuration
+#[derive(Debug, Clone, PartialEq)]
 pub struct ModulesContent {
     edge_agent: EdgeAgent,
     edge_hub: EdgeHub,
@@ -739,6 +1403,54 @@ impl ModulesContent {
     pub fn edge_hub_mut(&mut self) -> &mut EdgeHub {
         &mut self.edge_hub
     }
+
+    /// Get the deployment manifest of the module configuration, wrapped in the
+    /// `content.modulesContent` envelope as accepted by `az iot edge set-modules --content`
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::configuration::ModulesContentBuilder;
+    ///
+    /// let modules_content = ModulesContentBuilder::new()
+    ///     .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.0.9")
+    ///     .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.0.9")
+    ///     .minimum_docker_version("v1.25")
+    ///     .time_to_live_secs(10)
+    ///     .build()
+    ///     .expect("Failed to build the ModulesContent");
+    ///
+    /// let deployment = modules_content.to_deployment_json().expect("Failed to serialize");
+    /// assert!(deployment["content"]["modulesContent"]["$edgeAgent"].is_object());
+    /// ```
+    pub fn to_deployment_json(&self) -> Result<serde_json::Value, serde_json::Error> {
+        Ok(json!({
+            "content": {
+                "modulesContent": serde_json::to_value(self)?
+            }
+        }))
+    }
+
+    /// Check the module configuration for common mistakes, such as images without a version
+    ///
+    /// See [`lint_modules_content`](crate::configuration::lint::lint_modules_content) for the checks.
+    pub fn lint(&self) -> Vec<LintWarning> {
+        lint_modules_content(self)
+    }
+
+    /// Sign the desired properties of `$edgeAgent` and `$edgeHub`
+    ///
+    /// The schema version of both is raised to the version that supports signing,
+    /// before the properties are signed. Any previous signature is replaced.
+    pub fn sign(
+        &mut self,
+        signer: &ManifestSigner,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.edge_agent.schema_version = SIGNED_SCHEMA_VERSION.to_string();
+        self.edge_hub.schema_version = SIGNED_SCHEMA_VERSION.to_string();
+        self.edge_agent.integrity = Some(signer.sign(&self.edge_agent)?);
+        self.edge_hub.integrity = Some(signer.sign(&self.edge_hub)?);
+        Ok(())
+    }
 }
 
 impl Serialize for ModulesContent {
@@ -763,11 +1475,13 @@ impl Serialize for ModulesContent {
     }
 }
 
-#[derive(Default)]
+#[derive(Debug, Clone, Default)]
 pub struct ModulesContentBuilder {
+    runtime_type: Option<String>,
     minimum_docker_version: Option<String>,
     logging_options: Option<serde_json::Value>,
-    registry_credentials: HashMap<String, RegistryCredential>,
+    /// The address and credential provider of each registry, keyed by the name of the credential
+    registry_credentials: HashMap<String, (String, RegistryCredentialProvider)>,
     edge_agent_env: HashMap<String, EnvironmentVariable>,
     edge_hub_env: HashMap<String, EnvironmentVariable>,
     edge_agent_image: Option<String>,
@@ -777,6 +1491,7 @@ pub struct ModulesContentBuilder {
     modules: HashMap<String, EdgeModule>,
     routes: HashMap<String, String>,
     time_to_live_secs: Option<u64>,
+    manifest_signer: Option<ManifestSigner>,
 }
 
 impl ModulesContentBuilder {
@@ -791,6 +1506,22 @@ impl ModulesContentBuilder {
         Self::default()
     }
 
+    /// Set the runtime type of the edge device, defaults to "docker" when not set
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::configuration::{ModulesContentBuilder};
+    /// let modules_content_builder = ModulesContentBuilder::new()
+    ///     .runtime_type("kubernetes");
+    /// ```
+    pub fn runtime_type<T>(mut self, runtime_type: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.runtime_type = Some(runtime_type.into());
+        self
+    }
+
     /// Set the minimum docker version the edge device should have for this deployment
     ///
     /// # Example
@@ -830,15 +1561,43 @@ impl ModulesContentBuilder {
     {
         self.registry_credentials.insert(
             name.into(),
-            RegistryCredential {
-                username: username.into(),
-                password: password.into(),
-                address: address.into(),
-            },
+            (
+                address.into(),
+                RegistryCredentialProvider::inline(username, password),
+            ),
         );
         self
     }
 
+    /// Add a new registry credential to the deployment manifest, of which the username
+    /// and password are resolved by the provider when the modules content is built
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::configuration::{ModulesContentBuilder, RegistryCredentialProvider};
+    /// # let token = "<an acr access token>";
+    /// let modules_content_builder = ModulesContentBuilder::new()
+    ///     .registry_credential_provider(
+    ///         "some_credential",
+    ///         "some-acr.azurecr.io",
+    ///         RegistryCredentialProvider::acr_token(token),
+    ///     );
+    /// ```
+    pub fn registry_credential_provider<S, T>(
+        mut self,
+        name: S,
+        address: T,
+        provider: RegistryCredentialProvider,
+    ) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.registry_credentials
+            .insert(name.into(), (address.into(), provider));
+        self
+    }
+
     /// Add optional logging options to the deployment of the edge device
     ///
     /// # Example
@@ -851,7 +1610,7 @@ impl ModulesContentBuilder {
     /// }));
     /// ```
     pub fn logging_options(mut self, logging_options: serde_json::Value) -> Self {
-        self.logging_options = Some(logging_options.into());
+        self.logging_options = Some(logging_options);
         self
     }
 
@@ -885,6 +1644,29 @@ impl ModulesContentBuilder {
         self
     }
 
+    /// Sign the desired properties of the edge agent and edge hub when the modules content is built
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::configuration::{ManifestIntegrity, ManifestSigner, ModulesContentBuilder};
+    /// # fn sign_with_key(content: &[u8]) -> Vec<u8> { content.to_vec() }
+    /// let modules_content = ModulesContentBuilder::new()
+    ///     .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.2")
+    ///     .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.2")
+    ///     .minimum_docker_version("v1.25")
+    ///     .time_to_live_secs(7200)
+    ///     .manifest_signer(ManifestSigner::new(|content| {
+    ///         Ok(ManifestIntegrity::new("ES256", &sign_with_key(content)))
+    ///     }))
+    ///     .build()
+    ///     .expect("Failed to build the ModulesContent");
+    /// assert!(modules_content.edge_agent().integrity().is_some());
+    /// ```
+    pub fn manifest_signer(mut self, signer: ManifestSigner) -> Self {
+        self.manifest_signer = Some(signer);
+        self
+    }
+
     /// Set the image of the edge agent
     ///
     /// # Example
@@ -929,7 +1711,7 @@ impl ModulesContentBuilder {
     /// }));
     /// ```
     pub fn edge_agent_create_options(mut self, create_options: serde_json::Value) -> Self {
-        self.edge_agent_create_options = Some(create_options.into());
+        self.edge_agent_create_options = Some(create_options);
         self
     }
 
@@ -945,7 +1727,7 @@ impl ModulesContentBuilder {
     /// }));
     /// ```
     pub fn edge_hub_create_options(mut self, create_options: serde_json::Value) -> Self {
-        self.edge_hub_create_options = Some(create_options.into());
+        self.edge_hub_create_options = Some(create_options);
         self
     }
 
@@ -961,17 +1743,49 @@ impl ModulesContentBuilder {
     pub fn edge_agent_env<S, T>(mut self, key: S, value: T) -> Self
     where
         S: Into<String>,
-        T: Into<String>,
+        T: Into<EnvironmentValue>,
     {
-        self.edge_agent_env.insert(
-            key.into(),
-            EnvironmentVariable {
-                value: value.into(),
-            },
-        );
+        self.edge_agent_env
+            .insert(key.into(), EnvironmentVariable::new(value));
         self
     }
 
+    /// Enable or disable the experimental features of the edge agent
+    ///
+    /// This sets the `ExperimentalFeatures__Enabled` environment variable of the edge agent.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::configuration::{ModulesContentBuilder};
+    /// let modules_content_builder = ModulesContentBuilder::new()
+    ///     .experimental_features(true);
+    /// ```
+    pub fn experimental_features(self, enabled: bool) -> Self {
+        self.experimental_feature("Enabled", enabled)
+    }
+
+    /// Enable or disable a single experimental feature of the edge agent
+    ///
+    /// The feature name is prefixed with `ExperimentalFeatures__` and added as
+    /// an environment variable of the edge agent.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::configuration::{ModulesContentBuilder};
+    /// let modules_content_builder = ModulesContentBuilder::new()
+    ///     .experimental_features(true)
+    ///     .experimental_feature("EnableGetLogs", true);
+    /// ```
+    pub fn experimental_feature<T>(self, feature: T, enabled: bool) -> Self
+    where
+        T: Into<String>,
+    {
+        self.edge_agent_env(
+            format!("{}{}", EXPERIMENTAL_FEATURES_PREFIX, feature.into()),
+            enabled.to_string(),
+        )
+    }
+
     /// Add an environment variable to the edge hub
     ///
     /// # Example
@@ -984,14 +1798,10 @@ impl ModulesContentBuilder {
     pub fn edge_hub_env<S, T>(mut self, key: S, value: T) -> Self
     where
         S: Into<String>,
-        T: Into<String>,
+        T: Into<EnvironmentValue>,
     {
-        self.edge_hub_env.insert(
-            key.into(),
-            EnvironmentVariable {
-                value: value.into(),
-            },
-        );
+        self.edge_hub_env
+            .insert(key.into(), EnvironmentVariable::new(value));
         self
     }
 
@@ -1017,6 +1827,31 @@ impl ModulesContentBuilder {
         self
     }
 
+    /// Add multiple modules to the deployment manifest
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::configuration::{ModulesContentBuilder, EdgeModuleBuilder, Status, RestartPolicy};
+    /// let edge_modules = ["SensorA", "SensorB"].iter().map(|module_id| {
+    ///     EdgeModuleBuilder::new()
+    ///         .module_id(*module_id)
+    ///         .status(Status::Running)
+    ///         .restart_policy(RestartPolicy::Always)
+    ///         .image("some-image.acr")
+    ///         .version("1.0")
+    ///         .build()
+    ///         .expect("Failed to build the EdgeModule")
+    /// });
+    /// let modules_content_builder = ModulesContentBuilder::new().edge_modules(edge_modules);
+    /// ```
+    pub fn edge_modules<I>(mut self, edge_modules: I) -> Self
+    where
+        I: IntoIterator<Item = EdgeModule>,
+    {
+        self.extend(edge_modules);
+        self
+    }
+
     /// Build the ModulesContent
     ///
     /// # Example
@@ -1031,6 +1866,10 @@ impl ModulesContentBuilder {
     ///     .expect("Failed to build the ModulesContent");
     /// ```
     pub fn build(self) -> Result<ModulesContent, BuilderError> {
+        let runtime_type = self
+            .runtime_type
+            .unwrap_or_else(|| RUNTIME_TYPE.to_string());
+
         let time_to_live_secs =
             self.time_to_live_secs
                 .ok_or(BuilderError::new(BuilderErrorType::MissingValue(
@@ -1089,20 +1928,35 @@ impl ModulesContentBuilder {
             None => None,
         };
 
-        Ok(ModulesContent {
+        let mut registry_credentials = HashMap::new();
+        for (name, (address, provider)) in self.registry_credentials {
+            let (username, password) = provider.resolve(&address).map_err(|_| {
+                BuilderError::new(BuilderErrorType::IncorrectValue("registry_credentials"))
+            })?;
+            registry_credentials.insert(
+                name,
+                RegistryCredential {
+                    username,
+                    password,
+                    address,
+                },
+            );
+        }
+
+        let mut modules_content = ModulesContent {
             edge_agent: EdgeAgent {
                 schema_version: SCHEMA_VERSION.to_string(),
                 runtime: Runtime {
                     settings: RuntimeSettings {
                         min_docker_version: minimum_docker_version,
-                        logging_options: logging_options,
-                        registry_credentials: self.registry_credentials,
+                        logging_options,
+                        registry_credentials,
                     },
-                    runtime_type: RUNTIME_TYPE.to_string(),
+                    runtime_type: runtime_type.clone(),
                 },
                 system_modules: SystemModules {
                     edge_agent: EdgeAgentSettings {
-                        runtime_type: RUNTIME_TYPE.to_string(),
+                        runtime_type: runtime_type.clone(),
                         settings: ModuleSettings {
                             create_options: edgeagent_create_options,
                             image: edgeagent_image,
@@ -1114,35 +1968,71 @@ impl ModulesContentBuilder {
                             image: edgehub_image,
                             create_options: edgehub_create_options,
                         },
-                        runtime_type: RUNTIME_TYPE.to_string(),
+                        runtime_type,
                         restart_policy: RestartPolicy::Always,
                         status: Status::Running,
                         env: self.edge_hub_env,
+                        startup_order: None,
                     },
                 },
                 modules: self.modules,
+                integrity: None,
             },
             edge_hub: EdgeHub {
                 schema_version: SCHEMA_VERSION.to_string(),
                 routes: self.routes,
-                store_and_forward_configuration: StoreAndForwardConfiguration {
-                    time_to_live_secs: time_to_live_secs,
-                },
+                store_and_forward_configuration: StoreAndForwardConfiguration { time_to_live_secs },
+                integrity: None,
             },
-        })
+        };
+
+        if let Some(signer) = &self.manifest_signer {
+            modules_content.sign(signer).map_err(|_| {
+                BuilderError::new(BuilderErrorType::IncorrectValue("manifest_signer"))
+            })?;
+        }
+        Ok(modules_content)
+    }
+}
+
+impl Extend<EdgeModule> for ModulesContentBuilder {
+    fn extend<I>(&mut self, edge_modules: I)
+    where
+        I: IntoIterator<Item = EdgeModule>,
+    {
+        self.modules.extend(
+            edge_modules
+                .into_iter()
+                .map(|edge_module| (edge_module.module_id.clone(), edge_module)),
+        );
+    }
+}
+
+impl std::iter::FromIterator<EdgeModule> for ModulesContentBuilder {
+    fn from_iter<I>(edge_modules: I) -> Self
+    where
+        I: IntoIterator<Item = EdgeModule>,
+    {
+        let mut builder = Self::new();
+        builder.extend(edge_modules);
+        builder
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::configuration::modulescontent::{
-        EdgeAgent, EdgeHub, EdgeModuleBuilder, ImagePullPolicy, ModulesContentBuilder,
-        RestartPolicy, Status, RUNTIME_TYPE, SCHEMA_VERSION,
+        EdgeAgent, EdgeHub, EdgeModuleBuilder, EnvironmentVariable, ImagePullPolicy,
+        ManifestIntegrity, ManifestSigner, ModulesContentBuilder, RestartPolicy, Status,
+        RUNTIME_TYPE, SCHEMA_VERSION,
     };
     use serde_json::json;
+    use std::collections::HashMap;
     use std::path::PathBuf;
 
-    fn load_json_file(file_name: &str) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    fn load_json_file(
+        file_name: &str,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         d.push("resources/test/");
         d.push(file_name);
@@ -1152,7 +2042,8 @@ mod tests {
     }
 
     #[test]
-    fn edge_module_builder_should_succeed() -> Result<(), Box<dyn std::error::Error>> {
+    fn edge_module_builder_should_succeed() -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    {
         let create_options = json!({
             "settings": {
                 "important": "setting",
@@ -1191,11 +2082,26 @@ mod tests {
             edge_module.settings.create_options,
             Some(serde_json::to_string(&create_options)?)
         );
+        assert_eq!(
+            edge_module.settings.create_options_json()?,
+            Some(create_options)
+        );
+
+        #[derive(serde::Deserialize)]
+        struct Settings {
+            important: String,
+        }
+        #[derive(serde::Deserialize)]
+        struct CreateOptions {
+            settings: Settings,
+        }
+        let typed: Option<CreateOptions> = edge_module.settings.create_options_as()?;
+        assert_eq!(typed.unwrap().settings.important, "setting");
         Ok(())
     }
 
     #[test]
-    fn edge_agent_builder_should_succeed() -> Result<(), Box<dyn std::error::Error>> {
+    fn edge_agent_builder_should_succeed() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let create_options = json!({
             "settings": {
                 "important": "setting",
@@ -1314,7 +2220,60 @@ mod tests {
     }
 
     #[test]
-    fn modules_content_should_serialize_correctly() -> Result<(), Box<dyn std::error::Error>> {
+    fn modules_content_builder_should_set_runtime_type_and_experimental_features(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let modules_content = ModulesContentBuilder::new()
+            .runtime_type("kubernetes")
+            .minimum_docker_version("1.3.2")
+            .edge_agent_image("acr_agent_image.com:1.0")
+            .edge_hub_image("acr_hub_image.com:1.0")
+            .experimental_features(true)
+            .experimental_feature("EnableGetLogs", false)
+            .time_to_live_secs(1)
+            .build()?;
+
+        assert_eq!(
+            modules_content.edge_agent.runtime.runtime_type,
+            "kubernetes"
+        );
+        assert_eq!(
+            modules_content
+                .edge_agent
+                .system_modules
+                .edge_agent
+                .runtime_type,
+            "kubernetes"
+        );
+        assert_eq!(
+            modules_content
+                .edge_agent
+                .system_modules
+                .edge_hub
+                .runtime_type,
+            "kubernetes"
+        );
+
+        let edge_agent_env = &modules_content.edge_agent.system_modules.edge_agent.env;
+        assert_eq!(
+            edge_agent_env
+                .get("ExperimentalFeatures__Enabled")
+                .unwrap()
+                .value,
+            "true"
+        );
+        assert_eq!(
+            edge_agent_env
+                .get("ExperimentalFeatures__EnableGetLogs")
+                .unwrap()
+                .value,
+            "false"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn modules_content_should_serialize_correctly(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let test_json_file = load_json_file("configuration/modulescontent_serialization.json")?;
         let modules_content = ModulesContentBuilder::new()
             .minimum_docker_version("1.3.2")
@@ -1330,26 +2289,70 @@ mod tests {
         let edge_agent_json = serde_json::to_value(modules_content)?;
         assert!(
             edge_agent_json == test_json_file,
-            format!(
-                "{}\n is not equal to\n {}",
-                serde_json::to_string_pretty(&edge_agent_json)?,
-                serde_json::to_string_pretty(&test_json_file)?
-            )
+            "{}\n is not equal to\n {}",
+            serde_json::to_string_pretty(&edge_agent_json)?,
+            serde_json::to_string_pretty(&test_json_file)?
         );
         Ok(())
     }
 
     #[test]
-    fn edge_agent_should_deserialize_correctly() -> Result<(), Box<dyn std::error::Error>> {
+    fn modules_content_should_serialize_to_deployment_json(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let test_json_file = load_json_file("configuration/modulescontent_serialization.json")?;
+        let modules_content = ModulesContentBuilder::new()
+            .minimum_docker_version("1.3.2")
+            .logging_options(json!({"some": "option"}))
+            .edge_agent_image("agent-acr.xyz:1.0")
+            .edge_agent_create_options(json!({"some": "create options"}))
+            .edge_hub_image("hub-acr.xyz:1.0")
+            .edge_hub_create_options(json!({"some": "create options"}))
+            .registry_credential("TestCred", "username", "password", "url.xyz")
+            .time_to_live_secs(1)
+            .build()?;
+
+        let deployment = modules_content.to_deployment_json()?;
+        assert_eq!(deployment["content"]["modulesContent"], test_json_file);
+        assert_eq!(deployment.as_object().map(|object| object.len()), Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn edge_agent_should_deserialize_correctly(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let test_json_file = load_json_file("configuration/edgeagent_deserialization.json")?;
         let edge_agent: EdgeAgent = serde_json::from_value(test_json_file)?;
 
-        assert!(edge_agent.modules.get("SomeModule").is_some());
+        assert!(edge_agent.modules.contains_key("SomeModule"));
+        Ok(())
+    }
+
+    #[test]
+    fn environment_variable_should_support_typed_values(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let env: HashMap<String, EnvironmentVariable> = serde_json::from_value(json!({
+            "STRING": { "value": "some value" },
+            "NUMBER": { "value": 42 },
+            "BOOL": { "value": true }
+        }))?;
+
+        assert_eq!(
+            env.get("STRING").unwrap().value().as_str(),
+            Some("some value")
+        );
+        assert_eq!(env.get("NUMBER").unwrap().value().as_i64(), Some(42));
+        assert_eq!(env.get("BOOL").unwrap().value().as_bool(), Some(true));
+
+        let mut variable = EnvironmentVariable::new("1");
+        assert_eq!(serde_json::to_value(&variable)?, json!({ "value": "1" }));
+        variable.set_value(1u64);
+        assert_eq!(serde_json::to_value(&variable)?, json!({ "value": 1 }));
         Ok(())
     }
 
     #[test]
-    fn edge_hub_should_deserialize_correctly() -> Result<(), Box<dyn std::error::Error>> {
+    fn edge_hub_should_deserialize_correctly(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let test_json_file = load_json_file("configuration/edgehub_deserialization.json")?;
         let edge_hub: EdgeHub = serde_json::from_value(test_json_file)?;
 
@@ -1363,4 +2366,221 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn modules_content_should_clone_and_compare(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let builder = ModulesContentBuilder::new()
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.0.9")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.0.9")
+            .minimum_docker_version("v1.25")
+            .registry_credential("some_credential", "username", "secret", "some-acr.acr")
+            .time_to_live_secs(10);
+
+        let modules_content = builder.clone().build()?;
+        assert_eq!(modules_content, builder.build()?);
+
+        let mut changed = modules_content.clone();
+        changed.edge_hub_mut().routes.insert(
+            "SomeRoute".to_string(),
+            "FROM /messages/* INTO $upstream".to_string(),
+        );
+        assert_ne!(modules_content, changed);
+
+        let debug_output = format!("{:?}", modules_content);
+        assert!(debug_output.contains("some-acr.acr"));
+        assert!(!debug_output.contains("secret"));
+        Ok(())
+    }
+
+    #[test]
+    fn registry_credential_provider_should_resolve_on_build(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::configuration::modulescontent::{
+            RegistryCredentialProvider, ACR_TOKEN_USERNAME,
+        };
+
+        let builder = ModulesContentBuilder::new()
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.0.9")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.0.9")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(10)
+            .registry_credential_provider(
+                "acr",
+                "some-acr.azurecr.io",
+                RegistryCredentialProvider::acr_token("some-token"),
+            )
+            .registry_credential_provider(
+                "vault",
+                "registry.local",
+                RegistryCredentialProvider::callback(|address| {
+                    Ok((format!("user@{}", address), "from-vault".to_string()))
+                }),
+            );
+        assert!(!format!("{:?}", builder).contains("some-token"));
+
+        let modules_content = builder.build()?;
+        let registry_credentials = modules_content
+            .edge_agent()
+            .runtime
+            .settings
+            .registry_credentials();
+        assert_eq!(registry_credentials["acr"].username(), ACR_TOKEN_USERNAME);
+        assert_eq!(registry_credentials["acr"].password(), "some-token");
+        assert_eq!(
+            registry_credentials["vault"].username(),
+            "user@registry.local"
+        );
+        assert_eq!(registry_credentials["vault"].password(), "from-vault");
+
+        let result = ModulesContentBuilder::new()
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.0.9")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.0.9")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(10)
+            .registry_credential_provider(
+                "failing",
+                "registry.local",
+                RegistryCredentialProvider::callback(|_| Err("vault unavailable".into())),
+            )
+            .build();
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn modules_content_builder_should_collect_edge_modules(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let edge_module = |module_id: &str| {
+            EdgeModuleBuilder::new()
+                .module_id(module_id)
+                .status(Status::Running)
+                .restart_policy(RestartPolicy::Always)
+                .image("some-image.acr")
+                .version("1.0")
+                .build()
+        };
+
+        let mut builder = vec![edge_module("SensorA")?, edge_module("SensorB")?]
+            .into_iter()
+            .collect::<ModulesContentBuilder>()
+            .edge_modules(vec![edge_module("SensorC")?]);
+        builder.extend(vec![edge_module("SensorA")?]);
+
+        let modules_content = builder
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.0.9")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.0.9")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(10)
+            .build()?;
+
+        let mut module_ids: Vec<&String> = modules_content.edge_agent().modules.keys().collect();
+        module_ids.sort();
+        assert_eq!(module_ids, vec!["SensorA", "SensorB", "SensorC"]);
+        Ok(())
+    }
+
+    #[test]
+    fn registry_credential_builder_should_validate_address() {
+        use super::{is_valid_registry_address, RegistryCredentialBuilder};
+
+        for address in &[
+            "someacr.azurecr.io",
+            "SomeAcr.azurecr.cn",
+            "docker.io",
+            "localhost:5000",
+            "my-registry.example.com",
+        ] {
+            assert!(
+                is_valid_registry_address(address),
+                "{} should be valid",
+                address
+            );
+        }
+        for address in &[
+            "",
+            "https://someacr.azurecr.io",
+            "someacr.azurecr.io/images",
+            "some-acr.azurecr.io",
+            "acr.azurecr.io",
+            "localhost:port",
+            "-registry.io",
+            "some registry.io",
+        ] {
+            assert!(
+                !is_valid_registry_address(address),
+                "{} should be invalid",
+                address
+            );
+        }
+
+        let builder = RegistryCredentialBuilder::new()
+            .username("someacr")
+            .password("secret");
+        assert!(!format!("{:?}", builder).contains("secret"));
+        assert!(builder.clone().build().is_err());
+        let mut credential = builder
+            .address("someacr.azurecr.io")
+            .build()
+            .expect("Building the RegistryCredential should have succeeded");
+
+        credential.set_password("new-secret");
+        credential.set_address("otheracr.azurecr.io");
+        assert_eq!(credential.password(), "new-secret");
+        assert_eq!(credential.address(), "otheracr.azurecr.io");
+    }
+
+    #[test]
+    fn manifest_signer_should_sign_the_canonical_properties(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use std::sync::{Arc, Mutex};
+
+        let signed = Arc::new(Mutex::new(Vec::new()));
+        let recorded = signed.clone();
+        let signer = ManifestSigner::new(move |content| {
+            recorded.lock().unwrap().push(content.to_vec());
+            Ok(ManifestIntegrity::new("ES256", b"signature")
+                .with_signer_certificate(vec!["cert".to_string()]))
+        });
+
+        let modules_content = ModulesContentBuilder::new()
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.2")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.2")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(7200)
+            .route("upstream", "FROM /messages/* INTO $upstream")
+            .manifest_signer(signer)
+            .build()?;
+
+        let signed = signed.lock().unwrap();
+        assert_eq!(signed.len(), 2);
+        assert_eq!(
+            String::from_utf8(signed[1].clone())?,
+            r#"{"routes":{"upstream":"FROM /messages/* INTO $upstream"},"schemaVersion":"1.2","storeAndForwardConfiguration":{"timeToLiveSecs":7200}}"#
+        );
+        assert!(!String::from_utf8(signed[0].clone())?.contains("integrity"));
+
+        let desired = serde_json::to_value(&modules_content)?;
+        let integrity = &desired["$edgeHub"]["properties.desired"]["integrity"];
+        assert_eq!(integrity["signature"]["algorithm"], "ES256");
+        assert_eq!(
+            integrity["signature"]["bytes"],
+            base64::encode(b"signature")
+        );
+        assert_eq!(integrity["header"]["signercert"], json!(["cert"]));
+        assert_eq!(
+            desired["$edgeAgent"]["properties.desired"]["schemaVersion"],
+            "1.2"
+        );
+
+        let failing = ModulesContentBuilder::new()
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.2")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.2")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(7200)
+            .manifest_signer(ManifestSigner::new(|_| Err("no key".into())))
+            .build();
+        assert!(failing.is_err());
+        Ok(())
+    }
 }
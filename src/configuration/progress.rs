@@ -0,0 +1,17 @@
+//! The progress module contains the progress that is reported while a
+//! modules configuration is applied to many edge devices.
+use crate::configuration::ApplyResult;
+use crate::response::Response;
+
+/// The ApplyProgress is passed to the progress callback each time
+/// the modules configuration was applied to one of the devices.
+pub struct ApplyProgress<'r> {
+    /// The id of the device the configuration was applied to
+    pub device_id: &'r str,
+    /// The result of applying the configuration to the device
+    pub result: &'r Result<Response<ApplyResult>, Box<dyn std::error::Error + Send + Sync>>,
+    /// The number of devices the configuration was applied to so far
+    pub completed: usize,
+    /// The number of devices matching the target condition
+    pub total: usize,
+}
@@ -0,0 +1,327 @@
+//! The reported module contains the reported properties of the runtime modules
+//! of an edge device, so the state of the modules and the clients of the edge hub
+//! can be inspected without parsing the twins of `$edgeAgent` and `$edgeHub` by hand.
+use std::collections::HashMap;
+
+use serde::de::Deserializer;
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+use crate::twin::ConnectionState;
+
+/// The runtime status the edge agent reports for a module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeStatus {
+    Running,
+    Stopped,
+    Failed,
+    Backoff,
+    Unhealthy,
+    /// The edge agent does not know the status, or reported a status that is unknown to this crate
+    Unknown,
+}
+
+impl Default for RuntimeStatus {
+    fn default() -> Self {
+        RuntimeStatus::Unknown
+    }
+}
+
+impl RuntimeStatus {
+    /// Get the value of the RuntimeStatus as reported by the edge agent
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuntimeStatus::Running => "running",
+            RuntimeStatus::Stopped => "stopped",
+            RuntimeStatus::Failed => "failed",
+            RuntimeStatus::Backoff => "backoff",
+            RuntimeStatus::Unhealthy => "unhealthy",
+            RuntimeStatus::Unknown => "unknown",
+        }
+    }
+}
+
+impl std::fmt::Display for RuntimeStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Serialize for RuntimeStatus {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for RuntimeStatus {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "running" => RuntimeStatus::Running,
+            "stopped" => RuntimeStatus::Stopped,
+            "failed" => RuntimeStatus::Failed,
+            "backoff" => RuntimeStatus::Backoff,
+            "unhealthy" => RuntimeStatus::Unhealthy,
+            _ => RuntimeStatus::Unknown,
+        })
+    }
+}
+
+/// The status code and description the edge agent reports for the last desired properties
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DesiredStatus {
+    pub code: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// A module as reported by the edge agent
+///
+/// The times are reported in UTC as ISO 8601 strings, and fields that are
+/// unknown to this crate are collected in `extra`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportedModule {
+    #[serde(default)]
+    pub runtime_status: RuntimeStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_start_time_utc: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_exit_time_utc: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_count: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_restart_time_utc: Option<String>,
+    /// The fields of the module that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ReportedModule {
+    /// Get the image the module is running, as reported in its settings
+    pub fn image(&self) -> Option<&str> {
+        self.extra
+            .get("settings")
+            .and_then(|settings| settings.get("image"))
+            .and_then(serde_json::Value::as_str)
+    }
+
+    /// Get the version of the module, as set in the deployment
+    pub fn version(&self) -> Option<&str> {
+        self.extra
+            .get("version")
+            .and_then(serde_json::Value::as_str)
+    }
+}
+
+/// The reported properties of the `$edgeAgent` module
+///
+/// Fields that are unknown to this crate, such as `$metadata` and `$version`,
+/// are collected in `extra`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EdgeAgentReported {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_desired_version: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_desired_status: Option<DesiredStatus>,
+    #[serde(default)]
+    pub system_modules: HashMap<String, ReportedModule>,
+    #[serde(default)]
+    pub modules: HashMap<String, ReportedModule>,
+    /// The fields of the reported properties that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl EdgeAgentReported {
+    /// Get a reported module by name, looking in the system modules first
+    pub fn module(&self, module_name: &str) -> Option<&ReportedModule> {
+        self.system_modules
+            .get(module_name)
+            .or_else(|| self.modules.get(module_name))
+    }
+
+    /// Get the names of the modules, including the system modules, that do not report `running`
+    pub fn modules_not_running(&self) -> Vec<&String> {
+        let mut module_names: Vec<&String> = self
+            .system_modules
+            .iter()
+            .chain(self.modules.iter())
+            .filter(|(_, module)| module.runtime_status != RuntimeStatus::Running)
+            .map(|(module_name, _)| module_name)
+            .collect();
+        module_names.sort();
+        module_names
+    }
+}
+
+/// A client of the edge hub, such as a module or a downstream device, as reported by the edge hub
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportedClient {
+    pub status: ConnectionState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_connected_time_utc: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_disconnected_time_utc: Option<String>,
+    /// The fields of the client that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The reported properties of the `$edgeHub` module
+///
+/// The clients are keyed by their identity, which is `{device_id}/{module_id}` for
+/// modules and the device id for downstream devices.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EdgeHubReported {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_desired_version: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_desired_status: Option<DesiredStatus>,
+    #[serde(default)]
+    pub clients: HashMap<String, ReportedClient>,
+    /// The fields of the reported properties that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl EdgeHubReported {
+    /// Get the identities of the clients that are connected to the edge hub
+    pub fn connected_clients(&self) -> Vec<&String> {
+        let mut client_ids: Vec<&String> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.status == ConnectionState::Connected)
+            .map(|(client_id, _)| client_id)
+            .collect();
+        client_ids.sort();
+        client_ids
+    }
+
+    /// Check whether a module of the edge device is connected to the edge hub
+    pub fn is_module_connected(&self, device_id: &str, module_id: &str) -> bool {
+        self.clients
+            .get(&format!("{}/{}", device_id, module_id))
+            .map(|client| client.status == ConnectionState::Connected)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{EdgeAgentReported, EdgeHubReported, RuntimeStatus};
+
+    #[test]
+    fn edge_agent_reported_should_deserialize() -> Result<(), serde_json::Error> {
+        let reported: EdgeAgentReported = serde_json::from_value(json!({
+            "schemaVersion": "1.0",
+            "lastDesiredVersion": 4,
+            "lastDesiredStatus": { "code": 200 },
+            "systemModules": {
+                "edgeAgent": {
+                    "runtimeStatus": "running",
+                    "lastStartTimeUtc": "2020-04-01T10:00:00.0000000Z"
+                },
+                "edgeHub": {
+                    "runtimeStatus": "running",
+                    "exitCode": 0,
+                    "restartCount": 1,
+                    "lastStartTimeUtc": "2020-04-01T10:00:05.0000000Z",
+                    "lastExitTimeUtc": "2020-04-01T10:00:03.0000000Z"
+                }
+            },
+            "modules": {
+                "sensor": {
+                    "runtimeStatus": "backoff",
+                    "statusDescription": "Container exited",
+                    "exitCode": 139,
+                    "restartCount": 5,
+                    "settings": { "image": "sensor:1.0" }
+                },
+                "debugger": { "runtimeStatus": "restarting" }
+            },
+            "$version": 12
+        }))?;
+
+        assert_eq!(reported.last_desired_version, Some(4));
+        assert_eq!(reported.last_desired_status.as_ref().unwrap().code, 200);
+        assert_eq!(reported.extra["$version"], 12);
+
+        let edge_hub = reported.module("edgeHub").unwrap();
+        assert_eq!(edge_hub.runtime_status, RuntimeStatus::Running);
+        assert_eq!(edge_hub.exit_code, Some(0));
+        assert_eq!(edge_hub.restart_count, Some(1));
+        assert_eq!(
+            edge_hub.last_exit_time_utc.as_deref(),
+            Some("2020-04-01T10:00:03.0000000Z")
+        );
+
+        let sensor = reported.module("sensor").unwrap();
+        assert_eq!(sensor.runtime_status, RuntimeStatus::Backoff);
+        assert_eq!(sensor.exit_code, Some(139));
+        assert_eq!(sensor.extra["settings"]["image"], "sensor:1.0");
+
+        assert_eq!(
+            reported.module("debugger").unwrap().runtime_status,
+            RuntimeStatus::Unknown
+        );
+        assert_eq!(reported.modules_not_running(), vec!["debugger", "sensor"]);
+        Ok(())
+    }
+
+    #[test]
+    fn edge_hub_reported_should_deserialize() -> Result<(), serde_json::Error> {
+        let reported: EdgeHubReported = serde_json::from_value(json!({
+            "schemaVersion": "1.0",
+            "lastDesiredVersion": 3,
+            "lastDesiredStatus": { "code": 200 },
+            "clients": {
+                "some-device/sensor": {
+                    "status": "Connected",
+                    "lastConnectedTimeUtc": "2020-04-01T10:00:05.0000000Z"
+                },
+                "some-device/debugger": {
+                    "status": "Disconnected",
+                    "lastConnectedTimeUtc": "2020-04-01T10:00:05.0000000Z",
+                    "lastDisconnectedTimeUtc": "2020-04-01T10:05:00.0000000Z"
+                },
+                "downstream-device": { "status": "Connected" }
+            }
+        }))?;
+
+        assert_eq!(reported.last_desired_version, Some(3));
+        assert_eq!(
+            reported.connected_clients(),
+            vec!["downstream-device", "some-device/sensor"]
+        );
+        assert!(reported.is_module_connected("some-device", "sensor"));
+        assert!(!reported.is_module_connected("some-device", "debugger"));
+        assert!(!reported.is_module_connected("some-device", "unknown"));
+        assert_eq!(
+            reported.clients["some-device/debugger"]
+                .last_disconnected_time_utc
+                .as_deref(),
+            Some("2020-04-01T10:05:00.0000000Z")
+        );
+        Ok(())
+    }
+}
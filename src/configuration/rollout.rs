@@ -0,0 +1,473 @@
+//! The rollout module is used for applying a modules configuration to a fleet
+//! of edge devices in waves, so a faulty deployment is noticed on a small part
+//! of the fleet before it reaches all devices.
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::concurrent::for_each_bounded;
+use crate::configuration::ConvergenceReport;
+use crate::error::{BuilderError, BuilderErrorType};
+use crate::{IoTHubService, ModulesContent};
+
+/// The waves used when no waves are given
+const DEFAULT_WAVE_PERCENTAGES: [u8; 3] = [5, 25, 100];
+
+/// The time a device gets to converge when no timeout is given
+pub const DEFAULT_CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The number of devices the configuration is applied to at the same time when no maximum is given
+pub const DEFAULT_MAX_CONCURRENCY: usize = 10;
+
+/// A wave of a rollout, which selects the devices the configuration is applied to
+#[derive(Debug, Clone, PartialEq)]
+pub enum RolloutWave {
+    /// The given percentage of all target devices, including the devices of earlier waves
+    Percentage(u8),
+    /// The target devices matching an additional condition, such as `tags.ring = 'canary'`
+    Condition(String),
+}
+
+/// The result of a single wave of a rollout
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaveReport {
+    wave: RolloutWave,
+    converged_devices: Vec<String>,
+    failed_devices: Vec<String>,
+}
+
+impl WaveReport {
+    /// Get the wave this report is about
+    pub fn wave(&self) -> &RolloutWave {
+        &self.wave
+    }
+
+    /// Get the ids of the devices that converged to the configuration
+    pub fn converged_devices(&self) -> &Vec<String> {
+        &self.converged_devices
+    }
+
+    /// Get the ids of the devices the configuration could not be applied to or that did not converge
+    pub fn failed_devices(&self) -> &Vec<String> {
+        &self.failed_devices
+    }
+
+    /// Get the fraction of the devices in the wave that failed
+    pub fn failure_ratio(&self) -> f64 {
+        let total = self.converged_devices.len() + self.failed_devices.len();
+        if total == 0 {
+            0.0
+        } else {
+            self.failed_devices.len() as f64 / total as f64
+        }
+    }
+}
+
+/// The result of a rollout
+#[derive(Debug, Clone, PartialEq)]
+pub struct RolloutReport {
+    waves: Vec<WaveReport>,
+    halted: bool,
+}
+
+impl RolloutReport {
+    /// Get the reports of the waves that were rolled out
+    pub fn waves(&self) -> &Vec<WaveReport> {
+        &self.waves
+    }
+
+    /// Check whether the rollout was halted because a wave exceeded the failure threshold
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+}
+
+/// The RolloutOrchestratorBuilder is used for building a RolloutOrchestrator
+#[derive(Debug, Clone, Default)]
+pub struct RolloutOrchestratorBuilder {
+    target_condition: Option<String>,
+    waves: Vec<RolloutWave>,
+    failure_threshold: Option<f64>,
+    convergence_timeout: Option<Duration>,
+    max_concurrency: Option<usize>,
+}
+
+impl RolloutOrchestratorBuilder {
+    /// Create a new RolloutOrchestratorBuilder
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use azure_iothub_service::configuration::rollout::RolloutOrchestratorBuilder;
+    ///
+    /// let orchestrator = RolloutOrchestratorBuilder::new()
+    ///     .target_condition("tags.environment = 'production'")
+    ///     .condition_wave("tags.ring = 'canary'")
+    ///     .percentage_wave(25)
+    ///     .percentage_wave(100)
+    ///     .failure_threshold(0.05)
+    ///     .convergence_timeout(Duration::from_secs(300))
+    ///     .build()
+    ///     .expect("Failed to build the RolloutOrchestrator");
+    /// ```
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the condition the edge devices of the rollout must match
+    pub fn target_condition<S>(mut self, target_condition: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.target_condition = Some(target_condition.into());
+        self
+    }
+
+    /// Add a wave with a percentage of the target devices, which must be between 1 and 100
+    pub fn percentage_wave(mut self, percentage: u8) -> Self {
+        self.waves.push(RolloutWave::Percentage(percentage));
+        self
+    }
+
+    /// Add a wave with the target devices matching an additional condition
+    pub fn condition_wave<S>(mut self, condition: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.waves.push(RolloutWave::Condition(condition.into()));
+        self
+    }
+
+    /// Set the fraction of devices in a wave that may fail before the rollout is halted
+    pub fn failure_threshold(mut self, failure_threshold: f64) -> Self {
+        self.failure_threshold = Some(failure_threshold);
+        self
+    }
+
+    /// Set the time every device gets to converge to the configuration
+    pub fn convergence_timeout(mut self, convergence_timeout: Duration) -> Self {
+        self.convergence_timeout = Some(convergence_timeout);
+        self
+    }
+
+    /// Set the number of devices the configuration is applied to at the same time
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = Some(max_concurrency);
+        self
+    }
+
+    /// Build the RolloutOrchestrator
+    ///
+    /// Without waves the configuration is rolled out to 5%, 25% and 100% of the devices.
+    pub fn build(self) -> Result<RolloutOrchestrator, BuilderError> {
+        let target_condition =
+            self.target_condition
+                .ok_or(BuilderError::new(BuilderErrorType::MissingValue(
+                    "target_condition",
+                )))?;
+
+        let waves = if self.waves.is_empty() {
+            DEFAULT_WAVE_PERCENTAGES
+                .iter()
+                .map(|percentage| RolloutWave::Percentage(*percentage))
+                .collect()
+        } else {
+            self.waves
+        };
+        let valid_percentages = waves.iter().all(|wave| match wave {
+            RolloutWave::Percentage(percentage) => (1..=100).contains(percentage),
+            RolloutWave::Condition(_) => true,
+        });
+        if !valid_percentages {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                "percentage_wave",
+            )));
+        }
+
+        let failure_threshold = self.failure_threshold.unwrap_or_default();
+        if !(0.0..=1.0).contains(&failure_threshold) {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                "failure_threshold",
+            )));
+        }
+
+        Ok(RolloutOrchestrator {
+            target_condition,
+            waves,
+            failure_threshold,
+            convergence_timeout: self
+                .convergence_timeout
+                .unwrap_or(DEFAULT_CONVERGENCE_TIMEOUT),
+            max_concurrency: self
+                .max_concurrency
+                .unwrap_or(DEFAULT_MAX_CONCURRENCY)
+                .max(1),
+        })
+    }
+}
+
+/// The RolloutOrchestrator applies a modules configuration to edge devices in waves
+///
+/// Every wave waits until its devices converged to the configuration or the convergence
+/// timeout elapsed. When the fraction of failed devices in a wave exceeds the failure
+/// threshold, the rollout is halted and the remaining waves are skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RolloutOrchestrator {
+    target_condition: String,
+    waves: Vec<RolloutWave>,
+    failure_threshold: f64,
+    convergence_timeout: Duration,
+    max_concurrency: usize,
+}
+
+impl RolloutOrchestrator {
+    /// Get the waves of the rollout
+    pub fn waves(&self) -> &Vec<RolloutWave> {
+        &self.waves
+    }
+
+    /// Roll the modules configuration out to the target devices
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::{IoTHubService, ModulesContentBuilder};
+    /// use azure_iothub_service::configuration::rollout::RolloutOrchestratorBuilder;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// # let modules_content = ModulesContentBuilder::new().build()?;
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let report = RolloutOrchestratorBuilder::new()
+    ///     .target_condition("tags.environment = 'production'")
+    ///     .build()?
+    ///     .run(&iothub, &modules_content)
+    ///     .await?;
+    /// if report.is_halted() {
+    ///     let wave = report.waves().last().unwrap();
+    ///     println!("Halted at {:?}, failed: {:?}", wave.wave(), wave.failed_devices());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn run(
+        &self,
+        iothub_service: &IoTHubService,
+        modules_content: &ModulesContent,
+    ) -> Result<RolloutReport, Box<dyn std::error::Error + Send + Sync>> {
+        let mut target_devices = self.edge_device_ids(iothub_service, None).await?;
+        target_devices.sort();
+
+        let mut deployed = HashSet::new();
+        let mut waves = Vec::new();
+        for wave in &self.waves {
+            let wave_devices: Vec<String> = match wave {
+                RolloutWave::Percentage(percentage) => {
+                    let count = (target_devices.len() * *percentage as usize + 99) / 100;
+                    target_devices[..count].to_vec()
+                }
+                RolloutWave::Condition(condition) => {
+                    let mut devices = self
+                        .edge_device_ids(iothub_service, Some(condition))
+                        .await?;
+                    devices.sort();
+                    devices
+                }
+            };
+            let wave_devices: Vec<String> = wave_devices
+                .into_iter()
+                .filter(|device_id| !deployed.contains(device_id))
+                .collect();
+            deployed.extend(wave_devices.iter().cloned());
+
+            let report = self
+                .roll_out_wave(iothub_service, modules_content, wave, wave_devices)
+                .await;
+            let halted = report.failure_ratio() > self.failure_threshold;
+            waves.push(report);
+            if halted {
+                return Ok(RolloutReport {
+                    waves,
+                    halted: true,
+                });
+            }
+        }
+
+        Ok(RolloutReport {
+            waves,
+            halted: false,
+        })
+    }
+
+    /// Get the ids of the edge devices matching the target condition and an additional condition
+    async fn edge_device_ids(
+        &self,
+        iothub_service: &IoTHubService,
+        condition: Option<&str>,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut query = iothub_service
+            .build_query()
+            .select("deviceId")
+            .from("devices")
+            .and_where(format!(
+                "capabilities.iotEdge = true AND ({})",
+                self.target_condition
+            ));
+        if let Some(condition) = condition {
+            query = query.and_where(format!("({})", condition));
+        }
+
+        let items = query
+            .build()?
+            .execute_all_pages::<serde_json::Value>()
+            .await?;
+        Ok(items
+            .iter()
+            .filter_map(|item| item["deviceId"].as_str().map(String::from))
+            .collect())
+    }
+
+    /// Apply the configuration to the devices of a wave and wait for them to converge
+    async fn roll_out_wave(
+        &self,
+        iothub_service: &IoTHubService,
+        modules_content: &ModulesContent,
+        wave: &RolloutWave,
+        device_ids: Vec<String>,
+    ) -> WaveReport {
+        let apply = |device_id: String| async move {
+            let result: Result<ConvergenceReport, _> = iothub_service
+                .apply_modules_configuration_and_wait(
+                    device_id.as_str(),
+                    modules_content,
+                    self.convergence_timeout,
+                )
+                .await
+                .map(|report| report.into_inner());
+            (device_id, result)
+        };
+
+        let mut report = WaveReport {
+            wave: wave.clone(),
+            converged_devices: Vec::new(),
+            failed_devices: Vec::new(),
+        };
+        for (device_id, result) in for_each_bounded(device_ids, self.max_concurrency, apply).await {
+            match result {
+                Ok(convergence) if convergence.is_converged() => {
+                    report.converged_devices.push(device_id)
+                }
+                _ => report.failed_devices.push(device_id),
+            }
+        }
+
+        report.converged_devices.sort();
+        report.failed_devices.sort();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::configuration::rollout::{RolloutOrchestratorBuilder, RolloutWave};
+    use crate::testing::mocktransport::{json_response, MockTransport};
+    use crate::{IoTHubService, ModulesContentBuilder};
+
+    fn run_rollout(
+        failing: Vec<&'static str>,
+    ) -> Result<
+        (crate::configuration::rollout::RolloutReport, Vec<String>),
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        // Ten edge devices are returned in pages of five devices, of which the devices
+        // in `failing` never converge
+        let transport = MockTransport::new(move |request, _| {
+            let path = request.uri().path();
+            if path.ends_with("/devices/query") {
+                let mut response = http::Response::builder();
+                let first = match request.headers().get("x-ms-continuation") {
+                    Some(_) => 5,
+                    None => {
+                        response = response.header("x-ms-continuation", "page-2");
+                        0
+                    }
+                };
+                let devices: Vec<serde_json::Value> = (first..first + 5)
+                    .map(|index| serde_json::json!({ "deviceId": format!("device-{}", index) }))
+                    .collect();
+                return Ok(response.body(serde_json::to_vec(&devices)?)?);
+            }
+            if path.ends_with("/applyConfigurationContent") {
+                return Ok(http::Response::new(Vec::new()));
+            }
+
+            let device_id = path.split('/').nth(2).unwrap_or_default();
+            let converged = !failing.contains(&device_id);
+            json_response(&serde_json::json!({
+                "deviceId": device_id,
+                "moduleId": "$edgeAgent",
+                "etag": "AAAAAAAAAAI=",
+                "status": "enabled",
+                "connectionState": "Connected",
+                "properties": {
+                    "desired": { "$version": 2 },
+                    "reported": {
+                        "lastDesiredVersion": if converged { 2 } else { 1 },
+                        "lastDesiredStatus": { "code": 200 }
+                    }
+                }
+            }))
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let modules_content = ModulesContentBuilder::new()
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.0.9")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.0.9")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(10)
+            .build()?;
+
+        let orchestrator = RolloutOrchestratorBuilder::new()
+            .target_condition("tags.environment = 'production'")
+            .percentage_wave(10)
+            .percentage_wave(50)
+            .percentage_wave(100)
+            .failure_threshold(0.2)
+            .convergence_timeout(Duration::from_secs(0))
+            .build()?;
+        let report = futures::executor::block_on(orchestrator.run(&iothub, &modules_content))?;
+        let applied = transport
+            .requests()
+            .iter()
+            .map(|request| request.uri().path().to_string())
+            .filter(|path| path.ends_with("/applyConfigurationContent"))
+            .collect();
+        Ok((report, applied))
+    }
+
+    #[test]
+    fn rollout_should_apply_waves_until_failure_threshold(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (report, applied) = run_rollout(vec!["device-7"])?;
+        assert!(!report.is_halted());
+        assert_eq!(report.waves().len(), 3);
+        assert_eq!(report.waves()[0].converged_devices(), &vec!["device-0"]);
+        assert_eq!(report.waves()[1].converged_devices().len(), 4);
+        assert_eq!(report.waves()[2].failed_devices(), &vec!["device-7"]);
+        assert_eq!(applied.len(), 10);
+
+        let (report, applied) = run_rollout(vec!["device-2", "device-3"])?;
+        assert!(report.is_halted());
+        assert_eq!(report.waves().len(), 2);
+        assert_eq!(report.waves()[1].wave(), &RolloutWave::Percentage(50));
+        assert_eq!(report.waves()[1].failure_ratio(), 0.5);
+        assert_eq!(applied.len(), 5);
+
+        assert!(RolloutOrchestratorBuilder::new()
+            .target_condition("*")
+            .percentage_wave(0)
+            .build()
+            .is_err());
+        Ok(())
+    }
+}
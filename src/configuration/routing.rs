@@ -0,0 +1,359 @@
+//! The routing module contains a generator for the routes of an edge deployment
+//! and the report of the routes, which tells whether the edge hub applied the
+//! desired routes and whether the modules that send messages into the routes are connected.
+use crate::configuration::reported::EdgeHubReported;
+use crate::error::{BuilderError, BuilderErrorType};
+use crate::twin::ModuleTwin;
+
+/// The status code the edge hub reports when it applied the desired properties
+const DESIRED_STATUS_OK: i64 = 200;
+
+/// The prefix of the source of a route that receives the messages of a module
+const MODULE_SOURCE_PREFIX: &str = "/messages/modules/";
+
+/// The endpoint of a route that sends messages to the IoT Hub
+const UPSTREAM_ENDPOINT: &str = "$upstream";
+
+/// The Route generates the routes of an edge deployment, which are easy to get wrong by hand
+///
+/// A route selects messages from a source, optionally filters them with a condition,
+/// and sends them into the IoT Hub or the input of a module.
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::configuration::Route;
+///
+/// let route = Route::from_module_output("sensor", "temperature")
+///     .into_module_input("filter", "input1")
+///     .expect("Invalid route");
+/// assert_eq!(
+///     route,
+///     r#"FROM /messages/modules/sensor/outputs/temperature INTO BrokeredEndpoint("/modules/filter/inputs/input1")"#
+/// );
+///
+/// let route = Route::from_module_output("filter", "*")
+///     .with_condition("temperature > 20")
+///     .into_upstream()
+///     .expect("Invalid route");
+/// assert_eq!(
+///     route,
+///     "FROM /messages/modules/filter/outputs/* WHERE temperature > 20 INTO $upstream"
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    module_id: Option<String>,
+    output: Option<String>,
+    condition: Option<String>,
+}
+
+impl Route {
+    /// Route the messages of all modules and devices
+    pub fn from_all_messages() -> Self {
+        Route {
+            module_id: None,
+            output: None,
+            condition: None,
+        }
+    }
+
+    /// Route the messages of an output of a module, where the output `*` selects all outputs
+    pub fn from_module_output<S, T>(module_id: S, output: T) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        Route {
+            module_id: Some(module_id.into()),
+            output: Some(output.into()),
+            condition: None,
+        }
+    }
+
+    /// Only route the messages matching a condition, such as `temperature > 20`
+    pub fn with_condition<S>(mut self, condition: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.condition = Some(condition.into());
+        self
+    }
+
+    /// Create the route that sends the messages to the IoT Hub
+    pub fn into_upstream(self) -> Result<String, BuilderError> {
+        self.into_endpoint(UPSTREAM_ENDPOINT.to_string())
+    }
+
+    /// Create the route that sends the messages to an input of a module
+    pub fn into_module_input<S, T>(self, module_id: S, input: T) -> Result<String, BuilderError>
+    where
+        S: AsRef<str>,
+        T: AsRef<str>,
+    {
+        let (module_id, input) = (module_id.as_ref(), input.as_ref());
+        if !is_route_name(module_id) {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                "module_id",
+            )));
+        }
+        if !is_route_name(input) {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue("input")));
+        }
+
+        self.into_endpoint(format!(
+            "BrokeredEndpoint(\"/modules/{}/inputs/{}\")",
+            module_id, input
+        ))
+    }
+
+    /// Create the route that sends the messages to the given endpoint
+    fn into_endpoint(self, endpoint: String) -> Result<String, BuilderError> {
+        let source = match (&self.module_id, &self.output) {
+            (Some(module_id), Some(output)) => {
+                if !is_route_name(module_id) {
+                    return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                        "module_id",
+                    )));
+                }
+                if output != "*" && !is_route_name(output) {
+                    return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                        "output",
+                    )));
+                }
+                format!("{}{}/outputs/{}", MODULE_SOURCE_PREFIX, module_id, output)
+            }
+            _ => "/messages/*".to_string(),
+        };
+
+        match self.condition {
+            Some(condition) if condition.trim().is_empty() => Err(BuilderError::new(
+                BuilderErrorType::IncorrectValue("condition"),
+            )),
+            Some(condition) => Ok(format!(
+                "FROM {} WHERE {} INTO {}",
+                source,
+                condition.trim(),
+                endpoint
+            )),
+            None => Ok(format!("FROM {} INTO {}", source, endpoint)),
+        }
+    }
+}
+
+/// Check whether a name of a module, output or input can be used in a route
+fn is_route_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| !c.is_whitespace() && !matches!(c, '/' | '"' | '*'))
+}
+
+/// The validation of a single route of an edge deployment
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteValidation {
+    route_name: String,
+    route: String,
+    source_module: Option<String>,
+    source_connected: bool,
+}
+
+impl RouteValidation {
+    /// Get the name of the route in the deployment
+    pub fn route_name(&self) -> &String {
+        &self.route_name
+    }
+
+    /// Get the route, such as `FROM /messages/modules/sensor/outputs/* INTO $upstream`
+    pub fn route(&self) -> &String {
+        &self.route
+    }
+
+    /// Get the module whose messages are routed, if the route selects the messages of a single module
+    pub fn source_module(&self) -> &Option<String> {
+        &self.source_module
+    }
+
+    /// Check whether the module whose messages are routed is connected to the edge hub
+    ///
+    /// Routes that do not select the messages of a single module are always considered connected.
+    pub fn is_source_connected(&self) -> bool {
+        self.source_connected
+    }
+}
+
+/// The RouteReport describes whether the routes of an edge deployment took effect
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteReport {
+    desired_version: Option<i64>,
+    reported_version: Option<i64>,
+    reported_status_code: Option<i64>,
+    reported_status_description: Option<String>,
+    routes: Vec<RouteValidation>,
+}
+
+impl RouteReport {
+    /// Create the RouteReport from the twin of the `$edgeHub` module
+    pub(crate) fn from_edge_hub_twin(
+        edge_hub_twin: &ModuleTwin,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let desired = &edge_hub_twin.properties.desired;
+        let reported: EdgeHubReported =
+            serde_json::from_value(edge_hub_twin.properties.reported.clone())?;
+
+        let mut routes = Vec::new();
+        if let Some(desired_routes) = desired["routes"].as_object() {
+            for (route_name, route) in desired_routes {
+                // Routes are either a string or an object with the route and its priority
+                let route = match route.as_str() {
+                    Some(route) => route,
+                    None => route["route"].as_str().unwrap_or_default(),
+                };
+                let source_module = source_module(route);
+                let source_connected = match &source_module {
+                    Some(module_id) => {
+                        reported.is_module_connected(&edge_hub_twin.device_id, module_id)
+                    }
+                    None => true,
+                };
+                routes.push(RouteValidation {
+                    route_name: route_name.clone(),
+                    route: route.to_string(),
+                    source_module,
+                    source_connected,
+                });
+            }
+        }
+        routes.sort_by(|a, b| a.route_name.cmp(&b.route_name));
+
+        let (reported_status_code, reported_status_description) = match reported.last_desired_status
+        {
+            Some(status) => (Some(status.code), status.description),
+            None => (None, None),
+        };
+        Ok(RouteReport {
+            desired_version: desired["$version"].as_i64(),
+            reported_version: reported.last_desired_version,
+            reported_status_code,
+            reported_status_description,
+            routes,
+        })
+    }
+
+    /// Get the version of the desired properties of the `$edgeHub` twin
+    pub fn desired_version(&self) -> Option<i64> {
+        self.desired_version
+    }
+
+    /// Get the version of the desired properties the edge hub last applied
+    pub fn reported_version(&self) -> Option<i64> {
+        self.reported_version
+    }
+
+    /// Get the status code the edge hub reported for the last desired properties
+    pub fn reported_status_code(&self) -> Option<i64> {
+        self.reported_status_code
+    }
+
+    /// Get the description the edge hub reported for the last desired properties,
+    /// which explains why invalid routes were rejected
+    pub fn reported_status_description(&self) -> &Option<String> {
+        &self.reported_status_description
+    }
+
+    /// Get the validation of each route in the deployment, ordered by name
+    pub fn routes(&self) -> &Vec<RouteValidation> {
+        &self.routes
+    }
+
+    /// Get the routes whose source module is not connected to the edge hub
+    pub fn routes_without_connected_source(&self) -> Vec<&RouteValidation> {
+        self.routes
+            .iter()
+            .filter(|route| !route.source_connected)
+            .collect()
+    }
+
+    /// Check whether the edge hub applied the latest desired routes without errors
+    pub fn is_applied(&self) -> bool {
+        self.desired_version.is_some()
+            && self.desired_version == self.reported_version
+            && self.reported_status_code.unwrap_or(DESIRED_STATUS_OK) == DESIRED_STATUS_OK
+    }
+
+    /// Check whether the routes are applied and all source modules are connected
+    pub fn is_healthy(&self) -> bool {
+        self.is_applied() && self.routes.iter().all(RouteValidation::is_source_connected)
+    }
+}
+
+/// Get the module whose messages are selected by the `FROM` clause of a route
+fn source_module(route: &str) -> Option<String> {
+    let from = route.find("FROM")? + "FROM".len();
+    let source = route[from..].trim_start();
+    let module = source.strip_prefix(MODULE_SOURCE_PREFIX)?;
+    let end = module
+        .find(|c: char| c == '/' || c.is_whitespace())
+        .unwrap_or(module.len());
+    match &module[..end] {
+        "" | "*" => None,
+        module_id => Some(module_id.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{source_module, Route};
+
+    #[test]
+    fn source_module_should_be_parsed_from_the_route() {
+        assert_eq!(
+            source_module("FROM /messages/modules/sensor/outputs/* INTO $upstream"),
+            Some("sensor".to_string())
+        );
+        assert_eq!(
+            source_module(
+                "FROM /messages/modules/sensor/* WHERE temperature > 20 INTO BrokeredEndpoint(\"/modules/filter/inputs/input1\")"
+            ),
+            Some("sensor".to_string())
+        );
+        assert_eq!(source_module("FROM /messages/* INTO $upstream"), None);
+        assert_eq!(
+            source_module("FROM /messages/modules/*/outputs/* INTO $upstream"),
+            None
+        );
+    }
+
+    #[test]
+    fn routes_should_be_generated() {
+        let route = Route::from_module_output("sensor", "*")
+            .into_upstream()
+            .unwrap();
+        assert_eq!(
+            route,
+            "FROM /messages/modules/sensor/outputs/* INTO $upstream"
+        );
+        assert_eq!(source_module(&route), Some("sensor".to_string()));
+
+        assert_eq!(
+            Route::from_all_messages()
+                .with_condition("$connectionDeviceId = 'leaf'")
+                .into_module_input("filter", "input1")
+                .unwrap(),
+            r#"FROM /messages/* WHERE $connectionDeviceId = 'leaf' INTO BrokeredEndpoint("/modules/filter/inputs/input1")"#
+        );
+
+        assert!(Route::from_module_output("sensor/1", "output1")
+            .into_upstream()
+            .is_err());
+        assert!(Route::from_module_output("sensor", "")
+            .into_upstream()
+            .is_err());
+        assert!(Route::from_module_output("sensor", "output1")
+            .into_module_input("filter", "input 1")
+            .is_err());
+        assert!(Route::from_all_messages()
+            .with_condition(" ")
+            .into_upstream()
+            .is_err());
+    }
+}
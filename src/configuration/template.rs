@@ -0,0 +1,239 @@
+//! The template module is used for generating many similar modules
+//! configurations from a single ModulesContent with named parameters.
+use std::collections::{BTreeSet, HashMap};
+
+use crate::configuration::modulescontent::{EnvironmentVariable, ModuleSettings, ModulesContent};
+use crate::error::TemplateError;
+
+/// The start of a parameter in a template
+const PARAMETER_START: &str = "{{";
+
+/// The end of a parameter in a template
+const PARAMETER_END: &str = "}}";
+
+/// The ManifestTemplate is a ModulesContent in which the images, environment
+/// variables and routes can contain named parameters such as `{{version}}`.
+///
+/// The parameters are substituted when the template is rendered, which results in
+/// a new ModulesContent for every set of parameters.
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use azure_iothub_service::configuration::{
+///     EdgeModuleBuilder, ManifestTemplate, ModulesContentBuilder, RestartPolicy, Status,
+/// };
+///
+/// let modules_content = ModulesContentBuilder::new()
+///     .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.0.9")
+///     .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.0.9")
+///     .minimum_docker_version("v1.25")
+///     .time_to_live_secs(10)
+///     .edge_module(
+///         EdgeModuleBuilder::new()
+///             .module_id("Sensor")
+///             .status(Status::Running)
+///             .restart_policy(RestartPolicy::Always)
+///             .image("some-acr.azurecr.io/sensor:{{version}}")
+///             .environment_variable("SITE", "{{site}}")
+///             .version("1.0")
+///             .build()
+///             .expect("Failed to build the EdgeModule"),
+///     )
+///     .build()
+///     .expect("Failed to build the ModulesContent");
+///
+/// let template = ManifestTemplate::new(modules_content);
+/// let mut parameters = HashMap::new();
+/// parameters.insert("version".to_string(), "1.2.0".to_string());
+/// parameters.insert("site".to_string(), "amsterdam".to_string());
+/// let modules_content = template.render(&parameters).expect("Unresolved parameters");
+/// assert_eq!(
+///     modules_content.edge_agent().modules()["Sensor"].settings.image(),
+///     "some-acr.azurecr.io/sensor:1.2.0"
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestTemplate {
+    modules_content: ModulesContent,
+}
+
+impl ManifestTemplate {
+    /// Create a new ManifestTemplate from a ModulesContent containing parameters
+    pub fn new(modules_content: ModulesContent) -> Self {
+        ManifestTemplate { modules_content }
+    }
+
+    /// Get the ModulesContent of the template, with the parameters unsubstituted
+    pub fn modules_content(&self) -> &ModulesContent {
+        &self.modules_content
+    }
+
+    /// Get the names of all parameters used in the template
+    pub fn parameters(&self) -> BTreeSet<String> {
+        let mut parameters = BTreeSet::new();
+        let mut modules_content = self.modules_content.clone();
+        visit_strings(&mut modules_content, &mut |value| {
+            substitute(value, &HashMap::new(), &mut parameters);
+        });
+        parameters
+    }
+
+    /// Render the template by substituting the parameters
+    ///
+    /// When the template contains parameters that are not given, a TemplateError
+    /// listing all unresolved parameters is returned.
+    pub fn render(
+        &self,
+        parameters: &HashMap<String, String>,
+    ) -> Result<ModulesContent, TemplateError> {
+        let mut unresolved = BTreeSet::new();
+        let mut modules_content = self.modules_content.clone();
+        visit_strings(&mut modules_content, &mut |value| {
+            *value = substitute(value, parameters, &mut unresolved);
+        });
+
+        if !unresolved.is_empty() {
+            return Err(TemplateError::new(unresolved.into_iter().collect()));
+        }
+        Ok(modules_content)
+    }
+}
+
+/// Call the visitor for every string of the modules content that can contain parameters
+fn visit_strings(modules_content: &mut ModulesContent, visit: &mut dyn FnMut(&mut String)) {
+    let visit_env = |env: &mut HashMap<String, EnvironmentVariable>,
+                     visit: &mut dyn FnMut(&mut String)| {
+        for variable in env.values_mut() {
+            if let Some(value) = variable.value().as_str() {
+                let mut value = value.to_string();
+                visit(&mut value);
+                variable.set_value(value);
+            }
+        }
+    };
+
+    let edge_agent = modules_content.edge_agent_mut();
+    let system_modules = edge_agent.system_modules_mut();
+    let edge_agent_settings = system_modules.edge_agent_mut();
+    visit_module_settings(edge_agent_settings.settings_mut(), visit);
+    visit_env(edge_agent_settings.env_mut(), visit);
+
+    let edge_hub_settings = system_modules.edge_hub_mut();
+    visit_module_settings(edge_hub_settings.settings_mut(), visit);
+    visit_env(edge_hub_settings.env_mut(), visit);
+
+    for edge_module in edge_agent.modules_mut().values_mut() {
+        visit_module_settings(&mut edge_module.settings, visit);
+        visit_env(&mut edge_module.env, visit);
+    }
+
+    for route in modules_content.edge_hub_mut().routes_mut().values_mut() {
+        visit(route);
+    }
+}
+
+/// Call the visitor for the image of a module
+fn visit_module_settings(settings: &mut ModuleSettings, visit: &mut dyn FnMut(&mut String)) {
+    let mut image = settings.image().clone();
+    visit(&mut image);
+    settings.set_image(image);
+}
+
+/// Substitute the parameters in the text, collecting the names of unresolved parameters
+fn substitute(
+    text: &str,
+    parameters: &HashMap<String, String>,
+    unresolved: &mut BTreeSet<String>,
+) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(PARAMETER_START) {
+        let end = match rest[start..].find(PARAMETER_END) {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        result.push_str(&rest[..start]);
+        let name = rest[start + PARAMETER_START.len()..end].trim();
+        match parameters.get(name) {
+            Some(value) => result.push_str(value),
+            None => {
+                unresolved.insert(name.to_string());
+                result.push_str(&rest[start..end + PARAMETER_END.len()]);
+            }
+        }
+        rest = &rest[end + PARAMETER_END.len()..];
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::configuration::{
+        EdgeModuleBuilder, ManifestTemplate, ModulesContentBuilder, RestartPolicy, Status,
+    };
+
+    #[test]
+    fn manifest_template_should_list_unresolved_parameters(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut modules_content = ModulesContentBuilder::new()
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:{{ edge_version }}")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:{{edge_version}}")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(10)
+            .edge_module(
+                EdgeModuleBuilder::new()
+                    .module_id("Sensor")
+                    .status(Status::Running)
+                    .restart_policy(RestartPolicy::Always)
+                    .image("some-acr.azurecr.io/sensor:{{version}}")
+                    .environment_variable("SITE", "{{site}}")
+                    .environment_variable("INTERVAL", 30u64)
+                    .version("1.0")
+                    .build()?,
+            )
+            .build()?;
+        modules_content.edge_hub_mut().routes_mut().insert(
+            "Upstream".to_string(),
+            "FROM /messages/modules/Sensor/outputs/{{output}} INTO $upstream".to_string(),
+        );
+        let template = ManifestTemplate::new(modules_content);
+
+        let names: Vec<String> = template.parameters().into_iter().collect();
+        assert_eq!(names, vec!["edge_version", "output", "site", "version"]);
+
+        let mut parameters = HashMap::new();
+        parameters.insert("edge_version".to_string(), "1.0.9".to_string());
+        parameters.insert("version".to_string(), "1.2.0".to_string());
+        let err = template.render(&parameters).unwrap_err();
+        assert_eq!(err.unresolved(), &vec!["output", "site"]);
+
+        parameters.insert("site".to_string(), "amsterdam".to_string());
+        parameters.insert("output".to_string(), "*".to_string());
+        let rendered = template.render(&parameters)?;
+        let edge_agent = rendered.edge_agent();
+        assert_eq!(
+            edge_agent.system_modules().edge_hub().settings().image(),
+            "mcr.microsoft.com/azureiotedge-hub:1.0.9"
+        );
+        assert_eq!(
+            edge_agent.modules()["Sensor"].env["SITE"].value(),
+            "amsterdam"
+        );
+        assert_eq!(
+            edge_agent.modules()["Sensor"].env["INTERVAL"]
+                .value()
+                .as_i64(),
+            Some(30)
+        );
+        assert_eq!(
+            rendered.edge_hub().routes()["Upstream"],
+            "FROM /messages/modules/Sensor/outputs/* INTO $upstream"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+//! The conformance tests compare the serialization of the models of this crate
+//! with payloads captured from the IoT Hub, which are stored in `resources/test/golden`.
+//!
+//! Every payload is deserialized into its model and serialized again, which must
+//! result in the same JSON. Missing renames and fields the models drop show up as
+//! differences. Fields that are `null` in the payload are treated as missing, as the
+//! models skip serializing fields without a value, and fields that are read-only are
+//! listed per model, as the models do not send them back to the IoT Hub.
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::configuration::modulescontent::{EdgeAgent, EdgeHub};
+use crate::configuration::{Configuration, EdgeAgentReported, EdgeHubReported};
+use crate::registry::{DeviceIdentity, ModuleIdentity};
+use crate::twin::{DeviceTwin, ModuleTwin};
+
+/// Load a captured payload from `resources/test/golden`
+fn load_golden_file(file_name: &str) -> serde_json::Value {
+    let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    d.push("resources/test/golden/");
+    d.push(file_name);
+
+    let contents = std::fs::read_to_string(&d)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {}", d.display(), err));
+    serde_json::from_str(&contents)
+        .unwrap_or_else(|err| panic!("Failed to parse {}: {}", d.display(), err))
+}
+
+/// Remove the fields that are `null` from a JSON value
+fn without_nulls(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(object) => serde_json::Value::Object(
+            object
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, without_nulls(value)))
+                .collect(),
+        ),
+        serde_json::Value::Array(values) => {
+            serde_json::Value::Array(values.into_iter().map(without_nulls).collect())
+        }
+        value => value,
+    }
+}
+
+/// Check that a captured payload survives a round trip through its model
+///
+/// The `read_only` fields are top-level fields the model does not serialize.
+fn assert_round_trip<T>(file_name: &str, read_only: &[&str])
+where
+    T: DeserializeOwned + Serialize,
+{
+    let mut golden = load_golden_file(file_name);
+    if let Some(object) = golden.as_object_mut() {
+        for field in read_only {
+            object.remove(*field);
+        }
+    }
+
+    let model: T = serde_json::from_value(golden.clone())
+        .unwrap_or_else(|err| panic!("Failed to deserialize {}: {}", file_name, err));
+    let serialized = serde_json::to_value(&model)
+        .unwrap_or_else(|err| panic!("Failed to serialize {}: {}", file_name, err));
+
+    let expected = without_nulls(golden);
+    let actual = without_nulls(serialized);
+    assert!(
+        actual == expected,
+        "The round trip of {} changed the payload\n{}\n is not equal to\n{}",
+        file_name,
+        serde_json::to_string_pretty(&actual).unwrap(),
+        serde_json::to_string_pretty(&expected).unwrap()
+    );
+}
+
+#[test]
+fn device_twin_should_conform() {
+    assert_round_trip::<DeviceTwin>("devicetwin.json", &[]);
+}
+
+#[test]
+fn module_twin_should_conform() {
+    assert_round_trip::<ModuleTwin>("moduletwin.json", &[]);
+}
+
+#[test]
+fn device_identity_should_conform() {
+    assert_round_trip::<DeviceIdentity>("deviceidentity.json", &[]);
+}
+
+#[test]
+fn module_identity_should_conform() {
+    assert_round_trip::<ModuleIdentity>("moduleidentity.json", &[]);
+}
+
+#[test]
+fn configuration_should_conform() {
+    assert_round_trip::<Configuration>("configuration.json", &["systemMetrics"]);
+}
+
+#[test]
+fn edge_agent_desired_should_conform() {
+    assert_round_trip::<EdgeAgent>("edgeagent_desired.json", &[]);
+}
+
+#[test]
+fn edge_hub_desired_should_conform() {
+    assert_round_trip::<EdgeHub>("edgehub_desired.json", &[]);
+}
+
+#[test]
+fn edge_agent_reported_should_conform() {
+    assert_round_trip::<EdgeAgentReported>("edgeagent_reported.json", &[]);
+}
+
+#[test]
+fn edge_hub_reported_should_conform() {
+    assert_round_trip::<EdgeHubReported>("edgehub_reported.json", &[]);
+}
+
+#[test]
+fn x509_thumbprints_should_deserialize() {
+    let device_twin: DeviceTwin = serde_json::from_value(load_golden_file("devicetwin.json"))
+        .expect("Failed to deserialize the device twin");
+    assert_eq!(
+        device_twin.x509_thumbprint.primary_thumbprint.as_deref(),
+        Some("2FD4C51A2C3E09E130B31C51B6C01F4C0E7A99E0")
+    );
+}
@@ -0,0 +1,310 @@
+//! The connectionstring module contains the parser for IoT Hub connection
+//! strings, both of shared access policies and of devices or modules.
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use crate::redact::{optional_secret, RevealSecrets};
+
+/// The prefix of the Authorization header value of a shared access signature
+const SHARED_ACCESS_SIGNATURE_PREFIX: &str = "SharedAccessSignature ";
+
+/// The IoTHubConnectionString contains the parsed segments of a connection string
+///
+/// The segments can be in any order and unknown segments are ignored, so
+/// service connection strings as well as device and module connection strings
+/// can be parsed. Instead of a shared access key, a connection string can contain
+/// a `SharedAccessSignature` that was generated elsewhere. The shared access key and
+/// signature are redacted in the Debug output.
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::connectionstring::IoTHubConnectionString;
+///
+/// let connection_string: IoTHubConnectionString = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg=="
+///     .parse()
+///     .expect("Failed to parse the connection string");
+/// assert_eq!(connection_string.iothub_name(), "cool-iot-hub");
+/// assert_eq!(connection_string.key_name().as_deref(), Some("iothubowner"));
+/// ```
+#[derive(Clone, PartialEq)]
+pub struct IoTHubConnectionString {
+    hostname: String,
+    iothub_name: String,
+    domain_suffix: String,
+    key_name: Option<String>,
+    key: Option<String>,
+    shared_access_signature: Option<String>,
+    gateway_hostname: Option<String>,
+    device_id: Option<String>,
+    module_id: Option<String>,
+}
+
+impl IoTHubConnectionString {
+    /// Parse a connection string
+    pub fn parse<S>(connection_string: S) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsRef<str>,
+    {
+        let mut hostname: Option<String> = None;
+        let mut key_name: Option<String> = None;
+        let mut key: Option<String> = None;
+        let mut shared_access_signature: Option<String> = None;
+        let mut gateway_hostname: Option<String> = None;
+        let mut device_id: Option<String> = None;
+        let mut module_id: Option<String> = None;
+
+        for segment in connection_string.as_ref().split(';') {
+            let segment = segment.trim();
+            if segment.is_empty() {
+                continue;
+            }
+
+            let (name, value) = match segment.find('=') {
+                Some(index) => (&segment[..index], segment[index + 1..].to_string()),
+                None => {
+                    return Err(Box::new(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid connection string segment: {}", segment),
+                    )))
+                }
+            };
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "hostname" => hostname = Some(value),
+                "sharedaccesskeyname" => key_name = Some(value),
+                "sharedaccesskey" => key = Some(value),
+                "sharedaccesssignature" => shared_access_signature = Some(value),
+                "gatewayhostname" => gateway_hostname = Some(value),
+                "deviceid" => device_id = Some(value),
+                "moduleid" => module_id = Some(value),
+                _ => {}
+            }
+        }
+
+        let hostname = hostname.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Failed to get the hostname from the given connection string!",
+            )
+        })?;
+
+        let (iothub_name, domain_suffix) = match hostname.find('.') {
+            Some(index) if index > 0 && index + 1 < hostname.len() => (
+                hostname[..index].to_string(),
+                hostname[index + 1..].to_string(),
+            ),
+            _ => {
+                return Err(Box::new(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Failed to get the domain suffix from the given connection string!",
+                )))
+            }
+        };
+
+        if key.is_none() && shared_access_signature.is_none() {
+            return Err(Box::new(Error::new(
+                ErrorKind::InvalidInput,
+                "Failed to get the primary key or shared access signature from the given connection string!",
+            )));
+        }
+
+        Ok(IoTHubConnectionString {
+            hostname,
+            iothub_name,
+            domain_suffix,
+            key_name,
+            key,
+            shared_access_signature,
+            gateway_hostname,
+            device_id,
+            module_id,
+        })
+    }
+
+    /// Get the full hostname of the IoT Hub
+    pub fn hostname(&self) -> &String {
+        &self.hostname
+    }
+
+    /// Get the name of the IoT Hub
+    pub fn iothub_name(&self) -> &String {
+        &self.iothub_name
+    }
+
+    /// Get the domain suffix of the IoT Hub, such as `azure-devices.net`
+    pub fn domain_suffix(&self) -> &String {
+        &self.domain_suffix
+    }
+
+    /// Get the name of the shared access policy
+    pub fn key_name(&self) -> &Option<String> {
+        &self.key_name
+    }
+
+    /// Get the shared access key
+    pub fn key(&self) -> &Option<String> {
+        &self.key
+    }
+
+    /// Get the shared access key, or an error when the connection string only contains a signature
+    pub(crate) fn required_key(&self) -> Result<&str, Box<dyn std::error::Error + Send + Sync>> {
+        self.key.as_deref().ok_or_else(|| {
+            Box::new(Error::new(
+                ErrorKind::InvalidInput,
+                "The connection string contains no shared access key to sign tokens with",
+            )) as Box<dyn std::error::Error + Send + Sync>
+        })
+    }
+
+    /// Get the shared access signature, as the value of the Authorization header
+    ///
+    /// The `SharedAccessSignature` prefix is added when the connection string
+    /// only contains the fields of the signature.
+    pub fn shared_access_signature(&self) -> Option<String> {
+        self.shared_access_signature.as_ref().map(|signature| {
+            if signature.starts_with(SHARED_ACCESS_SIGNATURE_PREFIX) {
+                signature.clone()
+            } else {
+                format!("{}{}", SHARED_ACCESS_SIGNATURE_PREFIX, signature)
+            }
+        })
+    }
+
+    /// Get the hostname of the gateway the device connects through
+    pub fn gateway_hostname(&self) -> &Option<String> {
+        &self.gateway_hostname
+    }
+
+    /// Get the id of the device of a device or module connection string
+    pub fn device_id(&self) -> &Option<String> {
+        &self.device_id
+    }
+
+    /// Get the id of the module of a module connection string
+    pub fn module_id(&self) -> &Option<String> {
+        &self.module_id
+    }
+}
+
+impl RevealSecrets for IoTHubConnectionString {
+    fn fmt_secrets(&self, f: &mut fmt::Formatter<'_>, reveal: bool) -> fmt::Result {
+        f.debug_struct("IoTHubConnectionString")
+            .field("hostname", &self.hostname)
+            .field("iothub_name", &self.iothub_name)
+            .field("domain_suffix", &self.domain_suffix)
+            .field("key_name", &self.key_name)
+            .field("key", &optional_secret(&self.key, reveal))
+            .field(
+                "shared_access_signature",
+                &optional_secret(&self.shared_access_signature, reveal),
+            )
+            .field("gateway_hostname", &self.gateway_hostname)
+            .field("device_id", &self.device_id)
+            .field("module_id", &self.module_id)
+            .finish()
+    }
+}
+
+impl fmt::Debug for IoTHubConnectionString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_secrets(f, false)
+    }
+}
+
+impl FromStr for IoTHubConnectionString {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(connection_string: &str) -> Result<Self, Self::Err> {
+        Self::parse(connection_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connectionstring::IoTHubConnectionString;
+
+    #[test]
+    fn connection_string_should_parse_segments_in_any_order(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let connection_string = IoTHubConnectionString::parse(
+            "SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==;DeviceId=some-device;ModuleId=some-module;HostName=cool-iot-hub.azure-devices.net;GatewayHostName=gateway.local;",
+        )?;
+
+        assert_eq!(
+            connection_string.hostname(),
+            "cool-iot-hub.azure-devices.net"
+        );
+        assert_eq!(connection_string.iothub_name(), "cool-iot-hub");
+        assert_eq!(connection_string.domain_suffix(), "azure-devices.net");
+        assert_eq!(*connection_string.key_name(), None);
+        assert_eq!(
+            connection_string.key().as_deref(),
+            Some("YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==")
+        );
+        assert_eq!(
+            connection_string.gateway_hostname().as_deref(),
+            Some("gateway.local")
+        );
+        assert_eq!(
+            connection_string.device_id().as_deref(),
+            Some("some-device")
+        );
+        assert_eq!(
+            connection_string.module_id().as_deref(),
+            Some("some-module")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn connection_string_should_redact_key() -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    {
+        use crate::redact::RevealSecrets;
+
+        let connection_string = IoTHubConnectionString::parse(
+            "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==",
+        )?;
+
+        let debug_output = format!("{:?}", connection_string);
+        assert!(debug_output.contains("cool-iot-hub.azure-devices.net"));
+        assert!(debug_output.contains(r#"key: Some("****")"#));
+        assert!(!debug_output.contains("YSB2ZXJ5"));
+        assert!(format!("{:?}", connection_string.reveal()).contains("YSB2ZXJ5"));
+        Ok(())
+    }
+
+    #[test]
+    fn connection_string_should_fail_on_missing_segments() {
+        assert!(IoTHubConnectionString::parse("").is_err());
+        assert!(IoTHubConnectionString::parse("HostName=cool-iot-hub.azure-devices.net").is_err());
+        assert!(
+            IoTHubConnectionString::parse("HostName=cool-iot-hub;SharedAccessKey=a2V5").is_err()
+        );
+        assert!(IoTHubConnectionString::parse("HostName;SharedAccessKey=a2V5").is_err());
+    }
+
+    #[test]
+    fn connection_string_should_accept_shared_access_signature(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let connection_string = IoTHubConnectionString::parse(
+            "HostName=cool-iot-hub.azure-devices.net;SharedAccessSignature=sr=cool-iot-hub.azure-devices.net&sig=c2lnbmF0dXJl&se=1600000000&skn=iothubowner",
+        )?;
+        assert_eq!(*connection_string.key(), None);
+        assert_eq!(
+            connection_string.shared_access_signature().as_deref(),
+            Some("SharedAccessSignature sr=cool-iot-hub.azure-devices.net&sig=c2lnbmF0dXJl&se=1600000000&skn=iothubowner")
+        );
+        assert!(connection_string.required_key().is_err());
+        assert!(!format!("{:?}", connection_string).contains("c2lnbmF0dXJl"));
+
+        let connection_string = IoTHubConnectionString::parse(
+            "HostName=cool-iot-hub.azure-devices.net;SharedAccessSignature=SharedAccessSignature sr=cool-iot-hub.azure-devices.net&sig=c2lnbmF0dXJl&se=1600000000",
+        )?;
+        assert!(connection_string
+            .shared_access_signature()
+            .unwrap()
+            .starts_with("SharedAccessSignature sr="));
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! The delay module contains a timer future that does not depend on
+//! a specific async runtime, which is used for polling the IoT Hub.
+//!
+//! All delays share a single timer thread, which is started when the first delay
+//! is polled and wakes the delays when their deadline passes. A delay that is
+//! dropped before its deadline is removed from the timer.
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// The timer shared by all delays
+    static ref TIMER: Arc<Timer> = Timer::start();
+}
+
+/// The shared state of a Delay and the timer that completes it
+#[derive(Default)]
+struct DelayState {
+    completed: bool,
+    waker: Option<Waker>,
+}
+
+/// The key of a delay in the timer, its deadline and a unique id
+type TimerKey = (Instant, u64);
+
+/// The delays waiting for their deadline, ordered by their deadline
+#[derive(Default)]
+struct TimerQueue {
+    delays: BTreeMap<TimerKey, Weak<Mutex<DelayState>>>,
+    next_id: u64,
+}
+
+/// A timer running on its own thread, which completes the delays when their deadline passes
+struct Timer {
+    queue: Mutex<TimerQueue>,
+    changed: Condvar,
+}
+
+impl Timer {
+    /// Create the timer and start its thread
+    fn start() -> Arc<Self> {
+        let timer = Arc::new(Timer {
+            queue: Mutex::new(TimerQueue::default()),
+            changed: Condvar::new(),
+        });
+        let thread_timer = timer.clone();
+        thread::Builder::new()
+            .name("iothub-timer".to_string())
+            .spawn(move || thread_timer.run())
+            .expect("Failed to start the timer thread");
+        timer
+    }
+
+    /// Add a delay to the timer and return its key
+    fn register(&self, deadline: Instant, state: &Arc<Mutex<DelayState>>) -> TimerKey {
+        let mut queue = lock(&self.queue);
+        let key = (deadline, queue.next_id);
+        queue.next_id += 1;
+        queue.delays.insert(key, Arc::downgrade(state));
+        self.changed.notify_one();
+        key
+    }
+
+    /// Remove a delay from the timer
+    fn cancel(&self, key: &TimerKey) {
+        lock(&self.queue).delays.remove(key);
+    }
+
+    /// Complete the delays whose deadline passed, and sleep until the next deadline
+    fn run(&self) {
+        let mut queue = lock(&self.queue);
+        loop {
+            let now = Instant::now();
+            let pending = queue.delays.split_off(&(now, u64::MAX));
+            let expired = std::mem::replace(&mut queue.delays, pending);
+
+            // The delays are woken without holding the lock on the queue, so a woken
+            // task that polls or drops a delay right away does not wait for the timer
+            drop(queue);
+            for state in expired.values().filter_map(Weak::upgrade) {
+                let mut state = lock(&state);
+                state.completed = true;
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+            queue = lock(&self.queue);
+
+            let next_deadline = queue.delays.keys().next().map(|(deadline, _)| *deadline);
+            queue = match next_deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    self.changed
+                        .wait_timeout(queue, timeout)
+                        .map(|(queue, _)| queue)
+                        .unwrap_or_else(|err| err.into_inner().0)
+                }
+                None => self
+                    .changed
+                    .wait(queue)
+                    .unwrap_or_else(|err| err.into_inner()),
+            };
+        }
+    }
+}
+
+/// Lock a mutex, ignoring that another thread panicked while holding it
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// A future that completes after the given duration
+///
+/// The duration starts when the future is first polled. The timer runs on a shared
+/// thread, so the future can be awaited on any executor.
+pub(crate) struct Delay {
+    duration: Duration,
+    state: Option<Arc<Mutex<DelayState>>>,
+    key: Option<TimerKey>,
+}
+
+/// Create a future that completes after the given duration
+pub(crate) fn delay_for(duration: Duration) -> Delay {
+    Delay {
+        duration,
+        state: None,
+        key: None,
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.duration == Duration::from_secs(0) {
+            return Poll::Ready(());
+        }
+
+        if let Some(state) = &self.state {
+            let mut state = lock(state);
+            if state.completed {
+                return Poll::Ready(());
+            }
+            state.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let state = Arc::new(Mutex::new(DelayState {
+            completed: false,
+            waker: Some(cx.waker().clone()),
+        }));
+        self.key = Some(TIMER.register(Instant::now() + self.duration, &state));
+        self.state = Some(state);
+        Poll::Pending
+    }
+}
+
+impl Drop for Delay {
+    fn drop(&mut self) {
+        if let (Some(key), Some(state)) = (&self.key, &self.state) {
+            if !lock(state).completed {
+                TIMER.cancel(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use futures::FutureExt;
+
+    use crate::delay::{delay_for, lock, TIMER};
+
+    #[test]
+    fn delay_should_wait_for_duration() {
+        let started = Instant::now();
+        futures::executor::block_on(delay_for(Duration::from_millis(20)));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        let started = Instant::now();
+        futures::executor::block_on(futures::future::join_all(
+            (1..=20).map(|index| delay_for(Duration::from_millis(index))),
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn dropped_delay_should_be_removed_from_timer() {
+        let mut delay = delay_for(Duration::from_secs(60));
+        assert!((&mut delay).now_or_never().is_none());
+        let key = delay.key.expect("The delay was not registered");
+        assert!(lock(&TIMER.queue).delays.contains_key(&key));
+
+        drop(delay);
+        assert!(!lock(&TIMER.queue).delays.contains_key(&key));
+    }
+}
@@ -3,27 +3,169 @@
 //! from the iothub module.
 use std::fmt;
 
-use bytes::buf::BufExt as _;
-use hyper::{Body, Client, Method, Request};
-use hyper_tls::HttpsConnector;
+use http::{Method, Request};
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_json::json;
 
-use crate::error::{IoTHubError, ParsingError};
-use crate::{IoTHubService, API_VERSION};
+use crate::error::{BuilderError, BuilderErrorType, ErrorCode, IoTHubError, ParsingError};
+use crate::response::{Response, ResponseMetadata};
+use crate::transport::HttpResponse;
+use crate::IoTHubService;
+
+/// The minimum response time out of a direct method in seconds
+pub const MIN_RESPONSE_TIME_OUT: u64 = 5;
+
+/// The maximum response and connect time out of a direct method in seconds
+pub const MAX_TIME_OUT: u64 = 300;
+
+/// The response time out in seconds used by `create_device_method_with_defaults`
+pub const DEFAULT_RESPONSE_TIME_OUT: u64 = 30;
+
+/// The connect time out in seconds used by `create_device_method_with_defaults`,
+/// which means the method fails immediately when the device is not connected
+pub const DEFAULT_CONNECT_TIME_OUT: u64 = 0;
 
 /// The DirectMethodResponse struct contains the response
 /// from the IoT Hub when a direct method was invoked.
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct DirectMethodResponse<T> {
     pub status: u64,
     pub payload: T,
+    #[serde(skip)]
+    raw_payload: Vec<u8>,
+}
+
+/// The outcome of a direct method that was invoked without waiting for its result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MethodDelivery {
+    /// The device responded within the minimum response time out with this status
+    Completed(u64),
+    /// The IoT Hub delivered the method, but the device did not respond within the
+    /// minimum response time out
+    Delivered,
+}
+
+/// The response of a direct method before the payload is converted
+#[derive(Deserialize)]
+struct RawDirectMethodResponse {
+    status: u64,
+    #[serde(default)]
+    payload: serde_json::Value,
+}
+
+impl<T> DirectMethodResponse<T> {
+    /// Check whether the method reported a successful status code
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Get the payload as returned by the device, serialized as JSON
+    pub fn raw_payload(&self) -> &[u8] {
+        &self.raw_payload
+    }
+
+    /// Convert the payload into another type
+    ///
+    /// This is useful after invoking the method with [`DirectMethod::invoke_raw`],
+    /// as the type of the payload often depends on the status of the method.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use serde_json::json;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let method = iothub.create_device_method("some-device", "GetFirmwareVersion", 30, 30)?;
+    /// let response = method.invoke_raw(json!({})).await?.into_inner();
+    /// if response.is_success() {
+    ///     let version: String = response.into_typed()?.payload;
+    ///     println!("Firmware version: {}", version);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_typed<U: DeserializeOwned>(
+        self,
+    ) -> Result<DirectMethodResponse<U>, Box<dyn std::error::Error + Send + Sync>> {
+        let payload = parse_payload(&self.raw_payload)?;
+        Ok(DirectMethodResponse {
+            status: self.status,
+            payload,
+            raw_payload: self.raw_payload,
+        })
+    }
+
+    /// Convert the response into its payload, or into an error when the device
+    /// returned a status that is not successful
+    ///
+    /// The IoT Hub considers the invocation successful as soon as the device responds,
+    /// whatever the status returned by the device is, so the status has to be checked.
+    /// This turns a status outside of the 2xx range into a [`DeviceMethodError`] that
+    /// contains the status and the payload returned by the device.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use serde_json::json;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let method = iothub.create_device_method("some-device", "GetFirmwareVersion", 30, 30)?;
+    /// let payload = method.invoke_raw(json!({})).await?.into_inner().into_result()?;
+    /// println!("Firmware version: {}", payload);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_result(self) -> Result<T, DeviceMethodError> {
+        if self.is_success() {
+            return Ok(self.payload);
+        }
+
+        Err(DeviceMethodError {
+            status: self.status,
+            payload: serde_json::from_slice(&self.raw_payload).unwrap_or(serde_json::Value::Null),
+        })
+    }
+}
+
+/// The error for a direct method where the device returned a status that is not successful
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceMethodError {
+    /// The status returned by the device
+    pub status: u64,
+    /// The payload returned by the device
+    pub payload: serde_json::Value,
+}
+
+impl std::fmt::Display for DeviceMethodError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The device returned status {} with payload {}",
+            self.status, self.payload
+        )
+    }
+}
+
+impl std::error::Error for DeviceMethodError {}
+
+/// Parse the raw payload of a direct method into the given type
+fn parse_payload<T: DeserializeOwned>(raw_payload: &[u8]) -> Result<T, DirectMethodError> {
+    serde_json::from_slice(raw_payload).map_err(|err| {
+        DirectMethodError::ParsingError(ParsingError {
+            received_payload: String::from_utf8_lossy(raw_payload).to_string(),
+            serialization_error: Box::new(err),
+        })
+    })
 }
 
 #[derive(Debug)]
 pub enum DirectMethodError {
-    IoTHubError(IoTHubError),
+    IoTHubError(Box<IoTHubError>),
     ParsingError(ParsingError),
 }
 
@@ -40,8 +182,8 @@ impl std::error::Error for DirectMethodError {}
 
 /// The DirectMethod struct contains all neccessary properties
 /// to be able to invoke the method.
-pub struct DirectMethod<'a> {
-    iothub_service: &'a IoTHubService,
+pub struct DirectMethod {
+    iothub_service: IoTHubService,
     device_id: String,
     module_id: Option<String>,
     method_name: String,
@@ -49,24 +191,39 @@ pub struct DirectMethod<'a> {
     response_time_out: u64,
 }
 
-impl<'a> DirectMethod<'a> {
+impl DirectMethod {
     /// Create a new DirectMethod
+    ///
+    /// The response time out must be between 5 and 300 seconds and the connect
+    /// time out must be at most 300 seconds, as the IoT Hub rejects other values.
     pub(crate) fn new(
-        iothub_service: &'a IoTHubService,
+        iothub_service: &IoTHubService,
         device_id: String,
         module_id: Option<String>,
         method_name: String,
         response_time_out: u64,
         connect_time_out: u64,
-    ) -> Self {
-        DirectMethod {
-            iothub_service,
+    ) -> Result<Self, BuilderError> {
+        if !(MIN_RESPONSE_TIME_OUT..=MAX_TIME_OUT).contains(&response_time_out) {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                "response_time_out",
+            )));
+        }
+
+        if connect_time_out > MAX_TIME_OUT {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                "connect_time_out",
+            )));
+        }
+
+        Ok(DirectMethod {
+            iothub_service: iothub_service.clone(),
             device_id,
             module_id,
             method_name,
             connect_time_out,
             response_time_out,
-        }
+        })
     }
 
     /// Invoke the DirectMethod
@@ -87,82 +244,247 @@ impl<'a> DirectMethod<'a> {
     ///    "GreatMethod",
     ///    100,
     ///    60
-    /// );
+    /// ).expect("Invalid time outs");
     ///
     /// great_method.invoke::<serde_json::Value>(json!({"hello": "world"}));
     /// ```
     pub async fn invoke<T: DeserializeOwned>(
         &self,
         payload: serde_json::Value,
-    ) -> Result<DirectMethodResponse<T>, Box<dyn std::error::Error>> {
-        match &self.module_id {
-            Some(module_id_value) => {
-                let uri = format!(
-                    "https://{}.azure-devices.net/twins/{}/modules/{}/methods?api-version={}",
-                    self.iothub_service.iothub_name, self.device_id, module_id_value, API_VERSION
-                );
-                Ok(self.invoke_method(&uri, payload.into()).await?)
-            }
-            None => {
-                let uri = format!(
-                    "https://{}.azure-devices.net/twins/{}/methods?api-version={}",
-                    self.iothub_service.iothub_name, self.device_id, API_VERSION
-                );
-                Ok(self.invoke_method(&uri, payload.into()).await?)
+    ) -> Result<Response<DirectMethodResponse<T>>, Box<dyn std::error::Error + Send + Sync>> {
+        self.invoke_method(payload).await
+    }
+
+    /// Invoke the DirectMethod without waiting for the result of the method
+    ///
+    /// The method is invoked with the minimum response time out, so this returns as soon
+    /// as the IoT Hub delivered the method to the device, or the device responded within
+    /// the minimum response time out. This is useful for methods such as a reboot, where
+    /// waiting for the result is pointless. A device that is not connected within the
+    /// connect time out still results in an error.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use serde_json::json;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let reboot = iothub.create_device_method_with_defaults("some-device", "reboot")?;
+    /// let delivery = reboot.invoke_without_waiting(json!({})).await?;
+    /// println!("{:?}", *delivery);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn invoke_without_waiting(
+        &self,
+        payload: serde_json::Value,
+    ) -> Result<Response<MethodDelivery>, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.send_method(payload, MIN_RESPONSE_TIME_OUT).await?;
+        let metadata = ResponseMetadata::from_response(&response);
+        if !response.status().is_success() {
+            let error = IoTHubError::from_response(&response);
+            if error.code() == ErrorCode::GatewayTimeout
+                || response.status() == http::StatusCode::GATEWAY_TIMEOUT
+            {
+                return Ok(Response::new(metadata, MethodDelivery::Delivered));
             }
+            return Err(Box::new(DirectMethodError::IoTHubError(Box::new(error))));
         }
+
+        let raw_response: RawDirectMethodResponse = parse_payload(response.body())?;
+        Ok(Response::new(
+            metadata,
+            MethodDelivery::Completed(raw_response.status),
+        ))
+    }
+
+    /// Invoke the DirectMethod without converting the payload of the response
+    ///
+    /// The payload is kept as JSON value, so the invocation does not fail when the
+    /// payload has an unexpected shape. The payload can be converted afterwards
+    /// with [`DirectMethodResponse::into_typed`].
+    pub async fn invoke_raw(
+        &self,
+        payload: serde_json::Value,
+    ) -> Result<
+        Response<DirectMethodResponse<serde_json::Value>>,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        self.invoke(payload).await
     }
 
     /// Helper method for invoking the method
     async fn invoke_method<T: DeserializeOwned>(
         &self,
-        uri: &str,
         payload: serde_json::Value,
-    ) -> Result<DirectMethodResponse<T>, Box<dyn std::error::Error>> {
+    ) -> Result<Response<DirectMethodResponse<T>>, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.send_method(payload, self.response_time_out).await?;
+        let metadata = ResponseMetadata::from_response(&response);
+        if !response.status().is_success() {
+            return Err(Box::new(DirectMethodError::IoTHubError(Box::new(
+                IoTHubError::from_response(&response),
+            ))));
+        }
+
+        let raw_response: RawDirectMethodResponse = parse_payload(response.body())?;
+        let raw_payload = serde_json::to_vec(&raw_response.payload)?;
+        let payload = parse_payload(&raw_payload)?;
+        Ok(Response::new(
+            metadata,
+            DirectMethodResponse {
+                status: raw_response.status,
+                payload,
+                raw_payload,
+            },
+        ))
+    }
+
+    /// Send the request invoking the method with the given response time out
+    async fn send_method(
+        &self,
+        payload: serde_json::Value,
+        response_time_out: u64,
+    ) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let (operation, uri) = match &self.module_id {
+            Some(module_id) => (
+                "invoke_module_method",
+                format!(
+                    "https://{}/twins/{}/modules/{}/methods?api-version={}",
+                    self.iothub_service.hostname(),
+                    self.device_id,
+                    module_id,
+                    self.iothub_service.api_version()
+                ),
+            ),
+            None => (
+                "invoke_device_method",
+                format!(
+                    "https://{}/twins/{}/methods?api-version={}",
+                    self.iothub_service.hostname(),
+                    self.device_id,
+                    self.iothub_service.api_version()
+                ),
+            ),
+        };
+
         let json_payload = json!({
             "connectTimeoutInSeconds": self.connect_time_out,
             "methodName": self.method_name,
             "payload": payload,
-            "responseTimeoutInSeconds": self.response_time_out,
+            "responseTimeoutInSeconds": response_time_out,
         });
 
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, hyper::Body>(https);
         let request = Request::builder()
             .uri(uri)
             .method(Method::POST)
-            .header("Authorization", &self.iothub_service.sas_token)
+            .header("Authorization", self.iothub_service.authorization())
             .header("Content-Type", "application/json")
-            .body(Body::from(serde_json::to_string(&json_payload)?))?;
-
-        let mut response = client.request(request).await?;
-        if !response.status().is_success() {
-            let body = hyper::body::to_bytes(response.body_mut()).await.unwrap();
-            let error: IoTHubError = serde_json::from_reader(body.reader())?;
-            return Err(Box::new(DirectMethodError::IoTHubError(error)));
-        }
+            .body(serde_json::to_vec(&json_payload)?)?;
 
-        let body = hyper::body::to_bytes(response.body_mut()).await?;
-        let result: serde_json::Result<DirectMethodResponse<T>> = serde_json::from_slice(&body);
-        match result {
-            Ok(value) => Ok(value),
-            Err(err) => {
-                let body_string = String::from_utf8_lossy(&body);
-                Err(Box::new(DirectMethodError::ParsingError(ParsingError {
-                    received_payload: body_string.to_string(),
-                    serialization_error: Box::new(err),
-                })))
-            }
-        }
+        self.iothub_service
+            .send(operation, Some(&self.device_id), request)
+            .await
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::testing::mocktransport::{error_response, json_response, MockTransport};
     use crate::IoTHubService;
 
     #[test]
-    fn directmethod_new_should_succeed() {
+    fn invoke_raw_should_keep_payload_for_conversion(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // The method response has a plain string payload
+        let transport = MockTransport::new(|_, _| {
+            json_response(&serde_json::json!({ "status": 200, "payload": "1.2.3" }))
+        });
+        let service = IoTHubService::from_sas_token("test", "test").with_transport(transport);
+        let method = service.create_device_method("SomeDevice", "GetFirmwareVersion", 30, 30)?;
+
+        let typed = futures::executor::block_on(
+            method.invoke::<std::collections::HashMap<String, String>>(serde_json::json!({})),
+        );
+        assert!(typed.is_err());
+
+        let response =
+            futures::executor::block_on(method.invoke_raw(serde_json::json!({})))?.into_inner();
+        assert!(response.is_success());
+        assert_eq!(response.raw_payload(), br#""1.2.3""#);
+
+        let response = response.into_typed::<String>()?;
+        assert_eq!(response.payload, "1.2.3");
+        Ok(())
+    }
+
+    #[test]
+    fn into_result_should_fail_on_device_errors(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = crate::testing::MockHub::new();
+        hub.add_device("SomeDevice");
+        hub.set_method_response("Reset", 200, serde_json::json!({ "reset": true }));
+        hub.set_method_response("Upgrade", 409, serde_json::json!({ "busy": true }));
+        let service = hub.service();
+
+        let method = service.create_device_method("SomeDevice", "Reset", 30, 30)?;
+        let payload = futures::executor::block_on(method.invoke_raw(serde_json::json!({})))?
+            .into_inner()
+            .into_result()?;
+        assert_eq!(payload, serde_json::json!({ "reset": true }));
+
+        let method = service.create_device_method("SomeDevice", "Upgrade", 30, 30)?;
+        let error = futures::executor::block_on(method.invoke_raw(serde_json::json!({})))?
+            .into_inner()
+            .into_result()
+            .unwrap_err();
+        assert_eq!(error.status, 409);
+        assert_eq!(error.payload, serde_json::json!({ "busy": true }));
+        Ok(())
+    }
+
+    #[test]
+    fn invoke_without_waiting_should_accept_time_outs(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::directmethod::MethodDelivery;
+
+        // Methods named `slow` time out, `offline` is not connected and others answer with 200
+        let transport = MockTransport::new(|request, _| {
+            let body: serde_json::Value = serde_json::from_slice(request.body())?;
+            match body["methodName"].as_str() {
+                Some("slow") => Ok(http::Response::builder().status(504).body(
+                    br#"{"Message":"{\"errorCode\":504101,\"message\":\"Timed out waiting for the response from device.\"}"}"#
+                        .to_vec(),
+                )?),
+                Some("offline") => error_response(
+                    404,
+                    "DeviceNotOnline",
+                    "Timed out waiting for device to connect",
+                ),
+                _ => json_response(&serde_json::json!({ "status": 200, "payload": null })),
+            }
+        });
+        let service =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let invoke = |method_name: &str| {
+            let method = service.create_device_method("SomeDevice", method_name, 30, 0)?;
+            futures::executor::block_on(method.invoke_without_waiting(serde_json::json!({})))
+        };
+
+        assert_eq!(*invoke("reboot")?, MethodDelivery::Completed(200));
+        assert_eq!(*invoke("slow")?, MethodDelivery::Delivered);
+        assert!(invoke("offline").is_err());
+
+        for request in transport.requests().iter() {
+            let body: serde_json::Value = serde_json::from_slice(request.body())?;
+            assert_eq!(body["responseTimeoutInSeconds"], 5);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn directmethod_new_should_succeed() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         use crate::directmethod::DirectMethod;
 
         let service: IoTHubService = IoTHubService::from_sas_token("test", "test");
@@ -173,11 +495,38 @@ mod tests {
             "GreatMethod".to_string(),
             20,
             10,
-        );
+        )?;
         assert_eq!(direct_method.device_id, "SomeDevice");
         assert_eq!(direct_method.module_id, None);
         assert_eq!(direct_method.method_name, "GreatMethod");
         assert_eq!(direct_method.connect_time_out, 10);
         assert_eq!(direct_method.response_time_out, 20);
+        Ok(())
+    }
+
+    #[test]
+    fn directmethod_new_should_validate_time_outs() {
+        let service: IoTHubService = IoTHubService::from_sas_token("test", "test");
+        assert!(service
+            .create_device_method("SomeDevice", "GreatMethod", 4, 10)
+            .is_err());
+        assert!(service
+            .create_device_method("SomeDevice", "GreatMethod", 301, 10)
+            .is_err());
+        assert!(service
+            .create_module_method("SomeDevice", "SomeModule", "GreatMethod", 30, 301)
+            .is_err());
+
+        let direct_method = service
+            .create_device_method("SomeDevice", "GreatMethod", 20, 10)
+            .unwrap();
+        assert_eq!(direct_method.response_time_out, 20);
+        assert_eq!(direct_method.connect_time_out, 10);
+
+        let direct_method = service
+            .create_device_method_with_defaults("SomeDevice", "GreatMethod")
+            .unwrap();
+        assert_eq!(direct_method.response_time_out, 30);
+        assert_eq!(direct_method.connect_time_out, 0);
     }
 }
@@ -0,0 +1,646 @@
+//! The dps module is used for managing the individual enrollments and
+//! enrollment groups of a Device Provisioning Service (DPS).
+//!
+//! The enrollments are managed through the service API of the DPS, which is
+//! authenticated with a shared access policy of the DPS such as
+//! `provisioningserviceowner`.
+use std::fmt;
+use std::sync::Arc;
+
+use http::{Method, Request};
+use serde::{Deserialize, Serialize};
+
+use crate::connectionstring::IoTHubConnectionString;
+use crate::error::IoTHubError;
+use crate::redact::{secret, RevealSecrets};
+use crate::registry::{IfMatch, SymmetricKey};
+use crate::response::{Response, ResponseMetadata};
+use crate::transport::{default_transport, HttpRequest, HttpResponse, Transport};
+use crate::IoTHubService;
+
+/// The API version used for requests to the DPS
+pub const DPS_API_VERSION: &str = "2019-03-31";
+
+/// The default shared access policy of the DPS
+pub const DEFAULT_DPS_KEY_NAME: &str = "provisioningserviceowner";
+
+/// The type of attestation used by an enrollment
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AttestationType {
+    SymmetricKey,
+    X509,
+    Tpm,
+}
+
+/// An X.509 certificate of an attestation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct X509CertificateWithInfo {
+    /// The certificate in PEM or base64 encoded DER format, which is only sent to the DPS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<String>,
+    /// The details of the certificate, which are only returned by the DPS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info: Option<serde_json::Value>,
+}
+
+/// The primary and secondary X.509 certificates of an attestation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct X509Certificates {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub primary: Option<X509CertificateWithInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secondary: Option<X509CertificateWithInfo>,
+}
+
+/// The names of the CA certificates uploaded to the DPS
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct X509CaReferences {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub primary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secondary: Option<String>,
+}
+
+/// The X.509 attestation of an enrollment
+///
+/// Individual enrollments use client certificates, while enrollment groups
+/// use signing certificates or references to CA certificates.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct X509Attestation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_certificates: Option<X509Certificates>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signing_certificates: Option<X509Certificates>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_references: Option<X509CaReferences>,
+}
+
+/// The attestation mechanism of an enrollment
+///
+/// The symmetric keys are redacted in the Debug output.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationMechanism {
+    #[serde(rename = "type")]
+    pub attestation_type: AttestationType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symmetric_key: Option<SymmetricKey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x509: Option<X509Attestation>,
+    /// The TPM attestation, which can be read but is not managed by this crate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tpm: Option<serde_json::Value>,
+}
+
+impl AttestationMechanism {
+    /// Create a symmetric key attestation
+    ///
+    /// When no keys are given, the DPS generates them.
+    pub fn symmetric_key(primary_key: Option<String>, secondary_key: Option<String>) -> Self {
+        AttestationMechanism {
+            attestation_type: AttestationType::SymmetricKey,
+            symmetric_key: Some(SymmetricKey {
+                primary_key,
+                secondary_key,
+            }),
+            x509: None,
+            tpm: None,
+        }
+    }
+
+    /// Create an X.509 attestation with the client certificate of a device,
+    /// for use in an individual enrollment
+    pub fn x509_client_certificate<S>(certificate: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self::x509(X509Attestation {
+            client_certificates: Some(X509Certificates::primary(certificate)),
+            ..X509Attestation::default()
+        })
+    }
+
+    /// Create an X.509 attestation with an intermediate or root certificate that
+    /// signed the device certificates, for use in an enrollment group
+    pub fn x509_signing_certificate<S>(certificate: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self::x509(X509Attestation {
+            signing_certificates: Some(X509Certificates::primary(certificate)),
+            ..X509Attestation::default()
+        })
+    }
+
+    /// Create an X.509 attestation with the name of a CA certificate uploaded to
+    /// the DPS, for use in an enrollment group
+    pub fn x509_ca_reference<S>(ca_name: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self::x509(X509Attestation {
+            ca_references: Some(X509CaReferences {
+                primary: Some(ca_name.into()),
+                secondary: None,
+            }),
+            ..X509Attestation::default()
+        })
+    }
+
+    /// Create an X.509 attestation
+    pub fn x509(x509: X509Attestation) -> Self {
+        AttestationMechanism {
+            attestation_type: AttestationType::X509,
+            symmetric_key: None,
+            x509: Some(x509),
+            tpm: None,
+        }
+    }
+}
+
+impl X509Certificates {
+    /// Create the certificates with only a primary certificate
+    fn primary<S>(certificate: S) -> Self
+    where
+        S: Into<String>,
+    {
+        X509Certificates {
+            primary: Some(X509CertificateWithInfo {
+                certificate: Some(certificate.into()),
+                info: None,
+            }),
+            secondary: None,
+        }
+    }
+}
+
+/// An individual enrollment, which provisions a single device
+///
+/// Fields that are unknown to this crate are collected in `extra`, so they
+/// are sent back unchanged when the enrollment is updated.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IndividualEnrollment {
+    pub registration_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    pub attestation: AttestationMechanism,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iot_hub_host_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_twin: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provisioning_status: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub etag: String,
+    /// The fields of the enrollment that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl IndividualEnrollment {
+    /// Create a new IndividualEnrollment with the given registration id and attestation
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::dps::{AttestationMechanism, IndividualEnrollment};
+    ///
+    /// let enrollment = IndividualEnrollment::new(
+    ///     "some-device",
+    ///     AttestationMechanism::symmetric_key(None, None),
+    /// );
+    /// assert_eq!(enrollment.registration_id, "some-device");
+    /// ```
+    pub fn new<S>(registration_id: S, attestation: AttestationMechanism) -> Self
+    where
+        S: Into<String>,
+    {
+        IndividualEnrollment {
+            registration_id: registration_id.into(),
+            device_id: None,
+            attestation,
+            iot_hub_host_name: None,
+            initial_twin: None,
+            provisioning_status: None,
+            etag: String::new(),
+            extra: serde_json::Map::new(),
+        }
+    }
+}
+
+/// An enrollment group, which provisions all devices sharing an attestation
+///
+/// Fields that are unknown to this crate are collected in `extra`, so they
+/// are sent back unchanged when the enrollment group is updated.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EnrollmentGroup {
+    pub enrollment_group_id: String,
+    pub attestation: AttestationMechanism,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iot_hub_host_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_twin: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provisioning_status: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub etag: String,
+    /// The fields of the enrollment group that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl EnrollmentGroup {
+    /// Create a new EnrollmentGroup with the given id and attestation
+    pub fn new<S>(enrollment_group_id: S, attestation: AttestationMechanism) -> Self
+    where
+        S: Into<String>,
+    {
+        EnrollmentGroup {
+            enrollment_group_id: enrollment_group_id.into(),
+            attestation,
+            iot_hub_host_name: None,
+            initial_twin: None,
+            provisioning_status: None,
+            etag: String::new(),
+            extra: serde_json::Map::new(),
+        }
+    }
+}
+
+/// The ProvisioningService is used for managing the enrollments of a DPS
+pub struct ProvisioningService {
+    hostname: String,
+    sas_token: String,
+    transport: Arc<dyn Transport>,
+}
+
+impl RevealSecrets for ProvisioningService {
+    fn fmt_secrets(&self, f: &mut fmt::Formatter<'_>, reveal: bool) -> fmt::Result {
+        f.debug_struct("ProvisioningService")
+            .field("hostname", &self.hostname)
+            .field("sas_token", &secret(&self.sas_token, reveal))
+            .finish()
+    }
+}
+
+impl fmt::Debug for ProvisioningService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_secrets(f, false)
+    }
+}
+
+impl ProvisioningService {
+    /// Create a new ProvisioningService from the hostname of the DPS and a SAS token
+    pub fn from_sas_token<S, T>(hostname: S, sas_token: T) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        ProvisioningService {
+            hostname: hostname.into(),
+            sas_token: sas_token.into(),
+            transport: default_transport(),
+        }
+    }
+
+    /// Create a new ProvisioningService from the connection string of a shared access policy of the DPS
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::dps::ProvisioningService;
+    ///
+    /// let connection_string = "HostName=cool-dps.azure-devices-provisioning.net;SharedAccessKeyName=provisioningserviceowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    ///
+    /// let result = ProvisioningService::from_connection_string(connection_string, 3600);
+    /// assert!(result.is_ok());
+    /// ```
+    pub fn from_connection_string<S>(
+        connection_string: S,
+        expires_in_seconds: i64,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsRef<str>,
+    {
+        let connection_string = IoTHubConnectionString::parse(connection_string)?;
+        if let Some(sas_token) = connection_string.shared_access_signature() {
+            return Ok(Self::from_sas_token(
+                connection_string.hostname().to_string(),
+                sas_token,
+            ));
+        }
+
+        let sas_token = IoTHubService::generate_sas_token(
+            connection_string.hostname(),
+            Some(
+                connection_string
+                    .key_name()
+                    .as_deref()
+                    .unwrap_or(DEFAULT_DPS_KEY_NAME),
+            ),
+            connection_string.required_key()?,
+            expires_in_seconds,
+        )?;
+
+        Ok(Self::from_sas_token(
+            connection_string.hostname().to_string(),
+            sas_token,
+        ))
+    }
+
+    /// Send all requests to the DPS with the given transport
+    pub fn with_transport<T>(mut self, transport: T) -> Self
+    where
+        T: Transport + 'static,
+    {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    /// Get the hostname of the DPS
+    pub fn hostname(&self) -> &String {
+        &self.hostname
+    }
+
+    /// Get the SAS token used for authentication with the DPS
+    pub fn sas_token(&self) -> &String {
+        &self.sas_token
+    }
+
+    /// Get the uri of an enrollment, `collection` is either `enrollments` or `enrollmentGroups`
+    fn enrollment_uri(&self, collection: &str, id: &str) -> String {
+        format!(
+            "https://{}/{}/{}?api-version={}",
+            self.hostname, collection, id, DPS_API_VERSION
+        )
+    }
+
+    /// Send a request and fail on an unsuccessful status code
+    async fn send(
+        &self,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.transport.send(request).await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
+        }
+        Ok(response)
+    }
+
+    /// Get an enrollment and parse the body of the response
+    async fn get<T>(
+        &self,
+        uri: String,
+    ) -> Result<Response<T>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        for<'de> T: serde::Deserialize<'de>,
+    {
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::GET)
+            .header("Authorization", &self.sas_token)
+            .body(Vec::new())?;
+
+        let response = self.send(request).await?;
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            serde_json::from_slice(response.body())?,
+        ))
+    }
+
+    /// Create or update an enrollment and parse the body of the response
+    ///
+    /// An enrollment with an etag only replaces the enrollment with the same etag.
+    async fn put<T>(
+        &self,
+        uri: String,
+        etag: &str,
+        enrollment: &T,
+    ) -> Result<Response<T>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        for<'de> T: serde::Deserialize<'de> + serde::Serialize,
+    {
+        let mut request = Request::builder()
+            .uri(uri)
+            .method(Method::PUT)
+            .header("Authorization", &self.sas_token)
+            .header("Content-Type", "application/json");
+        if !etag.is_empty() {
+            request = request.header("If-Match", IfMatch::etag(etag).header_value());
+        }
+        let request = request.body(serde_json::to_vec(enrollment)?)?;
+
+        let response = self.send(request).await?;
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            serde_json::from_slice(response.body())?,
+        ))
+    }
+
+    /// Delete an enrollment
+    async fn delete(
+        &self,
+        uri: String,
+        if_match: &IfMatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>> {
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::DELETE)
+            .header("Authorization", &self.sas_token)
+            .header("If-Match", if_match.header_value())
+            .body(Vec::new())?;
+
+        let response = self.send(request).await?;
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            (),
+        ))
+    }
+
+    /// Create an individual enrollment, or update it when it has an etag
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::dps::{AttestationMechanism, IndividualEnrollment, ProvisioningService};
+    ///
+    /// # let connection_string = "HostName=cool-dps.azure-devices-provisioning.net;SharedAccessKeyName=provisioningserviceowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let dps = ProvisioningService::from_connection_string(connection_string, 3600)?;
+    /// let mut enrollment = IndividualEnrollment::new(
+    ///     "some-device",
+    ///     AttestationMechanism::symmetric_key(None, None),
+    /// );
+    /// enrollment.iot_hub_host_name = Some("cool-iot-hub.azure-devices.net".to_string());
+    /// let enrollment = dps.create_or_update_individual_enrollment(&enrollment).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn create_or_update_individual_enrollment(
+        &self,
+        enrollment: &IndividualEnrollment,
+    ) -> Result<Response<IndividualEnrollment>, Box<dyn std::error::Error + Send + Sync>> {
+        self.put(
+            self.enrollment_uri("enrollments", &enrollment.registration_id),
+            &enrollment.etag,
+            enrollment,
+        )
+        .await
+    }
+
+    /// Get an individual enrollment
+    pub async fn get_individual_enrollment<S>(
+        &self,
+        registration_id: S,
+    ) -> Result<Response<IndividualEnrollment>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsRef<str>,
+    {
+        self.get(self.enrollment_uri("enrollments", registration_id.as_ref()))
+            .await
+    }
+
+    /// Delete an individual enrollment
+    pub async fn delete_individual_enrollment<S>(
+        &self,
+        registration_id: S,
+        if_match: IfMatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsRef<str>,
+    {
+        self.delete(
+            self.enrollment_uri("enrollments", registration_id.as_ref()),
+            &if_match,
+        )
+        .await
+    }
+
+    /// Create an enrollment group, or update it when it has an etag
+    pub async fn create_or_update_enrollment_group(
+        &self,
+        enrollment_group: &EnrollmentGroup,
+    ) -> Result<Response<EnrollmentGroup>, Box<dyn std::error::Error + Send + Sync>> {
+        self.put(
+            self.enrollment_uri("enrollmentGroups", &enrollment_group.enrollment_group_id),
+            &enrollment_group.etag,
+            enrollment_group,
+        )
+        .await
+    }
+
+    /// Get an enrollment group
+    pub async fn get_enrollment_group<S>(
+        &self,
+        enrollment_group_id: S,
+    ) -> Result<Response<EnrollmentGroup>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsRef<str>,
+    {
+        self.get(self.enrollment_uri("enrollmentGroups", enrollment_group_id.as_ref()))
+            .await
+    }
+
+    /// Delete an enrollment group
+    pub async fn delete_enrollment_group<S>(
+        &self,
+        enrollment_group_id: S,
+        if_match: IfMatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsRef<str>,
+    {
+        self.delete(
+            self.enrollment_uri("enrollmentGroups", enrollment_group_id.as_ref()),
+            &if_match,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::dps::{
+        AttestationMechanism, AttestationType, EnrollmentGroup, IndividualEnrollment,
+        ProvisioningService,
+    };
+    use crate::registry::IfMatch;
+    use crate::testing::mocktransport::{json_response, MockTransport};
+
+    #[test]
+    fn enrollments_should_be_sent_to_the_dps(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Enrollments are answered with an etag, deletes with an empty body
+        let transport = MockTransport::new(|request, _| {
+            if request.method() != http::Method::PUT {
+                return Ok(http::Response::new(Vec::new()));
+            }
+            let mut enrollment: serde_json::Value = serde_json::from_slice(request.body())?;
+            enrollment["etag"] = json!("\"AAAAAAFD6M4=\"");
+            json_response(&enrollment)
+        });
+        let dps = ProvisioningService::from_connection_string(
+            "HostName=cool-dps.azure-devices-provisioning.net;SharedAccessKeyName=provisioningserviceowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==",
+            3600,
+        )?
+        .with_transport(transport.clone());
+        assert!(dps.sas_token().contains("skn=provisioningserviceowner"));
+        assert!(!format!("{:?}", dps).contains("sig="));
+
+        let mut enrollment = IndividualEnrollment::new(
+            "some-device",
+            AttestationMechanism::symmetric_key(Some("cHJpbWFyeQ==".to_string()), None),
+        );
+        enrollment
+            .extra
+            .insert("capabilities".to_string(), json!({ "iotEdge": true }));
+        let created =
+            futures::executor::block_on(dps.create_or_update_individual_enrollment(&enrollment))?;
+        assert_eq!(created.etag, "\"AAAAAAFD6M4=\"");
+        assert_eq!(
+            created.attestation.attestation_type,
+            AttestationType::SymmetricKey
+        );
+        futures::executor::block_on(dps.create_or_update_individual_enrollment(&created))?;
+
+        let group = EnrollmentGroup::new(
+            "some-group",
+            AttestationMechanism::x509_ca_reference("some-root-ca"),
+        );
+        futures::executor::block_on(dps.create_or_update_enrollment_group(&group))?;
+        futures::executor::block_on(dps.delete_enrollment_group("some-group", IfMatch::Any))?;
+
+        let requests = transport.requests();
+        assert_eq!(
+            requests[0].uri(),
+            "https://cool-dps.azure-devices-provisioning.net/enrollments/some-device?api-version=2019-03-31"
+        );
+        assert!(requests[0].headers().get("If-Match").is_none());
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(requests[0].body())?,
+            json!({
+                "registrationId": "some-device",
+                "attestation": { "type": "symmetricKey", "symmetricKey": { "primaryKey": "cHJpbWFyeQ==", "secondaryKey": null } },
+                "capabilities": { "iotEdge": true }
+            })
+        );
+        assert_eq!(requests[1].headers()["If-Match"], "\"AAAAAAFD6M4=\"");
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(requests[2].body())?["attestation"],
+            json!({ "type": "x509", "x509": { "caReferences": { "primary": "some-root-ca" } } })
+        );
+        assert_eq!(requests[3].method(), http::Method::DELETE);
+        assert_eq!(
+            requests[3].uri(),
+            "https://cool-dps.azure-devices-provisioning.net/enrollmentGroups/some-group?api-version=2019-03-31"
+        );
+        assert_eq!(requests[3].headers()["If-Match"], "*");
+        Ok(())
+    }
+}
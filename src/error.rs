@@ -1,6 +1,9 @@
-use serde::de::{self, Deserialize, Deserializer, MapAccess, Unexpected, Visitor};
+use serde::de::{Deserialize, Deserializer};
 use std::fmt;
 
+use crate::response::ResponseMetadata;
+use crate::transport::HttpResponse;
+
 /// Type of the builder error that occurred when building an object
 #[derive(Debug, Clone)]
 pub enum BuilderErrorType {
@@ -40,7 +43,7 @@ impl std::error::Error for BuilderError {
 #[derive(Debug)]
 pub struct ParsingError {
     pub received_payload: String,
-    pub serialization_error: Box<dyn std::error::Error>,
+    pub serialization_error: Box<dyn std::error::Error + Send + Sync>,
 }
 
 impl std::fmt::Display for ParsingError {
@@ -53,7 +56,39 @@ impl std::fmt::Display for ParsingError {
     }
 }
 
-/// The message object within an IoTHubError
+/// TemplateError struct that contains the names of the parameters
+/// of a template that were not given when rendering it
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError {
+    unresolved: Vec<String>,
+}
+
+impl TemplateError {
+    /// Create a new TemplateError struct
+    pub fn new(unresolved: Vec<String>) -> Self {
+        TemplateError { unresolved }
+    }
+
+    /// Get the names of the unresolved parameters
+    pub fn unresolved(&self) -> &Vec<String> {
+        &self.unresolved
+    }
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unresolved template parameters: {}",
+            self.unresolved.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// The message object within an IoTHubError, which the IoT Hub
+/// sends as stringified JSON in the `Message` field.
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct IoTHubErrorMessage {
@@ -64,79 +99,351 @@ pub struct IoTHubErrorMessage {
     pub timestamp_utc: String,
 }
 
-/// The IoTHubError
+/// The IoTHubError contains the error returned by the IoT Hub
+///
+/// The IoT Hub returns errors in several shapes, such as a stringified JSON
+/// object in the `Message` field, a plain `ErrorCode:<code>;<message>` string or
+/// the `Message`/`ExceptionMessage`/`TrackingId` fields of the twin API.
+/// All of these are parsed into the same struct, falling back to the raw body
+/// as the message when the body could not be parsed.
 #[derive(Debug)]
 pub struct IoTHubError {
-    pub message: IoTHubErrorMessage,
-    pub exception_message: String,
+    error_code: Option<String>,
+    message: String,
+    tracking_id: Option<String>,
+    exception_message: Option<String>,
+    info: serde_json::Value,
+    timestamp_utc: Option<String>,
+    raw_body: String,
+    metadata: ResponseMetadata,
 }
 
-impl<'de> Deserialize<'de> for IoTHubError {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        #[derive(Deserialize)]
-        #[serde(field_identifier)]
-        enum Field {
-            Message,
-            ExceptionMessage,
-        };
+impl IoTHubError {
+    /// Parse the IoTHubError from the body of a response
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::error::IoTHubError;
+    ///
+    /// let error = IoTHubError::from_body(br#"{"Message":"ErrorCode:DeviceNotFound;Device not found","ExceptionMessage":"Tracking ID:abc-G:1-TimeStamp:06/21/2020 16:38:35"}"#);
+    /// assert_eq!(error.error_code().as_deref(), Some("DeviceNotFound"));
+    /// assert_eq!(error.message(), "Device not found");
+    /// assert_eq!(error.tracking_id().as_deref(), Some("abc-G:1"));
+    /// ```
+    pub fn from_body(body: &[u8]) -> Self {
+        let raw_body = String::from_utf8_lossy(body).to_string();
+        match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(serde_json::Value::Object(object)) => Self::from_object(&object, raw_body),
+            _ => Self::from_raw_body(raw_body),
+        }
+    }
 
-        struct IoTHubErrorVisitor;
+    /// Parse the IoTHubError from a response of the IoT Hub
+    pub(crate) fn from_response(response: &HttpResponse) -> Self {
+        let mut error = Self::from_body(response.body());
+        error.metadata = ResponseMetadata::from_response(response);
+        error
+    }
 
-        impl<'de> Visitor<'de> for IoTHubErrorVisitor {
-            type Value = IoTHubError;
+    /// Create an IoTHubError that only contains the raw body as message
+    fn from_raw_body(raw_body: String) -> Self {
+        IoTHubError {
+            error_code: None,
+            message: raw_body.clone(),
+            tracking_id: None,
+            exception_message: None,
+            info: serde_json::Value::Null,
+            timestamp_utc: None,
+            raw_body,
+            metadata: ResponseMetadata::default(),
+        }
+    }
 
-            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("struct DirectMethodError")
+    /// Create an IoTHubError from the fields of a JSON object
+    fn from_object(object: &serde_json::Map<String, serde_json::Value>, raw_body: String) -> Self {
+        let mut error = Self::from_raw_body(raw_body);
+        error.exception_message = string_field(object, "ExceptionMessage");
+        error.tracking_id = string_field(object, "TrackingId");
+        error.error_code = code_field(object, "ErrorCode");
+        error.timestamp_utc = string_field(object, "TimestampUtc");
+        if let Some(info) = field(object, "Info") {
+            error.info = info.clone();
+        }
+
+        if let Some(message) = string_field(object, "Message") {
+            match serde_json::from_str::<serde_json::Value>(&message) {
+                Ok(serde_json::Value::Object(nested)) => {
+                    let nested_error = Self::from_object(&nested, message);
+                    error.message = nested_error.message;
+                    error.error_code = nested_error.error_code.or(error.error_code);
+                    error.tracking_id = nested_error.tracking_id.or(error.tracking_id);
+                    error.timestamp_utc = nested_error.timestamp_utc.or(error.timestamp_utc);
+                    if !nested_error.info.is_null() {
+                        error.info = nested_error.info;
+                    }
+                }
+                _ => match parse_error_code_message(&message) {
+                    Some((error_code, message)) => {
+                        error.error_code = error.error_code.or(Some(error_code));
+                        error.message = message;
+                    }
+                    None => error.message = message,
+                },
             }
+        }
+
+        if error.tracking_id.is_none() {
+            error.tracking_id = error
+                .exception_message
+                .as_deref()
+                .and_then(parse_tracking_id);
+        }
+
+        error
+    }
+
+    /// Get the error code, which is either numeric such as `404001` or a name such as `DeviceNotFound`
+    pub fn error_code(&self) -> &Option<String> {
+        &self.error_code
+    }
+
+    /// Get the typed error code, which is `ErrorCode::Other` when the code is unknown
+    /// and falls back to the HTTP status code when the body contained no error code
+    pub fn code(&self) -> ErrorCode {
+        match &self.error_code {
+            Some(error_code) => ErrorCode::from_code(error_code),
+            None => ErrorCode::Other(self.metadata.status_code().to_string()),
+        }
+    }
 
-            fn visit_map<V>(self, mut map: V) -> Result<IoTHubError, V::Error>
+    /// Check whether the error was caused by an etag that did not match the
+    /// current etag in the IoT Hub, which means the resource was changed concurrently
+    pub fn is_precondition_failed(&self) -> bool {
+        self.metadata.status_code() == 412 || self.code() == ErrorCode::PreconditionFailed
+    }
+
+    /// Check whether the request was throttled by the IoT Hub and can be retried later,
+    /// after the duration of the `Retry-After` header in the metadata if it was returned
+    pub fn is_throttling(&self) -> bool {
+        self.metadata.status_code() == 429 || self.code().is_throttling()
+    }
+
+    /// Get the error message, which is the raw body when the error could not be parsed
+    pub fn message(&self) -> &String {
+        &self.message
+    }
+
+    /// Get the tracking id of the error
+    pub fn tracking_id(&self) -> &Option<String> {
+        &self.tracking_id
+    }
+
+    /// Get the exception message of the error
+    pub fn exception_message(&self) -> &Option<String> {
+        &self.exception_message
+    }
+
+    /// Get the additional info of the error
+    pub fn info(&self) -> &serde_json::Value {
+        &self.info
+    }
+
+    /// Get the timestamp of the error
+    pub fn timestamp_utc(&self) -> &Option<String> {
+        &self.timestamp_utc
+    }
+
+    /// Get the raw body of the response that contained the error
+    pub fn raw_body(&self) -> &String {
+        &self.raw_body
+    }
+
+    /// Get the metadata of the response that contained the error
+    pub fn metadata(&self) -> &ResponseMetadata {
+        &self.metadata
+    }
+}
+
+/// Define the ErrorCode enum together with the numeric codes and names of the variants
+macro_rules! error_codes {
+    ($($(#[$attr:meta])* $name:ident = $code:expr,)*) => {
+        /// The ErrorCode contains the known error codes returned by the IoT Hub
+        ///
+        /// # Example
+        /// ```
+        /// use azure_iothub_service::error::{ErrorCode, IoTHubError};
+        ///
+        /// let error = IoTHubError::from_body(br#"{"Message":"ErrorCode:DeviceNotFound;Device not found"}"#);
+        /// assert!(matches!(error.code(), ErrorCode::DeviceNotFound));
+        /// assert_eq!(ErrorCode::DeviceNotFound.as_u64(), Some(404001));
+        /// ```
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub enum ErrorCode {
+            $($(#[$attr])* $name,)*
+            /// An error code that is not known by this crate
+            Other(String),
+        }
+
+        impl ErrorCode {
+            /// Get the ErrorCode from a numeric error code such as `404001`
+            /// or an error name such as `DeviceNotFound`
+            pub fn from_code<S>(code: S) -> Self
             where
-                V: MapAccess<'de>,
+                S: AsRef<str>,
             {
-                let mut message: Option<IoTHubErrorMessage> = None;
-                let mut exception_message: Option<String> = None;
-
-                while let Some(key) = map.next_key()? {
-                    match key {
-                        Field::Message => {
-                            if message.is_some() {
-                                return Err(de::Error::duplicate_field("Message"));
-                            }
-                            message = match serde_json::from_str(&map.next_value::<String>()?) {
-                                Ok(val) => Some(val),
-                                Err(err) => {
-                                    println!("{}", err);
-                                    return Err(de::Error::invalid_type(
-                                        Unexpected::Other(&"non stringified json"),
-                                        &"stringified json",
-                                    ));
-                                }
-                            };
-                        }
-                        Field::ExceptionMessage => {
-                            if exception_message.is_some() {
-                                return Err(de::Error::duplicate_field("ExceptionMessage"));
-                            }
-                            exception_message = Some(map.next_value()?);
-                        }
-                    }
+                let code = code.as_ref().trim();
+                match code.parse::<u64>() {
+                    $(Ok($code) => ErrorCode::$name,)*
+                    Ok(_) => ErrorCode::Other(code.to_string()),
+                    Err(_) => match code {
+                        $(stringify!($name) => ErrorCode::$name,)*
+                        _ => ErrorCode::Other(code.to_string()),
+                    },
                 }
+            }
+
+            /// Get the numeric error code, if the error code is known
+            pub fn as_u64(&self) -> Option<u64> {
+                match self {
+                    $(ErrorCode::$name => Some($code),)*
+                    ErrorCode::Other(code) => code.parse().ok(),
+                }
+            }
 
-                let message = message.ok_or_else(|| de::Error::missing_field("Message"))?;
-                let exception_message = exception_message
-                    .ok_or_else(|| de::Error::missing_field("ExceptionMessage"))?;
-                Ok(IoTHubError {
-                    message,
-                    exception_message,
-                })
+            /// Get the name of the error code
+            pub fn name(&self) -> &str {
+                match self {
+                    $(ErrorCode::$name => stringify!($name),)*
+                    ErrorCode::Other(code) => code,
+                }
             }
         }
+    };
+}
+
+error_codes! {
+    InvalidProtocolVersion = 400001,
+    DeviceInvalidResultCount = 400002,
+    InvalidOperation = 400003,
+    ArgumentInvalid = 400004,
+    ArgumentNull = 400005,
+    IotHubFormatError = 400006,
+    DeviceStorageEntitySerializationError = 400007,
+    BlobContainerValidationError = 400008,
+    ImportWarningExistsError = 400009,
+    InvalidSchemaVersion = 400010,
+    DeviceDefinedMultipleTimes = 400011,
+    DeserializationError = 400012,
+    BulkRegistryOperationFailure = 400013,
+    CannotRegisterModuleToModule = 400301,
+    IotHubNotFound = 401001,
+    IotHubUnauthorizedAccess = 401002,
+    IotHubUnauthorized = 401003,
+    IotHubSuspended = 403001,
+    IotHubQuotaExceeded = 403002,
+    JobQuotaExceeded = 403003,
+    DeviceMaximumQueueDepthExceeded = 403004,
+    IotHubMaxCbsTokenExceeded = 403005,
+    DeviceNotFound = 404001,
+    JobNotFound = 404002,
+    PartitionNotFound = 404003,
+    ModuleNotFound = 404010,
+    DeviceNotOnline = 404103,
+    DeviceAlreadyExists = 409001,
+    ModuleAlreadyExistsOnDevice = 409301,
+    PreconditionFailed = 412001,
+    DeviceMessageLockLost = 412002,
+    MessageTooLarge = 413001,
+    TooManyDevices = 413002,
+    TooManyModulesOnDevice = 413003,
+    ThrottlingException = 429001,
+    ThrottleBacklogLimitExceeded = 429002,
+    InvalidThrottleParameter = 429003,
+    ServerError = 500001,
+    JobCancelled = 500002,
+    ServiceUnavailable = 503001,
+    GatewayTimeout = 504101,
+}
+
+impl ErrorCode {
+    /// Get the HTTP status code that belongs to the error code
+    pub fn status_code(&self) -> Option<u16> {
+        self.as_u64()
+            .filter(|code| *code >= 100_000)
+            .map(|code| (code / 1000) as u16)
+    }
+
+    /// Check whether the request failed because of throttling and can be retried later
+    pub fn is_throttling(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::ThrottlingException | ErrorCode::ThrottleBacklogLimitExceeded
+        )
+    }
+}
 
-        const FIELDS: &'static [&'static str] = &["Message", "ExceptionMessage"];
-        deserializer.deserialize_struct("DirectMethodError", FIELDS, IoTHubErrorVisitor)
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Get a field from a JSON object, ignoring the casing of the first character
+fn field<'a>(
+    object: &'a serde_json::Map<String, serde_json::Value>,
+    name: &str,
+) -> Option<&'a serde_json::Value> {
+    object.get(name).or_else(|| {
+        let mut chars = name.chars();
+        let first = chars.next()?.to_ascii_lowercase();
+        object.get(&format!("{}{}", first, chars.as_str()))
+    })
+}
+
+/// Get a string field from a JSON object
+fn string_field(object: &serde_json::Map<String, serde_json::Value>, name: &str) -> Option<String> {
+    field(object, name)?.as_str().map(String::from)
+}
+
+/// Get an error code from a JSON object, which is either a number or a string
+fn code_field(object: &serde_json::Map<String, serde_json::Value>, name: &str) -> Option<String> {
+    match field(object, name)? {
+        serde_json::Value::Number(val) => Some(val.to_string()),
+        serde_json::Value::String(val) => Some(val.clone()),
+        _ => None,
+    }
+}
+
+/// Parse a message in the form of `ErrorCode:<code>;<message>`
+fn parse_error_code_message(message: &str) -> Option<(String, String)> {
+    let rest = message.trim().strip_prefix("ErrorCode:")?;
+    let (error_code, message) = match rest.find(';') {
+        Some(index) => (&rest[..index], &rest[index + 1..]),
+        None => (rest, ""),
+    };
+    Some((error_code.trim().to_string(), message.trim().to_string()))
+}
+
+/// Parse the tracking id from an exception message in the form of
+/// `Tracking ID:<tracking id>-TimeStamp:<timestamp>`
+fn parse_tracking_id(exception_message: &str) -> Option<String> {
+    let start = exception_message.find("Tracking ID:")? + "Tracking ID:".len();
+    let rest = &exception_message[start..];
+    let end = rest.find("-TimeStamp:").unwrap_or(rest.len());
+    Some(rest[..end].trim().to_string())
+}
+
+impl<'de> Deserialize<'de> for IoTHubError {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let raw_body = value.to_string();
+        match value {
+            serde_json::Value::Object(object) => Ok(Self::from_object(&object, raw_body)),
+            _ => Ok(Self::from_raw_body(raw_body)),
+        }
     }
 }
 
@@ -145,17 +452,132 @@ impl std::fmt::Display for IoTHubError {
         write!(
             f,
             "{{ error_code: {}, tracking_id: {}, message: {} }}",
-            self.message.error_code, self.message.tracking_id, self.message.message
+            self.error_code.as_deref().unwrap_or("unknown"),
+            self.tracking_id.as_deref().unwrap_or("unknown"),
+            self.message
         )
     }
 }
 
 impl std::error::Error for IoTHubError {}
 
+/// The reason why the connection to the IoT Hub could not be established
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionFailure {
+    /// The hostname of the IoT Hub could not be resolved
+    Dns,
+    /// The TLS handshake with the IoT Hub failed
+    Tls,
+    /// The connection to the IoT Hub failed for another reason, such as a time out
+    Network,
+    /// The IoT Hub rejected the credentials, such as an expired SAS token
+    Unauthorized,
+    /// The IoT Hub denied access, such as by the IP filter or missing permissions
+    Forbidden,
+    /// The IoT Hub throttled the request
+    Throttled,
+    /// The IoT Hub returned another error
+    Unexpected,
+}
+
+impl ConnectionFailure {
+    /// Get a description of what can be done about the failure
+    pub fn hint(&self) -> &'static str {
+        match self {
+            ConnectionFailure::Dns => "check the name of the IoT Hub and the DNS configuration",
+            ConnectionFailure::Tls => "check the trusted certificates and any TLS inspecting proxy",
+            ConnectionFailure::Network => "check the network connection and the proxy settings",
+            ConnectionFailure::Unauthorized => "check the shared access key or renew the SAS token",
+            ConnectionFailure::Forbidden => {
+                "check the IP filter of the IoT Hub and the permissions of the policy"
+            }
+            ConnectionFailure::Throttled => "retry later or scale up the IoT Hub",
+            ConnectionFailure::Unexpected => "check the error returned by the IoT Hub",
+        }
+    }
+}
+
+/// The error returned when checking the connection to the IoT Hub
+#[derive(Debug)]
+pub struct ConnectionCheckError {
+    failure: ConnectionFailure,
+    source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl ConnectionCheckError {
+    /// Create a ConnectionCheckError for a request that failed with an IoTHubError
+    pub(crate) fn from_iothub_error(error: IoTHubError) -> Self {
+        let failure = match error.metadata().status_code() {
+            401 => ConnectionFailure::Unauthorized,
+            403 => ConnectionFailure::Forbidden,
+            _ if error.is_throttling() => ConnectionFailure::Throttled,
+            _ => ConnectionFailure::Unexpected,
+        };
+        ConnectionCheckError {
+            failure,
+            source: Box::new(error),
+        }
+    }
+
+    /// Create a ConnectionCheckError for a request the transport could not send
+    ///
+    /// The transport can be implemented by any HTTP client, so the failure is
+    /// classified by the messages of the chain of errors.
+    pub(crate) fn from_transport_error(error: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        let mut messages = Vec::new();
+        let mut current: Option<&(dyn std::error::Error + 'static)> = Some(&*error);
+        while let Some(err) = current {
+            messages.push(err.to_string().to_ascii_lowercase());
+            current = err.source();
+        }
+        let mentions = |words: &[&str]| {
+            messages
+                .iter()
+                .any(|message| words.iter().any(|word| message.contains(word)))
+        };
+
+        let failure = if mentions(&["dns", "lookup", "resolve", "name or service not known"]) {
+            ConnectionFailure::Dns
+        } else if mentions(&["tls", "ssl", "certificate", "handshake"]) {
+            ConnectionFailure::Tls
+        } else {
+            ConnectionFailure::Network
+        };
+        ConnectionCheckError {
+            failure,
+            source: error,
+        }
+    }
+
+    /// Get the reason why the connection failed
+    pub fn failure(&self) -> ConnectionFailure {
+        self.failure
+    }
+}
+
+impl std::fmt::Display for ConnectionCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to connect to the IoT Hub ({:?}), {}: {}",
+            self.failure,
+            self.failure.hint(),
+            self.source
+        )
+    }
+}
+
+impl std::error::Error for ConnectionCheckError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+#[cfg(test)]
 mod tests {
 
     #[test]
-    fn iothuberror_should_deserialize() -> Result<(), Box<dyn std::error::Error>> {
+    fn iothuberror_should_deserialize() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         use crate::error::IoTHubError;
         use serde_json::json;
 
@@ -167,15 +589,78 @@ mod tests {
         println!("{}", direct_method_error_str);
 
         let direct_method_error: IoTHubError = serde_json::from_str(direct_method_error_str)?;
-        assert_eq!(direct_method_error.message.error_code, 12345);
-        assert_eq!(direct_method_error.message.tracking_id, "trackingid");
-        assert_eq!(direct_method_error.message.message, "an error occurred");
-        assert_eq!(direct_method_error.message.info, json!({}));
+        assert_eq!(direct_method_error.error_code().as_deref(), Some("12345"));
         assert_eq!(
-            direct_method_error.message.timestamp_utc,
-            "2020-06-21T16:38:35.671+00:00"
+            direct_method_error.tracking_id().as_deref(),
+            Some("trackingid")
+        );
+        assert_eq!(direct_method_error.message(), "an error occurred");
+        assert_eq!(*direct_method_error.info(), json!({}));
+        assert_eq!(
+            direct_method_error.timestamp_utc().as_deref(),
+            Some("2020-06-21T16:38:35.671+00:00")
+        );
+        assert_eq!(
+            direct_method_error.exception_message().as_deref(),
+            Some("a great exception")
         );
-        assert_eq!(direct_method_error.exception_message, "a great exception");
         Ok(())
     }
+
+    #[test]
+    fn iothuberror_should_parse_error_code_message() {
+        use crate::error::IoTHubError;
+
+        let error = IoTHubError::from_body(
+            br#"{"Message":"ErrorCode:DeviceNotFound;Device some-device not found","ExceptionMessage":"Tracking ID:1234abcd-G:10-TimeStamp:06/21/2020 16:38:35"}"#,
+        );
+        assert_eq!(error.error_code().as_deref(), Some("DeviceNotFound"));
+        assert_eq!(error.message(), "Device some-device not found");
+        assert_eq!(error.tracking_id().as_deref(), Some("1234abcd-G:10"));
+    }
+
+    #[test]
+    fn error_code_should_parse_numeric_and_named_codes() {
+        use crate::error::ErrorCode;
+
+        assert_eq!(ErrorCode::from_code("404001"), ErrorCode::DeviceNotFound);
+        assert_eq!(
+            ErrorCode::from_code("ThrottlingException"),
+            ErrorCode::ThrottlingException
+        );
+        assert_eq!(
+            ErrorCode::from_code("412001"),
+            ErrorCode::PreconditionFailed
+        );
+        assert_eq!(
+            ErrorCode::from_code("499999"),
+            ErrorCode::Other("499999".to_string())
+        );
+        assert_eq!(ErrorCode::IotHubQuotaExceeded.as_u64(), Some(403002));
+        assert_eq!(ErrorCode::IotHubQuotaExceeded.status_code(), Some(403));
+        assert!(ErrorCode::ThrottlingException.is_throttling());
+    }
+
+    #[test]
+    fn iothuberror_should_parse_twin_error() {
+        use crate::error::IoTHubError;
+
+        let error = IoTHubError::from_body(
+            br#"{"Message":"Twin not found","ExceptionMessage":"","TrackingId":"tracking","ErrorCode":404001}"#,
+        );
+        assert_eq!(error.error_code().as_deref(), Some("404001"));
+        assert_eq!(error.code(), crate::error::ErrorCode::DeviceNotFound);
+        assert_eq!(error.message(), "Twin not found");
+        assert_eq!(error.tracking_id().as_deref(), Some("tracking"));
+    }
+
+    #[test]
+    fn iothuberror_should_fall_back_to_raw_body() {
+        use crate::error::IoTHubError;
+
+        let error = IoTHubError::from_body(b"Service Unavailable");
+        assert_eq!(*error.error_code(), None);
+        assert_eq!(error.message(), "Service Unavailable");
+        assert_eq!(error.raw_body(), "Service Unavailable");
+    }
 }
@@ -0,0 +1,317 @@
+//! The eventgrid module contains the events the IoT Hub publishes to Event Grid,
+//! such as devices that are created, deleted, connected or disconnected, so a web
+//! service that receives the events with a webhook can react to changes of devices.
+//!
+//! Only the Event Grid schema is supported, not the CloudEvents schema.
+//!
+//! # Example
+//! ```
+//! use azure_iothub_service::eventgrid::{parse_events, EventGridMessage, IoTHubEvent};
+//!
+//! # fn handle(body: &[u8]) -> Result<Option<serde_json::Value>, Box<dyn std::error::Error + Send + Sync>> {
+//! match parse_events(body)? {
+//!     // Complete the handshake when the webhook is subscribed to the events
+//!     EventGridMessage::SubscriptionValidation(validation) => {
+//!         return Ok(Some(validation.response()));
+//!     }
+//!     EventGridMessage::Notification(events) => {
+//!         for event in events {
+//!             if let IoTHubEvent::DeviceDisconnected(event) = event {
+//!                 println!("{} disconnected", event.device_id);
+//!             }
+//!         }
+//!     }
+//! }
+//! # Ok(None)
+//! # }
+//! ```
+use std::collections::HashMap;
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::twin::DeviceTwin;
+
+/// The event type of the event that is sent when a webhook is subscribed to events
+pub const SUBSCRIPTION_VALIDATION_EVENT: &str = "Microsoft.EventGrid.SubscriptionValidationEvent";
+
+/// The event type of the event that is sent when a device or module is created
+pub const DEVICE_CREATED_EVENT: &str = "Microsoft.Devices.DeviceCreated";
+
+/// The event type of the event that is sent when a device or module is deleted
+pub const DEVICE_DELETED_EVENT: &str = "Microsoft.Devices.DeviceDeleted";
+
+/// The event type of the event that is sent when a device or module connects
+pub const DEVICE_CONNECTED_EVENT: &str = "Microsoft.Devices.DeviceConnected";
+
+/// The event type of the event that is sent when a device or module disconnects
+pub const DEVICE_DISCONNECTED_EVENT: &str = "Microsoft.Devices.DeviceDisconnected";
+
+/// The event type of the event that is sent when a device sends telemetry
+pub const DEVICE_TELEMETRY_EVENT: &str = "Microsoft.Devices.DeviceTelemetry";
+
+/// An event as delivered by Event Grid, with the data of the event as JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EventGridEvent {
+    pub id: String,
+    #[serde(default)]
+    pub topic: String,
+    pub subject: String,
+    pub event_type: String,
+    pub event_time: String,
+    #[serde(default)]
+    pub data: serde_json::Value,
+    #[serde(default)]
+    pub data_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_version: Option<String>,
+}
+
+/// The data of the event that is sent when a device or module is created or deleted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceLifecycleEvent {
+    pub hub_name: String,
+    pub device_id: String,
+    /// The module that was created or deleted, if the event is about a module
+    #[serde(
+        default,
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub module_id: Option<String>,
+    /// The twin of the device when it was created or deleted, the fields of a
+    /// module twin that a device twin does not have are collected in `extra`
+    pub twin: DeviceTwin,
+}
+
+/// The sequence number of a connection state event
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStateEventInfo {
+    /// The sequence number, which can be compared as a string to order the events of a device
+    pub sequence_number: String,
+}
+
+/// The data of the event that is sent when a device or module connects or disconnects
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceConnectionStateEvent {
+    pub hub_name: String,
+    pub device_id: String,
+    /// The module that connected or disconnected, if the event is about a module
+    #[serde(
+        default,
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub module_id: Option<String>,
+    pub device_connection_state_event_info: ConnectionStateEventInfo,
+}
+
+/// The data of the event that is sent when a device sends telemetry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceTelemetryEvent {
+    /// The body of the message, which is JSON or a base64 encoded string
+    #[serde(default)]
+    pub body: serde_json::Value,
+    /// The application properties of the message
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    /// The system properties of the message, such as `iothub-connection-device-id`
+    #[serde(default)]
+    pub system_properties: HashMap<String, String>,
+}
+
+/// The data of the event that is sent when a webhook is subscribed to events
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionValidation {
+    pub validation_code: String,
+    /// The url that can be visited to validate the subscription manually
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validation_url: Option<String>,
+}
+
+impl SubscriptionValidation {
+    /// Get the body of the response that completes the validation handshake
+    pub fn response(&self) -> serde_json::Value {
+        serde_json::json!({ "validationResponse": self.validation_code })
+    }
+
+    /// Get the http response that completes the validation handshake
+    pub fn http_response(
+        &self,
+    ) -> Result<http::Response<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(http::Response::builder()
+            .status(http::StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&self.response())?)?)
+    }
+}
+
+/// An event published by the IoT Hub
+#[derive(Debug, Clone, PartialEq)]
+pub enum IoTHubEvent {
+    DeviceCreated(DeviceLifecycleEvent),
+    DeviceDeleted(DeviceLifecycleEvent),
+    DeviceConnected(DeviceConnectionStateEvent),
+    DeviceDisconnected(DeviceConnectionStateEvent),
+    DeviceTelemetry(DeviceTelemetryEvent),
+    /// An event with a type that is unknown to this crate
+    Other(EventGridEvent),
+}
+
+impl IoTHubEvent {
+    /// Convert an Event Grid event to the IoT Hub event of its event type
+    pub fn from_event(
+        event: EventGridEvent,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(match event.event_type.as_str() {
+            DEVICE_CREATED_EVENT => IoTHubEvent::DeviceCreated(serde_json::from_value(event.data)?),
+            DEVICE_DELETED_EVENT => IoTHubEvent::DeviceDeleted(serde_json::from_value(event.data)?),
+            DEVICE_CONNECTED_EVENT => {
+                IoTHubEvent::DeviceConnected(serde_json::from_value(event.data)?)
+            }
+            DEVICE_DISCONNECTED_EVENT => {
+                IoTHubEvent::DeviceDisconnected(serde_json::from_value(event.data)?)
+            }
+            DEVICE_TELEMETRY_EVENT => {
+                IoTHubEvent::DeviceTelemetry(serde_json::from_value(event.data)?)
+            }
+            _ => IoTHubEvent::Other(event),
+        })
+    }
+}
+
+/// The contents of a request Event Grid sends to a webhook
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventGridMessage {
+    /// The webhook is subscribed to events and must respond with [`SubscriptionValidation::response`]
+    SubscriptionValidation(SubscriptionValidation),
+    /// A batch of events
+    Notification(Vec<IoTHubEvent>),
+}
+
+/// Parse the body of a request Event Grid sends to a webhook
+pub fn parse_events(
+    body: &[u8],
+) -> Result<EventGridMessage, Box<dyn std::error::Error + Send + Sync>> {
+    let events: Vec<EventGridEvent> = serde_json::from_slice(body)?;
+
+    if let Some(validation) = events
+        .iter()
+        .find(|event| event.event_type == SUBSCRIPTION_VALIDATION_EVENT)
+    {
+        return Ok(EventGridMessage::SubscriptionValidation(
+            serde_json::from_value(validation.data.clone())?,
+        ));
+    }
+
+    let iothub_events = events
+        .into_iter()
+        .map(IoTHubEvent::from_event)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(EventGridMessage::Notification(iothub_events))
+}
+
+/// Deserialize a module id, the IoT Hub sends an empty module id for events about devices
+fn empty_as_none<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let module_id: Option<String> = Option::deserialize(deserializer)?;
+    Ok(module_id.filter(|module_id| !module_id.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::eventgrid::{parse_events, EventGridMessage, IoTHubEvent};
+
+    fn load_json_file(file_name: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/");
+        d.push(file_name);
+
+        Ok(std::fs::read_to_string(d)?)
+    }
+
+    #[test]
+    fn parse_events_should_return_iothub_events(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let body = load_json_file("eventgrid/iothub_events.json")?;
+        let events = match parse_events(body.as_bytes())? {
+            EventGridMessage::Notification(events) => events,
+            message => panic!("Expected a notification, got {:?}", message),
+        };
+        assert_eq!(events.len(), 4);
+
+        match &events[0] {
+            IoTHubEvent::DeviceCreated(event) => {
+                assert_eq!(event.device_id, "some-device");
+                assert_eq!(event.module_id, None);
+                assert_eq!(event.twin.version, 2);
+            }
+            event => panic!("Expected a created device, got {:?}", event),
+        }
+        match &events[1] {
+            IoTHubEvent::DeviceDisconnected(event) => {
+                assert_eq!(event.hub_name, "cool-iot-hub");
+                assert_eq!(event.module_id, None);
+                assert_eq!(
+                    event.device_connection_state_event_info.sequence_number,
+                    "000000000000000001D4132452F67CE200000002000000000000000000000001"
+                );
+            }
+            event => panic!("Expected a disconnected device, got {:?}", event),
+        }
+        match &events[2] {
+            IoTHubEvent::DeviceConnected(event) => {
+                assert_eq!(event.module_id.as_deref(), Some("some-module"));
+            }
+            event => panic!("Expected a connected module, got {:?}", event),
+        }
+        match &events[3] {
+            IoTHubEvent::Other(event) => assert_eq!(event.event_type, "Microsoft.Devices.Unknown"),
+            event => panic!("Expected an unknown event, got {:?}", event),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_events_should_return_subscription_validation(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let body = br#"[{
+            "id": "2d1781af-3a4c-4d7c-bd0c-e34b19da4e66",
+            "topic": "/subscriptions/xx/resourceGroups/xx/providers/Microsoft.Devices/IotHubs/cool-iot-hub",
+            "subject": "",
+            "data": {
+                "validationCode": "512d38b6-c7b8-40c8-89fe-f46f9e9622b6",
+                "validationUrl": "https://rp-eastus2.eventgrid.azure.net:553/eventsubscriptions/validate"
+            },
+            "eventType": "Microsoft.EventGrid.SubscriptionValidationEvent",
+            "eventTime": "2018-01-25T22:12:19.4556811Z",
+            "metadataVersion": "1",
+            "dataVersion": "1"
+        }]"#;
+
+        let validation = match parse_events(body)? {
+            EventGridMessage::SubscriptionValidation(validation) => validation,
+            message => panic!("Expected a subscription validation, got {:?}", message),
+        };
+        assert_eq!(
+            validation.response(),
+            serde_json::json!({ "validationResponse": "512d38b6-c7b8-40c8-89fe-f46f9e9622b6" })
+        );
+        let response = validation.http_response()?;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.body(),
+            br#"{"validationResponse":"512d38b6-c7b8-40c8-89fe-f46f9e9622b6"}"#
+        );
+        Ok(())
+    }
+}
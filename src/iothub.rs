@@ -3,35 +3,248 @@
 //! A library used for communicating with a given IoT Hub. At the moment
 //! only some parts of the IoT Hub Service are implemented.
 
-use std::io::Read;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use base64::{decode, encode_config};
-use bytes::buf::BufExt as _;
 use chrono;
 use hmac::{Hmac, Mac, NewMac};
-use hyper::{Body, Client, Method, Request, StatusCode};
-use hyper_tls::HttpsConnector;
+use http::{Method, Request};
+use serde::de::DeserializeOwned;
 use serde_json::json;
 use sha2::Sha256;
 use url;
 
-use crate::directmethod::DirectMethod;
+use crate::concurrent::{for_each_bounded_with_progress, unique};
+use crate::configuration::{ApplyProgress, ApplyResult, ConfigurationManager, ConvergenceReport};
+use crate::connectionstring::IoTHubConnectionString;
+use crate::delay::delay_for;
+use crate::directmethod::{
+    DirectMethod, DirectMethodResponse, DEFAULT_CONNECT_TIME_OUT, DEFAULT_RESPONSE_TIME_OUT,
+};
+use crate::error::{BuilderError, ConnectionCheckError, IoTHubError};
+use crate::messaging::MessagingManager;
+use crate::metrics::{Metrics, NoMetrics};
+#[cfg(feature = "hyper-transport")]
+use crate::proxy::ProxySettings;
 use crate::query::QueryBuilder;
+use crate::redact::{optional_secret, secret, RevealSecrets};
+use crate::registry::RegistryManager;
+use crate::response::{Response, ResponseMetadata};
+use crate::tier::{HubLimits, HubTier};
+#[cfg(feature = "hyper-transport")]
+use crate::transport::HyperTransport;
+use crate::transport::{default_transport, HttpRequest, HttpResponse, Transport};
 use crate::twin::TwinManager;
 use crate::ModulesContent;
 
+/// The default API version used for requests to the IoT Hub
 pub const API_VERSION: &str = "2020-03-13";
 
+/// The name of the shared access policy used when none is given
+pub const DEFAULT_KEY_NAME: &str = "iothubowner";
+
+/// The domain suffix of IoT Hubs in the global Azure cloud
+pub const DEFAULT_DOMAIN_SUFFIX: &str = "azure-devices.net";
+
+/// The interval at which the `$edgeAgent` twin is polled while waiting for a deployment
+pub const DEPLOYMENT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The margin for differences between the local clock and the clock of the IoT Hub
+pub const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// The id of the edge agent module
+const EDGE_AGENT_MODULE_ID: &str = "$edgeAgent";
+
+/// Features of the IoT Hub that require a minimum API version
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiFeature {
+    /// Invoking device and module methods
+    DirectMethods,
+    /// Applying a modules configuration on an edge device
+    ModulesConfiguration,
+    /// The device scope of device identities
+    DeviceScope,
+    /// The parent scopes of device identities for nested edge
+    ParentScopes,
+    /// The digital twin API for IoT Plug and Play devices
+    DigitalTwins,
+}
+
+impl ApiFeature {
+    /// Get the minimum API version that supports the feature
+    pub fn minimum_api_version(self) -> &'static str {
+        match self {
+            ApiFeature::DirectMethods => "2018-06-30",
+            ApiFeature::ModulesConfiguration => "2018-06-30",
+            ApiFeature::DeviceScope => "2019-03-30",
+            ApiFeature::ParentScopes => "2020-03-13",
+            ApiFeature::DigitalTwins => "2020-09-30",
+        }
+    }
+
+    /// Check whether the feature is supported by the given API version
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::iothub::ApiFeature;
+    ///
+    /// assert!(ApiFeature::ParentScopes.is_supported_by("2020-03-13"));
+    /// assert!(!ApiFeature::DigitalTwins.is_supported_by("2020-03-13"));
+    /// ```
+    pub fn is_supported_by(self, api_version: &str) -> bool {
+        // API versions are dates optionally followed by a suffix such as "-preview",
+        // so comparing the date part is enough to order them.
+        let date = api_version.get(..10).unwrap_or(api_version);
+        date >= self.minimum_api_version()
+    }
+}
+
 /// The IoTHubService is the main entry point for communicating with the IoT Hub.
 ///
 /// There are several ways to construct the IoTHub Service object. Either by:
 /// - providing the IoT Hub name and the private key.
 /// - providing the connection string.
+///
 /// The IoTHubService then uses the provided information to create a SAS token that it will
 /// use to communicate with the IoT Hub.
+///
+/// By default the IoT Hub is expected to live in the global Azure cloud (`azure-devices.net`).
+/// For other clouds, such as Azure Government or Azure China, or for custom domains the
+/// domain suffix can be changed.
+///
+/// Requests are made with the API version in [`API_VERSION`], which can be changed
+/// per IoTHubService to target newer or older IoT Hub capabilities.
+///
+/// All requests are sent through a [`Transport`], which uses hyper by default. When the
+/// IoT Hub can only be reached through a proxy, the proxy settings are used by every
+/// request made through the IoTHubService.
+///
+/// The IoTHubService is cheap to clone, as all clones share the same configuration.
+/// The managers own a clone of the IoTHubService, so they can be stored in structs or
+/// moved onto other tasks without borrowing the IoTHubService.
+///
+/// The SAS token and the private key are redacted in the Debug output.
+#[derive(Clone)]
 pub struct IoTHubService {
-    pub iothub_name: String,
-    pub sas_token: String,
+    inner: Arc<IoTHubServiceInner>,
+}
+
+/// The configuration of an IoTHubService that is shared by its clones
+#[derive(Clone)]
+struct IoTHubServiceInner {
+    iothub_name: String,
+    domain_suffix: String,
+    api_version: String,
+    credential: Credential,
+    transport: Arc<dyn Transport>,
+    metrics: Arc<dyn Metrics>,
+    clock_skew: Duration,
+    token_cache: Arc<TokenCache>,
+    limits: Option<HubLimits>,
+    #[cfg(feature = "tracing")]
+    log_bodies: bool,
+}
+
+/// The credential used for authenticating requests to the IoT Hub
+#[derive(Clone)]
+struct Credential {
+    sas_token: String,
+    key_name: Option<String>,
+    private_key: Option<String>,
+}
+
+impl Credential {
+    /// Create a credential from a SAS token that cannot be used for signing other tokens
+    fn from_sas_token(sas_token: String) -> Self {
+        Credential {
+            sas_token,
+            key_name: Some(DEFAULT_KEY_NAME.to_string()),
+            private_key: None,
+        }
+    }
+}
+
+/// The scoped tokens generated by an IoTHubService, by resource URI and time to live
+#[derive(Default)]
+struct TokenCache {
+    tokens: std::sync::Mutex<HashMap<(String, Duration), CachedToken>>,
+}
+
+/// A scoped token and the moment it was generated
+struct CachedToken {
+    token: String,
+    generated_at: Instant,
+}
+
+impl CachedToken {
+    /// Check whether the token still has at least half of its time to live left,
+    /// after subtracting the clock skew margin
+    fn is_reusable(&self, time_to_live: Duration, clock_skew: Duration) -> bool {
+        self.generated_at.elapsed() + clock_skew < time_to_live / 2
+    }
+}
+
+impl TokenCache {
+    /// Get a token from the cache, or generate and cache a new token
+    fn get_or_generate<F>(
+        &self,
+        resource_uri: String,
+        time_to_live: Duration,
+        clock_skew: Duration,
+        generate: F,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>>
+    where
+        F: FnOnce(&str) -> Result<String, Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let mut tokens = self
+            .tokens
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let key = (resource_uri, time_to_live);
+        if let Some(cached) = tokens.get(&key) {
+            if cached.is_reusable(time_to_live, clock_skew) {
+                return Ok(cached.token.clone());
+            }
+        }
+
+        let token = generate(&key.0)?;
+        // Drop the tokens that can no longer be reused, so the cache does not keep growing
+        tokens.retain(|(_, time_to_live), cached| cached.is_reusable(*time_to_live, clock_skew));
+        tokens.insert(
+            key,
+            CachedToken {
+                token: token.clone(),
+                generated_at: Instant::now(),
+            },
+        );
+        Ok(token)
+    }
+}
+
+impl RevealSecrets for IoTHubService {
+    fn fmt_secrets(&self, f: &mut fmt::Formatter<'_>, reveal: bool) -> fmt::Result {
+        let inner = &self.inner;
+        f.debug_struct("IoTHubService")
+            .field("iothub_name", &inner.iothub_name)
+            .field("domain_suffix", &inner.domain_suffix)
+            .field("api_version", &inner.api_version)
+            .field("sas_token", &secret(&inner.credential.sas_token, reveal))
+            .field("key_name", &inner.credential.key_name)
+            .field(
+                "private_key",
+                &optional_secret(&inner.credential.private_key, reveal),
+            )
+            .finish()
+    }
+}
+
+impl fmt::Debug for IoTHubService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_secrets(f, false)
+    }
 }
 
 impl IoTHubService {
@@ -51,42 +264,442 @@ impl IoTHubService {
         S: Into<String>,
         T: Into<String>,
     {
-        Self {
+        Self::from_inner(IoTHubServiceInner {
             iothub_name: iothub_name.into(),
-            sas_token: sas_token.into(),
+            domain_suffix: DEFAULT_DOMAIN_SUFFIX.to_string(),
+            api_version: API_VERSION.to_string(),
+            credential: Credential::from_sas_token(sas_token.into()),
+            transport: default_transport(),
+            metrics: Arc::new(NoMetrics),
+            clock_skew: DEFAULT_CLOCK_SKEW,
+            token_cache: Arc::default(),
+            limits: None,
+            #[cfg(feature = "tracing")]
+            log_bodies: false,
+        })
+    }
+
+    /// Create a new IoTHubService struct from its configuration
+    fn from_inner(inner: IoTHubServiceInner) -> Self {
+        IoTHubService {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Set the domain suffix of the IoT Hub
+    ///
+    /// This only changes the hostname used for requests, the SAS token is not regenerated.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>")
+    ///     .with_domain_suffix("azure-devices.us");
+    /// assert_eq!(iothub.hostname(), "cool-iot-hub.azure-devices.us");
+    /// ```
+    pub fn with_domain_suffix<S>(mut self, domain_suffix: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Arc::make_mut(&mut self.inner).domain_suffix = domain_suffix.into();
+        self
+    }
+
+    /// Set the API version used for requests to the IoT Hub
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::iothub::ApiFeature;
+    ///
+    /// let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>")
+    ///     .with_api_version("2020-09-30");
+    /// assert!(iothub.supports(ApiFeature::DigitalTwins));
+    /// ```
+    pub fn with_api_version<S>(mut self, api_version: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Arc::make_mut(&mut self.inner).api_version = api_version.into();
+        self
+    }
+
+    /// Set the margin for differences between the local clock and the clock of the IoT Hub
+    ///
+    /// Scoped tokens are cached, so generating a token for the same resource and time to
+    /// live does not sign a new token every time. A cached token is reused while it has
+    /// at least half of its time to live left after subtracting this margin, which is
+    /// [`DEFAULT_CLOCK_SKEW`] by default. A margin of half the time to live or more
+    /// disables the caching for that time to live.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)
+    ///     .expect("Failed to create the IoTHubService!")
+    ///     .with_clock_skew(Duration::from_secs(120));
+    /// ```
+    pub fn with_clock_skew(mut self, clock_skew: Duration) -> Self {
+        Arc::make_mut(&mut self.inner).clock_skew = clock_skew;
+        self
+    }
+
+    /// Get the margin for differences between the local clock and the clock of the IoT Hub
+    pub fn clock_skew(&self) -> Duration {
+        self.inner.clock_skew
+    }
+
+    /// Set the tier and number of units of the IoT Hub, so requests are sized to its limits
+    ///
+    /// The tier cannot be read through the service API of the IoT Hub, so it has to be
+    /// configured, or read with [`ArmClient::get_sku`](crate::arm::ArmClient::get_sku)
+    /// when the `arm` feature is enabled. With a tier, queries request pages of
+    /// [`MAX_QUERY_PAGE_SIZE`](crate::tier::MAX_QUERY_PAGE_SIZE) results.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::tier::HubTier;
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)
+    ///     .expect("Failed to create the IoTHubService!")
+    ///     .with_tier(HubTier::Standard2, 3);
+    /// let limits = iothub.limits().expect("The tier is configured");
+    /// assert_eq!(limits.twin_operations_per_second(), 360);
+    /// ```
+    pub fn with_tier(mut self, tier: HubTier, units: u32) -> Self {
+        Arc::make_mut(&mut self.inner).limits = Some(tier.limits(units));
+        self
+    }
+
+    /// Get the limits of the IoT Hub, if its tier is configured
+    pub fn limits(&self) -> Option<HubLimits> {
+        self.inner.limits
+    }
+
+    /// Log the bodies of requests and responses, such as twins, configurations and direct methods
+    ///
+    /// The bodies are logged as `debug` events in the `iothub_request` span of the request,
+    /// after the secrets in them are redacted with [`redact_json`](crate::redact::redact_json).
+    /// Bodies that are not JSON are only logged by their length.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>")
+    ///     .with_body_logging(true);
+    /// ```
+    #[cfg(feature = "tracing")]
+    pub fn with_body_logging(mut self, log_bodies: bool) -> Self {
+        Arc::make_mut(&mut self.inner).log_bodies = log_bodies;
+        self
+    }
+
+    /// Send all requests to the IoT Hub through the given proxy
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::proxy::ProxySettings;
+    ///
+    /// let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>")
+    ///     .with_proxy(ProxySettings::new("http://proxy.local:8080").basic_auth("username", "password"));
+    /// ```
+    #[cfg(feature = "hyper-transport")]
+    pub fn with_proxy(self, proxy: ProxySettings) -> Self {
+        self.with_transport(HyperTransport::with_proxy(proxy))
+    }
+
+    /// Send all requests to the IoT Hub with the given transport
+    ///
+    /// See the [`transport`](crate::transport) module for more information.
+    pub fn with_transport<T>(mut self, transport: T) -> Self
+    where
+        T: Transport + 'static,
+    {
+        Arc::make_mut(&mut self.inner).transport = Arc::new(transport);
+        self
+    }
+
+    /// Report every request to the IoT Hub to the given metrics
+    ///
+    /// See the [`metrics`](crate::metrics) module for more information.
+    pub fn with_metrics<M>(mut self, metrics: M) -> Self
+    where
+        M: Metrics + 'static,
+    {
+        Arc::make_mut(&mut self.inner).metrics = Arc::new(metrics);
+        self
+    }
+
+    /// Get the metrics that every request to the IoT Hub is reported to
+    pub(crate) fn metrics(&self) -> &dyn Metrics {
+        &*self.inner.metrics
+    }
+
+    /// Send a request to the IoT Hub with the configured transport
+    ///
+    /// The device id is only used for instrumentation when the `tracing` feature is enabled.
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) async fn send(
+        &self,
+        operation: &'static str,
+        _device_id: Option<&str>,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+        self.send_measured(operation, request).await
+    }
+
+    /// Send a request to the IoT Hub with the configured transport
+    ///
+    /// Every request is wrapped in an `iothub_request` span containing the operation
+    /// name, device id, status code, duration and the `x-ms-request-id` of the response.
+    #[cfg(feature = "tracing")]
+    pub(crate) async fn send(
+        &self,
+        operation: &'static str,
+        device_id: Option<&str>,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+        use tracing::field::Empty;
+        use tracing::Instrument;
+
+        let span = tracing::info_span!(
+            "iothub_request",
+            operation,
+            device_id = device_id.unwrap_or_default(),
+            method = %request.method(),
+            status_code = Empty,
+            duration_ms = Empty,
+            request_id = Empty,
+        );
+
+        if self.inner.log_bodies {
+            let body = crate::redact::redacted_body(request.body());
+            span.in_scope(|| tracing::debug!(body = %body, "sending request body to IoT Hub"));
+        }
+
+        let start = std::time::Instant::now();
+        let result = self
+            .send_measured(operation, request)
+            .instrument(span.clone())
+            .await;
+        span.record("duration_ms", start.elapsed().as_millis() as u64);
+
+        match &result {
+            Ok(response) => {
+                span.record("status_code", response.status().as_u16());
+                if let Some(request_id) = response
+                    .headers()
+                    .get("x-ms-request-id")
+                    .and_then(|val| val.to_str().ok())
+                {
+                    span.record("request_id", request_id);
+                }
+                span.in_scope(|| tracing::debug!("received response from IoT Hub"));
+                if self.inner.log_bodies {
+                    let body = crate::redact::redacted_body(response.body());
+                    span.in_scope(
+                        || tracing::debug!(body = %body, "received response body from IoT Hub"),
+                    );
+                }
+            }
+            Err(err) => {
+                span.in_scope(|| tracing::warn!(error = %err, "request to IoT Hub failed"));
+            }
+        }
+
+        result
+    }
+
+    /// Send a request whose response may be compressed, such as a query or twin
+    ///
+    /// Compressed responses are only accepted when the `compression` feature is enabled,
+    /// and they are decompressed before they are returned.
+    pub(crate) async fn send_compressible(
+        &self,
+        operation: &'static str,
+        device_id: Option<&str>,
+        #[cfg_attr(not(feature = "compression"), allow(unused_mut))] mut request: HttpRequest,
+    ) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+        #[cfg(feature = "compression")]
+        {
+            crate::compression::accept_compressed(&mut request);
+            crate::compression::decompress(self.send(operation, device_id, request).await?)
+        }
+
+        #[cfg(not(feature = "compression"))]
+        {
+            self.send(operation, device_id, request).await
+        }
+    }
+
+    /// Send a request with the configured transport and report it to the metrics
+    async fn send_measured(
+        &self,
+        operation: &'static str,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let metrics = self.metrics();
+        metrics.request_sent(operation);
+        let start = std::time::Instant::now();
+        let result = self.inner.transport.send(request).await;
+
+        match &result {
+            Ok(response) => {
+                let status = response.status();
+                metrics.response_received(operation, status.as_u16(), start.elapsed());
+                if !status.is_success() {
+                    metrics.request_failed(operation);
+                }
+                if status == http::StatusCode::TOO_MANY_REQUESTS {
+                    metrics.request_throttled(operation);
+                }
+            }
+            Err(_) => metrics.request_failed(operation),
+        }
+        result
+    }
+
+    /// Get the name of the IoT Hub
+    pub fn iothub_name(&self) -> &String {
+        &self.inner.iothub_name
+    }
+
+    /// Get the domain suffix of the IoT Hub, such as `azure-devices.net`
+    pub fn domain_suffix(&self) -> &String {
+        &self.inner.domain_suffix
+    }
+
+    /// Get the API version used for requests to the IoT Hub
+    pub fn api_version(&self) -> &String {
+        &self.inner.api_version
+    }
+
+    /// Get the SAS token used for authenticating requests to the IoT Hub
+    pub fn sas_token(&self) -> &String {
+        &self.inner.credential.sas_token
+    }
+
+    /// Get the value of the Authorization header of requests to the IoT Hub
+    pub(crate) fn authorization(&self) -> &str {
+        &self.inner.credential.sas_token
+    }
+
+    /// Check whether the configured API version supports the given feature
+    pub fn supports(&self, feature: ApiFeature) -> bool {
+        feature.is_supported_by(&self.inner.api_version)
+    }
+
+    /// Get the full hostname of the IoT Hub
+    pub fn hostname(&self) -> String {
+        format!("{}.{}", self.inner.iothub_name, self.inner.domain_suffix)
+    }
+
+    /// Get the URI of a resource of the IoT Hub, which is relative to the hostname
+    fn resource_uri(hostname: String, resource: &str) -> String {
+        let resource = resource.trim_matches('/');
+        if resource.is_empty() {
+            hostname
+        } else {
+            format!("{}/{}", hostname, resource)
         }
     }
 
     /// Generate a new SAS token to use for authentication with IoT Hub
-    fn generate_sas_token(
-        iothub_name: &str,
+    ///
+    /// The key name is left out of the token when the key is a device or module key.
+    pub(crate) fn generate_sas_token(
+        resource_uri: &str,
+        key_name: Option<&str>,
         private_key: &str,
         expires_in_seconds: i64,
-    ) -> Result<String, Box<dyn std::error::Error>> {
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
         type HmacSHA256 = Hmac<Sha256>;
         let expiry_date = chrono::Utc::now() + chrono::Duration::seconds(expires_in_seconds);
         let expiry_date_seconds = expiry_date.timestamp();
-        let data = format!(
-            "{}.azure-devices.net\n{}",
-            iothub_name, &expiry_date_seconds
-        );
+        let encoded_resource_uri: String =
+            url::form_urlencoded::byte_serialize(resource_uri.as_bytes()).collect();
+        let data = format!("{}\n{}", encoded_resource_uri, &expiry_date_seconds);
 
         let key = decode(private_key)?;
         let mut hmac = HmacSHA256::new_varkey(key.as_ref())?;
         hmac.update(data.as_bytes());
         let result = hmac.finalize();
-        let sas_token: &str = &encode_config(&result.into_bytes(), base64::STANDARD);
+        let sas_token: &str = &encode_config(result.into_bytes(), base64::STANDARD);
 
-        let encoded: String = url::form_urlencoded::Serializer::new(String::new())
-            .append_pair("sr", &format!("{}.azure-devices.net", iothub_name))
-            .append_pair("sig", sas_token)
-            .append_pair("skn", "iothubowner")
+        let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+        serializer
+            .append_pair("sr", resource_uri)
+            .append_pair("sig", sas_token);
+        if let Some(key_name) = key_name {
+            serializer.append_pair("skn", key_name);
+        }
+        let encoded = serializer
             .append_pair("se", &expiry_date_seconds.to_string())
             .finish();
 
         Ok(format!("SharedAccessSignature {}", encoded))
     }
 
+    /// Generate a SAS token that is scoped to a resource of the IoT Hub
+    ///
+    /// The resource is relative to the hostname of the IoT Hub, such as `devices/{device_id}`
+    /// or `devices/{device_id}/modules/{module_id}`. The token is signed with the private key
+    /// the IoTHubService was created with, so it is not available when the IoTHubService was
+    /// created from a SAS token. Tokens are cached and reused, as described for
+    /// [`with_clock_skew`](IoTHubService::with_clock_skew).
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600).expect("Failed to create the IoTHubService!");
+    /// let token = iothub.scoped_token("devices/some-device", Duration::from_secs(600))
+    ///     .expect("Failed to generate the token");
+    /// assert!(token.contains("sr=cool-iot-hub.azure-devices.net%2Fdevices%2Fsome-device"));
+    /// ```
+    pub fn scoped_token<S>(
+        &self,
+        resource: S,
+        time_to_live: std::time::Duration,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsRef<str>,
+    {
+        let private_key = match &self.inner.credential.private_key {
+            Some(private_key) => private_key,
+            None => {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "A private key is required to generate scoped tokens",
+                )))
+            }
+        };
+
+        self.inner.token_cache.get_or_generate(
+            Self::resource_uri(self.hostname(), resource.as_ref()),
+            time_to_live,
+            self.inner.clock_skew,
+            |resource_uri| {
+                Self::generate_sas_token(
+                    resource_uri,
+                    self.inner.credential.key_name.as_deref(),
+                    private_key,
+                    time_to_live.as_secs() as i64,
+                )
+            },
+        )
+    }
+
     /// Create a new IoTHubService struct based on a given IoT Hub name and a private key
     ///
     /// The private key should preferably be of a user / group that has the rights to make service requests.
@@ -103,28 +716,95 @@ impl IoTHubService {
         iothub_name: S,
         private_key: T,
         expires_in_seconds: i64,
-    ) -> Result<Self, Box<dyn std::error::Error>>
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
     where
         S: Into<String>,
         T: AsRef<str>,
     {
-        let iothub_name_str = iothub_name.into();
+        Self::from_private_key_with_domain_suffix(
+            iothub_name,
+            DEFAULT_DOMAIN_SUFFIX,
+            private_key,
+            expires_in_seconds,
+        )
+    }
 
-        let sas_token = Self::generate_sas_token(
-            iothub_name_str.as_str(),
+    /// Create a new IoTHubService struct based on a given IoT Hub name, domain suffix and a private key
+    ///
+    /// This should be used for IoT Hubs outside of the global Azure cloud, such as
+    /// Azure Government (`azure-devices.us`) or Azure China (`azure-devices.cn`).
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// let iothub_name = "cool-iot-hub";
+    /// let private_key = "YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    ///
+    /// let result = IoTHubService::from_private_key_with_domain_suffix(iothub_name, "azure-devices.cn", private_key, 3600);
+    /// assert!(result.is_ok(), true);
+    /// ```
+    pub fn from_private_key_with_domain_suffix<S, T, U>(
+        iothub_name: S,
+        domain_suffix: T,
+        private_key: U,
+        expires_in_seconds: i64,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+        U: AsRef<str>,
+    {
+        Self::from_shared_access_key(
+            iothub_name.into(),
+            domain_suffix.into(),
+            Some(DEFAULT_KEY_NAME.to_string()),
+            "",
             private_key.as_ref(),
             expires_in_seconds,
+        )
+    }
+
+    /// Create a new IoTHubService struct based on a shared access key
+    ///
+    /// The SAS token is scoped to the given resource, which is relative to the hostname.
+    fn from_shared_access_key(
+        iothub_name: String,
+        domain_suffix: String,
+        key_name: Option<String>,
+        resource: &str,
+        private_key: &str,
+        expires_in_seconds: i64,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let sas_token = Self::generate_sas_token(
+            &Self::resource_uri(format!("{}.{}", iothub_name, domain_suffix), resource),
+            key_name.as_deref(),
+            private_key,
+            expires_in_seconds,
         )?;
 
-        Ok(IoTHubService {
-            iothub_name: iothub_name_str,
-            sas_token,
-        })
+        Ok(Self::from_inner(IoTHubServiceInner {
+            iothub_name,
+            domain_suffix,
+            api_version: API_VERSION.to_string(),
+            credential: Credential {
+                sas_token,
+                key_name,
+                private_key: Some(private_key.to_string()),
+            },
+            transport: default_transport(),
+            metrics: Arc::new(NoMetrics),
+            clock_skew: DEFAULT_CLOCK_SKEW,
+            token_cache: Arc::default(),
+            limits: None,
+            #[cfg(feature = "tracing")]
+            log_bodies: false,
+        }))
     }
 
     /// Create a new IoTHubService struct based on a given connection string
     ///
     /// The connection string should preferably be from a user / group that has the rights to make service requests.
+    /// When the connection string contains a `SharedAccessSignature` instead of a `SharedAccessKey`,
+    /// the signature is used as the SAS token and `expires_in_seconds` is ignored.
     /// ```
     /// use azure_iothub_service::IoTHubService;
     ///
@@ -136,68 +816,145 @@ impl IoTHubService {
     pub fn from_connection_string<S>(
         connection_string: S,
         expires_in_seconds: i64,
-    ) -> Result<Self, Box<dyn std::error::Error>>
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
     where
         S: AsRef<str>,
     {
-        let parts: Vec<&str> = connection_string.as_ref().split(';').collect();
-
-        let mut iothub_name: Option<&str> = None;
-        let mut primary_key: Option<&str> = None;
+        let connection_string = IoTHubConnectionString::parse(connection_string)?;
+        let key_name = Some(
+            connection_string
+                .key_name()
+                .as_deref()
+                .unwrap_or(DEFAULT_KEY_NAME)
+                .to_string(),
+        );
+        Self::from_parsed_connection_string(connection_string, key_name, "", expires_in_seconds)
+    }
 
-        if parts.len() != 3 {
+    /// Create a new IoTHubService struct based on the connection string of a device
+    ///
+    /// The SAS token is scoped to the device, so it can only be used for requests
+    /// the device itself is allowed to make.
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// let connection_string = "HostName=cool-iot-hub.azure-devices.net;DeviceId=some-device;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    ///
+    /// let iothub = IoTHubService::from_device_connection_string(connection_string, 3600)
+    ///     .expect("Failed to create the IoTHubService!");
+    /// assert!(iothub.sas_token().contains("sr=cool-iot-hub.azure-devices.net%2Fdevices%2Fsome-device"));
+    /// ```
+    pub fn from_device_connection_string<S>(
+        connection_string: S,
+        expires_in_seconds: i64,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsRef<str>,
+    {
+        let connection_string = IoTHubConnectionString::parse(connection_string)?;
+        if connection_string.module_id().is_some() {
             return Err(Box::new(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
-                "Given connection string is invalid",
+                "The connection string is of a module, not of a device!",
             )));
         }
 
-        for val in parts.iter() {
-            let start = match val.find('=') {
-                Some(size) => size + 1,
-                None => continue,
-            };
-
-            if val.contains("HostName=") {
-                let end = match val.find(".azure-devices.net") {
-                    Some(size) => size,
-                    None => continue,
-                };
-                iothub_name = Some(&val[start..end])
-            }
+        let resource = format!("devices/{}", required_device_id(&connection_string)?);
+        Self::from_scoped_connection_string(connection_string, &resource, expires_in_seconds)
+    }
 
-            if val.contains("SharedAccessKey=") {
-                primary_key = Some(&val[start..val.len()])
-            }
-        }
+    /// Create a new IoTHubService struct based on the connection string of a module
+    ///
+    /// The SAS token is scoped to the module, so it can only be used for requests
+    /// the module itself is allowed to make.
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// let connection_string = "HostName=cool-iot-hub.azure-devices.net;DeviceId=some-device;ModuleId=some-module;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    ///
+    /// let iothub = IoTHubService::from_module_connection_string(connection_string, 3600)
+    ///     .expect("Failed to create the IoTHubService!");
+    /// assert!(iothub.sas_token().contains("%2Fmodules%2Fsome-module"));
+    /// ```
+    pub fn from_module_connection_string<S>(
+        connection_string: S,
+        expires_in_seconds: i64,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsRef<str>,
+    {
+        let connection_string = IoTHubConnectionString::parse(connection_string)?;
+        let module_id = connection_string.module_id().as_ref().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Failed to get the module id from the given connection string!",
+            )
+        })?;
 
-        let matched_iothub_name = match iothub_name {
-            Some(val) => val,
-            None => {
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    "Failed to get the hostname from the given connection string!",
-                )));
-            }
-        };
+        let resource = format!(
+            "devices/{}/modules/{}",
+            required_device_id(&connection_string)?,
+            module_id
+        );
+        Self::from_scoped_connection_string(connection_string, &resource, expires_in_seconds)
+    }
 
-        let matched_primary_key = match primary_key {
-            Some(val) => val,
+    /// Create a new IoTHubService struct with a SAS token scoped to a resource of the connection string
+    fn from_scoped_connection_string(
+        connection_string: IoTHubConnectionString,
+        resource: &str,
+        expires_in_seconds: i64,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let key_name = connection_string.key_name().clone();
+        Self::from_parsed_connection_string(
+            connection_string,
+            key_name,
+            resource,
+            expires_in_seconds,
+        )
+    }
+
+    /// Create a new IoTHubService struct from a parsed connection string
+    ///
+    /// A shared access signature in the connection string is used as is, without
+    /// generating a new token. A shared access key next to it is kept for scoped tokens.
+    fn from_parsed_connection_string(
+        connection_string: IoTHubConnectionString,
+        key_name: Option<String>,
+        resource: &str,
+        expires_in_seconds: i64,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let sas_token = match connection_string.shared_access_signature() {
+            Some(sas_token) => sas_token,
             None => {
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    "Failed to get the primary key from the given connection string!",
-                )));
+                return Self::from_shared_access_key(
+                    connection_string.iothub_name().to_string(),
+                    connection_string.domain_suffix().to_string(),
+                    key_name,
+                    resource,
+                    connection_string.required_key()?,
+                    expires_in_seconds,
+                )
             }
         };
 
-        let sas_token =
-            Self::generate_sas_token(matched_iothub_name, matched_primary_key, expires_in_seconds)?;
-
-        Ok(IoTHubService {
-            iothub_name: matched_iothub_name.to_string(),
-            sas_token: sas_token,
-        })
+        Ok(Self::from_inner(IoTHubServiceInner {
+            iothub_name: connection_string.iothub_name().to_string(),
+            domain_suffix: connection_string.domain_suffix().to_string(),
+            api_version: API_VERSION.to_string(),
+            credential: Credential {
+                sas_token,
+                key_name,
+                private_key: connection_string.key().clone(),
+            },
+            transport: default_transport(),
+            metrics: Arc::new(NoMetrics),
+            clock_skew: DEFAULT_CLOCK_SKEW,
+            token_cache: Arc::default(),
+            limits: None,
+            #[cfg(feature = "tracing")]
+            log_bodies: false,
+        }))
     }
 
     /// Get a twin manager
@@ -210,17 +967,60 @@ impl IoTHubService {
     /// let twin_manager = iothub.twin_manager();
     /// ```
     pub fn twin_manager(&self) -> TwinManager {
-        TwinManager::new(&self)
+        TwinManager::new(self)
+    }
+
+    /// Get a configuration manager
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600).expect("Failed to create the IoTHubService!");
+    /// let configuration_manager = iothub.configuration_manager();
+    /// ```
+    pub fn configuration_manager(&self) -> ConfigurationManager {
+        ConfigurationManager::new(self)
+    }
+
+    /// Get a registry manager
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600).expect("Failed to create the IoTHubService!");
+    /// let registry_manager = iothub.registry_manager();
+    /// ```
+    pub fn registry_manager(&self) -> RegistryManager {
+        RegistryManager::new(self)
+    }
+
+    /// Get a messaging manager
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600).expect("Failed to create the IoTHubService!");
+    /// let messaging_manager = iothub.messaging_manager();
+    /// ```
+    pub fn messaging_manager(&self) -> MessagingManager {
+        MessagingManager::new(self)
     }
 
     /// Create a new device method
     ///
+    /// The response time out must be between 5 and 300 seconds and the connect time out
+    /// must be at most 300 seconds, otherwise a BuilderError is returned.
+    ///
     /// ```
     /// use azure_iothub_service::IoTHubService;
     ///
     /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
     /// let iothub = IoTHubService::from_connection_string(connection_string, 3600).expect("Failed to create the IoTHubService!");
     /// let device_method = iothub.create_device_method("some-device", "hello-world", 30, 30);
+    /// assert!(device_method.is_ok());
     /// ```
     pub fn create_device_method<S, T>(
         &self,
@@ -228,29 +1028,60 @@ impl IoTHubService {
         method_name: T,
         response_time_out: u64,
         connect_time_out: u64,
-    ) -> DirectMethod
+    ) -> Result<DirectMethod, BuilderError>
     where
         S: Into<String>,
         T: Into<String>,
     {
         DirectMethod::new(
-            &self,
+            self,
             device_id.into(),
             None,
             method_name.into(),
-            connect_time_out,
             response_time_out,
+            connect_time_out,
+        )
+    }
+
+    /// Create a new device method with the default response and connect time outs
+    ///
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600).expect("Failed to create the IoTHubService!");
+    /// let device_method = iothub.create_device_method_with_defaults("some-device", "hello-world");
+    /// assert!(device_method.is_ok());
+    /// ```
+    pub fn create_device_method_with_defaults<S, T>(
+        &self,
+        device_id: S,
+        method_name: T,
+    ) -> Result<DirectMethod, BuilderError>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.create_device_method(
+            device_id,
+            method_name,
+            DEFAULT_RESPONSE_TIME_OUT,
+            DEFAULT_CONNECT_TIME_OUT,
         )
     }
 
     /// Create a new module method
     ///
+    /// The response time out must be between 5 and 300 seconds and the connect time out
+    /// must be at most 300 seconds, otherwise a BuilderError is returned.
+    ///
     /// ```
     /// use azure_iothub_service::IoTHubService;
     ///
     /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
     /// let iothub = IoTHubService::from_connection_string(connection_string, 3600).expect("Failed to create the IoTHubService!");
-    /// let device_method = iothub.create_module_method("some-device", "some-module", "hello-world", 30, 30);
+    /// let module_method = iothub.create_module_method("some-device", "some-module", "hello-world", 30, 30);
+    /// assert!(module_method.is_ok());
     /// ```
     pub fn create_module_method<S, T, U>(
         &self,
@@ -259,22 +1090,126 @@ impl IoTHubService {
         method_name: U,
         response_time_out: u64,
         connect_time_out: u64,
-    ) -> DirectMethod
+    ) -> Result<DirectMethod, BuilderError>
     where
         S: Into<String>,
         T: Into<String>,
         U: Into<String>,
     {
         DirectMethod::new(
-            &self,
+            self,
             device_id.into(),
             Some(module_id.into()),
             method_name.into(),
-            connect_time_out,
             response_time_out,
+            connect_time_out,
         )
     }
 
+    /// Create a new module method with the default response and connect time outs
+    pub fn create_module_method_with_defaults<S, T, U>(
+        &self,
+        device_id: S,
+        module_id: T,
+        method_name: U,
+    ) -> Result<DirectMethod, BuilderError>
+    where
+        S: Into<String>,
+        T: Into<String>,
+        U: Into<String>,
+    {
+        self.create_module_method(
+            device_id,
+            module_id,
+            method_name,
+            DEFAULT_RESPONSE_TIME_OUT,
+            DEFAULT_CONNECT_TIME_OUT,
+        )
+    }
+
+    /// Invoke a method on all custom modules of a device
+    ///
+    /// The module identities of the device are listed and the method is invoked on every
+    /// module, except for the system modules such as `$edgeAgent` and `$edgeHub`. The results
+    /// are returned per module id, so a failing module does not hide the results of the others.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use serde_json::json;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let results = iothub
+    ///     .invoke_method_on_all_modules::<_, _, serde_json::Value>("some-device", "GetHealth", json!({}), 30, 30)
+    ///     .await?;
+    /// for (module_id, result) in results {
+    ///     match result {
+    ///         Ok(response) => println!("{} returned {}", module_id, response.status),
+    ///         Err(err) => println!("{} failed with {}", module_id, err),
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn invoke_method_on_all_modules<S, T, U>(
+        &self,
+        device_id: S,
+        method_name: T,
+        payload: serde_json::Value,
+        response_time_out: u64,
+        connect_time_out: u64,
+    ) -> Result<
+        HashMap<
+            String,
+            Result<Response<DirectMethodResponse<U>>, Box<dyn std::error::Error + Send + Sync>>,
+        >,
+        Box<dyn std::error::Error + Send + Sync>,
+    >
+    where
+        S: Into<String>,
+        T: Into<String>,
+        U: DeserializeOwned,
+    {
+        let device_id = device_id.into();
+        let method_name = method_name.into();
+        // Validate the time outs before any request is sent
+        self.create_device_method(
+            device_id.as_str(),
+            method_name.as_str(),
+            response_time_out,
+            connect_time_out,
+        )?;
+
+        let module_identities = self
+            .registry_manager()
+            .get_module_identities(device_id.as_str())
+            .await?
+            .into_inner();
+
+        let mut results = HashMap::new();
+        for module_identity in module_identities {
+            if module_identity.is_system_module() {
+                continue;
+            }
+
+            let result = self
+                .create_module_method(
+                    device_id.as_str(),
+                    module_identity.module_id.as_str(),
+                    method_name.as_str(),
+                    response_time_out,
+                    connect_time_out,
+                )?
+                .invoke(payload.clone())
+                .await;
+            results.insert(module_identity.module_id, result);
+        }
+
+        Ok(results)
+    }
+
     /// Create a new IoT Hub query
     ///
     /// ```
@@ -287,64 +1222,423 @@ impl IoTHubService {
     ///             .from("a table")
     ///             .build();
     /// ```
-    pub fn build_query(&self) -> QueryBuilder<'_> {
-        QueryBuilder::new(&self)
+    pub fn build_query(&self) -> QueryBuilder {
+        QueryBuilder::new(self)
+    }
+
+    /// Count the devices matching the condition
+    ///
+    /// The condition is a query condition such as `tags.environment = 'production'`,
+    /// an empty condition or `*` counts all devices.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let total = iothub.count_devices("tags.environment = 'production'").await?;
+    /// println!("{} production devices", *total);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn count_devices(
+        &self,
+        condition: &str,
+    ) -> Result<Response<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut query = self.build_query().from("devices");
+        let condition = condition.trim();
+        if !condition.is_empty() && condition != "*" {
+            query = query.and_where(condition);
+        }
+        query.count().await
+    }
+
+    /// Check whether the IoT Hub can be reached with the credentials of the service
+    ///
+    /// This requests the service statistics of the IoT Hub, which is a cheap authenticated
+    /// request, so services can fail fast at startup. The returned error tells why the
+    /// connection failed, such as a DNS or TLS failure, rejected credentials or the IP filter.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::error::ConnectionFailure;
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// if let Err(err) = iothub.check_connection().await {
+    ///     if err.failure() == ConnectionFailure::Forbidden {
+    ///         eprintln!("Is this address allowed by the IP filter?");
+    ///     }
+    ///     return Err(Box::new(err));
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn check_connection(&self) -> Result<(), ConnectionCheckError> {
+        let uri = format!(
+            "https://{}/statistics/service?api-version={}",
+            self.hostname(),
+            self.api_version()
+        );
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::GET)
+            .header("Authorization", self.authorization())
+            .body(Vec::new())
+            .map_err(|err| ConnectionCheckError::from_transport_error(Box::new(err)))?;
+
+        let response = self
+            .send("check_connection", None, request)
+            .await
+            .map_err(ConnectionCheckError::from_transport_error)?;
+        if !response.status().is_success() {
+            return Err(ConnectionCheckError::from_iothub_error(
+                IoTHubError::from_response(&response),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Send a request to an endpoint of the IoT Hub that has no wrapper in this crate
+    ///
+    /// The request is authorized and sent like every other request, and the path is
+    /// relative to the hostname of the IoT Hub. The `api-version` query parameter is
+    /// added unless it is part of the query. The response is returned as is, also
+    /// when its status is not a success, so the status, headers and body can be inspected.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let response = iothub
+    ///     .raw_request(http::Method::GET, "/jobs/v2/query", &[("jobType", "scheduleUpdateTwin")], None)
+    ///     .await?;
+    /// println!("{}: {}", response.status(), String::from_utf8_lossy(response.body()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn raw_request(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<serde_json::Value>,
+    ) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+        if path.contains(|c: char| c == '?' || c == '#' || c.is_whitespace()) || path.contains("//")
+        {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("The path {:?} is not a path of the IoT Hub", path),
+            )));
+        }
+
+        let query = {
+            let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+            serializer.extend_pairs(query);
+            if !query.iter().any(|(name, _)| *name == "api-version") {
+                serializer.append_pair("api-version", self.api_version());
+            }
+            serializer.finish()
+        };
+        let uri = format!(
+            "https://{}/{}?{}",
+            self.hostname(),
+            path.trim_start_matches('/'),
+            query
+        );
+
+        let mut request = Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("Authorization", self.authorization());
+        let body = match body {
+            Some(body) => {
+                request = request.header("Content-Type", "application/json");
+                serde_json::to_vec(&body)?
+            }
+            None => Vec::new(),
+        };
+        let request = request.body(body)?;
+
+        self.send_compressible("raw_request", None, request).await
     }
 
     /// Apply a new modules configuration on a given edge device
-    pub async fn apply_modules_configuration<'a, S>(
+    ///
+    /// The result lists the modules that were sent and the validation warnings
+    /// the IoT Hub returned, see [`ApplyResult`] for an example.
+    pub async fn apply_modules_configuration<S>(
         &self,
         device_id: S,
-        modules_content: &'a ModulesContent,
-    ) -> Result<(), Box<dyn std::error::Error>>
+        modules_content: &ModulesContent,
+    ) -> Result<Response<ApplyResult>, Box<dyn std::error::Error + Send + Sync>>
     where
         S: Into<String>,
     {
+        let device_id = device_id.into();
         let uri: &str = &format!(
-            "https://{}.azure-devices.net/devices/{}/applyConfigurationContent?api-version={}",
-            self.iothub_name,
-            device_id.into(),
-            API_VERSION
+            "https://{}/devices/{}/applyConfigurationContent?api-version={}",
+            self.hostname(),
+            device_id,
+            self.api_version()
         );
 
         let json_payload = json!({
             "modulesContent": modules_content,
         });
 
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, hyper::Body>(https);
         let request = Request::builder()
             .uri(uri)
             .method(Method::POST)
-            .header("Authorization", &self.sas_token)
+            .header("Authorization", self.authorization())
             .header("Content-Type", "application/json")
-            .body(Body::from(serde_json::to_string(&json_payload)?))?;
-
-        let response = client.request(request).await?;
-        let status_code = response.status();
-        let body = hyper::body::aggregate(response).await?;
-        if status_code != StatusCode::OK || status_code != StatusCode::NO_CONTENT {
-            let mut error_payload = String::new();
-            body.reader().read_to_string(&mut error_payload)?;
+            .body(serde_json::to_vec(&json_payload)?)?;
+
+        let response = self
+            .send("apply_modules_configuration", Some(&device_id), request)
+            .await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
         }
 
-        Ok(())
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            ApplyResult::new(modules_content, response.body()),
+        ))
+    }
+
+    /// Apply the modules configuration on an edge device and wait until the deployment converged
+    ///
+    /// After applying the configuration, the `$edgeAgent` twin is polled until the edge agent
+    /// applied the new desired properties and every module reports its desired runtime status,
+    /// or until the timeout expired. The returned report tells whether the deployment converged
+    /// and which modules are still pending.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use std::time::Duration;
+    /// use azure_iothub_service::{IoTHubService, ModulesContentBuilder};
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// # let modules_content = ModulesContentBuilder::new().build()?;
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let report = iothub
+    ///     .apply_modules_configuration_and_wait("some-device", &modules_content, Duration::from_secs(300))
+    ///     .await?;
+    /// if !report.is_converged() {
+    ///     for module in report.pending_modules() {
+    ///         println!("{} is {:?}", module.module_name(), module.runtime_status());
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn apply_modules_configuration_and_wait<S>(
+        &self,
+        device_id: S,
+        modules_content: &ModulesContent,
+        timeout: Duration,
+    ) -> Result<Response<ConvergenceReport>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let device_id = device_id.into();
+        self.apply_modules_configuration(device_id.as_str(), modules_content)
+            .await?;
+
+        let started = Instant::now();
+        let twin_manager = self.twin_manager();
+        loop {
+            let (metadata, edge_agent_twin) = twin_manager
+                .get_module_twin(device_id.as_str(), EDGE_AGENT_MODULE_ID)
+                .await?
+                .into_parts();
+
+            let elapsed = started.elapsed();
+            let report = ConvergenceReport::from_edge_agent_twin(&edge_agent_twin, elapsed);
+            if report.is_converged() || elapsed >= timeout {
+                return Ok(Response::new(metadata, report));
+            }
+
+            delay_for(DEPLOYMENT_POLL_INTERVAL.min(timeout - elapsed)).await;
+        }
     }
+
+    /// Apply the modules configuration on all edge devices matching the target condition
+    ///
+    /// The target condition is evaluated with a query on the devices, such as
+    /// `tags.environment = 'production'`, and only edge devices are selected. The configuration
+    /// is applied to at most `max_concurrency` devices at the same time and the progress callback
+    /// is called each time the configuration was applied to a device. The results are returned
+    /// per device id, so a failing device does not stop the others.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::{IoTHubService, ModulesContentBuilder};
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// # let modules_content = ModulesContentBuilder::new().build()?;
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let results = iothub
+    ///     .apply_modules_configuration_to_target(
+    ///         "tags.environment = 'production'",
+    ///         &modules_content,
+    ///         10,
+    ///         |progress| println!("{}/{} {}", progress.completed, progress.total, progress.device_id),
+    ///     )
+    ///     .await?;
+    /// let failed = results.values().filter(|result| result.is_err()).count();
+    /// println!("Failed to apply the configuration to {} devices", failed);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn apply_modules_configuration_to_target<S, F>(
+        &self,
+        target_condition: S,
+        modules_content: &ModulesContent,
+        max_concurrency: usize,
+        mut progress: F,
+    ) -> Result<
+        HashMap<String, Result<Response<ApplyResult>, Box<dyn std::error::Error + Send + Sync>>>,
+        Box<dyn std::error::Error + Send + Sync>,
+    >
+    where
+        S: Into<String>,
+        F: FnMut(&ApplyProgress<'_>),
+    {
+        let device_ids: Vec<String> = self
+            .build_query()
+            .select("deviceId")
+            .from("devices")
+            .and_where(format!(
+                "capabilities.iotEdge = true AND ({})",
+                target_condition.into()
+            ))
+            .build()?
+            .execute_all_pages::<serde_json::Value>()
+            .await?
+            .iter()
+            .filter_map(|item| item["deviceId"].as_str().map(String::from))
+            .collect();
+
+        // Every device is configured once, even when the query returns it twice
+        let device_ids = unique(device_ids);
+        let total = device_ids.len();
+        let apply = |device_id: String| async move {
+            let result = self
+                .apply_modules_configuration(device_id.as_str(), modules_content)
+                .await;
+            (device_id, result)
+        };
+
+        Ok(for_each_bounded_with_progress(
+            device_ids,
+            max_concurrency,
+            apply,
+            |device_id, result, completed| {
+                progress(&ApplyProgress {
+                    device_id,
+                    result,
+                    completed,
+                    total,
+                })
+            },
+        )
+        .await
+        .into_iter()
+        .collect())
+    }
+}
+
+/// Get the device id of a device or module connection string
+fn required_device_id(
+    connection_string: &IoTHubConnectionString,
+) -> Result<&String, Box<dyn std::error::Error + Send + Sync>> {
+    connection_string.device_id().as_ref().ok_or_else(|| {
+        Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Failed to get the device id from the given connection string!",
+        )) as Box<dyn std::error::Error + Send + Sync>
+    })
 }
 
 #[cfg(test)]
 mod tests {
     #[test]
-    fn from_connectionstring_success() -> Result<(), Box<dyn std::error::Error>> {
+    fn from_connectionstring_success() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         use crate::IoTHubService;
         let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
         let _ = IoTHubService::from_connection_string(connection_string, 3600)?;
         Ok(())
     }
 
+    #[test]
+    fn from_connectionstring_should_parse_domain_suffix(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::IoTHubService;
+        let connection_string = "HostName=cool-iot-hub.azure-devices.cn;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+        let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+        assert_eq!(iothub.iothub_name(), "cool-iot-hub");
+        assert_eq!(iothub.domain_suffix(), "azure-devices.cn");
+        assert_eq!(iothub.hostname(), "cool-iot-hub.azure-devices.cn");
+        assert!(iothub
+            .sas_token()
+            .contains("sr=cool-iot-hub.azure-devices.cn"));
+        Ok(())
+    }
+
+    #[test]
+    fn managers_should_not_borrow_the_iothub_service(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::IoTHubService;
+
+        fn assert_static<T: 'static>(_: &T) {}
+
+        let (twin_manager, query) = {
+            let iothub = IoTHubService::from_sas_token("test", "test");
+            let clone = iothub.clone().with_api_version("2020-09-30");
+            assert_eq!(clone.api_version(), "2020-09-30");
+            assert_ne!(iothub.api_version(), clone.api_version());
+            (
+                iothub.twin_manager(),
+                iothub.build_query().select("*").from("devices").build()?,
+            )
+        };
+        assert_static(&twin_manager);
+        assert_static(&query);
+        Ok(())
+    }
+
+    #[test]
+    fn api_version_should_be_configurable() {
+        use crate::iothub::{ApiFeature, API_VERSION};
+        use crate::IoTHubService;
+
+        let iothub = IoTHubService::from_sas_token("test", "test");
+        assert_eq!(iothub.api_version(), API_VERSION);
+        assert!(iothub.supports(ApiFeature::ModulesConfiguration));
+        assert!(!iothub.supports(ApiFeature::DigitalTwins));
+
+        let iothub = iothub.with_api_version("2018-06-30");
+        assert!(iothub.supports(ApiFeature::DirectMethods));
+        assert!(!iothub.supports(ApiFeature::ParentScopes));
+
+        let iothub = iothub.with_api_version("2020-09-30-preview");
+        assert!(iothub.supports(ApiFeature::DigitalTwins));
+    }
+
     #[test]
     fn from_connectionstring_should_fail_on_incorrect_hostname(
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         use crate::IoTHubService;
         let connection_string = "HostName==cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
         let _ = IoTHubService::from_connection_string(connection_string, 3600).is_err();
@@ -356,7 +1650,7 @@ mod tests {
 
     #[test]
     fn from_connectionstring_should_fail_on_empty_connection_string(
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         use crate::IoTHubService;
         let _ = IoTHubService::from_connection_string("", 3600).is_err();
         Ok(())
@@ -364,9 +1658,439 @@ mod tests {
 
     #[test]
     fn from_connectionstring_should_fail_on_incomplete_connection_string(
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         use crate::IoTHubService;
         let _ = IoTHubService::from_connection_string("HostName=cool-iot-hub.azure-devices.net;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==", 3600).is_err();
         Ok(())
     }
+
+    #[test]
+    fn invoke_method_on_all_modules_should_skip_system_modules(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::testing::mocktransport::{error_response, json_response, MockTransport};
+        use crate::IoTHubService;
+
+        let transport = MockTransport::new(|request, _| {
+            let path = request.uri().path();
+            if path.ends_with("/modules") {
+                json_response(&serde_json::json!([
+                    { "moduleId": "$edgeAgent", "deviceId": "some-device" },
+                    { "moduleId": "healthy", "deviceId": "some-device" },
+                    { "moduleId": "unhealthy", "deviceId": "some-device" }
+                ]))
+            } else if path.contains("/modules/healthy/") {
+                json_response(&serde_json::json!({ "status": 200, "payload": { "healthy": true } }))
+            } else if path.contains("/modules/unhealthy/") {
+                error_response(
+                    404,
+                    "DeviceNotOnline",
+                    "Timed out waiting for the module to connect",
+                )
+            } else {
+                panic!("Unexpected request to {}", path);
+            }
+        });
+        let iothub = IoTHubService::from_sas_token("test", "test").with_transport(transport);
+        let results = futures::executor::block_on(
+            iothub.invoke_method_on_all_modules::<_, _, serde_json::Value>(
+                "some-device",
+                "GetHealth",
+                serde_json::json!({}),
+                30,
+                30,
+            ),
+        )?;
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results["healthy"].as_ref().unwrap().status, 200);
+        assert!(results["unhealthy"].is_err());
+        Ok(())
+    }
+
+    /// Create a transport that accepts a deployment and returns an `$edgeAgent` twin that
+    /// converges after the given number of polls
+    fn edge_agent_transport(
+        converges_after: usize,
+    ) -> crate::testing::mocktransport::MockTransport {
+        crate::testing::mocktransport::MockTransport::new(move |request, earlier| {
+            let is_poll = |request: &crate::transport::HttpRequest| {
+                request.uri().path().ends_with("/modules/$edgeAgent")
+            };
+            let converged = if is_poll(request) {
+                earlier.iter().filter(|request| is_poll(request)).count() >= converges_after
+            } else {
+                assert!(request.uri().path().ends_with("/applyConfigurationContent"));
+                false
+            };
+            let (version, sensor_status) = if converged {
+                (2, "running")
+            } else {
+                (1, "backoff")
+            };
+
+            crate::testing::mocktransport::json_response(&serde_json::json!({
+                "deviceId": "some-device",
+                "moduleId": "$edgeAgent",
+                "etag": "AAAAAAAAAAI=",
+                "status": "enabled",
+                "connectionState": "Connected",
+                "properties": {
+                    "desired": {
+                        "systemModules": {
+                            "edgeAgent": { "type": "docker" },
+                            "edgeHub": { "type": "docker", "status": "running" }
+                        },
+                        "modules": {
+                            "sensor": { "type": "docker", "status": "running" },
+                            "debugger": { "type": "docker", "status": "stopped" }
+                        },
+                        "$version": 2
+                    },
+                    "reported": {
+                        "lastDesiredVersion": version,
+                        "lastDesiredStatus": { "code": 200 },
+                        "systemModules": {
+                            "edgeAgent": { "runtimeStatus": "running" },
+                            "edgeHub": { "runtimeStatus": "running" }
+                        },
+                        "modules": {
+                            "sensor": { "runtimeStatus": sensor_status },
+                            "debugger": { "runtimeStatus": "stopped" }
+                        }
+                    }
+                }
+            }))
+        })
+    }
+
+    fn modules_content() -> crate::ModulesContent {
+        crate::ModulesContentBuilder::new()
+            .edge_agent_image("mcr.microsoft.com/azureiotedge-agent:1.0.9")
+            .edge_hub_image("mcr.microsoft.com/azureiotedge-hub:1.0.9")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(10)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn apply_modules_configuration_and_wait_should_poll_until_converged(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::IoTHubService;
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(edge_agent_transport(1));
+
+        let report = futures::executor::block_on(iothub.apply_modules_configuration_and_wait(
+            "some-device",
+            &modules_content(),
+            std::time::Duration::from_millis(50),
+        ))?;
+        assert!(report.is_converged());
+        assert_eq!(report.reported_version(), Some(2));
+        assert_eq!(report.modules().len(), 3);
+        assert!(report.pending_modules().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn apply_modules_configuration_and_wait_should_report_pending_modules(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::IoTHubService;
+        let iothub = IoTHubService::from_sas_token("test", "test")
+            .with_transport(edge_agent_transport(usize::MAX));
+
+        let report = futures::executor::block_on(iothub.apply_modules_configuration_and_wait(
+            "some-device",
+            &modules_content(),
+            std::time::Duration::from_secs(0),
+        ))?;
+        assert!(!report.is_converged());
+        assert_eq!(report.desired_version(), Some(2));
+        assert_eq!(report.reported_version(), Some(1));
+        let pending = report.pending_modules();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].module_name(), "sensor");
+        assert_eq!(pending[0].runtime_status().as_deref(), Some("backoff"));
+        Ok(())
+    }
+
+    #[test]
+    fn apply_modules_configuration_to_target_should_apply_to_matching_devices(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::testing::mocktransport::{error_response, json_response, MockTransport};
+        use crate::IoTHubService;
+
+        // The query returns three edge devices, with the last one on a second page, and
+        // applying the configuration to one of them fails
+        let transport = MockTransport::new(|request, _| {
+            let path = request.uri().path();
+            if path.ends_with("/devices/query") {
+                match request.headers().get("x-ms-continuation") {
+                    None => Ok(http::Response::builder()
+                        .header("x-ms-continuation", "page-2")
+                        .body(serde_json::to_vec(&serde_json::json!([
+                            { "deviceId": "device-1" },
+                            { "deviceId": "device-2" }
+                        ]))?)?),
+                    Some(_) => json_response(&serde_json::json!([{ "deviceId": "device-3" }])),
+                }
+            } else if path.contains("/devices/device-2/") {
+                error_response(404, "DeviceNotFound", "Device not found")
+            } else {
+                Ok(http::Response::new(Vec::new()))
+            }
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let mut completed = Vec::new();
+        let results = futures::executor::block_on(iothub.apply_modules_configuration_to_target(
+            "tags.environment = 'production'",
+            &modules_content(),
+            2,
+            |progress| completed.push((progress.completed, progress.total)),
+        ))?;
+
+        let body: serde_json::Value = serde_json::from_slice(transport.requests()[0].body())?;
+        assert_eq!(
+            body["query"],
+            "SELECT deviceId FROM devices WHERE capabilities.iotEdge = true AND (tags.environment = 'production')"
+        );
+        assert_eq!(results.len(), 3);
+        assert!(results["device-1"].is_ok());
+        assert!(results["device-2"].is_err());
+        assert!(results["device-3"].is_ok());
+        assert_eq!(completed, vec![(1, 3), (2, 3), (3, 3)]);
+        Ok(())
+    }
+
+    #[test]
+    fn iothub_service_should_redact_secrets() -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    {
+        use crate::redact::RevealSecrets;
+        use crate::IoTHubService;
+        let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+        let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+
+        let debug_output = format!("{:?}", iothub);
+        assert!(debug_output.contains("cool-iot-hub"));
+        assert!(!debug_output.contains("SharedAccessSignature"));
+        assert!(!debug_output.contains("YSB2ZXJ5"));
+
+        let revealed_output = format!("{:?}", iothub.reveal());
+        assert!(revealed_output.contains("SharedAccessSignature"));
+        assert!(revealed_output.contains("YSB2ZXJ5"));
+        Ok(())
+    }
+
+    #[test]
+    fn scoped_token_should_use_key_name_and_resource(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::IoTHubService;
+        let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=registryReadWrite;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+        let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+        assert!(iothub.sas_token().contains("skn=registryReadWrite"));
+
+        let token = iothub.scoped_token(
+            "/devices/some-device/modules/some-module",
+            std::time::Duration::from_secs(60),
+        )?;
+        assert!(token.starts_with("SharedAccessSignature "));
+        assert!(token.contains(
+            "sr=cool-iot-hub.azure-devices.net%2Fdevices%2Fsome-device%2Fmodules%2Fsome-module"
+        ));
+        assert!(token.contains("skn=registryReadWrite"));
+
+        let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>");
+        assert!(iothub
+            .scoped_token("devices/some-device", std::time::Duration::from_secs(60))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn token_cache_should_reuse_tokens_within_clock_skew(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use super::TokenCache;
+        use std::time::Duration;
+
+        let cache = TokenCache::default();
+        let generated = std::cell::Cell::new(0);
+        let get = |resource: &str, time_to_live, clock_skew| {
+            cache.get_or_generate(resource.to_string(), time_to_live, clock_skew, |uri| {
+                generated.set(generated.get() + 1);
+                Ok(format!("{}-{}", uri, generated.get()))
+            })
+        };
+
+        let hour = Duration::from_secs(3600);
+        let skew = Duration::from_secs(30);
+        assert_eq!(get("devices/a", hour, skew)?, "devices/a-1");
+        assert_eq!(get("devices/a", hour, skew)?, "devices/a-1");
+        assert_eq!(get("devices/b", hour, skew)?, "devices/b-2");
+        assert_eq!(
+            get("devices/a", Duration::from_secs(600), skew)?,
+            "devices/a-3"
+        );
+        // A clock skew of half the time to live leaves no time to reuse the token
+        assert_eq!(
+            get("devices/a", Duration::from_secs(60), skew)?,
+            "devices/a-4"
+        );
+        assert_eq!(
+            get("devices/a", Duration::from_secs(60), skew)?,
+            "devices/a-5"
+        );
+        assert!(cache
+            .get_or_generate(
+                "devices/c".to_string(),
+                hour,
+                skew,
+                |_| Err("no key".into())
+            )
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn from_device_connection_string_should_scope_token(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::IoTHubService;
+        let connection_string = "DeviceId=some-device;HostName=cool-iot-hub.azure-devices.net;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==;GatewayHostName=gateway.local;";
+        let iothub = IoTHubService::from_device_connection_string(connection_string, 3600)?;
+        assert_eq!(iothub.iothub_name(), "cool-iot-hub");
+        assert!(iothub
+            .sas_token()
+            .contains("sr=cool-iot-hub.azure-devices.net%2Fdevices%2Fsome-device&"));
+        assert!(!iothub.sas_token().contains("skn="));
+
+        let module_connection_string = format!("{};ModuleId=some-module", connection_string);
+        let iothub = IoTHubService::from_module_connection_string(&module_connection_string, 3600)?;
+        assert!(iothub.sas_token().contains(
+            "sr=cool-iot-hub.azure-devices.net%2Fdevices%2Fsome-device%2Fmodules%2Fsome-module&"
+        ));
+
+        assert!(
+            IoTHubService::from_device_connection_string(&module_connection_string, 3600).is_err()
+        );
+        assert!(IoTHubService::from_module_connection_string(connection_string, 3600).is_err());
+        assert!(IoTHubService::from_device_connection_string(
+            "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==",
+            3600
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn from_connectionstring_should_accept_extra_segments(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::IoTHubService;
+        let connection_string = "SharedAccessKeyName=iothubowner;HostName=cool-iot-hub.azure-devices.net;GatewayHostName=gateway.local;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==;";
+        let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+        assert_eq!(iothub.iothub_name(), "cool-iot-hub");
+        Ok(())
+    }
+
+    #[test]
+    fn from_connection_string_should_use_shared_access_signature(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::IoTHubService;
+        let connection_string = "HostName=cool-iot-hub.azure-devices.cn;SharedAccessSignature=SharedAccessSignature sr=cool-iot-hub.azure-devices.cn&sig=c2lnbmF0dXJl&se=1600000000&skn=iothubowner";
+        let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+        assert_eq!(
+            iothub.sas_token(),
+            "SharedAccessSignature sr=cool-iot-hub.azure-devices.cn&sig=c2lnbmF0dXJl&se=1600000000&skn=iothubowner"
+        );
+        assert_eq!(iothub.hostname(), "cool-iot-hub.azure-devices.cn");
+        assert!(iothub
+            .scoped_token("devices/some-device", std::time::Duration::from_secs(60))
+            .is_err());
+
+        let iothub = IoTHubService::from_device_connection_string(
+            "HostName=cool-iot-hub.azure-devices.net;DeviceId=some-device;SharedAccessSignature=sr=cool-iot-hub.azure-devices.net%2Fdevices%2Fsome-device&sig=c2lnbmF0dXJl&se=1600000000",
+            3600,
+        )?;
+        assert!(iothub
+            .sas_token()
+            .starts_with("SharedAccessSignature sr=cool-iot-hub.azure-devices.net%2Fdevices"));
+        Ok(())
+    }
+
+    #[test]
+    fn check_connection_should_classify_failures() {
+        use crate::error::ConnectionFailure;
+        use crate::testing::mocktransport::{error_response, MockTransport};
+        use crate::IoTHubService;
+
+        // The transport answers with the given status, or fails with a DNS error without a status
+        let check = |status: Option<u16>| {
+            let transport = MockTransport::new(move |request, _| {
+                assert_eq!(request.uri().path(), "/statistics/service");
+                match status {
+                    Some(status) => {
+                        error_response(status, "IotHubUnauthorizedAccess", "Unauthorized")
+                    }
+                    None => Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "dns error: failed to lookup address information",
+                    ))),
+                }
+            });
+            let iothub = IoTHubService::from_sas_token("test", "test").with_transport(transport);
+            futures::executor::block_on(iothub.check_connection()).map_err(|err| err.failure())
+        };
+        assert_eq!(check(Some(200)), Ok(()));
+        assert_eq!(check(Some(401)), Err(ConnectionFailure::Unauthorized));
+        assert_eq!(check(Some(403)), Err(ConnectionFailure::Forbidden));
+        assert_eq!(check(Some(429)), Err(ConnectionFailure::Throttled));
+        assert_eq!(check(Some(500)), Err(ConnectionFailure::Unexpected));
+        assert_eq!(check(None), Err(ConnectionFailure::Dns));
+    }
+
+    #[test]
+    fn raw_request_should_sign_and_return_response(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::testing::mocktransport::MockTransport;
+        use crate::IoTHubService;
+
+        let transport = MockTransport::new(|_, _| {
+            Ok(http::Response::builder()
+                .status(409)
+                .header("x-ms-request-id", "some-request")
+                .body(br#"{"Message":"ErrorCode:PreconditionFailed"}"#.to_vec())?)
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let response = futures::executor::block_on(iothub.raw_request(
+            http::Method::PUT,
+            "/devices/some-device/some%20thing",
+            &[("filter", "a&b")],
+            Some(serde_json::json!({ "status": "disabled" })),
+        ))?;
+        assert_eq!(response.status(), 409);
+        assert_eq!(response.headers()["x-ms-request-id"], "some-request");
+        assert!(response.body().starts_with(b"{"));
+
+        assert!(futures::executor::block_on(iothub.raw_request(
+            http::Method::GET,
+            "//evil.example.com/devices",
+            &[],
+            None,
+        ))
+        .is_err());
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method(), http::Method::PUT);
+        assert_eq!(
+            requests[0].uri().to_string(),
+            "https://test.azure-devices.net/devices/some-device/some%20thing?filter=a%26b&api-version=2020-03-13"
+        );
+        assert_eq!(requests[0].headers()["Authorization"], "test");
+        assert_eq!(requests[0].headers()["Content-Type"], "application/json");
+        let body: serde_json::Value = serde_json::from_slice(requests[0].body())?;
+        assert_eq!(body["status"], "disabled");
+        Ok(())
+    }
 }
@@ -1,13 +1,223 @@
 #[macro_use]
 extern crate serde_derive;
 
+#[cfg(feature = "arm")]
+pub mod arm;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "compression")]
+mod compression;
+mod concurrent;
 pub mod configuration;
+#[cfg(test)]
+mod conformance;
+pub mod connectionstring;
+mod delay;
 pub mod directmethod;
+#[cfg(feature = "dps")]
+pub mod dps;
 pub mod error;
+pub mod eventgrid;
 pub mod iothub;
+pub mod messaging;
+pub mod metrics;
+#[cfg(feature = "hyper-transport")]
+pub mod proxy;
 pub mod query;
+pub mod redact;
+pub mod registry;
+pub mod response;
+pub mod tagimport;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod tier;
+pub mod transport;
 pub mod twin;
+pub mod twinsize;
+pub mod watch;
 
 pub use configuration::modulescontent::{EdgeModuleBuilder, ModulesContent, ModulesContentBuilder};
 pub use iothub::IoTHubService;
-use iothub::API_VERSION;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::configuration::{ConfigurationManager, ModulesContent};
+    use crate::directmethod::DirectMethod;
+    use crate::messaging::MessagingManager;
+    use crate::query::{Query, QueryBuilder};
+    use crate::registry::{DeviceIdentity, IfMatch, RegistryManager};
+    use crate::twin::{DesiredTwinBuilder, Status, TwinManager};
+    use crate::IoTHubService;
+
+    fn assert_send<T: Send>(_: T) {}
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn service_and_managers_should_be_send_and_sync() {
+        assert_send_sync::<IoTHubService>();
+        assert_send_sync::<TwinManager>();
+        assert_send_sync::<RegistryManager>();
+        assert_send_sync::<ConfigurationManager>();
+        assert_send_sync::<DirectMethod>();
+        assert_send_sync::<MessagingManager>();
+        assert_send_sync::<Query>();
+        assert_send_sync::<QueryBuilder>();
+        assert_send_sync::<ModulesContent>();
+    }
+
+    #[test]
+    fn errors_should_be_send_and_sync() {
+        assert_send_sync::<Box<dyn std::error::Error + Send + Sync>>();
+        assert_send_sync::<crate::error::IoTHubError>();
+        assert_send_sync::<crate::error::BuilderError>();
+        assert_send_sync::<crate::error::ParsingError>();
+        assert_send_sync::<crate::error::TemplateError>();
+    }
+
+    #[test]
+    fn futures_should_be_send() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let iothub = IoTHubService::from_sas_token("test", "test");
+        let modules_content: ModulesContent = crate::ModulesContentBuilder::new()
+            .edge_agent_image("agent")
+            .edge_hub_image("hub")
+            .minimum_docker_version("v1.25")
+            .time_to_live_secs(10)
+            .build()?;
+        let device_identity = DeviceIdentity::new("some-device");
+
+        let twin_manager = iothub.twin_manager();
+        assert_send(twin_manager.get_module_twin("some-device", "some-module"));
+        assert_send(
+            twin_manager
+                .get_typed_device_twin::<serde_json::Value, serde_json::Value, _>("some-device"),
+        );
+        assert_send(
+            twin_manager.get_typed_module_twin::<serde_json::Value, serde_json::Value, _, _>(
+                "some-device",
+                "some-module",
+            ),
+        );
+        assert_send(twin_manager.update_module_twin(
+            "some-device",
+            "some-module",
+            DesiredTwinBuilder::new().build(),
+        ));
+        assert_send(
+            twin_manager.modify_module_twin("some-device", "some-module", 1, |_| {
+                DesiredTwinBuilder::new().build()
+            }),
+        );
+        assert_send(twin_manager.find_stale_devices(Duration::from_secs(60)));
+        assert_send(twin_manager.get_edge_agent_reported("some-device"));
+        assert_send(
+            twin_manager
+                .watch_device_twin("some-device", Duration::from_secs(1))
+                .next(),
+        );
+        assert_send(
+            twin_manager
+                .monitor_connection_states("", Duration::from_secs(1))
+                .next(),
+        );
+        assert_send(twin_manager.get_edge_hub_reported("some-device"));
+        assert_send(twin_manager.get_route_report("some-device"));
+        let twin_snapshot = crate::twin::TwinSnapshot {
+            created_time_utc: "2020-06-21T16:38:35Z".to_string(),
+            twins: Vec::new(),
+        };
+        assert_send(twin_manager.restore(&twin_snapshot, 10));
+        assert_send(twin_manager.get_device_twins(vec!["some-device"], 10));
+        assert_send(twin_manager.import_tags(&crate::tagimport::TagImport::default(), 10, true));
+        assert_send(twin_manager.find_modules_by_image("someacr.azurecr.io/sensor:*"));
+        assert_send(twin_manager.set_module_image("some-device", "sensor", "sensor:1.1"));
+        assert_send(
+            twin_manager.check_device_twin_patch("some-device", &DesiredTwinBuilder::new().build()),
+        );
+        assert_send(twin_manager.wait_for_reported(
+            "some-device",
+            "/firmware/version",
+            serde_json::json!("2.1.0"),
+            std::time::Duration::from_secs(60),
+        ));
+        assert_send(twin_manager.set_module_desired_properties(
+            "some-device",
+            "some-module",
+            serde_json::json!({}),
+        ));
+
+        let query = iothub.build_query().select("*").from("devices").build()?;
+        assert_send(query.execute_typed::<crate::twin::DeviceTwin>());
+        let query = iothub.build_query().select("*").from("devices").build()?;
+        assert_send(query.execute_to_writer(Vec::new(), crate::query::Format::Csv));
+        assert_send(iothub.build_query().select("*").from("devices").execute());
+
+        let registry_manager = iothub.registry_manager();
+        assert_send(registry_manager.get_module_identities("some-device"));
+        assert_send(registry_manager.get_device_overview("some-device"));
+        assert_send(registry_manager.create_device_identity(&device_identity));
+        assert_send(registry_manager.provision_from_dir("devices", 10));
+        assert_send(registry_manager.list_devices_with_twins("", |_| true, 10));
+        assert_send(registry_manager.update_device_identity(&device_identity, IfMatch::Any));
+        assert_send(registry_manager.delete_device_identity("some-device", IfMatch::Any));
+        assert_send(registry_manager.list_devices_by_status(Status::Enabled));
+        assert_send(registry_manager.set_parent("some-device", "some-parent"));
+        assert_send(registry_manager.disable_devices_matching(
+            "tags.batch = 'B42'",
+            "Recalled",
+            10,
+        ));
+
+        let configuration_manager = iothub.configuration_manager();
+        assert_send(configuration_manager.get_configurations(10));
+        assert_send(configuration_manager.delete_configuration("some-configuration"));
+
+        let orchestrator = crate::configuration::RolloutOrchestratorBuilder::new()
+            .target_condition("*")
+            .build()?;
+        assert_send(orchestrator.run(&iothub, &modules_content));
+
+        let messaging_manager = iothub.messaging_manager();
+        assert_send(messaging_manager.wait_for_feedback(Duration::from_secs(60)));
+
+        let direct_method = iothub.create_device_method_with_defaults("some-device", "Ping")?;
+        assert_send(direct_method.invoke_raw(serde_json::json!({})));
+        assert_send(direct_method.invoke_without_waiting(serde_json::json!({})));
+        assert_send(
+            iothub
+                .build_query()
+                .select("*")
+                .from("devices")
+                .build()?
+                .execute(),
+        );
+        assert_send(iothub.build_query().from("devices").count());
+        assert_send(iothub.check_connection());
+        assert_send(iothub.raw_request(http::Method::GET, "/devices", &[], None));
+
+        assert_send(
+            iothub.invoke_method_on_all_modules::<_, _, serde_json::Value>(
+                "some-device",
+                "Ping",
+                serde_json::json!({}),
+                30,
+                0,
+            ),
+        );
+        assert_send(iothub.apply_modules_configuration("some-device", &modules_content));
+        assert_send(iothub.apply_modules_configuration_and_wait(
+            "some-device",
+            &modules_content,
+            Duration::from_secs(60),
+        ));
+        assert_send(iothub.apply_modules_configuration_to_target(
+            "tags.environment = 'test'",
+            &modules_content,
+            4,
+            |_| {},
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+//! The messaging module is used for receiving the feedback of the devices on
+//! cloud-to-device messages.
+//!
+//! The service REST API of the IoT Hub has no operation for sending cloud-to-device
+//! messages, which are only accepted over AMQP. This crate does not support AMQP, so
+//! it cannot send messages or set their `ExpiryTimeUtc`, `MessageId` or `Ack`
+//! properties. Send the messages with another client, with `Ack` set to `full` for
+//! feedback on every message. Their feedback is available over HTTP and is received
+//! here.
+use std::time::{Duration, Instant};
+
+use http::{Method, Request, StatusCode};
+use serde::Deserialize;
+
+use crate::delay::delay_for;
+use crate::error::IoTHubError;
+use crate::response::{Response, ResponseMetadata};
+use crate::IoTHubService;
+
+/// The interval at which the feedback queue is polled while waiting for feedback
+pub const FEEDBACK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The outcome of a cloud-to-device message as reported in its feedback
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedbackStatus {
+    Success,
+    Expired,
+    DeliveryCountExceeded,
+    Rejected,
+    Purged,
+    #[serde(other)]
+    Unknown,
+}
+
+/// The feedback on a single cloud-to-device message
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedbackRecord {
+    pub original_message_id: String,
+    pub device_id: String,
+    #[serde(default)]
+    pub device_generation_id: String,
+    pub enqueued_time_utc: String,
+    pub status_code: FeedbackStatus,
+    #[serde(default)]
+    pub description: String,
+}
+
+impl FeedbackRecord {
+    /// Check whether the message was completed by the device
+    pub fn is_success(&self) -> bool {
+        self.status_code == FeedbackStatus::Success
+    }
+}
+
+/// A batch of feedback records received from the feedback queue
+///
+/// The batch must be completed with [`MessagingManager::complete_feedback`],
+/// otherwise it is delivered again after its lock expires.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedbackBatch {
+    lock_token: String,
+    records: Vec<FeedbackRecord>,
+}
+
+impl FeedbackBatch {
+    /// Get the lock token of the batch
+    pub fn lock_token(&self) -> &String {
+        &self.lock_token
+    }
+
+    /// Get the feedback records in the batch
+    pub fn records(&self) -> &Vec<FeedbackRecord> {
+        &self.records
+    }
+}
+
+/// The MessagingManager is used for receiving the feedback on cloud-to-device messages
+pub struct MessagingManager {
+    iothub_service: IoTHubService,
+}
+
+impl MessagingManager {
+    /// Create a new MessagingManager
+    pub fn new(iothub_service: &IoTHubService) -> Self {
+        MessagingManager {
+            iothub_service: iothub_service.clone(),
+        }
+    }
+
+    /// Receive the next batch of feedback records, if there is any
+    pub async fn receive_feedback(
+        &self,
+    ) -> Result<Response<Option<FeedbackBatch>>, Box<dyn std::error::Error + Send + Sync>> {
+        let uri = format!(
+            "https://{}/messages/serviceBound/feedback?api-version={}",
+            self.iothub_service.hostname(),
+            self.iothub_service.api_version()
+        );
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::GET)
+            .header("Authorization", self.iothub_service.authorization())
+            .body(Vec::new())?;
+
+        let response = self
+            .iothub_service
+            .send("receive_feedback", None, request)
+            .await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
+        }
+
+        let metadata = ResponseMetadata::from_response(&response);
+        if response.status() == StatusCode::NO_CONTENT || response.body().is_empty() {
+            return Ok(Response::new(metadata, None));
+        }
+
+        let lock_token = response
+            .headers()
+            .get("ETag")
+            .and_then(|val| val.to_str().ok())
+            .unwrap_or_default()
+            .trim_matches('"')
+            .to_string();
+        let records = serde_json::from_slice(response.body())?;
+        Ok(Response::new(
+            metadata,
+            Some(FeedbackBatch {
+                lock_token,
+                records,
+            }),
+        ))
+    }
+
+    /// Complete a batch of feedback records, removing it from the feedback queue
+    pub async fn complete_feedback(
+        &self,
+        batch: &FeedbackBatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>> {
+        let uri = format!(
+            "https://{}/messages/serviceBound/feedback/{}?api-version={}",
+            self.iothub_service.hostname(),
+            batch.lock_token,
+            self.iothub_service.api_version()
+        );
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::DELETE)
+            .header("Authorization", self.iothub_service.authorization())
+            .body(Vec::new())?;
+
+        let response = self
+            .iothub_service
+            .send("complete_feedback", None, request)
+            .await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
+        }
+
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            (),
+        ))
+    }
+
+    /// Abandon a batch of feedback records, so it is delivered again
+    pub async fn abandon_feedback(
+        &self,
+        batch: &FeedbackBatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>> {
+        let uri = format!(
+            "https://{}/messages/serviceBound/feedback/{}/abandon?api-version={}",
+            self.iothub_service.hostname(),
+            batch.lock_token,
+            self.iothub_service.api_version()
+        );
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::POST)
+            .header("Authorization", self.iothub_service.authorization())
+            .body(Vec::new())?;
+
+        let response = self
+            .iothub_service
+            .send("abandon_feedback", None, request)
+            .await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
+        }
+
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            (),
+        ))
+    }
+
+    /// Wait for the next batch of feedback records
+    ///
+    /// The feedback queue is polled until a batch is received or the timeout elapses, in
+    /// which case an error of kind `TimedOut` is returned. The batch is returned as it is
+    /// received, so it must be completed or abandoned by the caller.
+    ///
+    /// The feedback queue is shared by every consumer of the IoT Hub and delivers the
+    /// batches in order, so an abandoned batch is delivered again before the batches after
+    /// it. A batch may also hold the records of many messages, which are all removed when
+    /// the batch is completed. Handle every record of the batch before completing it.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use std::time::Duration;
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let messaging_manager = iothub.messaging_manager();
+    /// let batch = messaging_manager
+    ///     .wait_for_feedback(Duration::from_secs(60))
+    ///     .await?
+    ///     .into_inner();
+    /// for record in batch.records() {
+    ///     println!("{} reported {:?}", record.device_id, record.status_code);
+    /// }
+    /// messaging_manager.complete_feedback(&batch).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn wait_for_feedback(
+        &self,
+        timeout: Duration,
+    ) -> Result<Response<FeedbackBatch>, Box<dyn std::error::Error + Send + Sync>> {
+        let started = Instant::now();
+        loop {
+            let (metadata, batch) = self.receive_feedback().await?.into_parts();
+            if let Some(batch) = batch {
+                return Ok(Response::new(metadata, batch));
+            }
+
+            let elapsed = started.elapsed();
+            if elapsed >= timeout {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "No feedback received",
+                )));
+            }
+            delay_for(FEEDBACK_POLL_INTERVAL.min(timeout - elapsed)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use crate::messaging::FeedbackStatus;
+    use crate::testing::mocktransport::MockTransport;
+    use crate::IoTHubService;
+
+    /// Create a transport with a feedback queue that holds the feedback of another message
+    /// first and the feedback of `command-42` second
+    ///
+    /// Like the feedback queue of the IoT Hub, an abandoned batch goes back to the front
+    /// of the queue and a completed batch is removed from it.
+    fn feedback_transport() -> MockTransport {
+        let queue = Mutex::new(VecDeque::from(vec![
+            (
+                "lock-1",
+                r#"[{"originalMessageId":"other","deviceId":"other-device","enqueuedTimeUtc":"2020-06-01T12:00:00Z","statusCode":"Expired"}]"#,
+            ),
+            (
+                "lock-2",
+                r#"[{"originalMessageId":"command-42","deviceId":"some-device","deviceGenerationId":"1","enqueuedTimeUtc":"2020-06-01T12:00:01Z","statusCode":"Success","description":"Success"}]"#,
+            ),
+        ]));
+        let locked = Mutex::new(Vec::new());
+        MockTransport::new(move |request, _| {
+            let mut queue = queue.lock().unwrap();
+            let mut locked = locked.lock().unwrap();
+            let path = request.uri().path();
+            if request.method() == http::Method::GET {
+                return match queue.pop_front() {
+                    Some((lock_token, records)) => {
+                        locked.push((lock_token, records));
+                        Ok(http::Response::builder()
+                            .header("ETag", format!("\"{}\"", lock_token))
+                            .body(records.as_bytes().to_vec())?)
+                    }
+                    None => Ok(http::Response::builder().status(204).body(Vec::new())?),
+                };
+            }
+
+            let index = locked
+                .iter()
+                .position(|(lock_token, _)| path.contains(lock_token))
+                .unwrap();
+            let batch = locked.remove(index);
+            if path.ends_with("/abandon") {
+                queue.push_front(batch);
+            }
+            Ok(http::Response::builder().status(204).body(Vec::new())?)
+        })
+    }
+
+    #[test]
+    fn wait_for_feedback_should_return_redelivered_batches_first(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let transport = feedback_transport();
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let messaging_manager = iothub.messaging_manager();
+        let wait = || {
+            futures::executor::block_on(
+                messaging_manager.wait_for_feedback(Duration::from_secs(10)),
+            )
+            .map(|batch| batch.into_inner())
+        };
+
+        let batch = wait()?;
+        assert_eq!(batch.lock_token(), "lock-1");
+        assert_eq!(batch.records()[0].status_code, FeedbackStatus::Expired);
+        futures::executor::block_on(messaging_manager.abandon_feedback(&batch))?;
+
+        let batch = wait()?;
+        assert_eq!(batch.lock_token(), "lock-1");
+        futures::executor::block_on(messaging_manager.complete_feedback(&batch))?;
+
+        let batch = wait()?;
+        assert_eq!(batch.lock_token(), "lock-2");
+        let record = &batch.records()[0];
+        assert!(record.is_success());
+        assert_eq!(record.original_message_id, "command-42");
+        assert_eq!(record.device_id, "some-device");
+
+        // The batches are only settled by the caller
+        let settled: Vec<(http::Method, String)> = transport
+            .requests()
+            .iter()
+            .filter(|request| request.method() != http::Method::GET)
+            .map(|request| (request.method().clone(), request.uri().path().to_string()))
+            .collect();
+        assert_eq!(
+            settled,
+            vec![
+                (
+                    http::Method::POST,
+                    "/messages/serviceBound/feedback/lock-1/abandon".to_string()
+                ),
+                (
+                    http::Method::DELETE,
+                    "/messages/serviceBound/feedback/lock-1".to_string()
+                )
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_feedback_should_time_out_on_an_empty_queue() {
+        let transport =
+            MockTransport::new(|_, _| Ok(http::Response::builder().status(204).body(Vec::new())?));
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let result = futures::executor::block_on(
+            iothub
+                .messaging_manager()
+                .wait_for_feedback(Duration::from_millis(50)),
+        );
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<std::io::Error>().map(|err| err.kind()),
+            Some(std::io::ErrorKind::TimedOut)
+        );
+        assert!(transport
+            .requests()
+            .iter()
+            .all(|request| request.method() == http::Method::GET));
+    }
+}
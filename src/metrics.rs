@@ -0,0 +1,208 @@
+//! The metrics module is used for collecting counters about the requests that
+//! are sent to the IoT Hub, so operators can alert on the health of the interaction
+//! with the IoT Hub.
+//!
+//! Every request sent through an IoTHubService is reported to its [`Metrics`],
+//! which can be set with [`IoTHubService::with_metrics`](crate::IoTHubService::with_metrics).
+//! The [`CountingMetrics`] keep in-memory counters and a latency histogram, other
+//! implementations can forward the measurements to a metrics system.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The upper bounds in milliseconds of the buckets of the latency histogram
+///
+/// The histogram has one more bucket for the requests that took longer than the last bound.
+pub const LATENCY_BUCKETS_MS: [u64; 10] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// The Metrics trait receives a measurement for every request to the IoT Hub
+///
+/// The operation is the name of the method that sent the request, such as
+/// `get_device_twin`. All methods have an empty default implementation.
+pub trait Metrics: Send + Sync {
+    /// Called before a request is sent
+    fn request_sent(&self, _operation: &'static str) {}
+
+    /// Called when a response is received, with the time it took to receive it
+    fn response_received(&self, _operation: &'static str, _status_code: u16, _latency: Duration) {}
+
+    /// Called when a request failed, either without a response or with an unsuccessful status
+    fn request_failed(&self, _operation: &'static str) {}
+
+    /// Called when a request was throttled by the IoT Hub, in addition to `request_failed`
+    fn request_throttled(&self, _operation: &'static str) {}
+
+    /// Called when an operation is retried, such as updating a twin that changed in between
+    fn request_retried(&self, _operation: &'static str) {}
+}
+
+impl<T: Metrics + ?Sized> Metrics for Arc<T> {
+    fn request_sent(&self, operation: &'static str) {
+        (**self).request_sent(operation)
+    }
+
+    fn response_received(&self, operation: &'static str, status_code: u16, latency: Duration) {
+        (**self).response_received(operation, status_code, latency)
+    }
+
+    fn request_failed(&self, operation: &'static str) {
+        (**self).request_failed(operation)
+    }
+
+    fn request_throttled(&self, operation: &'static str) {
+        (**self).request_throttled(operation)
+    }
+
+    fn request_retried(&self, operation: &'static str) {
+        (**self).request_retried(operation)
+    }
+}
+
+/// The metrics used when no metrics are set, which ignore every measurement
+pub(crate) struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// Metrics that count the requests to the IoT Hub in memory
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+///
+/// use azure_iothub_service::metrics::CountingMetrics;
+/// use azure_iothub_service::IoTHubService;
+///
+/// let metrics = Arc::new(CountingMetrics::new());
+/// let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>")
+///     .with_metrics(metrics.clone());
+///
+/// let snapshot = metrics.snapshot();
+/// println!("{} of {} requests failed", snapshot.failures, snapshot.requests_sent);
+/// ```
+#[derive(Debug, Default)]
+pub struct CountingMetrics {
+    requests_sent: AtomicU64,
+    retries: AtomicU64,
+    throttles: AtomicU64,
+    failures: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+/// The values of CountingMetrics at a point in time
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MetricsSnapshot {
+    pub requests_sent: u64,
+    pub retries: u64,
+    pub throttles: u64,
+    pub failures: u64,
+    /// The number of responses per bucket of [`LATENCY_BUCKETS_MS`], followed by the
+    /// number of responses that took longer than the last bucket
+    pub latency_buckets: Vec<u64>,
+}
+
+impl CountingMetrics {
+    /// Create CountingMetrics with all counters at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the current values of the counters
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            requests_sent: self.requests_sent.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            throttles: self.throttles.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            latency_buckets: self
+                .latency_buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+impl Metrics for CountingMetrics {
+    fn request_sent(&self, _operation: &'static str) {
+        self.requests_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn response_received(&self, _operation: &'static str, _status_code: u16, latency: Duration) {
+        let latency_ms = latency.as_millis();
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| latency_ms <= u128::from(*bound))
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn request_failed(&self, _operation: &'static str) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn request_throttled(&self, _operation: &'static str) {
+        self.throttles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn request_retried(&self, _operation: &'static str) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::metrics::{CountingMetrics, Metrics};
+    use crate::testing::mocktransport::{error_response, json_response, MockTransport};
+    use crate::IoTHubService;
+
+    #[test]
+    fn counting_metrics_should_count_requests() {
+        // The transport answers with a twin, then throttles, then fails without a response
+        let transport = MockTransport::new(|_, earlier| match earlier.len() {
+            0 => json_response(&serde_json::json!({
+                "deviceId": "some-device",
+                "etag": "AAAAAAAAAAE=",
+                "status": "enabled",
+                "connectionState": "Connected"
+            })),
+            1 => error_response(429, "ThrottlingException", "Too many requests"),
+            _ => Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "connection reset",
+            ))),
+        });
+        let metrics = Arc::new(CountingMetrics::new());
+        let iothub = IoTHubService::from_sas_token("test", "test")
+            .with_transport(transport)
+            .with_metrics(metrics.clone());
+
+        for _ in 0..3 {
+            let _ =
+                futures::executor::block_on(iothub.twin_manager().get_device_twin("some-device"));
+        }
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.requests_sent, 3);
+        assert_eq!(snapshot.failures, 2);
+        assert_eq!(snapshot.throttles, 1);
+        assert_eq!(snapshot.retries, 0);
+        assert_eq!(snapshot.latency_buckets.iter().sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn counting_metrics_should_bucket_latencies() {
+        let metrics = CountingMetrics::new();
+        metrics.response_received("get_device_twin", 200, Duration::from_millis(10));
+        metrics.response_received("get_device_twin", 200, Duration::from_millis(11));
+        metrics.response_received("get_device_twin", 200, Duration::from_secs(60));
+
+        let latency_buckets = metrics.snapshot().latency_buckets;
+        assert_eq!(latency_buckets.len(), 11);
+        assert_eq!(latency_buckets[0], 1);
+        assert_eq!(latency_buckets[1], 1);
+        assert_eq!(latency_buckets[10], 1);
+    }
+}
@@ -0,0 +1,133 @@
+//! The proxy module contains the settings for sending all requests
+//! to the IoT Hub through an HTTP or HTTPS proxy.
+use base64::encode;
+use hyper::header::{HeaderValue, PROXY_AUTHORIZATION};
+use hyper::Uri;
+use hyper_proxy::{Intercept, Proxy};
+use std::fmt;
+
+use crate::redact::{optional_secret, RevealSecrets};
+
+/// The ProxySettings struct contains the address of the proxy
+/// and the optional basic authentication credentials.
+///
+/// The password is redacted in the Debug output.
+#[derive(Clone)]
+pub struct ProxySettings {
+    uri: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl RevealSecrets for ProxySettings {
+    fn fmt_secrets(&self, f: &mut fmt::Formatter<'_>, reveal: bool) -> fmt::Result {
+        f.debug_struct("ProxySettings")
+            .field("uri", &self.uri)
+            .field("username", &self.username)
+            .field("password", &optional_secret(&self.password, reveal))
+            .finish()
+    }
+}
+
+impl fmt::Debug for ProxySettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_secrets(f, false)
+    }
+}
+
+impl ProxySettings {
+    /// Create new ProxySettings for the given proxy address
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::proxy::ProxySettings;
+    ///
+    /// let proxy = ProxySettings::new("http://proxy.local:8080");
+    /// ```
+    pub fn new<S>(uri: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            uri: uri.into(),
+            username: None,
+            password: None,
+        }
+    }
+
+    /// Set the basic authentication credentials for the proxy
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::proxy::ProxySettings;
+    ///
+    /// let proxy = ProxySettings::new("http://proxy.local:8080")
+    ///     .basic_auth("username", "password");
+    /// ```
+    pub fn basic_auth<S, T>(mut self, username: S, password: T) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.username = Some(username.into());
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Get the address of the proxy
+    pub fn uri(&self) -> &String {
+        &self.uri
+    }
+
+    /// Get the username used for authenticating with the proxy
+    pub fn username(&self) -> &Option<String> {
+        &self.username
+    }
+
+    /// Convert the settings into a proxy that can be used by the connector
+    pub(crate) fn to_proxy(&self) -> Result<Proxy, Box<dyn std::error::Error + Send + Sync>> {
+        let uri: Uri = self.uri.parse()?;
+        let mut proxy = Proxy::new(Intercept::All, uri);
+
+        if let Some(username) = &self.username {
+            let credentials = format!(
+                "{}:{}",
+                username,
+                self.password.as_deref().unwrap_or_default()
+            );
+            proxy.set_header(
+                PROXY_AUTHORIZATION,
+                HeaderValue::from_str(&format!("Basic {}", encode(&credentials)))?,
+            );
+        }
+
+        Ok(proxy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::ProxySettings;
+
+    #[test]
+    fn proxy_settings_should_set_authorization_header(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let proxy = ProxySettings::new("http://proxy.local:8080")
+            .basic_auth("username", "password")
+            .to_proxy()?;
+
+        assert_eq!(proxy.uri(), "http://proxy.local:8080/");
+        assert_eq!(
+            proxy.headers().get(hyper::header::PROXY_AUTHORIZATION),
+            Some(&hyper::header::HeaderValue::from_static(
+                "Basic dXNlcm5hbWU6cGFzc3dvcmQ="
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn proxy_settings_should_fail_on_invalid_uri() {
+        assert!(ProxySettings::new("not a uri").to_proxy().is_err());
+    }
+}
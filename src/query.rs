@@ -1,61 +1,640 @@
-use bytes::buf::BufExt as _;
-use hyper::{Body, Client, Method, Request};
-use hyper_tls::HttpsConnector;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Write;
+use std::marker::PhantomData;
+
+use http::{Method, Request};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, Deserializer, Visitor};
+use serde::Deserialize;
 use serde_json::json;
 
-use crate::error::{BuilderError, BuilderErrorType};
-use crate::{IoTHubService, API_VERSION};
+use crate::error::{BuilderError, BuilderErrorType, IoTHubError};
+use crate::response::{Response, ResponseMetadata, CONTINUATION_HEADER};
+use crate::transport::HttpResponse;
+use crate::twin::{DeviceTwin, ModuleTwin};
+use crate::IoTHubService;
+
+/// The item type of queries returning device or module twins
+const TWIN_ITEM_TYPE: &str = "twin";
+
+/// The item type of queries on the jobs of devices
+const DEVICE_JOB_ITEM_TYPE: &str = "deviceJob";
+
+/// The maximum length of a query the IoT Hub accepts
+pub const MAX_QUERY_LENGTH: usize = 8192;
+
+/// The header containing the maximum number of items in a page of query results
+const MAX_ITEM_COUNT_HEADER: &str = "x-ms-max-item-count";
+
+/// Quote a value as a string literal of the IoT Hub query language
+///
+/// Quotes and backslashes within the value are escaped, so the value
+/// cannot change the meaning of the query.
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::query::quote;
+///
+/// assert_eq!(quote("it's"), r"'it\'s'");
+/// ```
+pub fn quote<S>(value: S) -> String
+where
+    S: AsRef<str>,
+{
+    let mut quoted = String::with_capacity(value.as_ref().len() + 2);
+    quoted.push('\'');
+    for character in value.as_ref().chars() {
+        if matches!(character, '\'' | '"' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(character);
+    }
+    quoted.push('\'');
+    quoted
+}
 
-pub struct Query<'a> {
-    iothub_service: &'a IoTHubService,
+pub struct Query {
+    iothub_service: IoTHubService,
     query: String,
 }
 
-impl<'a> Query<'a> {
-    pub async fn execute(self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+impl Query {
+    pub async fn execute(
+        self,
+    ) -> Result<Response<serde_json::Value>, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.send().await?;
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            serde_json::from_slice(response.body())?,
+        ))
+    }
+
+    /// Execute the query and pass the returned items to a closure one at a time
+    ///
+    /// The items are deserialized from the body of the response one at a time, without
+    /// building a JSON value of the page, so the deserialized items of a large page are
+    /// never held in memory together. The number of items is returned.
+    ///
+    /// The response is not streamed: the [`Transport`](crate::transport::Transport)
+    /// returns the whole body of the page, which is held in memory until every item
+    /// is visited. The peak memory use is therefore still at least the size of the page.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::twin::{ConnectionState, DeviceTwin};
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let mut disconnected = 0;
+    /// iothub
+    ///     .build_query()
+    ///     .select("*")
+    ///     .from("devices")
+    ///     .build()?
+    ///     .execute_for_each(|device_twin: DeviceTwin| {
+    ///         if matches!(device_twin.connection_state, ConnectionState::Disconnected) {
+    ///             disconnected += 1;
+    ///         }
+    ///     })
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn execute_for_each<T, F>(
+        self,
+        mut visit: F,
+    ) -> Result<Response<usize>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: DeserializeOwned,
+        F: FnMut(T),
+    {
+        let response = self.send().await?;
+        let mut deserializer = serde_json::Deserializer::from_slice(response.body());
+        let item_count = ForEachItem {
+            visit: &mut visit,
+            item: PhantomData,
+        }
+        .deserialize(&mut deserializer)?;
+        deserializer.end()?;
+
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            item_count,
+        ))
+    }
+
+    /// Execute the query and deserialize the returned items into the given type
+    ///
+    /// Unlike `execute`, the items are deserialized from the body of the response
+    /// without first building a JSON value of the whole page. The body itself is
+    /// still read completely before the items are deserialized.
+    pub async fn execute_typed<T>(
+        self,
+    ) -> Result<Response<Vec<T>>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: DeserializeOwned,
+    {
+        let mut items = Vec::new();
+        let (metadata, _) = self
+            .execute_for_each(|item| items.push(item))
+            .await?
+            .into_parts();
+        Ok(Response::new(metadata, items))
+    }
+
+    /// Execute the query and write all pages of items to a writer
+    ///
+    /// With [`Format::Ndjson`] every item is written as JSON on its own line. With
+    /// [`Format::Csv`] nested properties are flattened into columns such as
+    /// `properties.reported.firmware`, and the columns are the properties of the
+    /// items of the first page. Only one page of items is held in memory at a time.
+    /// The number of written items is returned.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::query::Format;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let file = std::fs::File::create("devices.csv")?;
+    /// iothub
+    ///     .build_query()
+    ///     .select("deviceId, status, tags.site")
+    ///     .from("devices")
+    ///     .build()?
+    ///     .execute_to_writer(std::io::BufWriter::new(file), Format::Csv)
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn execute_to_writer<W>(
+        self,
+        mut writer: W,
+        format: Format,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>>
+    where
+        W: Write + Send,
+    {
+        let mut columns: Option<Vec<String>> = None;
+        let mut continuation = None;
+        let mut item_count = 0;
+        loop {
+            let response = self.send_page(continuation.as_deref()).await?;
+            let mut items = Vec::new();
+            let mut deserializer = serde_json::Deserializer::from_slice(response.body());
+            ForEachItem {
+                visit: &mut |item: serde_json::Value| items.push(item),
+                item: PhantomData,
+            }
+            .deserialize(&mut deserializer)?;
+            deserializer.end()?;
+
+            for item in &items {
+                match format {
+                    Format::Ndjson => {
+                        serde_json::to_writer(&mut writer, item)?;
+                        writer.write_all(b"\n")?;
+                    }
+                    Format::Csv => {
+                        let mut fields = BTreeMap::new();
+                        flatten("", item, &mut fields);
+                        let columns = match &columns {
+                            Some(columns) => columns,
+                            None => {
+                                let header = csv_columns(&items);
+                                write_csv_record(&mut writer, header.iter().map(String::as_str))?;
+                                columns.get_or_insert(header)
+                            }
+                        };
+                        write_csv_record(
+                            &mut writer,
+                            columns
+                                .iter()
+                                .map(|column| fields.get(column).map_or("", String::as_str)),
+                        )?;
+                    }
+                }
+                item_count += 1;
+            }
+
+            continuation = ResponseMetadata::from_response(&response)
+                .continuation()
+                .clone();
+            if continuation.is_none() {
+                break;
+            }
+        }
+        writer.flush()?;
+        Ok(item_count)
+    }
+
+    /// Execute the query and deserialize the items of all pages into the given type
+    ///
+    /// The metadata is the metadata of the last page.
+    pub(crate) async fn execute_all_pages<T>(
+        self,
+    ) -> Result<Response<Vec<T>>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: DeserializeOwned,
+    {
+        let mut items = Vec::new();
+        let mut continuation = None;
+        loop {
+            let response = self.send_page(continuation.as_deref()).await?;
+            let mut deserializer = serde_json::Deserializer::from_slice(response.body());
+            ForEachItem {
+                visit: &mut |item: T| items.push(item),
+                item: PhantomData,
+            }
+            .deserialize(&mut deserializer)?;
+            deserializer.end()?;
+
+            let metadata = ResponseMetadata::from_response(&response);
+            continuation = metadata.continuation().clone();
+            if continuation.is_none() {
+                return Ok(Response::new(metadata, items));
+            }
+        }
+    }
+
+    /// Send the query to the IoT Hub
+    async fn send(&self) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+        self.send_page(None).await
+    }
+
+    /// Send the query to the IoT Hub, continuing after an earlier page if a continuation token is given
+    async fn send_page(
+        &self,
+        continuation: Option<&str>,
+    ) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
         let uri = format!(
-            "https://{}.azure-devices.net/devices/query?api-version={}",
-            self.iothub_service.iothub_name, API_VERSION
+            "https://{}/devices/query?api-version={}",
+            self.iothub_service.hostname(),
+            self.iothub_service.api_version()
         );
 
         let json_payload = json!({
             "query": self.query,
         });
 
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, hyper::Body>(https);
-        let request = Request::builder()
+        let mut request = Request::builder()
             .uri(uri)
             .method(Method::POST)
-            .header("Authorization", &self.iothub_service.sas_token)
-            .header("Content-Type", "application/json")
-            .body(Body::from(serde_json::to_string(&json_payload)?))?;
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json");
+        if let Some(continuation) = continuation {
+            request = request.header(CONTINUATION_HEADER, continuation);
+        }
+        if let Some(limits) = self.iothub_service.limits() {
+            request = request.header(MAX_ITEM_COUNT_HEADER, limits.query_page_size());
+        }
+        let request = request.body(serde_json::to_vec(&json_payload)?)?;
+
+        let response = self
+            .iothub_service
+            .send_compressible("query", None, request)
+            .await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
+        }
+        Ok(response)
+    }
+}
+
+/// The format in which query results are written by [`Query::execute_to_writer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Newline delimited JSON, with one item per line
+    Ndjson,
+    /// Comma separated values, with a header row and nested properties flattened into columns
+    Csv,
+}
+
+/// Flatten the nested objects of an item into fields named by their dotted path
+///
+/// Arrays are kept as JSON and `null` becomes an empty field.
+fn flatten(path: &str, value: &serde_json::Value, fields: &mut BTreeMap<String, String>) {
+    let field = match value {
+        serde_json::Value::Object(object) if !object.is_empty() || path.is_empty() => {
+            for (name, value) in object {
+                let path = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                flatten(&path, value, fields);
+            }
+            return;
+        }
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(value) => value.clone(),
+        value => value.to_string(),
+    };
+    let path = if path.is_empty() { "value" } else { path };
+    fields.insert(path.to_string(), field);
+}
+
+/// Get the CSV columns of a page of items, which are the flattened fields of all items
+fn csv_columns(items: &[serde_json::Value]) -> Vec<String> {
+    let mut columns = BTreeSet::new();
+    for item in items {
+        let mut fields = BTreeMap::new();
+        flatten("", item, &mut fields);
+        columns.extend(fields.into_iter().map(|(column, _)| column));
+    }
+    columns.into_iter().collect()
+}
+
+/// Write a CSV record, quoting the fields that contain separators, quotes or line breaks
+fn write_csv_record<'a, W, I>(writer: &mut W, fields: I) -> std::io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a str>,
+{
+    for (index, field) in fields.into_iter().enumerate() {
+        if index > 0 {
+            writer.write_all(b",")?;
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            write!(writer, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            writer.write_all(field.as_bytes())?;
+        }
+    }
+    writer.write_all(b"\r\n")
+}
+
+/// Deserializes the items of a query result one at a time and passes them to a closure
+///
+/// A result of `null` contains no items, and a result that is an object is a single item.
+struct ForEachItem<'a, T, F> {
+    visit: &'a mut F,
+    item: PhantomData<fn() -> T>,
+}
+
+impl<'de, 'a, T, F> DeserializeSeed<'de> for ForEachItem<'a, T, F>
+where
+    T: Deserialize<'de>,
+    F: FnMut(T),
+{
+    type Value = usize;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_option(self)
+    }
+}
+
+impl<'de, 'a, T, F> Visitor<'de> for ForEachItem<'a, T, F>
+where
+    T: Deserialize<'de>,
+    F: FnMut(T),
+{
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of query items")
+    }
+
+    fn visit_none<E>(self) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(0)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(0)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let mut item_count = 0;
+        while let Some(item) = seq.next_element()? {
+            (self.visit)(item);
+            item_count += 1;
+        }
+        Ok(item_count)
+    }
 
-        let response = client.request(request).await?;
-        let body = hyper::body::aggregate(response).await?;
-        Ok(serde_json::from_reader(body.reader())?)
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        (self.visit)(T::deserialize(de::value::MapAccessDeserializer::new(map))?);
+        Ok(1)
+    }
+}
+
+impl Response<serde_json::Value> {
+    /// Get the number of items returned by a query
+    ///
+    /// The type of the items is available through the `item_type` of the metadata.
+    pub fn item_count(&self) -> usize {
+        match &**self {
+            serde_json::Value::Array(items) => items.len(),
+            serde_json::Value::Null => 0,
+            _ => 1,
+        }
+    }
+
+    /// Convert the items returned by a query into a QueryResult based on their item type
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::query::{QueryResult, TwinItem};
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let result = iothub
+    ///     .build_query()
+    ///     .select("*")
+    ///     .from("devices.modules")
+    ///     .build()?
+    ///     .execute()
+    ///     .await?
+    ///     .into_query_result()?;
+    ///
+    /// if let QueryResult::Twins(twins) = &*result {
+    ///     for twin in twins {
+    ///         if let TwinItem::Module(module_twin) = twin {
+    ///             println!("{}/{}", module_twin.device_id, module_twin.module_id);
+    ///         }
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_query_result(
+        self,
+    ) -> Result<Response<QueryResult>, Box<dyn std::error::Error + Send + Sync>> {
+        let (metadata, result) = self.into_parts();
+        let items = match result {
+            serde_json::Value::Array(items) => items,
+            serde_json::Value::Null => Vec::new(),
+            item => vec![item],
+        };
+
+        let query_result = match metadata.item_type().as_deref() {
+            Some(TWIN_ITEM_TYPE) => QueryResult::Twins(
+                items
+                    .into_iter()
+                    .map(TwinItem::from_value)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Some(DEVICE_JOB_ITEM_TYPE) => QueryResult::DeviceJobs(
+                items
+                    .into_iter()
+                    .map(serde_json::from_value)
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => QueryResult::Raw(items),
+        };
+        Ok(Response::new(metadata, query_result))
     }
 }
 
-pub struct QueryBuilder<'a> {
-    iothub_service: &'a IoTHubService,
+/// The items returned by a query, typed by the `x-ms-item-type` of the response
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryResult {
+    /// The twins of devices or modules, returned for `SELECT *` queries on twins
+    Twins(Vec<TwinItem>),
+    /// The jobs of devices, returned for queries on `devices.jobs`
+    DeviceJobs(Vec<DeviceJob>),
+    /// Any other items, such as projections and aggregations
+    Raw(Vec<serde_json::Value>),
+}
+
+/// A twin returned by a query, which is either the twin of a device or of a module
+#[derive(Debug, Clone, PartialEq)]
+pub enum TwinItem {
+    /// The twin of a device
+    Device(DeviceTwin),
+    /// The twin of a module
+    Module(ModuleTwin),
+}
+
+impl TwinItem {
+    /// Deserialize a twin, which is the twin of a module when it contains a module id
+    pub(crate) fn from_value(value: serde_json::Value) -> Result<Self, serde_json::Error> {
+        if value.get("moduleId").is_some() {
+            Ok(TwinItem::Module(serde_json::from_value(value)?))
+        } else {
+            Ok(TwinItem::Device(serde_json::from_value(value)?))
+        }
+    }
+
+    /// Get the id of the device the twin belongs to
+    pub fn device_id(&self) -> &String {
+        match self {
+            TwinItem::Device(device_twin) => &device_twin.device_id,
+            TwinItem::Module(module_twin) => &module_twin.device_id,
+        }
+    }
+}
+
+/// The job of a single device, as returned by queries on `devices.jobs`
+///
+/// Fields that are unknown to this crate are collected in `extra`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceJob {
+    pub device_id: String,
+    pub job_id: String,
+    #[serde(default)]
+    pub job_type: String,
+    #[serde(default)]
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time_utc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time_utc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_date_time_utc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_updated_date_time_utc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+    /// The fields of the job that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The QueryBuilder builds queries for the twins and jobs of an IoT Hub
+///
+/// A builder without an IoT Hub, created with [`QueryBuilder::default`], can be used as a
+/// template that is cloned and bound to an IoT Hub with [`QueryBuilder::iothub_service`].
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::query::QueryBuilder;
+/// use azure_iothub_service::IoTHubService;
+///
+/// let template = QueryBuilder::default()
+///     .select("deviceId")
+///     .from("devices")
+///     .and_where("status = 'enabled'");
+/// let hubs = vec![
+///     IoTHubService::from_sas_token("north-iot-hub", "<a generated sas token>"),
+///     IoTHubService::from_sas_token("south-iot-hub", "<a generated sas token>"),
+/// ];
+/// let queries = hubs
+///     .iter()
+///     .map(|iothub| template.clone().iothub_service(iothub).build())
+///     .collect::<Result<Vec<_>, _>>()
+///     .expect("Failed to build the queries");
+/// assert!(template.build().is_err());
+/// ```
+#[derive(Clone, Default)]
+pub struct QueryBuilder {
+    iothub_service: Option<IoTHubService>,
+    top: Option<u64>,
     select: Option<String>,
     from: Option<String>,
     and_where: Option<String>,
     group_by: Option<String>,
+    order_by: Option<String>,
+    raw: Vec<String>,
+    defined: Vec<String>,
+    where_in: Option<(String, Vec<String>)>,
 }
 
-impl<'a> QueryBuilder<'a> {
-    pub fn new(iothub_service: &'a IoTHubService) -> Self {
+impl QueryBuilder {
+    pub fn new(iothub_service: &IoTHubService) -> Self {
         QueryBuilder {
-            iothub_service,
-            select: None,
-            from: None,
-            and_where: None,
-            group_by: None,
+            iothub_service: Some(iothub_service.clone()),
+            ..Self::default()
         }
     }
 
+    /// Set the IoT Hub the query is sent to, which replaces the IoT Hub the builder was created with
+    pub fn iothub_service(mut self, iothub_service: &IoTHubService) -> Self {
+        self.iothub_service = Some(iothub_service.clone());
+        self
+    }
+
     pub fn select<T>(mut self, select_query: T) -> Self
     where
         T: Into<String>,
@@ -88,55 +667,472 @@ impl<'a> QueryBuilder<'a> {
         self
     }
 
-    pub fn build(self) -> Result<Query<'a>, BuilderError> {
-        let mut query: String = "".to_string();
+    /// Only select the items where a property, such as `tags.site`, is defined
+    ///
+    /// The condition is combined with the other conditions of the query with `AND`.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>");
+    /// let query = iothub
+    ///     .build_query()
+    ///     .select("deviceId")
+    ///     .from("devices")
+    ///     .where_defined("tags.site")
+    ///     .build()
+    ///     .expect("Failed to build the query");
+    /// ```
+    pub fn where_defined<T>(mut self, property: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.defined.push(property.into());
+        self
+    }
+
+    /// Only select the items where a property, such as `deviceId`, equals one of the values
+    ///
+    /// The values are quoted as strings and the condition is combined with the other
+    /// conditions of the query with `AND`. Long lists of values can exceed the maximum
+    /// length of a query, use [`QueryBuilder::build_chunked`] to split them over multiple queries.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>");
+    /// let query = iothub
+    ///     .build_query()
+    ///     .select("*")
+    ///     .from("devices")
+    ///     .where_in("deviceId", vec!["some-device", "other-device"])
+    ///     .build()
+    ///     .expect("Failed to build the query");
+    /// ```
+    pub fn where_in<T, I>(mut self, property: T, values: I) -> Self
+    where
+        T: Into<String>,
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        self.where_in = Some((property.into(), values.into_iter().map(quote).collect()));
+        self
+    }
+
+    /// Order the results of the query, such as `lastActivityTime DESC`
+    pub fn order_by<T>(mut self, order_by_query: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.order_by = Some(order_by_query.into());
+        self
+    }
+
+    /// Limit the query to the first `max_items` results with `SELECT TOP`
+    pub fn top(mut self, max_items: u64) -> Self {
+        self.top = Some(max_items);
+        self
+    }
+
+    /// Append a clause to the end of the query as is
+    ///
+    /// This can be used for parts of the query language the builder does not support.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>");
+    /// let query = iothub
+    ///     .build_query()
+    ///     .top(10)
+    ///     .select("deviceId")
+    ///     .from("devices")
+    ///     .raw("WHERE IS_DEFINED(tags.location)")
+    ///     .order_by("deviceId")
+    ///     .build()
+    ///     .expect("Failed to build the query");
+    /// ```
+    pub fn raw<T>(mut self, clause: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.raw.push(clause.into());
+        self
+    }
+
+    /// Build the query
+    ///
+    /// The query needs a `select` and a `from` clause, which must not be empty,
+    /// and the number of items of `top` must be at least one. Queries longer than
+    /// [`MAX_QUERY_LENGTH`] are rejected.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>");
+    /// assert!(iothub.build_query().select("*").from("devices").build().is_ok());
+    /// assert!(iothub.build_query().select("*").build().is_err());
+    /// assert!(iothub.build_query().select(" ").from("devices").build().is_err());
+    /// ```
+    pub fn build(self) -> Result<Query, BuilderError> {
+        let values = self.where_in.as_ref().map(|(_, values)| &values[..]);
+        let query = self.query_string(values)?;
+        if query.len() > MAX_QUERY_LENGTH {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                "where_in",
+            )));
+        }
+
+        Ok(Query {
+            iothub_service: self.iothub_service.ok_or_else(|| {
+                BuilderError::new(BuilderErrorType::MissingValue("iothub_service"))
+            })?,
+            query,
+        })
+    }
+
+    /// Build the query, split into multiple queries when the values of `where_in`
+    /// do not fit within the maximum length of a single query
+    ///
+    /// Each query selects the items for a part of the values, so `top` and `order_by`
+    /// apply to each query separately.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>");
+    /// let device_ids: Vec<String> = (0..2000).map(|i| format!("device-{}", i)).collect();
+    /// let queries = iothub
+    ///     .build_query()
+    ///     .select("*")
+    ///     .from("devices")
+    ///     .where_in("deviceId", &device_ids)
+    ///     .build_chunked()
+    ///     .expect("Failed to build the queries");
+    /// assert!(queries.len() > 1);
+    /// ```
+    pub fn build_chunked(self) -> Result<Vec<Query>, BuilderError> {
+        let values = match &self.where_in {
+            Some((_, values)) => values,
+            None => return Ok(vec![self.build()?]),
+        };
+        let iothub_service = self
+            .iothub_service
+            .as_ref()
+            .ok_or_else(|| BuilderError::new(BuilderErrorType::MissingValue("iothub_service")))?;
 
-        match self.select {
-            Some(select_query) => {
-                query = [query, "SELECT ".to_string(), select_query].concat();
+        let mut queries = Vec::new();
+        let mut start = 0;
+        while start < values.len() {
+            // Add values to the chunk as long as the query fits, each value
+            // adds its separator and itself to the length of the query
+            let mut query_length = self.query_string(Some(&values[start..=start]))?.len();
+            if query_length > MAX_QUERY_LENGTH {
+                return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                    "where_in",
+                )));
             }
-            None => return Err(BuilderError::new(BuilderErrorType::MissingValue("select"))),
+            let mut end = start + 1;
+            while end < values.len() && query_length + 2 + values[end].len() <= MAX_QUERY_LENGTH {
+                query_length += 2 + values[end].len();
+                end += 1;
+            }
+
+            let query = self.query_string(Some(&values[start..end]))?;
+            queries.push(Query {
+                iothub_service: iothub_service.clone(),
+                query,
+            });
+            start = end;
         }
+        Ok(queries)
+    }
+
+    /// Create the query, using the given quoted values for the `where_in` condition
+    fn query_string(&self, in_values: Option<&[String]>) -> Result<String, BuilderError> {
+        if self.top == Some(0) {
+            return Err(BuilderError::new(BuilderErrorType::IncorrectValue("top")));
+        }
+
+        let mut query = match &self.select {
+            Some(select_query) if select_query.trim().is_empty() => {
+                return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                    "select",
+                )))
+            }
+            Some(select_query) => match self.top {
+                Some(max_items) => format!("SELECT TOP {} {}", max_items, select_query),
+                None => format!("SELECT {}", select_query),
+            },
+            None => return Err(BuilderError::new(BuilderErrorType::MissingValue("select"))),
+        };
 
-        match self.from {
+        match &self.from {
+            Some(from_query) if from_query.trim().is_empty() => {
+                return Err(BuilderError::new(BuilderErrorType::IncorrectValue("from")))
+            }
             Some(from_query) => {
-                query = [query, " FROM ".to_string(), from_query].concat();
+                query = [query, " FROM ".to_string(), from_query.clone()].concat();
             }
             None => return Err(BuilderError::new(BuilderErrorType::MissingValue("from"))),
         }
 
-        match self.and_where {
-            Some(filter_query) => {
-                query = [query, " WHERE ".to_string(), filter_query].concat();
+        let mut conditions = Vec::new();
+        if let Some(filter_query) = &self.and_where {
+            conditions.push(filter_query.clone());
+        }
+        for property in &self.defined {
+            if !is_property_path(property) {
+                return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                    "where_defined",
+                )));
             }
-            None => {}
+            conditions.push(format!("IS_DEFINED({})", property));
         }
-
-        match self.group_by {
-            Some(group_by_query) => {
-                query = [query, " GROUP BY ".to_string(), group_by_query].concat();
+        if let (Some((property, _)), Some(values)) = (&self.where_in, in_values) {
+            if !is_property_path(property) || values.is_empty() {
+                return Err(BuilderError::new(BuilderErrorType::IncorrectValue(
+                    "where_in",
+                )));
+            }
+            conditions.push(format!("{} IN [{}]", property, values.join(", ")));
+        }
+        match conditions.len() {
+            0 => {}
+            1 => query = [query, " WHERE ".to_string(), conditions.remove(0)].concat(),
+            // The conditions are wrapped, so an OR within a condition keeps its meaning
+            _ => {
+                let conditions: Vec<String> = conditions
+                    .into_iter()
+                    .map(|condition| format!("({})", condition))
+                    .collect();
+                query = [query, " WHERE ".to_string(), conditions.join(" AND ")].concat();
             }
-            None => {}
         }
 
-        Ok(Query {
-            iothub_service: self.iothub_service,
-            query,
-        })
+        if let Some(group_by_query) = &self.group_by {
+            query = [query, " GROUP BY ".to_string(), group_by_query.clone()].concat();
+        }
+
+        for clause in &self.raw {
+            query = [query, " ".to_string(), clause.clone()].concat();
+        }
+
+        if let Some(order_by_query) = &self.order_by {
+            query = [query, " ORDER BY ".to_string(), order_by_query.clone()].concat();
+        }
+
+        Ok(query)
+    }
+
+    /// Build the query and execute it
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let edge_devices = iothub
+    ///     .build_query()
+    ///     .select("deviceId")
+    ///     .from("devices")
+    ///     .and_where("capabilities.iotEdge = true")
+    ///     .execute()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn execute(
+        self,
+    ) -> Result<Response<serde_json::Value>, Box<dyn std::error::Error + Send + Sync>> {
+        self.build()?.execute().await
+    }
+
+    /// Count the items matching the `from` and `where` clauses of the query
+    ///
+    /// The `select`, `top`, `group by` and `order by` clauses are replaced by `SELECT COUNT() AS total`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let connected_devices = iothub
+    ///     .build_query()
+    ///     .from("devices")
+    ///     .and_where("connectionState = 'Connected'")
+    ///     .count()
+    ///     .await?;
+    /// println!("{} devices are connected", *connected_devices);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn count(self) -> Result<Response<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        let query = QueryBuilder {
+            select: Some("COUNT() AS total".to_string()),
+            top: None,
+            group_by: None,
+            order_by: None,
+            ..self
+        }
+        .build()?;
+
+        let (metadata, result) = query.execute().await?.into_parts();
+        let total = result
+            .get(0)
+            .and_then(|item| item.get("total"))
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or_default();
+        Ok(Response::new(metadata, total))
     }
 }
 
+/// Check whether a property path, such as `tags.site`, consists of non-empty names
+fn is_property_path(path: &str) -> bool {
+    !path.is_empty()
+        && path.split('.').all(|name| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        })
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::testing::mocktransport::MockTransport;
     use crate::IoTHubService;
 
     #[test]
-    fn querybuilder_success() -> Result<(), Box<dyn std::error::Error>> {
-        use crate::query::QueryBuilder;
-        let iothub_service = IoTHubService {
-            iothub_name: "test".to_string(),
-            sas_token: "test".to_string(),
+    fn querybuilder_count_should_return_total(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let transport = MockTransport::new(|_, _| {
+            Ok(http::Response::builder()
+                .header("x-ms-item-type", "raw")
+                .body(br#"[{"total": 42}]"#.to_vec())?)
+        });
+        let iothub_service =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let count = futures::executor::block_on(
+            iothub_service
+                .build_query()
+                .select("*")
+                .from("devices")
+                .and_where("status = 'enabled'")
+                .count(),
+        )?;
+
+        assert_eq!(*count, 42);
+        assert_eq!(count.metadata().item_type().as_deref(), Some("raw"));
+
+        let count =
+            futures::executor::block_on(iothub_service.count_devices(" status = 'enabled' "))?;
+        assert_eq!(*count, 42);
+
+        for request in transport.requests().iter() {
+            let query: serde_json::Value = serde_json::from_slice(request.body())?;
+            assert_eq!(
+                query["query"],
+                "SELECT COUNT() AS total FROM devices WHERE status = 'enabled'"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn query_result_should_use_item_type() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::query::{QueryResult, TwinItem};
+        use crate::response::{Response, ResponseMetadata};
+
+        let query_result = |item_type: &str, items: serde_json::Value| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert("x-ms-item-type", item_type.parse().unwrap());
+            Response::new(ResponseMetadata::from_parts(200, &headers), items).into_query_result()
         };
+
+        let result = query_result(
+            "twin",
+            serde_json::json!([
+                { "deviceId": "some-device", "etag": "AAAAAAAAAAE=", "status": "enabled", "connectionState": "Connected" },
+                { "deviceId": "some-device", "moduleId": "some-module", "etag": "AAAAAAAAAAE=", "status": "enabled", "connectionState": "Disconnected" }
+            ]),
+        )?;
+        match &*result {
+            QueryResult::Twins(twins) => {
+                assert!(matches!(&twins[0], TwinItem::Device(_)));
+                assert!(
+                    matches!(&twins[1], TwinItem::Module(module_twin) if module_twin.module_id == "some-module")
+                );
+                assert_eq!(twins[1].device_id(), "some-device");
+            }
+            result => panic!("Expected twins, got {:?}", result),
+        }
+
+        let result = query_result(
+            "deviceJob",
+            serde_json::json!([{ "deviceId": "some-device", "jobId": "some-job", "status": "completed" }]),
+        )?;
+        match &*result {
+            QueryResult::DeviceJobs(jobs) => assert_eq!(jobs[0].status, "completed"),
+            result => panic!("Expected device jobs, got {:?}", result),
+        }
+
+        let result = query_result("raw", serde_json::json!([{ "total": 42 }]))?;
+        assert_eq!(
+            *result,
+            QueryResult::Raw(vec![serde_json::json!({ "total": 42 })])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn quote_should_escape_quotes_and_backslashes() {
+        use crate::query::quote;
+        assert_eq!(quote("some-device"), "'some-device'");
+        assert_eq!(quote(r#"a'b"c\d"#), r#"'a\'b\"c\\d'"#);
+    }
+
+    #[test]
+    fn querybuilder_template_should_need_an_iothub_service(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::query::QueryBuilder;
+        let template = QueryBuilder::default()
+            .select("*")
+            .from("devices")
+            .where_in("deviceId", vec!["some-device"]);
+        assert!(template.clone().build().is_err());
+        assert!(template.clone().build_chunked().is_err());
+
+        let iothub_service = IoTHubService::from_sas_token("test", "test");
+        let query = template
+            .clone()
+            .and_where("status = 'enabled'")
+            .iothub_service(&iothub_service)
+            .build()?;
+        assert_eq!(
+            query.query,
+            "SELECT * FROM devices WHERE (status = 'enabled') AND (deviceId IN ['some-device'])"
+        );
+        assert_eq!(
+            template.iothub_service(&iothub_service).build()?.query,
+            "SELECT * FROM devices WHERE deviceId IN ['some-device']"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn querybuilder_success() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::query::QueryBuilder;
+        let iothub_service = IoTHubService::from_sas_token("test", "test");
         let query = QueryBuilder::new(&iothub_service)
             .select("properties.something")
             .from("modules")
@@ -150,4 +1146,263 @@ mod tests {
         assert_eq!(expected_query, query.query);
         Ok(())
     }
+
+    #[test]
+    fn querybuilder_should_add_top_order_by_and_raw_clauses(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::query::QueryBuilder;
+        let iothub_service = IoTHubService::from_sas_token("test", "test");
+        let query = QueryBuilder::new(&iothub_service)
+            .order_by("deviceId DESC")
+            .top(10)
+            .select("deviceId")
+            .from("devices")
+            .and_where("status = 'enabled'")
+            .raw("AND IS_DEFINED(tags.location)")
+            .build()?;
+
+        assert_eq!(
+            query.query,
+            "SELECT TOP 10 deviceId FROM devices WHERE status = 'enabled' AND IS_DEFINED(tags.location) ORDER BY deviceId DESC"
+        );
+        assert!(QueryBuilder::new(&iothub_service)
+            .select("*")
+            .build()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn querybuilder_should_combine_where_helpers(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::query::QueryBuilder;
+        let iothub_service = IoTHubService::from_sas_token("test", "test");
+        let query = QueryBuilder::new(&iothub_service)
+            .select("*")
+            .from("devices")
+            .and_where("status = 'enabled' OR status = 'disabled'")
+            .where_defined("tags.site")
+            .where_in("deviceId", vec!["some-device", "it's"])
+            .build()?;
+
+        assert_eq!(
+            query.query,
+            r"SELECT * FROM devices WHERE (status = 'enabled' OR status = 'disabled') AND (IS_DEFINED(tags.site)) AND (deviceId IN ['some-device', 'it\'s'])"
+        );
+        assert!(QueryBuilder::new(&iothub_service)
+            .select("*")
+            .from("devices")
+            .where_defined("tags..site")
+            .build()
+            .is_err());
+        assert!(QueryBuilder::new(&iothub_service)
+            .select("*")
+            .from("devices")
+            .where_in("deviceId", Vec::<String>::new())
+            .build()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn querybuilder_should_chunk_long_where_in_lists(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::query::{QueryBuilder, MAX_QUERY_LENGTH};
+        let iothub_service = IoTHubService::from_sas_token("test", "test");
+        let device_ids: Vec<String> = (0..2000).map(|i| format!("device-{}", i)).collect();
+
+        assert!(QueryBuilder::new(&iothub_service)
+            .select("*")
+            .from("devices")
+            .where_in("deviceId", &device_ids)
+            .build()
+            .is_err());
+
+        let queries = QueryBuilder::new(&iothub_service)
+            .select("*")
+            .from("devices")
+            .where_in("deviceId", &device_ids)
+            .build_chunked()?;
+        assert!(queries.len() > 1);
+        assert!(queries
+            .iter()
+            .all(|query| query.query.len() <= MAX_QUERY_LENGTH));
+        let quoted_ids: usize = queries
+            .iter()
+            .map(|query| query.query.matches("'device-").count())
+            .sum();
+        assert_eq!(quoted_ids, device_ids.len());
+        assert!(queries[0].query.contains("'device-0'"));
+        assert!(queries[queries.len() - 1].query.contains("'device-1999'"));
+        Ok(())
+    }
+
+    #[test]
+    fn querybuilder_should_reject_empty_clauses() {
+        use crate::query::QueryBuilder;
+        let iothub_service = IoTHubService::from_sas_token("test", "test");
+        assert!(QueryBuilder::new(&iothub_service)
+            .select("")
+            .from("devices")
+            .build()
+            .is_err());
+        assert!(QueryBuilder::new(&iothub_service)
+            .select("*")
+            .from("  ")
+            .build()
+            .is_err());
+        assert!(QueryBuilder::new(&iothub_service)
+            .top(0)
+            .select("*")
+            .from("devices")
+            .build()
+            .is_err());
+    }
+
+    /// Create a transport that answers every request with the given body
+    fn items_transport(body: &'static [u8]) -> MockTransport {
+        MockTransport::new(move |_, _| Ok(http::Response::new(body.to_vec())))
+    }
+
+    #[test]
+    fn querybuilder_execute_should_build_and_execute(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let iothub_service = IoTHubService::from_sas_token("test", "test")
+            .with_transport(items_transport(br#"[{"deviceId": "some-device"}]"#));
+        let result = futures::executor::block_on(
+            iothub_service
+                .build_query()
+                .select("deviceId")
+                .from("devices")
+                .execute(),
+        )?;
+        assert_eq!(result[0]["deviceId"], "some-device");
+
+        assert!(futures::executor::block_on(
+            iothub_service.build_query().from("devices").execute()
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn execute_for_each_should_visit_items_one_at_a_time(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let iothub_service = IoTHubService::from_sas_token("test", "test").with_transport(
+            items_transport(br#"[{"deviceId": "some-device"}, {"deviceId": "other-device"}]"#),
+        );
+        let query = iothub_service
+            .build_query()
+            .select("deviceId")
+            .from("devices")
+            .build()?;
+
+        let mut device_ids = Vec::new();
+        let item_count =
+            futures::executor::block_on(query.execute_for_each(|item: serde_json::Value| {
+                device_ids.push(item["deviceId"].as_str().unwrap().to_string());
+            }))?;
+        assert_eq!(*item_count, 2);
+        assert_eq!(device_ids, vec!["some-device", "other-device"]);
+
+        let iothub_service =
+            IoTHubService::from_sas_token("test", "test").with_transport(items_transport(b"null"));
+        let query = iothub_service
+            .build_query()
+            .select("*")
+            .from("devices")
+            .build()?;
+        let device_twins =
+            futures::executor::block_on(query.execute_typed::<crate::twin::DeviceTwin>())?;
+        assert!(device_twins.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn execute_to_writer_should_write_all_pages(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use crate::query::Format;
+
+        let transport = MockTransport::new(|request, _| {
+            match request.headers().get("x-ms-continuation") {
+                None => Ok(http::Response::builder()
+                    .header("x-ms-continuation", "page-2")
+                    .body(
+                        br#"[{"deviceId": "sensor-1", "tags": {"site": "north", "floor": 3}, "labels": ["a", "b"]}]"#
+                            .to_vec(),
+                    )?),
+                Some(token) => {
+                    assert_eq!(token, "page-2");
+                    Ok(http::Response::new(
+                        br#"[{"deviceId": "sensor-2", "tags": {"site": "south, east"}, "labels": null}]"#
+                            .to_vec(),
+                    ))
+                }
+            }
+        });
+        let iothub_service =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport);
+        let query = || {
+            iothub_service
+                .build_query()
+                .select("deviceId, tags, labels")
+                .from("devices")
+                .build()
+        };
+
+        let mut csv = Vec::new();
+        let item_count =
+            futures::executor::block_on(query()?.execute_to_writer(&mut csv, Format::Csv))?;
+        assert_eq!(item_count, 2);
+        assert_eq!(
+            String::from_utf8(csv)?,
+            "deviceId,labels,tags.floor,tags.site\r\nsensor-1,\"[\"\"a\"\",\"\"b\"\"]\",3,north\r\nsensor-2,,,\"south, east\"\r\n"
+        );
+
+        let mut ndjson = Vec::new();
+        futures::executor::block_on(query()?.execute_to_writer(&mut ndjson, Format::Ndjson))?;
+        let lines: Vec<serde_json::Value> = String::from_utf8(ndjson)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["tags"]["site"], "south, east");
+        Ok(())
+    }
+
+    #[test]
+    fn queries_should_request_the_page_size_of_the_tier(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let transport = MockTransport::new(|_, _| Ok(http::Response::new(b"[]".to_vec())));
+        let iothub_service =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let query = iothub_service
+            .build_query()
+            .select("*")
+            .from("devices")
+            .build()?;
+        futures::executor::block_on(query.execute())?;
+        let query = iothub_service
+            .clone()
+            .with_tier(crate::tier::HubTier::Standard1, 1)
+            .build_query()
+            .select("*")
+            .from("devices")
+            .build()?;
+        futures::executor::block_on(query.execute())?;
+
+        let page_sizes = transport
+            .requests()
+            .iter()
+            .map(|request| {
+                request
+                    .headers()
+                    .get("x-ms-max-item-count")
+                    .map(|value| value.to_str().unwrap_or_default().to_string())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(page_sizes, vec![None, Some("100".to_string())]);
+        Ok(())
+    }
 }
@@ -0,0 +1,192 @@
+//! The redact module is used for keeping secrets such as shared access keys,
+//! SAS tokens and registry passwords out of the Debug output of the types
+//! that contain them.
+//!
+//! Secrets are redacted by default. The secrets of a value are only shown
+//! when explicitly asked for with [`RevealSecrets::reveal`]. The secrets in
+//! JSON, such as the bodies of requests, are redacted with [`redact_json`].
+use std::fmt;
+
+/// The text shown in place of a secret
+pub const REDACTED: &str = "****";
+
+/// The names of the JSON fields that contain secrets, which are compared case insensitively
+const SECRET_FIELDS: [&str; 7] = [
+    "password",
+    "primaryKey",
+    "secondaryKey",
+    "sharedAccessKey",
+    "sharedAccessSignature",
+    "sasToken",
+    "connectionString",
+];
+
+/// The texts that mark a string as a SAS token or connection string
+const SECRET_MARKERS: [&str; 3] = [
+    "SharedAccessSignature ",
+    "SharedAccessKey=",
+    "SharedAccessSignature=",
+];
+
+/// The RevealSecrets trait is implemented by the types that redact secrets in their Debug output
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::configuration::modulescontent::RegistryCredential;
+/// use azure_iothub_service::redact::RevealSecrets;
+///
+/// let credential = RegistryCredential::new("username", "secret", "some-acr.azurecr.io");
+/// assert!(!format!("{:?}", credential).contains("secret"));
+/// assert!(format!("{:?}", credential.reveal()).contains("secret"));
+/// ```
+pub trait RevealSecrets {
+    /// Format the value for debugging, showing the secrets only when `reveal` is true
+    fn fmt_secrets(&self, f: &mut fmt::Formatter<'_>, reveal: bool) -> fmt::Result;
+
+    /// Get a wrapper whose Debug output contains the secrets of the value
+    fn reveal(&self) -> Revealed<'_, Self>
+    where
+        Self: Sized,
+    {
+        Revealed(self)
+    }
+}
+
+/// A wrapper whose Debug output contains the secrets of the wrapped value
+pub struct Revealed<'a, T>(&'a T);
+
+impl<'a, T> fmt::Debug for Revealed<'a, T>
+where
+    T: RevealSecrets,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_secrets(f, true)
+    }
+}
+
+/// A secret in the Debug output, which is redacted unless it is revealed
+pub(crate) struct Secret<'a, T: ?Sized> {
+    value: &'a T,
+    reveal: bool,
+}
+
+impl<'a, T> fmt::Debug for Secret<'a, T>
+where
+    T: fmt::Debug + ?Sized,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.reveal {
+            self.value.fmt(f)
+        } else {
+            REDACTED.fmt(f)
+        }
+    }
+}
+
+/// Get the value to show for a secret in the Debug output
+pub(crate) fn secret<T: ?Sized>(value: &T, reveal: bool) -> Secret<'_, T> {
+    Secret { value, reveal }
+}
+
+/// Get the value to show for an optional secret in the Debug output
+pub(crate) fn optional_secret(value: &Option<String>, reveal: bool) -> Option<Secret<'_, String>> {
+    value.as_ref().map(|value| secret(value, reveal))
+}
+
+/// Redact the secrets in JSON
+///
+/// The values of fields with a name such as `password` or `primaryKey` are replaced,
+/// as are strings that contain a SAS token or connection string.
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::redact::redact_json;
+/// use serde_json::json;
+///
+/// let mut credentials = json!({ "username": "someacr", "password": "secret" });
+/// redact_json(&mut credentials);
+/// assert_eq!(credentials, json!({ "username": "someacr", "password": "****" }));
+/// ```
+pub fn redact_json(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (name, value) in object.iter_mut() {
+                let is_secret = SECRET_FIELDS
+                    .iter()
+                    .any(|field| field.eq_ignore_ascii_case(name));
+                if is_secret && !value.is_null() {
+                    *value = serde_json::Value::from(REDACTED);
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact_json),
+        serde_json::Value::String(text)
+            if SECRET_MARKERS.iter().any(|marker| text.contains(marker)) =>
+        {
+            *text = REDACTED.to_string();
+        }
+        _ => {}
+    }
+}
+
+/// Get the body of a request or response for logging, with its secrets redacted
+///
+/// Bodies that are not JSON, such as compressed bodies, are only logged by their length.
+#[cfg(feature = "tracing")]
+pub(crate) fn redacted_body(body: &[u8]) -> String {
+    if body.is_empty() {
+        return String::new();
+    }
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(mut value) => {
+            redact_json(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes>", body.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::redact_json;
+
+    #[test]
+    fn redact_json_should_replace_secrets() {
+        let mut manifest = json!({
+            "modulesContent": {
+                "$edgeAgent": {
+                    "properties.desired": {
+                        "runtime": {
+                            "settings": {
+                                "registryCredentials": {
+                                    "someacr": { "username": "someacr", "Password": "secret", "address": "someacr.azurecr.io" }
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            "authentication": { "symmetricKey": { "primaryKey": "a2V5", "secondaryKey": null } },
+            "env": [{ "value": "HostName=hub.azure-devices.net;SharedAccessKey=a2V5" }, { "value": 42 }]
+        });
+        redact_json(&mut manifest);
+
+        let credential = &manifest["modulesContent"]["$edgeAgent"]["properties.desired"]["runtime"]
+            ["settings"]["registryCredentials"]["someacr"];
+        assert_eq!(credential["Password"], "****");
+        assert_eq!(credential["username"], "someacr");
+        assert_eq!(
+            manifest["authentication"]["symmetricKey"]["primaryKey"],
+            "****"
+        );
+        assert!(manifest["authentication"]["symmetricKey"]["secondaryKey"].is_null());
+        assert_eq!(
+            manifest["env"],
+            json!([{ "value": "****" }, { "value": 42 }])
+        );
+    }
+}
@@ -0,0 +1,2125 @@
+//! The registry module is used for managing the device identities
+//! in the identity registry of the IoT Hub.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use chrono::{SecondsFormat, Utc};
+use http::{Method, Request};
+use serde::{Deserialize, Serialize};
+
+use crate::concurrent::{for_each_bounded, join3, unique};
+use crate::error::IoTHubError;
+use crate::iothub::ApiFeature;
+use crate::query::quote;
+use crate::redact::{optional_secret, RevealSecrets};
+use crate::response::{Response, ResponseMetadata};
+use crate::transport::HttpRequest;
+use crate::twin::{
+    AuthenticationType, ConnectionState, DesiredTwinBuilder, DeviceCapabilities, DeviceTwin,
+    Status, X509ThumbPrint,
+};
+use crate::IoTHubService;
+
+/// The number of times the status of a device is set again when its identity changed in between
+const STATUS_UPDATE_RETRIES: u32 = 3;
+
+/// The maximum length of a device id
+const MAX_DEVICE_ID_LENGTH: usize = 128;
+
+/// The characters a device id can contain besides ASCII letters and digits
+const DEVICE_ID_SPECIAL_CHARACTERS: &str = "-.+%_#*?!(),:=@$'";
+
+/// The symmetric keys used for authenticating a device
+///
+/// The keys are redacted in the Debug output.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SymmetricKey {
+    pub primary_key: Option<String>,
+    pub secondary_key: Option<String>,
+}
+
+impl RevealSecrets for SymmetricKey {
+    fn fmt_secrets(&self, f: &mut fmt::Formatter<'_>, reveal: bool) -> fmt::Result {
+        f.debug_struct("SymmetricKey")
+            .field("primary_key", &optional_secret(&self.primary_key, reveal))
+            .field(
+                "secondary_key",
+                &optional_secret(&self.secondary_key, reveal),
+            )
+            .finish()
+    }
+}
+
+impl fmt::Debug for SymmetricKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_secrets(f, false)
+    }
+}
+
+/// The number of random bytes in a generated symmetric key
+const SYMMETRIC_KEY_LENGTH: usize = 32;
+
+/// Generate a new random symmetric key, encoded as base64
+fn generate_symmetric_key() -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut key = [0u8; SYMMETRIC_KEY_LENGTH];
+    getrandom::getrandom(&mut key)?;
+    Ok(base64::encode(key))
+}
+
+/// Get the symmetric keys of an authentication mechanism that uses SAS authentication
+fn sas_keys(
+    authentication: &mut Option<AuthenticationMechanism>,
+) -> Result<&mut SymmetricKey, Box<dyn std::error::Error + Send + Sync>> {
+    match authentication {
+        Some(AuthenticationMechanism {
+            authentication_type: Some(AuthenticationType::SAS),
+            symmetric_key: Some(symmetric_key),
+            ..
+        }) => Ok(symmetric_key),
+        _ => Err(Box::new(Error::new(
+            ErrorKind::InvalidInput,
+            "Only identities with SAS authentication have symmetric keys to rotate",
+        ))),
+    }
+}
+
+/// The authentication mechanism of a device identity
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticationMechanism {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub authentication_type: Option<AuthenticationType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symmetric_key: Option<SymmetricKey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x509_thumbprint: Option<X509ThumbPrint>,
+}
+
+/// The identity of a device in the identity registry
+///
+/// Fields that are unknown to this crate are collected in `extra`, so they
+/// are sent back unchanged when the identity is updated.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceIdentity {
+    pub device_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub generation_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub etag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_state: Option<ConnectionState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_state_updated_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_updated_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_activity_time: Option<String>,
+    #[serde(default)]
+    pub cloud_to_device_message_count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authentication: Option<AuthenticationMechanism>,
+    #[serde(default)]
+    pub capabilities: DeviceCapabilities,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_scopes: Option<Vec<String>>,
+    /// The fields of the identity that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl DeviceIdentity {
+    /// Create a new DeviceIdentity with the given device id
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::registry::DeviceIdentity;
+    ///
+    /// let device_identity = DeviceIdentity::new("some-device");
+    /// assert_eq!(device_identity.device_id, "some-device");
+    /// ```
+    pub fn new<S>(device_id: S) -> Self
+    where
+        S: Into<String>,
+    {
+        DeviceIdentity {
+            device_id: device_id.into(),
+            generation_id: String::new(),
+            etag: String::new(),
+            connection_state: None,
+            status: None,
+            status_reason: None,
+            connection_state_updated_time: None,
+            status_updated_time: None,
+            last_activity_time: None,
+            cloud_to_device_message_count: 0,
+            authentication: None,
+            capabilities: DeviceCapabilities::default(),
+            device_scope: None,
+            parent_scopes: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    /// Read the identity of a new device from JSON, such as a provisioning file
+    ///
+    /// The identity is validated before it is returned: the device id must be a valid
+    /// device id of the IoT Hub, and a self-signed device must have a primary thumbprint.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::registry::DeviceIdentity;
+    ///
+    /// let json = r#"{ "deviceId": "some-device", "capabilities": { "iotEdge": true } }"#;
+    /// let device_identity = DeviceIdentity::from_json(json.as_bytes()).expect("Invalid identity");
+    /// assert!(device_identity.is_edge_device());
+    /// assert!(DeviceIdentity::from_json(r#"{ "deviceId": "some device" }"#.as_bytes()).is_err());
+    /// ```
+    pub fn from_json<R>(reader: R) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        R: Read,
+    {
+        let device_identity: DeviceIdentity = serde_json::from_reader(reader)?;
+        validate_device_id(&device_identity.device_id)?;
+
+        if let Some(AuthenticationMechanism {
+            authentication_type: Some(AuthenticationType::SelfSigned),
+            x509_thumbprint,
+            ..
+        }) = &device_identity.authentication
+        {
+            let has_thumbprint = x509_thumbprint
+                .as_ref()
+                .and_then(|thumbprint| thumbprint.primary_thumbprint.as_deref())
+                .map_or(false, |thumbprint| !thumbprint.is_empty());
+            if !has_thumbprint {
+                return Err(Box::new(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "The self-signed device {} has no primary thumbprint",
+                        device_identity.device_id
+                    ),
+                )));
+            }
+        }
+        Ok(device_identity)
+    }
+
+    /// Check whether the device is an edge device
+    pub fn is_edge_device(&self) -> bool {
+        self.capabilities.iotedge
+    }
+}
+
+/// Check whether a device id is accepted by the IoT Hub
+fn validate_device_id(device_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let is_valid = !device_id.is_empty()
+        && device_id.len() <= MAX_DEVICE_ID_LENGTH
+        && device_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || DEVICE_ID_SPECIAL_CHARACTERS.contains(c));
+    if !is_valid {
+        return Err(Box::new(Error::new(
+            ErrorKind::InvalidData,
+            format!("{:?} is not a valid device id", device_id),
+        )));
+    }
+    Ok(())
+}
+
+/// The result of provisioning the device identities of a directory with `provision_from_dir`
+#[derive(Debug, Default)]
+pub struct ProvisioningReport {
+    created: Vec<String>,
+    failed: HashMap<String, Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl ProvisioningReport {
+    /// Get the ids of the devices that were created, ordered by id
+    pub fn created(&self) -> &Vec<String> {
+        &self.created
+    }
+
+    /// Get the errors of the devices that could not be created, by device id
+    pub fn failed(&self) -> &HashMap<String, Box<dyn std::error::Error + Send + Sync>> {
+        &self.failed
+    }
+
+    /// Check whether all devices were created
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl fmt::Display for ProvisioningReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} devices created, {} failed",
+            self.created.len(),
+            self.failed.len()
+        )?;
+        let mut failed: Vec<_> = self.failed.iter().collect();
+        failed.sort_by(|a, b| a.0.cmp(b.0));
+        for (device_id, err) in failed {
+            write!(f, "\n{}: {}", device_id, err)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the device identities from the JSON files of a directory, ordered by file name
+///
+/// The identities are validated and their device ids must be unique.
+fn read_device_identities(
+    directory: &Path,
+) -> Result<Vec<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(std::ffi::OsStr::new("json")) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut device_ids = HashSet::new();
+    let mut device_identities = Vec::with_capacity(paths.len());
+    for path in paths {
+        let file = std::fs::File::open(&path)?;
+        let device_identity =
+            DeviceIdentity::from_json(std::io::BufReader::new(file)).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), err),
+                )
+            })?;
+        if !device_ids.insert(device_identity.device_id.clone()) {
+            return Err(Box::new(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{}: the device id {} is used by another file",
+                    path.display(),
+                    device_identity.device_id
+                ),
+            )));
+        }
+        device_identities.push(device_identity);
+    }
+    Ok(device_identities)
+}
+
+/// The identity of a module in the identity registry
+///
+/// Fields that are unknown to this crate are collected in `extra`, so they
+/// are sent back unchanged when the identity is updated.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleIdentity {
+    pub module_id: String,
+    pub device_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub generation_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub etag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_state: Option<ConnectionState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_state_updated_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_activity_time: Option<String>,
+    #[serde(default)]
+    pub cloud_to_device_message_count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authentication: Option<AuthenticationMechanism>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub managed_by: Option<String>,
+    /// The fields of the identity that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ModuleIdentity {
+    /// Check whether the module is a system module of IoT Edge, such as `$edgeAgent`
+    pub fn is_system_module(&self) -> bool {
+        self.module_id.starts_with('$')
+    }
+}
+
+/// The identity, twin and modules of a device, as returned by `get_device_overview`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceOverview {
+    pub identity: DeviceIdentity,
+    pub twin: DeviceTwin,
+    pub modules: Vec<ModuleIdentity>,
+}
+
+impl DeviceOverview {
+    /// Get the authentication type of the device
+    pub fn authentication_type(&self) -> Option<AuthenticationType> {
+        self.identity
+            .authentication
+            .as_ref()
+            .and_then(|authentication| authentication.authentication_type)
+    }
+
+    /// Check whether the identity of the device has a primary or secondary symmetric key
+    pub fn has_symmetric_keys(&self) -> bool {
+        has_symmetric_keys(&self.identity)
+    }
+
+    /// Get the status of the device, which is enabled or disabled
+    pub fn status(&self) -> Status {
+        self.twin.status
+    }
+
+    /// Get the ids of the modules on the device, including the system modules of IoT Edge
+    pub fn module_ids(&self) -> Vec<&String> {
+        self.modules
+            .iter()
+            .map(|module_identity| &module_identity.module_id)
+            .collect()
+    }
+}
+
+/// The twin of a device joined with its identity, as returned by `list_devices_with_twins`
+///
+/// The identity is only requested for the devices that need it, as it contains fields
+/// that are not part of the twin, such as whether the device has symmetric keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceListing {
+    pub twin: DeviceTwin,
+    pub identity: Option<DeviceIdentity>,
+}
+
+impl DeviceListing {
+    /// Get the id of the device
+    pub fn device_id(&self) -> &String {
+        &self.twin.device_id
+    }
+
+    /// Get the authentication type of the device, from the identity if it was requested
+    pub fn authentication_type(&self) -> Option<AuthenticationType> {
+        self.identity
+            .as_ref()
+            .and_then(|identity| identity.authentication.as_ref())
+            .and_then(|authentication| authentication.authentication_type)
+            .or(self.twin.authentication_type)
+    }
+
+    /// Check whether the identity of the device has a primary or secondary symmetric key,
+    /// or `None` when the identity was not requested
+    pub fn has_symmetric_keys(&self) -> Option<bool> {
+        self.identity.as_ref().map(has_symmetric_keys)
+    }
+}
+
+/// Check whether an identity has a primary or secondary symmetric key
+fn has_symmetric_keys(identity: &DeviceIdentity) -> bool {
+    let has_key = |key: &Option<String>| matches!(key.as_deref(), Some(key) if !key.is_empty());
+    match identity
+        .authentication
+        .as_ref()
+        .and_then(|authentication| authentication.symmetric_key.as_ref())
+    {
+        Some(symmetric_key) => {
+            has_key(&symmetric_key.primary_key) || has_key(&symmetric_key.secondary_key)
+        }
+        None => false,
+    }
+}
+
+/// The IfMatch enum selects the identity an update or delete applies to
+///
+/// With `IfMatch::Etag` the operation fails with a `412 Precondition Failed` error
+/// when the identity was changed since the etag was read, which can be checked
+/// with `IoTHubError::is_precondition_failed`. With `IfMatch::Any` the operation
+/// is forced, regardless of any concurrent changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IfMatch {
+    Etag(String),
+    Any,
+}
+
+impl IfMatch {
+    /// Create an IfMatch for the given etag
+    pub fn etag<S>(etag: S) -> Self
+    where
+        S: Into<String>,
+    {
+        IfMatch::Etag(etag.into())
+    }
+
+    /// Get the value of the If-Match header
+    pub(crate) fn header_value(&self) -> String {
+        match self {
+            IfMatch::Etag(etag) => format!("\"{}\"", etag.trim_matches('"')),
+            IfMatch::Any => "*".to_string(),
+        }
+    }
+}
+
+/// The RegistryManager is used for reading and updating the
+/// device identities in the identity registry of the IoT Hub.
+pub struct RegistryManager {
+    iothub_service: IoTHubService,
+}
+
+impl RegistryManager {
+    /// Create a new RegistryManager
+    pub fn new(iothub_service: &IoTHubService) -> Self {
+        RegistryManager {
+            iothub_service: iothub_service.clone(),
+        }
+    }
+
+    /// Get the uri of a device identity
+    fn device_uri(&self, device_id: &str) -> String {
+        format!(
+            "https://{}/devices/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            self.iothub_service.api_version()
+        )
+    }
+
+    /// Get the uri of a module identity
+    fn module_uri(&self, device_id: &str, module_id: &str) -> String {
+        format!(
+            "https://{}/devices/{}/modules/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            module_id,
+            self.iothub_service.api_version()
+        )
+    }
+
+    /// Send a request and parse the body of the response
+    async fn send<T>(
+        &self,
+        operation: &'static str,
+        device_id: &str,
+        request: HttpRequest,
+    ) -> Result<Response<T>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        for<'de> T: serde::Deserialize<'de>,
+    {
+        let response = self
+            .iothub_service
+            .send(operation, Some(device_id), request)
+            .await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
+        }
+
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            serde_json::from_slice(response.body())?,
+        ))
+    }
+
+    /// Send a delete request, which returns no body
+    async fn send_delete(
+        &self,
+        operation: &'static str,
+        device_id: &str,
+        uri: String,
+        if_match: &IfMatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>> {
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::DELETE)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("If-Match", if_match.header_value())
+            .body(Vec::new())?;
+
+        let response = self
+            .iothub_service
+            .send(operation, Some(device_id), request)
+            .await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
+        }
+
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            (),
+        ))
+    }
+
+    /// Check whether the api version of the IoTHubService supports the given feature
+    fn ensure_supported(
+        &self,
+        feature: ApiFeature,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if !self.iothub_service.supports(feature) {
+            return Err(Box::new(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{:?} requires api version {} or newer, but {} is used",
+                    feature,
+                    feature.minimum_api_version(),
+                    self.iothub_service.api_version()
+                ),
+            )));
+        }
+        Ok(())
+    }
+
+    /// Get the identity of a device
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let device_identity = iothub.registry_manager().get_device_identity("some-device").await?;
+    /// println!("Device scope: {:?}", device_identity.device_scope);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_device_identity<S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let device_id = device_id.into();
+        let request = Request::builder()
+            .uri(self.device_uri(&device_id))
+            .method(Method::GET)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json")
+            .body(Vec::new())?;
+
+        self.send("get_device_identity", &device_id, request).await
+    }
+
+    /// Get the identity, twin and modules of a device
+    ///
+    /// The three requests are sent at the same time. The metadata of the response
+    /// is the metadata of the request for the identity.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let overview = iothub.registry_manager().get_device_overview("some-device").await?;
+    /// println!(
+    ///     "{} is {} with modules {:?}",
+    ///     overview.identity.device_id,
+    ///     overview.status(),
+    ///     overview.module_ids()
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_device_overview<S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<DeviceOverview>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let device_id = device_id.into();
+        let (identity, twin, modules) = join3(
+            self.get_device_identity(device_id.as_str()),
+            self.iothub_service
+                .twin_manager()
+                .get_device_twin(device_id.as_str()),
+            self.get_module_identities(device_id.as_str()),
+        )
+        .await;
+
+        let (metadata, identity) = identity?.into_parts();
+        Ok(Response::new(
+            metadata,
+            DeviceOverview {
+                identity,
+                twin: twin?.into_inner(),
+                modules: modules?.into_inner(),
+            },
+        ))
+    }
+
+    /// Get the identities of all modules on a device
+    pub async fn get_module_identities<S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<Vec<ModuleIdentity>>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let device_id = device_id.into();
+        let uri = format!(
+            "https://{}/devices/{}/modules?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            self.iothub_service.api_version()
+        );
+
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::GET)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json")
+            .body(Vec::new())?;
+
+        self.send("get_module_identities", &device_id, request)
+            .await
+    }
+
+    /// Update the identity of a device
+    ///
+    /// When the etag does not match the etag in the identity registry, the update
+    /// fails with an `IoTHubError` for which `is_precondition_failed` returns true.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::registry::IfMatch;
+    /// use azure_iothub_service::twin::Status;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let registry_manager = iothub.registry_manager();
+    /// let mut device_identity = registry_manager.get_device_identity("some-device").await?.into_inner();
+    /// device_identity.status = Some(Status::Disabled);
+    /// let if_match = IfMatch::etag(&device_identity.etag);
+    /// registry_manager.update_device_identity(&device_identity, if_match).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn update_device_identity(
+        &self,
+        device_identity: &DeviceIdentity,
+        if_match: IfMatch,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>> {
+        let request = Request::builder()
+            .uri(self.device_uri(&device_identity.device_id))
+            .method(Method::PUT)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json")
+            .header("If-Match", if_match.header_value())
+            .body(serde_json::to_vec(device_identity)?)?;
+
+        self.send(
+            "update_device_identity",
+            &device_identity.device_id,
+            request,
+        )
+        .await
+    }
+
+    /// Create the identity of a new device
+    ///
+    /// The creation fails with a `409 Conflict` error when a device with the same id
+    /// already exists. The keys of a SAS device without keys are generated by the IoT Hub.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::registry::DeviceIdentity;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let device_identity = iothub
+    ///     .registry_manager()
+    ///     .create_device_identity(&DeviceIdentity::new("some-device"))
+    ///     .await?;
+    /// println!("Created {} with etag {}", device_identity.device_id, device_identity.etag);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn create_device_identity(
+        &self,
+        device_identity: &DeviceIdentity,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>> {
+        let request = Request::builder()
+            .uri(self.device_uri(&device_identity.device_id))
+            .method(Method::PUT)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(device_identity)?)?;
+
+        self.send(
+            "create_device_identity",
+            &device_identity.device_id,
+            request,
+        )
+        .await
+    }
+
+    /// Create the devices described by the JSON files of a directory
+    ///
+    /// Every file contains the identity of one device, as read by `DeviceIdentity::from_json`.
+    /// All files are read and validated before the IoT Hub is changed, so an invalid file or
+    /// a device id that is used by more than one file fails the whole provisioning. At most
+    /// `max_concurrency` devices are created at the same time, and a device that fails does
+    /// not stop the others.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let report = iothub.registry_manager().provision_from_dir("devices", 10).await?;
+    /// println!("{}", report);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn provision_from_dir<P>(
+        &self,
+        directory: P,
+        max_concurrency: usize,
+    ) -> Result<ProvisioningReport, Box<dyn std::error::Error + Send + Sync>>
+    where
+        P: AsRef<Path>,
+    {
+        let device_identities = read_device_identities(directory.as_ref())?;
+
+        let create = |device_identity: DeviceIdentity| async move {
+            let result = self
+                .create_device_identity(&device_identity)
+                .await
+                .map(|_| ());
+            (device_identity.device_id, result)
+        };
+
+        let mut report = ProvisioningReport::default();
+        for (device_id, result) in
+            for_each_bounded(device_identities, max_concurrency, create).await
+        {
+            match result {
+                Ok(()) => report.created.push(device_id),
+                Err(err) => {
+                    report.failed.insert(device_id, err);
+                }
+            }
+        }
+        report.created.sort();
+        Ok(report)
+    }
+
+    /// Delete the identity of a device
+    ///
+    /// When the etag does not match the etag in the identity registry, the delete
+    /// fails with an `IoTHubError` for which `is_precondition_failed` returns true.
+    pub async fn delete_device_identity<S>(
+        &self,
+        device_id: S,
+        if_match: IfMatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let device_id = device_id.into();
+        self.send_delete(
+            "delete_device_identity",
+            &device_id,
+            self.device_uri(&device_id),
+            &if_match,
+        )
+        .await
+    }
+
+    /// Rotate the symmetric keys of a device
+    ///
+    /// The keys are rotated in two updates like [`RegistryManager::rotate_module_keys`], so the
+    /// new key becomes the primary key and the old primary key the secondary key.
+    pub async fn rotate_device_keys<S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<SymmetricKey>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let mut device_identity = self.get_device_identity(device_id).await?.into_inner();
+
+        let new_key = generate_symmetric_key()?;
+        sas_keys(&mut device_identity.authentication)?.secondary_key = Some(new_key);
+        let if_match = IfMatch::etag(device_identity.etag.as_str());
+        let mut device_identity = self
+            .update_device_identity(&device_identity, if_match)
+            .await?
+            .into_inner();
+
+        let keys = sas_keys(&mut device_identity.authentication)?;
+        std::mem::swap(&mut keys.primary_key, &mut keys.secondary_key);
+        let if_match = IfMatch::etag(device_identity.etag.as_str());
+        let (metadata, mut device_identity) = self
+            .update_device_identity(&device_identity, if_match)
+            .await?
+            .into_parts();
+
+        Ok(Response::new(
+            metadata,
+            sas_keys(&mut device_identity.authentication)?.clone(),
+        ))
+    }
+
+    /// Rotate the symmetric keys of a device and stamp the time of the rotation in a twin tag
+    ///
+    /// The tag contains the time in RFC 3339 format, so devices that were not rotated
+    /// recently can be found with a query such as `tags.keysRotatedAt < '2020-01-01'`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let keys = iothub
+    ///     .registry_manager()
+    ///     .rotate_device_keys_with_tag("some-device", "keysRotatedAt")
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn rotate_device_keys_with_tag<S, T>(
+        &self,
+        device_id: S,
+        tag_name: T,
+    ) -> Result<Response<SymmetricKey>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let device_id = device_id.into();
+        let keys = self.rotate_device_keys(device_id.as_str()).await?;
+
+        let rotated_at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        self.iothub_service
+            .twin_manager()
+            .update_device_twin(
+                device_id,
+                DesiredTwinBuilder::new()
+                    .add_tag(tag_name, rotated_at)
+                    .build(),
+            )
+            .await?;
+        Ok(keys)
+    }
+
+    /// Update the X.509 thumbprints of a device that uses self-signed certificates
+    ///
+    /// This is used when the certificates of a device are renewed. Keep the thumbprint of the
+    /// old certificate as secondary thumbprint until the device uses the new certificate.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::twin::X509ThumbPrint;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let thumbprints = X509ThumbPrint::new(
+    ///     "2FD4C51A2C3E09E130B31C51B6C01F4C0E7A99E0",
+    ///     Some("8D6B4F6E2A0C3D1B9E7F5A4C2B0D8E6F4A2C0B1D"),
+    /// )?;
+    /// iothub
+    ///     .registry_manager()
+    ///     .update_x509_thumbprints("some-device", thumbprints)
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn update_x509_thumbprints<S>(
+        &self,
+        device_id: S,
+        thumbprints: X509ThumbPrint,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let mut device_identity = self.get_device_identity(device_id).await?.into_inner();
+        match &mut device_identity.authentication {
+            Some(AuthenticationMechanism {
+                authentication_type: Some(AuthenticationType::SelfSigned),
+                x509_thumbprint,
+                ..
+            }) => *x509_thumbprint = Some(thumbprints),
+            _ => {
+                return Err(Box::new(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Only identities with self-signed certificates have X.509 thumbprints",
+                )))
+            }
+        }
+
+        let if_match = IfMatch::etag(device_identity.etag.as_str());
+        self.update_device_identity(&device_identity, if_match)
+            .await
+    }
+
+    /// Get the identity of a module
+    pub async fn get_module_identity<S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+    ) -> Result<Response<ModuleIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let device_id = device_id.into();
+        let request = Request::builder()
+            .uri(self.module_uri(&device_id, &module_id.into()))
+            .method(Method::GET)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json")
+            .body(Vec::new())?;
+
+        self.send("get_module_identity", &device_id, request).await
+    }
+
+    /// Rotate the symmetric keys of a module
+    ///
+    /// The rotation is done in two updates, each of which only succeeds when the identity
+    /// was not changed in the meantime. First the secondary key is replaced by a newly
+    /// generated key, then the keys are swapped, so the new key becomes the primary key and
+    /// the old primary key the secondary key. Clients using the old primary key keep working
+    /// until the next rotation, which gives them time to switch to the new key.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let keys = iothub
+    ///     .registry_manager()
+    ///     .rotate_module_keys("some-device", "some-module")
+    ///     .await?;
+    /// // Distribute keys.primary_key to the module
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn rotate_module_keys<S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+    ) -> Result<Response<SymmetricKey>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let mut module_identity = self
+            .get_module_identity(device_id, module_id)
+            .await?
+            .into_inner();
+
+        let new_key = generate_symmetric_key()?;
+        sas_keys(&mut module_identity.authentication)?.secondary_key = Some(new_key);
+        let if_match = IfMatch::etag(module_identity.etag.as_str());
+        let mut module_identity = self
+            .update_module_identity(&module_identity, if_match)
+            .await?
+            .into_inner();
+
+        let keys = sas_keys(&mut module_identity.authentication)?;
+        std::mem::swap(&mut keys.primary_key, &mut keys.secondary_key);
+        let if_match = IfMatch::etag(module_identity.etag.as_str());
+        let (metadata, mut module_identity) = self
+            .update_module_identity(&module_identity, if_match)
+            .await?
+            .into_parts();
+
+        Ok(Response::new(
+            metadata,
+            sas_keys(&mut module_identity.authentication)?.clone(),
+        ))
+    }
+
+    /// Update the identity of a module
+    ///
+    /// When the etag does not match the etag in the identity registry, the update
+    /// fails with an `IoTHubError` for which `is_precondition_failed` returns true.
+    pub async fn update_module_identity(
+        &self,
+        module_identity: &ModuleIdentity,
+        if_match: IfMatch,
+    ) -> Result<Response<ModuleIdentity>, Box<dyn std::error::Error + Send + Sync>> {
+        let request = Request::builder()
+            .uri(self.module_uri(&module_identity.device_id, &module_identity.module_id))
+            .method(Method::PUT)
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json")
+            .header("If-Match", if_match.header_value())
+            .body(serde_json::to_vec(module_identity)?)?;
+
+        self.send(
+            "update_module_identity",
+            &module_identity.device_id,
+            request,
+        )
+        .await
+    }
+
+    /// Delete the identity of a module
+    ///
+    /// When the etag does not match the etag in the identity registry, the delete
+    /// fails with an `IoTHubError` for which `is_precondition_failed` returns true.
+    pub async fn delete_module_identity<S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+        if_match: IfMatch,
+    ) -> Result<Response<()>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let device_id = device_id.into();
+        let uri = self.module_uri(&device_id, &module_id.into());
+        self.send_delete("delete_module_identity", &device_id, uri, &if_match)
+            .await
+    }
+
+    /// List the twins of the devices matching the condition
+    async fn list_devices(
+        &self,
+        condition: String,
+    ) -> Result<Response<Vec<DeviceTwin>>, Box<dyn std::error::Error + Send + Sync>> {
+        self.iothub_service
+            .build_query()
+            .select("*")
+            .from("devices")
+            .and_where(condition)
+            .build()?
+            .execute_all_pages()
+            .await
+    }
+
+    /// List the twins of all edge devices
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// for edge_device in iothub.registry_manager().list_edge_devices().await?.iter() {
+    ///     println!("{} is {}", edge_device.device_id, edge_device.connection_state);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn list_edge_devices(
+        &self,
+    ) -> Result<Response<Vec<DeviceTwin>>, Box<dyn std::error::Error + Send + Sync>> {
+        self.list_devices("capabilities.iotEdge = true".to_string())
+            .await
+    }
+
+    /// List the twins of all devices with the given status
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::twin::Status;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let disabled_devices = iothub
+    ///     .registry_manager()
+    ///     .list_devices_by_status(Status::Disabled)
+    ///     .await?;
+    /// println!("{} devices are disabled", disabled_devices.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn list_devices_by_status(
+        &self,
+        status: Status,
+    ) -> Result<Response<Vec<DeviceTwin>>, Box<dyn std::error::Error + Send + Sync>> {
+        self.list_devices(format!("status = {}", quote(status.as_str())))
+            .await
+    }
+
+    /// List the twins of the devices matching a condition, joined with the identities
+    /// of the devices for which `needs_identity` returns true
+    ///
+    /// The twins are read with a single query, which is followed through all pages, and an
+    /// empty condition lists all devices. The identities are requested for at most
+    /// `max_concurrency` devices at the same time, and the listing fails when one of them
+    /// cannot be requested. The devices are listed in the order of the query.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::twin::AuthenticationType;
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let devices = iothub
+    ///     .registry_manager()
+    ///     .list_devices_with_twins(
+    ///         "tags.site = 'north'",
+    ///         |twin| twin.authentication_type == Some(AuthenticationType::SAS),
+    ///         10,
+    ///     )
+    ///     .await?;
+    /// for device in devices {
+    ///     println!("{} has keys: {:?}", device.device_id(), device.has_symmetric_keys());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn list_devices_with_twins<S, F>(
+        &self,
+        condition: S,
+        needs_identity: F,
+        max_concurrency: usize,
+    ) -> Result<Vec<DeviceListing>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        F: Fn(&DeviceTwin) -> bool,
+    {
+        let condition = condition.into();
+        let mut query = self
+            .iothub_service
+            .build_query()
+            .select("*")
+            .from("devices");
+        if !condition.trim().is_empty() {
+            query = query.and_where(condition);
+        }
+        let twins: Vec<DeviceTwin> = query.build()?.execute_all_pages().await?.into_inner();
+
+        let device_ids: Vec<String> = twins
+            .iter()
+            .filter(|twin| needs_identity(twin))
+            .map(|twin| twin.device_id.clone())
+            .collect();
+        let get = |device_id: String| async move {
+            let result = self
+                .get_device_identity(device_id.as_str())
+                .await
+                .map(Response::into_inner);
+            (device_id, result)
+        };
+
+        let mut identities = HashMap::new();
+        for (device_id, result) in for_each_bounded(device_ids, max_concurrency, get).await {
+            identities.insert(device_id, result?);
+        }
+
+        Ok(twins
+            .into_iter()
+            .map(|twin| DeviceListing {
+                identity: identities.remove(&twin.device_id),
+                twin,
+            })
+            .collect())
+    }
+
+    /// Set the device scope of a device
+    pub async fn set_device_scope<S, T>(
+        &self,
+        device_id: S,
+        device_scope: T,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.ensure_supported(ApiFeature::DeviceScope)?;
+        let mut device_identity = self.get_device_identity(device_id).await?.into_inner();
+        device_identity.device_scope = Some(device_scope.into());
+        let if_match = IfMatch::etag(&device_identity.etag);
+        self.update_device_identity(&device_identity, if_match)
+            .await
+    }
+
+    /// Set the parent scopes of a device
+    pub async fn set_parent_scopes<S>(
+        &self,
+        device_id: S,
+        parent_scopes: Vec<String>,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        self.ensure_supported(ApiFeature::ParentScopes)?;
+        let mut device_identity = self.get_device_identity(device_id).await?.into_inner();
+        device_identity.parent_scopes = Some(parent_scopes);
+        let if_match = IfMatch::etag(&device_identity.etag);
+        self.update_device_identity(&device_identity, if_match)
+            .await
+    }
+
+    /// Set the parent of a device in a nested edge hierarchy
+    ///
+    /// The parent must be an edge device. When the child is an edge device, the
+    /// device scope of the parent becomes the parent scope of the child. Otherwise
+    /// the device scope of the child is set to the device scope of the parent.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// iothub.registry_manager().set_parent("child-device", "parent-device").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn set_parent<S, T>(
+        &self,
+        child_id: S,
+        parent_id: T,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.ensure_supported(ApiFeature::ParentScopes)?;
+
+        let parent = self.get_device_identity(parent_id).await?.into_inner();
+        let parent_scope = match (&parent.device_scope, parent.is_edge_device()) {
+            (Some(device_scope), true) => device_scope.clone(),
+            _ => {
+                return Err(Box::new(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "The parent {} must be an edge device with a device scope",
+                        parent.device_id
+                    ),
+                )))
+            }
+        };
+
+        let mut child = self.get_device_identity(child_id).await?.into_inner();
+        child.parent_scopes = Some(vec![parent_scope.clone()]);
+        if !child.is_edge_device() {
+            child.device_scope = Some(parent_scope);
+        }
+        let if_match = IfMatch::etag(&child.etag);
+        self.update_device_identity(&child, if_match).await
+    }
+
+    /// Disable a device, so it can no longer connect to the IoT Hub
+    ///
+    /// The reason is stored as the status reason of the identity. The identity is
+    /// updated with its etag, and read again when it changed in between.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// iothub
+    ///     .registry_manager()
+    ///     .disable_device("some-device", "Credentials leaked")
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn disable_device<S, T>(
+        &self,
+        device_id: S,
+        reason: T,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.set_device_status(device_id.into(), Status::Disabled, Some(reason.into()))
+            .await
+    }
+
+    /// Enable a device and clear its status reason
+    pub async fn enable_device<S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        self.set_device_status(device_id.into(), Status::Enabled, None)
+            .await
+    }
+
+    /// Disable all devices matching a query condition, such as `tags.batch = 'B42'`
+    ///
+    /// At most `max_concurrency` devices are disabled at the same time. The results are
+    /// returned per device id, so a failing device does not stop the others.
+    pub async fn disable_devices_matching<S, T>(
+        &self,
+        condition: S,
+        reason: T,
+        max_concurrency: usize,
+    ) -> Result<
+        HashMap<String, Result<(), Box<dyn std::error::Error + Send + Sync>>>,
+        Box<dyn std::error::Error + Send + Sync>,
+    >
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let reason = reason.into();
+        let device_ids: Vec<String> = self
+            .iothub_service
+            .build_query()
+            .select("deviceId")
+            .from("devices")
+            .and_where(condition)
+            .build()?
+            .execute_all_pages::<serde_json::Value>()
+            .await?
+            .iter()
+            .filter_map(|item| item["deviceId"].as_str().map(String::from))
+            .collect();
+
+        let disable = |device_id: String| {
+            let reason = reason.clone();
+            async move {
+                let result = self
+                    .set_device_status(device_id.clone(), Status::Disabled, Some(reason))
+                    .await
+                    .map(|_| ());
+                (device_id, result)
+            }
+        };
+
+        // Every device is disabled once, even when the query returns it twice
+        Ok(
+            for_each_bounded(unique(device_ids), max_concurrency, disable)
+                .await
+                .into_iter()
+                .collect(),
+        )
+    }
+
+    /// Set the status and status reason of a device, retrying when the identity changed in between
+    async fn set_device_status(
+        &self,
+        device_id: String,
+        status: Status,
+        reason: Option<String>,
+    ) -> Result<Response<DeviceIdentity>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut retries = 0;
+        loop {
+            let mut device_identity = self
+                .get_device_identity(device_id.as_str())
+                .await?
+                .into_inner();
+            device_identity.status = Some(status);
+            device_identity.status_reason = reason.clone();
+
+            let if_match = IfMatch::etag(device_identity.etag.as_str());
+            match self
+                .update_device_identity(&device_identity, if_match)
+                .await
+            {
+                Err(err) if retries < STATUS_UPDATE_RETRIES && is_precondition_failed(&*err) => {
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Check whether an error was caused by a mismatching etag
+fn is_precondition_failed(err: &(dyn std::error::Error + 'static)) -> bool {
+    match err.downcast_ref::<IoTHubError>() {
+        Some(error) => error.is_precondition_failed(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+
+    use crate::error::IoTHubError;
+    use crate::registry::{AuthenticationMechanism, SymmetricKey};
+    use crate::registry::{DeviceIdentity, IfMatch, ModuleIdentity};
+    use crate::testing::mocktransport::{error_response, json_response, MockTransport};
+    use crate::transport::HttpRequest;
+    use crate::twin::{AuthenticationType, Status, X509ThumbPrint};
+    use crate::IoTHubService;
+
+    #[test]
+    fn set_parent_should_set_scopes_of_leaf_device(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Updates are answered with the updated identity
+        let transport = MockTransport::new(|request, _| {
+            if request.method() == http::Method::PUT {
+                Ok(http::Response::new(request.body().clone()))
+            } else if request.uri().path().ends_with("parent-device") {
+                json_response(&json!({
+                    "deviceId": "parent-device",
+                    "etag": "cGFyZW50",
+                    "capabilities": { "iotEdge": true },
+                    "deviceScope": "ms-azure-iot-edge://parent-device-1234"
+                }))
+            } else {
+                json_response(&json!({
+                    "deviceId": "child-device",
+                    "etag": "Y2hpbGQ=",
+                    "capabilities": { "iotEdge": false }
+                }))
+            }
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let child = futures::executor::block_on(
+            iothub
+                .registry_manager()
+                .set_parent("child-device", "parent-device"),
+        )?;
+        assert_eq!(
+            child.device_scope.as_deref(),
+            Some("ms-azure-iot-edge://parent-device-1234")
+        );
+        assert_eq!(
+            child.parent_scopes,
+            Some(vec!["ms-azure-iot-edge://parent-device-1234".to_string()])
+        );
+
+        let requests = transport.requests();
+        let updates = requests
+            .iter()
+            .filter(|request| request.method() == http::Method::PUT)
+            .collect::<Vec<_>>();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].headers()["If-Match"], "\"Y2hpbGQ=\"");
+        Ok(())
+    }
+
+    /// Create a transport that returns the identity, twin and modules of a device
+    fn overview_transport() -> MockTransport {
+        MockTransport::new(|request, _| {
+            let body = match request.uri().path() {
+                "/devices/some-device" => json!({
+                    "deviceId": "some-device",
+                    "etag": "aWRlbnRpdHk=",
+                    "status": "enabled",
+                    "authentication": {
+                        "type": "sas",
+                        "symmetricKey": { "primaryKey": "cHJpbWFyeQ==", "secondaryKey": "" }
+                    },
+                    "capabilities": { "iotEdge": true }
+                }),
+                "/twins/some-device" => json!({
+                    "deviceId": "some-device",
+                    "etag": "AAAAAAAAAAE=",
+                    "status": "enabled",
+                    "connectionState": "Connected",
+                    "tags": { "environment": "production" }
+                }),
+                "/devices/some-device/modules" => json!([
+                    { "moduleId": "$edgeAgent", "deviceId": "some-device" },
+                    { "moduleId": "sensor", "deviceId": "some-device" }
+                ]),
+                "/devices/query" => json!([
+                    {
+                        "deviceId": "some-device",
+                        "etag": "AAAAAAAAAAE=",
+                        "status": "enabled",
+                        "connectionState": "Connected",
+                        "authenticationType": "sas"
+                    },
+                    {
+                        "deviceId": "other-device",
+                        "etag": "AAAAAAAAAAE=",
+                        "status": "enabled",
+                        "connectionState": "Disconnected",
+                        "authenticationType": "selfSigned"
+                    }
+                ]),
+                path => panic!("Unexpected request for {}", path),
+            };
+            json_response(&body)
+        })
+    }
+
+    #[test]
+    fn get_device_overview_should_merge_identity_twin_and_modules(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(overview_transport());
+
+        let overview = futures::executor::block_on(
+            iothub.registry_manager().get_device_overview("some-device"),
+        )?;
+        assert_eq!(
+            overview.authentication_type(),
+            Some(AuthenticationType::SAS)
+        );
+        assert!(overview.has_symmetric_keys());
+        assert_eq!(overview.status(), Status::Enabled);
+        assert!(overview.identity.is_edge_device());
+        assert_eq!(overview.twin.tags["environment"], "production");
+        assert_eq!(overview.module_ids(), vec!["$edgeAgent", "sensor"]);
+        Ok(())
+    }
+
+    #[test]
+    fn list_devices_with_twins_should_join_flagged_identities(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(overview_transport());
+
+        let devices =
+            futures::executor::block_on(iothub.registry_manager().list_devices_with_twins(
+                "",
+                |twin| twin.authentication_type == Some(AuthenticationType::SAS),
+                2,
+            ))?;
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].device_id(), "some-device");
+        assert_eq!(devices[0].has_symmetric_keys(), Some(true));
+        assert_eq!(devices[1].device_id(), "other-device");
+        assert_eq!(devices[1].has_symmetric_keys(), None);
+        assert_eq!(
+            devices[1].authentication_type(),
+            Some(AuthenticationType::SelfSigned)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn registry_operations_should_respect_if_match(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Only requests with the etag of the identity or any etag are accepted
+        let transport = MockTransport::new(|request, _| {
+            let if_match = &request.headers()["If-Match"];
+            if if_match != "*" && if_match != "\"Y3VycmVudA==\"" {
+                error_response(412, "PreconditionFailed", "Precondition failed")
+            } else if request.method() == http::Method::DELETE {
+                Ok(http::Response::builder().status(204).body(Vec::new())?)
+            } else {
+                Ok(http::Response::new(request.body().clone()))
+            }
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let registry_manager = iothub.registry_manager();
+
+        let err = futures::executor::block_on(
+            registry_manager.delete_device_identity("some-device", IfMatch::etag("b3V0ZGF0ZWQ=")),
+        )
+        .unwrap_err();
+        assert!(err
+            .downcast_ref::<IoTHubError>()
+            .map(IoTHubError::is_precondition_failed)
+            .unwrap_or(false));
+
+        let response = futures::executor::block_on(
+            registry_manager.delete_device_identity("some-device", IfMatch::etag("Y3VycmVudA==")),
+        )?;
+        assert_eq!(response.metadata().status_code(), 204);
+
+        let module_identity: ModuleIdentity = serde_json::from_value(json!({
+            "moduleId": "some-module",
+            "deviceId": "some-device",
+            "etag": "b3V0ZGF0ZWQ="
+        }))?;
+        let updated = futures::executor::block_on(
+            registry_manager.update_module_identity(&module_identity, IfMatch::Any),
+        )?;
+        assert_eq!(updated.module_id, "some-module");
+
+        let if_match = transport
+            .requests()
+            .iter()
+            .map(|request| request.headers()["If-Match"].to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(if_match, vec!["\"b3V0ZGF0ZWQ=\"", "\"Y3VycmVudA==\"", "*"]);
+        Ok(())
+    }
+
+    #[test]
+    fn list_devices_should_return_typed_twins(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // The queries are answered with the twins of two disabled devices, each on its own page
+        let transport = MockTransport::new(|request, _| {
+            let twin = |device_id: &str| {
+                json!({
+                    "deviceId": device_id,
+                    "etag": "AAAAAAAAAAE=",
+                    "status": "disabled",
+                    "connectionState": "Disconnected",
+                    "capabilities": { "iotEdge": true }
+                })
+            };
+            let response = http::Response::builder().header("x-ms-item-type", "twin");
+            Ok(match request.headers().get("x-ms-continuation") {
+                None => response
+                    .header("x-ms-continuation", "page-2")
+                    .body(serde_json::to_vec(&json!([twin("some-device")]))?)?,
+                Some(_) => response.body(serde_json::to_vec(&json!([twin("other-device")]))?)?,
+            })
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let registry_manager = iothub.registry_manager();
+
+        let edge_devices = futures::executor::block_on(registry_manager.list_edge_devices())?;
+        assert_eq!(edge_devices.len(), 2);
+        assert_eq!(edge_devices[0].device_id, "some-device");
+        assert_eq!(edge_devices[1].device_id, "other-device");
+
+        let disabled_devices =
+            futures::executor::block_on(registry_manager.list_devices_by_status(Status::Disabled))?;
+        assert_eq!(disabled_devices[0].status, Status::Disabled);
+
+        let queries = transport
+            .requests()
+            .iter()
+            .filter(|request| request.headers().get("x-ms-continuation").is_none())
+            .map(|request| {
+                let body: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+                body["query"].as_str().unwrap().to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            queries,
+            vec![
+                "SELECT * FROM devices WHERE capabilities.iotEdge = true",
+                "SELECT * FROM devices WHERE status = 'disabled'"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn device_identity_should_roundtrip() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let identity_json = json!({
+            "deviceId": "some-device",
+            "generationId": "637284443151212386",
+            "etag": "NzY3NTY5NDg2",
+            "connectionState": "Disconnected",
+            "status": "enabled",
+            "statusReason": null,
+            "connectionStateUpdatedTime": "0001-01-01T00:00:00Z",
+            "statusUpdatedTime": "0001-01-01T00:00:00Z",
+            "lastActivityTime": "0001-01-01T00:00:00Z",
+            "cloudToDeviceMessageCount": 0,
+            "authentication": {
+                "symmetricKey": {
+                    "primaryKey": "cHJpbWFyeQ==",
+                    "secondaryKey": "c2Vjb25kYXJ5"
+                },
+                "x509Thumbprint": {
+                    "primaryThumbprint": null,
+                    "secondaryThumbprint": null
+                },
+                "type": "sas"
+            },
+            "capabilities": {
+                "iotEdge": true
+            },
+            "deviceScope": "ms-azure-iot-edge://some-device-637284443151212386",
+            "parentScopes": []
+        });
+
+        let device_identity: DeviceIdentity = serde_json::from_value(identity_json)?;
+        assert!(device_identity.is_edge_device());
+        assert_eq!(device_identity.status, Some(Status::Enabled));
+        let authentication = device_identity.authentication.clone().unwrap_or_default();
+        assert_eq!(
+            authentication.authentication_type,
+            Some(AuthenticationType::SAS)
+        );
+        assert_eq!(
+            authentication.symmetric_key.unwrap_or_default().primary_key,
+            Some("cHJpbWFyeQ==".to_string())
+        );
+
+        assert!(!format!("{:?}", device_identity).contains("cHJpbWFyeQ=="));
+
+        let serialized = serde_json::to_value(&device_identity)?;
+        assert_eq!(serialized["authentication"]["type"], json!("sas"));
+        assert_eq!(
+            serialized["deviceScope"],
+            json!("ms-azure-iot-edge://some-device-637284443151212386")
+        );
+        assert_eq!(
+            serde_json::from_value::<DeviceIdentity>(serialized)?,
+            device_identity
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn device_identity_new_should_only_serialize_device_id(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let serialized = serde_json::to_value(DeviceIdentity::new("some-device"))?;
+        assert_eq!(
+            serialized,
+            json!({
+                "deviceId": "some-device",
+                "cloudToDeviceMessageCount": 0,
+                "capabilities": { "iotEdge": false }
+            })
+        );
+        Ok(())
+    }
+
+    /// Create a transport that stores a single identity, which is only updated with its
+    /// current etag
+    fn rotation_transport(identity: serde_json::Value) -> MockTransport {
+        MockTransport::new(move |request, earlier| {
+            if request.uri().path().starts_with("/twins/") {
+                let twin = include_bytes!(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/resources/test/twin/devicetwin_deserialization.json"
+                ));
+                return Ok(http::Response::new(twin.to_vec()));
+            }
+
+            // The identity is the body of the last update, which gets a new etag
+            let updates = identity_updates(earlier);
+            let mut identity = updates.last().cloned().unwrap_or_else(|| identity.clone());
+            if request.method() == http::Method::PUT {
+                let etag = format!("\"{}\"", identity["etag"].as_str().unwrap());
+                assert_eq!(request.headers()["If-Match"], etag.as_str());
+                identity = serde_json::from_slice(request.body())?;
+                identity["etag"] = json!(format!("etag-{}", updates.len() + 1));
+            }
+            json_response(&identity)
+        })
+    }
+
+    /// Get the identities the requests update, with the etags the transport gives them
+    fn identity_updates(requests: &[HttpRequest]) -> Vec<serde_json::Value> {
+        requests
+            .iter()
+            .filter(|request| {
+                request.method() == http::Method::PUT
+                    && !request.uri().path().starts_with("/twins/")
+            })
+            .enumerate()
+            .map(|(index, request)| {
+                let mut identity: serde_json::Value =
+                    serde_json::from_slice(request.body()).unwrap();
+                identity["etag"] = json!(format!("etag-{}", index + 1));
+                identity
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rotate_module_keys_should_swap_in_new_key(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let transport = rotation_transport(json!({
+            "moduleId": "some-module",
+            "deviceId": "some-device",
+            "etag": "MQ==",
+            "authentication": {
+                "type": "sas",
+                "symmetricKey": { "primaryKey": "b2xkLXByaW1hcnk=", "secondaryKey": "b2xkLXNlY29uZGFyeQ==" }
+            }
+        }));
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let keys = futures::executor::block_on(
+            iothub
+                .registry_manager()
+                .rotate_module_keys("some-device", "some-module"),
+        )?
+        .into_inner();
+        assert_eq!(keys.secondary_key.as_deref(), Some("b2xkLXByaW1hcnk="));
+        let primary_key = keys.primary_key.unwrap();
+        assert_eq!(base64::decode(&primary_key)?.len(), 32);
+        assert_ne!(primary_key, "b2xkLXNlY29uZGFyeQ==");
+
+        let identity = identity_updates(&transport.requests()).pop().unwrap();
+        let module_identity: ModuleIdentity = serde_json::from_value(identity)?;
+        assert_eq!(
+            module_identity.authentication,
+            Some(AuthenticationMechanism {
+                authentication_type: Some(AuthenticationType::SAS),
+                symmetric_key: Some(SymmetricKey {
+                    primary_key: Some(primary_key),
+                    secondary_key: Some("b2xkLXByaW1hcnk=".to_string()),
+                }),
+                x509_thumbprint: None,
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_device_keys_with_tag_should_stamp_rotation_time(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let transport = rotation_transport(json!({
+            "deviceId": "some-device",
+            "etag": "MQ==",
+            "authentication": {
+                "type": "sas",
+                "symmetricKey": { "primaryKey": "b2xkLXByaW1hcnk=", "secondaryKey": "b2xkLXNlY29uZGFyeQ==" }
+            }
+        }));
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let keys = futures::executor::block_on(
+            iothub
+                .registry_manager()
+                .rotate_device_keys_with_tag("some-device", "keysRotatedAt"),
+        )?;
+        assert_eq!(keys.secondary_key.as_deref(), Some("b2xkLXByaW1hcnk="));
+        let requests = transport.requests();
+        let identity = identity_updates(&requests).pop().unwrap();
+        assert_eq!(
+            identity["authentication"]["symmetricKey"]["primaryKey"],
+            json!(keys.primary_key)
+        );
+
+        let twin_patches = requests
+            .iter()
+            .filter(|request| request.uri().path().starts_with("/twins/"))
+            .collect::<Vec<_>>();
+        assert_eq!(twin_patches.len(), 1);
+        let patch: serde_json::Value = serde_json::from_slice(twin_patches[0].body())?;
+        let rotated_at = patch["tags"]["keysRotatedAt"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(rotated_at).is_ok());
+
+        let iothub = IoTHubService::from_sas_token("test", "test").with_transport(
+            rotation_transport(json!({
+                "deviceId": "x509-device",
+                "etag": "MQ==",
+                "authentication": { "type": "selfSigned" }
+            })),
+        );
+        assert!(futures::executor::block_on(
+            iothub.registry_manager().rotate_device_keys("x509-device")
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn update_x509_thumbprints_should_normalize_thumbprints(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let iothub = IoTHubService::from_sas_token("test", "test").with_transport(
+            rotation_transport(json!({
+                "deviceId": "x509-device",
+                "etag": "MQ==",
+                "authentication": { "type": "selfSigned" }
+            })),
+        );
+
+        let thumbprints = X509ThumbPrint::new(
+            "2f:d4:c5:1a:2c:3e:09:e1:30:b3:1c:51:b6:c0:1f:4c:0e:7a:99:e0",
+            Some("8D6B4F6E2A0C3D1B9E7F5A4C2B0D8E6F4A2C0B1D"),
+        )?;
+        let device_identity = futures::executor::block_on(
+            iothub
+                .registry_manager()
+                .update_x509_thumbprints("x509-device", thumbprints),
+        )?;
+        let thumbprints = device_identity
+            .authentication
+            .as_ref()
+            .and_then(|authentication| authentication.x509_thumbprint.as_ref())
+            .unwrap();
+        assert_eq!(
+            thumbprints.primary_thumbprint.as_deref(),
+            Some("2FD4C51A2C3E09E130B31C51B6C01F4C0E7A99E0")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn disable_device_should_set_status_and_retry_on_conflicts(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let identities = Arc::new(Mutex::new(std::collections::HashMap::new()));
+        for device_id in &["busy-device", "other-device"] {
+            identities.lock().unwrap().insert(
+                device_id.to_string(),
+                json!({ "deviceId": device_id, "etag": "MQ==", "status": "enabled" }),
+            );
+        }
+        // The first update of `busy-device` fails with a mismatching etag, and queries are
+        // answered with all stored devices, one device per page
+        let stored = identities.clone();
+        let transport = MockTransport::new(move |request, earlier| {
+            let mut identities = stored.lock().unwrap();
+            if request.method() == http::Method::POST {
+                let mut device_ids: Vec<_> = identities.keys().cloned().collect();
+                device_ids.sort();
+                let page: usize = match request.headers().get("x-ms-continuation") {
+                    Some(continuation) => continuation.to_str()?.parse()?,
+                    None => 0,
+                };
+                let mut response = http::Response::builder();
+                if page + 1 < device_ids.len() {
+                    response = response.header("x-ms-continuation", (page + 1).to_string());
+                }
+                return Ok(response.body(serde_json::to_vec(
+                    &json!([{ "deviceId": device_ids[page] }]),
+                )?)?);
+            }
+
+            let device_id = request.uri().path().trim_start_matches("/devices/");
+            let identity = identities.get_mut(device_id).unwrap();
+            if request.method() == http::Method::PUT {
+                let first_update = !earlier
+                    .iter()
+                    .any(|request| request.method() == http::Method::PUT);
+                if device_id == "busy-device" && first_update {
+                    identity["etag"] = json!("Mg==");
+                }
+                let if_match = request.headers()["If-Match"].to_str()?;
+                if if_match.trim_matches('"') != identity["etag"] {
+                    return error_response(412, "PreconditionFailed", "Precondition failed");
+                }
+                *identity = serde_json::from_slice(request.body())?;
+            }
+            json_response(identity)
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let registry_manager = iothub.registry_manager();
+
+        let device_identity = futures::executor::block_on(
+            registry_manager.disable_device("busy-device", "Credentials leaked"),
+        )?;
+        assert_eq!(device_identity.status, Some(Status::Disabled));
+        assert_eq!(
+            device_identity.status_reason.as_deref(),
+            Some("Credentials leaked")
+        );
+        let updates = transport
+            .requests()
+            .iter()
+            .filter(|request| request.method() == http::Method::PUT)
+            .count();
+        assert_eq!(updates, 2);
+
+        let device_identity =
+            futures::executor::block_on(registry_manager.enable_device("busy-device"))?;
+        assert_eq!(device_identity.status, Some(Status::Enabled));
+        assert!(device_identity.status_reason.is_none());
+
+        let results = futures::executor::block_on(registry_manager.disable_devices_matching(
+            "tags.batch = 'B42'",
+            "Recalled",
+            1,
+        ))?;
+        // The devices are returned on separate pages, so both pages must have been read
+        assert_eq!(results.len(), 2);
+        assert!(results["other-device"].is_ok());
+        assert!(results.values().all(|result| result.is_ok()));
+        assert!(identities
+            .lock()
+            .unwrap()
+            .values()
+            .all(|identity| identity["status"] == "disabled"
+                && identity["statusReason"] == "Recalled"));
+        Ok(())
+    }
+
+    #[test]
+    fn provision_from_dir_should_create_devices_and_report_failures(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Creating `existing-device` fails, other identities are created
+        let transport = MockTransport::new(|request, _| {
+            let identity: serde_json::Value = serde_json::from_slice(request.body())?;
+            if identity["deviceId"] == "existing-device" {
+                return error_response(
+                    409,
+                    "DeviceAlreadyExists",
+                    "A device with this id already exists",
+                );
+            }
+            Ok(http::Response::new(request.body().clone()))
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let registry_manager = iothub.registry_manager();
+
+        let mut directory = std::env::temp_dir();
+        directory.push(format!(
+            "azure-iothub-service-provisioning-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&directory)?;
+        let write = |name: &str, contents: &str| std::fs::write(directory.join(name), contents);
+        write("a.json", r#"{ "deviceId": "sensor-1" }"#)?;
+        write("b.json", r#"{ "deviceId": "existing-device" }"#)?;
+        write(
+            "c.json",
+            r#"{ "deviceId": "gateway-1", "capabilities": { "iotEdge": true } }"#,
+        )?;
+        write("README.md", "Not a device")?;
+
+        let report =
+            futures::executor::block_on(registry_manager.provision_from_dir(&directory, 2));
+
+        write("d.json", r#"{ "deviceId": "sensor-1" }"#)?;
+        let duplicate =
+            futures::executor::block_on(registry_manager.provision_from_dir(&directory, 2));
+        std::fs::remove_file(directory.join("d.json"))?;
+        write(
+            "d.json",
+            r#"{ "deviceId": "camera-1", "authentication": { "type": "selfSigned" } }"#,
+        )?;
+        let invalid =
+            futures::executor::block_on(registry_manager.provision_from_dir(&directory, 2));
+        std::fs::remove_dir_all(&directory)?;
+
+        let report = report?;
+        assert_eq!(report.created(), &vec!["gateway-1", "sensor-1"]);
+        assert_eq!(report.failed().len(), 1);
+        assert!(report.failed()["existing-device"]
+            .to_string()
+            .contains("DeviceAlreadyExists"));
+        assert!(!report.is_success());
+        assert!(report
+            .to_string()
+            .starts_with("2 devices created, 1 failed"));
+
+        assert!(duplicate.unwrap_err().to_string().contains("sensor-1"));
+        assert!(invalid
+            .unwrap_err()
+            .to_string()
+            .contains("no primary thumbprint"));
+        let requests = transport.requests();
+        for request in requests.iter() {
+            assert_eq!(request.method(), http::Method::PUT);
+            assert!(request.headers().get("If-Match").is_none());
+        }
+        assert_eq!(requests.len(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! The response module contains the metadata that the IoT Hub returns
+//! alongside the result of an operation, such as the request id and
+//! throttling information.
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use http::HeaderMap;
+
+use crate::transport::HttpResponse;
+
+/// The header containing the id of the request within the IoT Hub
+pub const REQUEST_ID_HEADER: &str = "x-ms-request-id";
+
+/// The header containing the type of the items returned by a query
+pub const ITEM_TYPE_HEADER: &str = "x-ms-item-type";
+
+/// The header containing the token for requesting the next page of a query
+pub const CONTINUATION_HEADER: &str = "x-ms-continuation";
+
+/// The prefix of the headers containing the remaining quota
+pub const QUOTA_REMAINING_HEADER_PREFIX: &str = "x-ms-ratelimit-remaining-";
+
+/// The ResponseMetadata struct contains the metadata of a response
+/// from the IoT Hub, which can be used to correlate incidents with Azure support.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResponseMetadata {
+    status_code: u16,
+    request_id: Option<String>,
+    quota_remaining: HashMap<String, String>,
+    retry_after: Option<Duration>,
+    item_type: Option<String>,
+    continuation: Option<String>,
+}
+
+impl ResponseMetadata {
+    /// Create the ResponseMetadata from the status code and the headers of a response
+    pub(crate) fn from_parts(status_code: u16, headers: &HeaderMap) -> Self {
+        let header_value =
+            |name: &str| -> Option<String> { headers.get(name)?.to_str().ok().map(String::from) };
+
+        let quota_remaining = headers
+            .iter()
+            .filter_map(|(name, value)| {
+                let name = name.as_str();
+                if !name.starts_with(QUOTA_REMAINING_HEADER_PREFIX) {
+                    return None;
+                }
+                Some((
+                    name[QUOTA_REMAINING_HEADER_PREFIX.len()..].to_string(),
+                    value.to_str().ok()?.to_string(),
+                ))
+            })
+            .collect();
+
+        ResponseMetadata {
+            status_code,
+            request_id: header_value(REQUEST_ID_HEADER),
+            quota_remaining,
+            retry_after: header_value(http::header::RETRY_AFTER.as_str())
+                .and_then(|val| parse_retry_after(&val)),
+            item_type: header_value(ITEM_TYPE_HEADER),
+            continuation: header_value(CONTINUATION_HEADER),
+        }
+    }
+
+    /// Create the ResponseMetadata from a response of the IoT Hub
+    pub(crate) fn from_response(response: &HttpResponse) -> Self {
+        Self::from_parts(response.status().as_u16(), response.headers())
+    }
+
+    /// Get the HTTP status code of the response
+    pub fn status_code(&self) -> u16 {
+        self.status_code
+    }
+
+    /// Get the id of the request, as returned in the `x-ms-request-id` header
+    pub fn request_id(&self) -> &Option<String> {
+        &self.request_id
+    }
+
+    /// Get the remaining quota, as returned in the `x-ms-ratelimit-remaining-*` headers
+    ///
+    /// The keys are the header names without the `x-ms-ratelimit-remaining-` prefix.
+    pub fn quota_remaining(&self) -> &HashMap<String, String> {
+        &self.quota_remaining
+    }
+
+    /// Get the time to wait before retrying, as returned in the `Retry-After` header
+    pub fn retry_after(&self) -> &Option<Duration> {
+        &self.retry_after
+    }
+
+    /// Get the type of the items returned by a query, as returned in the `x-ms-item-type` header
+    ///
+    /// The IoT Hub returns `twin`, `deviceJob`, `jobResponse` or `raw` for queries.
+    pub fn item_type(&self) -> &Option<String> {
+        &self.item_type
+    }
+
+    /// Get the continuation token of a query, as returned in the `x-ms-continuation` header
+    ///
+    /// The token is only returned when the query has more results than fit in the response.
+    pub fn continuation(&self) -> &Option<String> {
+        &self.continuation
+    }
+}
+
+/// Parse the value of a Retry-After header, which is either
+/// a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    let seconds = date.with_timezone(&Utc).timestamp() - Utc::now().timestamp();
+    Some(Duration::from_secs(seconds.max(0) as u64))
+}
+
+/// The Response struct contains the result of an operation on the IoT Hub
+/// together with the metadata of the response.
+///
+/// The Response dereferences to the result, so the fields and methods
+/// of the result can be used directly.
+#[derive(Debug, Clone)]
+pub struct Response<T> {
+    metadata: ResponseMetadata,
+    value: T,
+}
+
+impl<T> Response<T> {
+    /// Create a new Response
+    pub(crate) fn new(metadata: ResponseMetadata, value: T) -> Self {
+        Response { metadata, value }
+    }
+
+    /// Get the metadata of the response
+    pub fn metadata(&self) -> &ResponseMetadata {
+        &self.metadata
+    }
+
+    /// Get the result of the operation
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    /// Split the response into the metadata and the result of the operation
+    pub fn into_parts(self) -> (ResponseMetadata, T) {
+        (self.metadata, self.value)
+    }
+}
+
+impl<T> Deref for Response<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Response<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::HeaderMap;
+
+    use crate::response::ResponseMetadata;
+
+    #[test]
+    fn response_metadata_should_parse_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ms-request-id", "some-request-id".parse().unwrap());
+        headers.insert("x-ms-ratelimit-remaining-requests", "42".parse().unwrap());
+        headers.insert("retry-after", "10".parse().unwrap());
+        headers.insert("x-ms-item-type", "twin".parse().unwrap());
+        headers.insert("x-ms-continuation", "some-token".parse().unwrap());
+
+        let metadata = ResponseMetadata::from_parts(429, &headers);
+        assert_eq!(metadata.status_code(), 429);
+        assert_eq!(metadata.request_id().as_deref(), Some("some-request-id"));
+        assert_eq!(
+            metadata
+                .quota_remaining()
+                .get("requests")
+                .map(String::as_str),
+            Some("42")
+        );
+        assert_eq!(*metadata.retry_after(), Some(Duration::from_secs(10)));
+        assert_eq!(metadata.item_type().as_deref(), Some("twin"));
+        assert_eq!(metadata.continuation().as_deref(), Some("some-token"));
+    }
+
+    #[test]
+    fn response_metadata_should_parse_retry_after_date() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "retry-after",
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+
+        let metadata = ResponseMetadata::from_parts(503, &headers);
+        assert_eq!(*metadata.retry_after(), Some(Duration::from_secs(0)));
+        assert_eq!(*metadata.request_id(), None);
+    }
+}
@@ -0,0 +1,292 @@
+//! The tagimport module reads the tags of many devices from CSV or JSON, so the
+//! tags can be applied to the device twins with [`TwinManager::import_tags`].
+//!
+//! [`TwinManager::import_tags`]: crate::twin::TwinManager::import_tags
+use std::collections::BTreeMap;
+use std::io::Read;
+
+/// The name of the CSV column that contains the device ids
+const DEVICE_ID_COLUMN: &str = "deviceId";
+
+/// The tags to apply to each device
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::tagimport::TagImport;
+///
+/// let csv = "deviceId,environment,location.building\nsensor-1,production,43\nsensor-2,test,\n";
+/// let import = TagImport::from_csv(csv.as_bytes()).expect("Invalid CSV");
+/// assert_eq!(import.tags("sensor-1").unwrap()["location"]["building"], "43");
+/// assert!(import.tags("sensor-2").unwrap().get("location").is_none());
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TagImport {
+    devices: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
+}
+
+impl TagImport {
+    /// Read the tags from CSV with a header row
+    ///
+    /// The `deviceId` column contains the device ids and every other column is a tag,
+    /// where a column named `location.building` sets the `building` of the `location` tag.
+    /// The values are set as strings and empty values are skipped.
+    pub fn from_csv<R>(mut reader: R) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        R: Read,
+    {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents)?;
+        let mut records = parse_csv(&contents)?.into_iter();
+
+        let header = records.next().unwrap_or_default();
+        let device_id_column = header
+            .iter()
+            .position(|column| column.trim() == DEVICE_ID_COLUMN)
+            .ok_or_else(|| invalid_data(format!("Missing the {} column", DEVICE_ID_COLUMN)))?;
+
+        let mut import = TagImport::default();
+        for (line, record) in records.enumerate() {
+            // Skip empty lines, such as a trailing line
+            if record.iter().all(|field| field.trim().is_empty()) {
+                continue;
+            }
+            if record.len() != header.len() {
+                return Err(invalid_data(format!(
+                    "Record {} has {} fields instead of {}",
+                    line + 1,
+                    record.len(),
+                    header.len()
+                )));
+            }
+
+            let device_id = record[device_id_column].trim();
+            if device_id.is_empty() {
+                return Err(invalid_data(format!(
+                    "Record {} has no device id",
+                    line + 1
+                )));
+            }
+            let tags = import.devices.entry(device_id.to_string()).or_default();
+            for (column, value) in header.iter().zip(record.iter()) {
+                if column.trim() == DEVICE_ID_COLUMN || value.is_empty() {
+                    continue;
+                }
+                set_tag(tags, column.trim(), serde_json::Value::from(value.as_str()))?;
+            }
+        }
+        Ok(import)
+    }
+
+    /// Read the tags from a JSON object, which maps the device ids to the tags of each device
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::tagimport::TagImport;
+    ///
+    /// let json = r#"{ "sensor-1": { "environment": "production", "floor": 3 } }"#;
+    /// let import = TagImport::from_json(json.as_bytes()).expect("Invalid JSON");
+    /// assert_eq!(import.tags("sensor-1").unwrap()["floor"], 3);
+    /// ```
+    pub fn from_json<R>(reader: R) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        R: Read,
+    {
+        Ok(TagImport {
+            devices: serde_json::from_reader(reader)?,
+        })
+    }
+
+    /// Get the ids of the devices, ordered by id
+    pub fn device_ids(&self) -> Vec<&String> {
+        self.devices.keys().collect()
+    }
+
+    /// Get the tags to apply to a device
+    pub fn tags(&self, device_id: &str) -> Option<&serde_json::Map<String, serde_json::Value>> {
+        self.devices.get(device_id)
+    }
+
+    /// Get the number of devices
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Check whether there are no devices
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
+
+/// The change of the tags of a single device by an import
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagChange {
+    changed_tags: Vec<String>,
+    applied: bool,
+}
+
+impl TagChange {
+    pub(crate) fn new(changed_tags: Vec<String>, applied: bool) -> Self {
+        TagChange {
+            changed_tags,
+            applied,
+        }
+    }
+
+    /// Get the names of the tags that are added or changed, ordered by name
+    pub fn changed_tags(&self) -> &Vec<String> {
+        &self.changed_tags
+    }
+
+    /// Check whether the tags of the device already matched the import
+    pub fn is_unchanged(&self) -> bool {
+        self.changed_tags.is_empty()
+    }
+
+    /// Check whether the changes were applied, which is not the case for a dry run
+    pub fn is_applied(&self) -> bool {
+        self.applied
+    }
+}
+
+/// Get the tags of the import that would change the current tags of a device
+///
+/// The IoT Hub merges the objects of a tag when it is patched, so an object tag
+/// is unchanged when all of its imported fields already have the same value.
+pub(crate) fn changed_tags(
+    current: &serde_json::Map<String, serde_json::Value>,
+    imported: &serde_json::Map<String, serde_json::Value>,
+) -> serde_json::Map<String, serde_json::Value> {
+    imported
+        .iter()
+        .filter(|(name, value)| match current.get(*name) {
+            Some(current_value) => !contains(current_value, value),
+            None => true,
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Check whether a value already contains all fields of another value
+fn contains(current: &serde_json::Value, imported: &serde_json::Value) -> bool {
+    match (current, imported) {
+        (serde_json::Value::Object(current), serde_json::Value::Object(imported)) => imported
+            .iter()
+            .all(|(name, value)| match current.get(name) {
+                Some(current_value) => contains(current_value, value),
+                None => false,
+            }),
+        (current, imported) => current == imported,
+    }
+}
+
+/// Set a tag at a path separated by dots, creating the objects along the path
+fn set_tag(
+    tags: &mut serde_json::Map<String, serde_json::Value>,
+    path: &str,
+    value: serde_json::Value,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if path.split('.').any(str::is_empty) {
+        return Err(invalid_data(format!("Invalid tag path {:?}", path)));
+    }
+
+    let mut names = path.split('.').peekable();
+    let mut object = tags;
+    while let Some(name) = names.next() {
+        if names.peek().is_none() {
+            object.insert(name.to_string(), value);
+            break;
+        }
+        let entry = object
+            .entry(name.to_string())
+            .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        object = entry
+            .as_object_mut()
+            .ok_or_else(|| invalid_data(format!("The tag {} is not an object", name)))?;
+    }
+    Ok(())
+}
+
+/// Parse CSV into records of fields
+///
+/// Fields can be quoted with `"`, in which case they can contain separators,
+/// line breaks and quotes escaped as `""`.
+fn parse_csv(contents: &str) -> Result<Vec<Vec<String>>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = contents.chars().peekable();
+    while let Some(character) = chars.next() {
+        match character {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            character => field.push(character),
+        }
+    }
+    if quoted {
+        return Err(invalid_data("Unterminated quoted field".to_string()));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Create the error for invalid import data
+fn invalid_data(message: String) -> Box<dyn std::error::Error + Send + Sync> {
+    Box::new(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        message,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{changed_tags, parse_csv, TagImport};
+
+    #[test]
+    fn csv_should_be_parsed_with_quotes() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let records = parse_csv("a,\"b,c\",\"say \"\"hi\"\"\"\r\n\"multi\nline\",,x")?;
+        assert_eq!(
+            records,
+            vec![vec!["a", "b,c", "say \"hi\""], vec!["multi\nline", "", "x"]]
+        );
+        assert!(parse_csv("\"open").is_err());
+
+        assert!(TagImport::from_csv("name,site\nsensor-1,a\n".as_bytes()).is_err());
+        assert!(TagImport::from_csv("deviceId,site\nsensor-1\n".as_bytes()).is_err());
+        assert!(TagImport::from_csv("deviceId,site..name\nsensor-1,a\n".as_bytes()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn changed_tags_should_ignore_matching_tags() {
+        let current = json!({
+            "environment": "production",
+            "location": { "building": "43", "floor": 3 }
+        });
+        let imported = json!({
+            "environment": "production",
+            "location": { "building": "43" },
+            "site": "north"
+        });
+        let changed = changed_tags(current.as_object().unwrap(), imported.as_object().unwrap());
+        assert_eq!(json!(changed), json!({ "site": "north" }));
+    }
+}
@@ -0,0 +1,524 @@
+//! The mockhub module contains an in-memory IoT Hub, which can be used for
+//! testing applications without a live IoT Hub.
+//!
+//! The [`MockHub`] implements the [`Transport`] trait. It stores device and
+//! module twins, records the direct methods that are invoked and answers with
+//! the responses that are scripted by the test.
+//!
+//! # Example
+//! ```
+//! # fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//! use azure_iothub_service::testing::MockHub;
+//! use azure_iothub_service::twin::DesiredTwinBuilder;
+//! use serde_json::json;
+//!
+//! let hub = MockHub::new();
+//! hub.add_device("some-device");
+//! hub.set_method_response("reboot", 200, json!({ "rebooting": true }));
+//!
+//! let iothub = hub.service();
+//! futures::executor::block_on(async {
+//!     iothub
+//!         .twin_manager()
+//!         .update_device_twin(
+//!             "some-device",
+//!             DesiredTwinBuilder::new()
+//!                 .properties(json!({ "telemetryInterval": 60 }))
+//!                 .build(),
+//!         )
+//!         .await?;
+//!     iothub
+//!         .create_device_method("some-device", "reboot", 30, 30)?
+//!         .invoke_raw(json!({}))
+//!         .await?;
+//!     Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+//! })?;
+//!
+//! let device_twin = hub.device_twin("some-device").unwrap();
+//! assert_eq!(device_twin.properties.desired["telemetryInterval"], 60);
+//! assert_eq!(hub.method_invocations()[0].method_name, "reboot");
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use http::{Method, StatusCode};
+use serde_json::json;
+
+use crate::transport::{HttpRequest, HttpResponse, Transport, TransportFuture};
+use crate::twin::{DeviceTwin, ModuleTwin};
+use crate::IoTHubService;
+
+/// A direct method that was invoked on the MockHub
+#[derive(Debug, Clone, PartialEq)]
+pub struct MethodInvocation {
+    pub device_id: String,
+    pub module_id: Option<String>,
+    pub method_name: String,
+    pub payload: serde_json::Value,
+}
+
+/// A response that is returned once for the first request matching its method and path
+struct ScriptedResponse {
+    method: Method,
+    path: String,
+    status: u16,
+    body: serde_json::Value,
+}
+
+#[derive(Default)]
+struct MockHubState {
+    twins: HashMap<(String, Option<String>), serde_json::Value>,
+    method_responses: HashMap<String, (u64, serde_json::Value)>,
+    method_invocations: Vec<MethodInvocation>,
+    scripted_responses: Vec<ScriptedResponse>,
+}
+
+/// An in-memory IoT Hub for tests
+///
+/// The MockHub is cheap to clone, and all clones share the same state, so the
+/// test can inspect the hub after handing a clone to the IoTHubService.
+#[derive(Clone, Default)]
+pub struct MockHub {
+    state: Arc<Mutex<MockHubState>>,
+}
+
+impl MockHub {
+    /// Create an empty MockHub
+    pub fn new() -> Self {
+        MockHub::default()
+    }
+
+    /// Create an IoTHubService that sends its requests to this MockHub
+    pub fn service(&self) -> IoTHubService {
+        IoTHubService::from_sas_token("mock-hub", "mock-sas-token").with_transport(self.clone())
+    }
+
+    /// Add a device with an empty twin, unless the device already exists
+    pub fn add_device<S>(&self, device_id: S)
+    where
+        S: Into<String>,
+    {
+        let device_id = device_id.into();
+        let twin = empty_twin(&device_id, None);
+        self.state().twins.entry((device_id, None)).or_insert(twin);
+    }
+
+    /// Add a module with an empty twin, unless the module already exists
+    pub fn add_module<S, T>(&self, device_id: S, module_id: T)
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let device_id = device_id.into();
+        let module_id = module_id.into();
+        let twin = empty_twin(&device_id, Some(&module_id));
+        self.state()
+            .twins
+            .entry((device_id, Some(module_id)))
+            .or_insert(twin);
+    }
+
+    /// Get the current twin of a device
+    pub fn device_twin<S>(&self, device_id: S) -> Option<DeviceTwin>
+    where
+        S: Into<String>,
+    {
+        self.state()
+            .twins
+            .get(&(device_id.into(), None))
+            .and_then(|twin| serde_json::from_value(twin.clone()).ok())
+    }
+
+    /// Get the current twin of a module
+    pub fn module_twin<S, T>(&self, device_id: S, module_id: T) -> Option<ModuleTwin>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.state()
+            .twins
+            .get(&(device_id.into(), Some(module_id.into())))
+            .and_then(|twin| serde_json::from_value(twin.clone()).ok())
+    }
+
+    /// Merge properties into the reported properties of a device, as if they were reported by the device
+    pub fn report_device_properties<S>(&self, device_id: S, properties: serde_json::Value)
+    where
+        S: Into<String>,
+    {
+        self.report_properties((device_id.into(), None), properties);
+    }
+
+    /// Merge properties into the reported properties of a module, as if they were reported by the module
+    pub fn report_module_properties<S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+        properties: serde_json::Value,
+    ) where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        self.report_properties((device_id.into(), Some(module_id.into())), properties);
+    }
+
+    /// Set the status and payload that are returned when a direct method with this name is invoked
+    ///
+    /// Methods without a response fail as if the device is not online.
+    pub fn set_method_response<S>(&self, method_name: S, status: u64, payload: serde_json::Value)
+    where
+        S: Into<String>,
+    {
+        self.state()
+            .method_responses
+            .insert(method_name.into(), (status, payload));
+    }
+
+    /// Get the direct methods that were invoked, in the order they were invoked
+    pub fn method_invocations(&self) -> Vec<MethodInvocation> {
+        self.state().method_invocations.clone()
+    }
+
+    /// Return a response once for the first request with this method and path
+    ///
+    /// Scripted responses take precedence over the twins and direct methods of the MockHub,
+    /// so they can be used for injecting failures, or for answering requests the MockHub
+    /// does not implement, such as queries.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::testing::MockHub;
+    /// use serde_json::json;
+    ///
+    /// let hub = MockHub::new();
+    /// hub.push_response(
+    ///     http::Method::POST,
+    ///     "/devices/query",
+    ///     200,
+    ///     json!([{ "deviceId": "some-device" }]),
+    /// );
+    /// ```
+    pub fn push_response<S>(&self, method: Method, path: S, status: u16, body: serde_json::Value)
+    where
+        S: Into<String>,
+    {
+        self.state().scripted_responses.push(ScriptedResponse {
+            method,
+            path: path.into(),
+            status,
+            body,
+        });
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockHubState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn report_properties(&self, key: (String, Option<String>), properties: serde_json::Value) {
+        let mut state = self.state();
+        let twin = state
+            .twins
+            .entry(key.clone())
+            .or_insert_with(|| empty_twin(&key.0, key.1.as_deref()));
+        merge(&mut twin["properties"]["reported"], &properties);
+        increment_version(&mut twin["properties"]["reported"]["$version"]);
+        increment_version(&mut twin["version"]);
+        update_etag(twin);
+    }
+
+    /// Handle a request, returning the status and body of the response
+    fn handle(&self, request: &HttpRequest) -> (u16, serde_json::Value) {
+        let mut state = self.state();
+        let path = request.uri().path();
+        if let Some(index) = state
+            .scripted_responses
+            .iter()
+            .position(|scripted| scripted.method == request.method() && scripted.path == path)
+        {
+            let scripted = state.scripted_responses.remove(index);
+            return (scripted.status, scripted.body);
+        }
+
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let (key, is_method) = match segments.as_slice() {
+            ["twins", device_id] => ((device_id.to_string(), None), false),
+            ["twins", device_id, "methods"] => ((device_id.to_string(), None), true),
+            ["twins", device_id, "modules", module_id] => {
+                ((device_id.to_string(), Some(module_id.to_string())), false)
+            }
+            ["twins", device_id, "modules", module_id, "methods"] => {
+                ((device_id.to_string(), Some(module_id.to_string())), true)
+            }
+            _ => return not_found("NotFound", "The MockHub does not implement this request"),
+        };
+
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body()).unwrap_or(serde_json::Value::Null);
+        if is_method && request.method() == Method::POST {
+            return state.invoke_method(key, body);
+        }
+
+        let twin = match state.twins.get_mut(&key) {
+            Some(twin) => twin,
+            None if key.1.is_some() => {
+                return not_found("ModuleNotFound", "The module does not exist")
+            }
+            None => return not_found("DeviceNotFound", "The device does not exist"),
+        };
+
+        if let Some(if_match) = request.headers().get("If-Match") {
+            let if_match = if_match.to_str().unwrap_or_default().trim_matches('"');
+            if if_match != "*" && Some(if_match) != twin["etag"].as_str() {
+                return (
+                    StatusCode::PRECONDITION_FAILED.as_u16(),
+                    json!({ "Message": "ErrorCode:PreconditionFailed;Precondition failed" }),
+                );
+            }
+        }
+
+        match *request.method() {
+            Method::GET => {}
+            Method::PATCH => {
+                if let Some(tags) = body.get("tags") {
+                    merge(&mut twin["tags"], tags);
+                }
+                if let Some(desired) = body.pointer("/properties/desired") {
+                    merge(&mut twin["properties"]["desired"], desired);
+                    increment_version(&mut twin["properties"]["desired"]["$version"]);
+                }
+                increment_version(&mut twin["version"]);
+                update_etag(twin);
+            }
+            Method::PUT => {
+                twin["tags"] = body.get("tags").cloned().unwrap_or_else(|| json!({}));
+                let desired_version = twin["properties"]["desired"]["$version"].clone();
+                twin["properties"]["desired"] = body
+                    .pointer("/properties/desired")
+                    .cloned()
+                    .unwrap_or_else(|| json!({}));
+                twin["properties"]["desired"]["$version"] = desired_version;
+                increment_version(&mut twin["properties"]["desired"]["$version"]);
+                increment_version(&mut twin["version"]);
+                update_etag(twin);
+            }
+            _ => {
+                return (
+                    StatusCode::METHOD_NOT_ALLOWED.as_u16(),
+                    json!({ "Message": "ErrorCode:MethodNotAllowed;The method is not allowed" }),
+                )
+            }
+        }
+
+        (StatusCode::OK.as_u16(), twin.clone())
+    }
+}
+
+impl MockHubState {
+    fn invoke_method(
+        &mut self,
+        (device_id, module_id): (String, Option<String>),
+        body: serde_json::Value,
+    ) -> (u16, serde_json::Value) {
+        let method_name = body["methodName"].as_str().unwrap_or_default().to_string();
+        self.method_invocations.push(MethodInvocation {
+            device_id,
+            module_id,
+            method_name: method_name.clone(),
+            payload: body["payload"].clone(),
+        });
+
+        match self.method_responses.get(&method_name) {
+            Some((status, payload)) => (
+                StatusCode::OK.as_u16(),
+                json!({ "status": status, "payload": payload }),
+            ),
+            None => not_found("DeviceNotOnline", "Timed out waiting for device to connect"),
+        }
+    }
+}
+
+impl Transport for MockHub {
+    fn send(&self, request: HttpRequest) -> TransportFuture<'_> {
+        let (status, body) = self.handle(&request);
+        Box::pin(async move {
+            let response: HttpResponse = http::Response::builder()
+                .status(status)
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_vec(&body)?)?;
+            Ok(response)
+        })
+    }
+}
+
+/// Create the twin of a device or module that was just added
+fn empty_twin(device_id: &str, module_id: Option<&str>) -> serde_json::Value {
+    let mut twin = json!({
+        "deviceId": device_id,
+        "etag": "",
+        "status": "enabled",
+        "connectionState": "Disconnected",
+        "version": 1,
+        "tags": {},
+        "properties": {
+            "desired": { "$version": 1 },
+            "reported": { "$version": 1 }
+        }
+    });
+    if let Some(module_id) = module_id {
+        twin["moduleId"] = json!(module_id);
+    }
+    update_etag(&mut twin);
+    twin
+}
+
+/// Apply a JSON merge patch, in which null values remove properties
+fn merge(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    match patch {
+        serde_json::Value::Object(patch) => {
+            if !target.is_object() {
+                *target = json!({});
+            }
+            let target = target.as_object_mut().unwrap();
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge(target.entry(key.clone()).or_insert(json!(null)), value);
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
+fn increment_version(version: &mut serde_json::Value) {
+    *version = json!(version.as_i64().unwrap_or_default() + 1);
+}
+
+/// Derive the etag of a twin from its version, like the IoT Hub does
+fn update_etag(twin: &mut serde_json::Value) {
+    let version = twin["version"].as_i64().unwrap_or_default();
+    twin["etag"] = json!(base64::encode(version.to_be_bytes()));
+}
+
+fn not_found(error_code: &str, message: &str) -> (u16, serde_json::Value) {
+    (
+        StatusCode::NOT_FOUND.as_u16(),
+        json!({ "Message": format!("ErrorCode:{};{}", error_code, message) }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::testing::MockHub;
+    use crate::twin::{DesiredTwinBuilder, FullTwinContent};
+
+    #[test]
+    fn mock_hub_should_update_twins() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = MockHub::new();
+        hub.add_device("some-device");
+        hub.add_module("some-device", "some-module");
+        hub.report_module_properties("some-device", "some-module", json!({ "threshold": 3 }));
+        let twin_manager = hub.service().twin_manager();
+
+        let device_twin = futures::executor::block_on(
+            twin_manager.update_device_twin(
+                "some-device",
+                DesiredTwinBuilder::new()
+                    .add_tag("environment", "production")
+                    .properties(json!({ "telemetryInterval": 60 }))
+                    .build(),
+            ),
+        )?;
+        assert_eq!(device_twin.tags["environment"], "production");
+        assert_eq!(device_twin.properties.desired["telemetryInterval"], 60);
+        assert_eq!(device_twin.properties.desired["$version"], 2);
+
+        let module_twin = futures::executor::block_on(twin_manager.modify_module_twin(
+            "some-device",
+            "some-module",
+            1,
+            |twin| {
+                DesiredTwinBuilder::new()
+                    .properties(json!({ "threshold": twin.properties.reported["threshold"] }))
+                    .build()
+            },
+        ))?;
+        assert_eq!(module_twin.properties.desired["threshold"], 3);
+
+        futures::executor::block_on(twin_manager.replace_device_twin(
+            "some-device",
+            &FullTwinContent::new(Default::default(), json!({ "logLevel": "debug" })),
+        ))?;
+        let device_twin = hub.device_twin("some-device").unwrap();
+        assert!(device_twin.tags.is_empty());
+        assert_eq!(device_twin.properties.desired["logLevel"], "debug");
+        assert!(device_twin
+            .properties
+            .desired
+            .get("telemetryInterval")
+            .is_none());
+
+        let result = futures::executor::block_on(
+            hub.service().twin_manager().get_device_twin("other-device"),
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn mock_hub_should_record_method_invocations(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = MockHub::new();
+        hub.set_method_response("reboot", 200, json!({ "rebooting": true }));
+        let iothub = hub.service();
+
+        let response = futures::executor::block_on(
+            iothub
+                .create_module_method("some-device", "some-module", "reboot", 30, 30)?
+                .invoke_raw(json!({ "delay": 5 })),
+        )?;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.payload, json!({ "rebooting": true }));
+
+        let result = futures::executor::block_on(
+            iothub
+                .create_device_method("some-device", "factoryReset", 30, 30)?
+                .invoke_raw(json!({})),
+        );
+        assert!(result.is_err());
+
+        let invocations = hub.method_invocations();
+        assert_eq!(invocations.len(), 2);
+        assert_eq!(invocations[0].module_id.as_deref(), Some("some-module"));
+        assert_eq!(invocations[0].payload, json!({ "delay": 5 }));
+        assert_eq!(invocations[1].method_name, "factoryReset");
+        Ok(())
+    }
+
+    #[test]
+    fn mock_hub_should_return_scripted_responses_once(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = MockHub::new();
+        hub.add_device("some-device");
+        hub.push_response(
+            http::Method::GET,
+            "/twins/some-device",
+            503,
+            json!({ "Message": "ErrorCode:ServiceUnavailable;Try again later" }),
+        );
+        let iothub = hub.service();
+
+        let result =
+            futures::executor::block_on(iothub.twin_manager().get_device_twin("some-device"));
+        assert!(result.is_err());
+        let device_twin =
+            futures::executor::block_on(iothub.twin_manager().get_device_twin("some-device"))?;
+        assert_eq!(device_twin.device_id, "some-device");
+        Ok(())
+    }
+}
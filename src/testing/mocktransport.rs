@@ -0,0 +1,78 @@
+//! The mocktransport module contains the transport the unit tests of the crate
+//! answer requests with.
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::transport::{HttpRequest, HttpResponse, Transport, TransportFuture};
+
+/// The function that answers a request, given the requests that were sent before it
+type Handler = dyn Fn(
+        &HttpRequest,
+        &[HttpRequest],
+    ) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>>
+    + Send
+    + Sync;
+
+/// A transport that answers every request with a handler and records the requests
+///
+/// Clones of the MockTransport share their recorded requests, so a clone can be kept
+/// for checking the requests after the transport is handed to the IoTHubService.
+#[derive(Clone)]
+pub(crate) struct MockTransport {
+    handler: Arc<Handler>,
+    requests: Arc<Mutex<Vec<HttpRequest>>>,
+}
+
+impl MockTransport {
+    /// Create a MockTransport that answers requests with the given handler
+    ///
+    /// The handler gets the request and the requests that were sent before it, so it
+    /// can answer differently as the requests come in.
+    pub(crate) fn new<F>(handler: F) -> Self
+    where
+        F: Fn(
+                &HttpRequest,
+                &[HttpRequest],
+            ) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        MockTransport {
+            handler: Arc::new(handler),
+            requests: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Get the requests that were sent, in the order they were sent
+    pub(crate) fn requests(&self) -> MutexGuard<'_, Vec<HttpRequest>> {
+        self.requests.lock().unwrap()
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&self, request: HttpRequest) -> TransportFuture<'_> {
+        let mut requests = self.requests();
+        let response = (self.handler)(&request, &requests);
+        requests.push(request);
+        Box::pin(async { response })
+    }
+}
+
+/// Create a successful response with the given JSON body
+pub(crate) fn json_response(
+    body: &serde_json::Value,
+) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(http::Response::new(serde_json::to_vec(body)?))
+}
+
+/// Create a response with the given error status and the error message the IoT Hub sends
+pub(crate) fn error_response(
+    status: u16,
+    error_code: &str,
+    message: &str,
+) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let body = serde_json::json!({ "Message": format!("ErrorCode:{};{}", error_code, message) });
+    Ok(http::Response::builder()
+        .status(status)
+        .body(serde_json::to_vec(&body)?)?)
+}
@@ -0,0 +1,9 @@
+//! The testing module contains helpers for testing applications without a live
+//! IoT Hub. It is only available with the `testing` feature.
+pub mod mockhub;
+#[cfg(test)]
+pub(crate) mod mocktransport;
+pub mod recording;
+
+pub use mockhub::{MethodInvocation, MockHub};
+pub use recording::{Cassette, Interaction, RecordingTransport, ReplayTransport};
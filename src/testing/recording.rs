@@ -0,0 +1,441 @@
+//! The recording module is used for capturing the responses of a live IoT Hub
+//! in a cassette file, and replaying them in tests that run without network access.
+//!
+//! Secrets are scrubbed before an interaction is recorded. These are SAS tokens,
+//! the signatures and shared access keys of connection strings, and the symmetric
+//! keys of identities. The host name is not recorded either, so a cassette can be
+//! replayed with any IoTHubService.
+//!
+//! # Example
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//! use azure_iothub_service::testing::ReplayTransport;
+//! use azure_iothub_service::IoTHubService;
+//!
+//! let iothub = IoTHubService::from_sas_token("test", "test")
+//!     .with_transport(ReplayTransport::from_file("resources/test/cassettes/twin.json")?);
+//! let device_twin = iothub.twin_manager().get_device_twin("some-device").await?;
+//! assert_eq!(device_twin.device_id, "some-device");
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::redact::REDACTED;
+use crate::transport::{HttpRequest, HttpResponse, Transport, TransportFuture};
+
+/// The JSON fields that contain secrets, whose values are scrubbed when recording
+const SECRET_FIELDS: [&str; 2] = ["primaryKey", "secondaryKey"];
+
+/// The response headers that are not recorded, because scrubbing may change the length of the body
+const SKIPPED_HEADERS: [http::header::HeaderName; 2] = [
+    http::header::CONTENT_LENGTH,
+    http::header::TRANSFER_ENCODING,
+];
+
+/// A request to the IoT Hub together with the response it received
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Interaction {
+    pub method: String,
+    /// The path and query of the request
+    pub uri: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<String>,
+    pub status: u16,
+    #[serde(default)]
+    pub response_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub response_body: String,
+}
+
+/// The interactions that are recorded by a RecordingTransport
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    /// Write the cassette to a file as JSON
+    pub fn write_to_file<P>(&self, path: P) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// Read a cassette from a JSON file
+    pub fn read_from_file<P>(path: P) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+}
+
+/// A transport that records the requests it sends through another transport
+///
+/// Clones of the RecordingTransport share their recording, so a clone can be kept
+/// for writing the cassette after the transport is handed to the IoTHubService.
+/// Requests that fail without a response are not recorded.
+///
+/// # Example
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// use azure_iothub_service::testing::RecordingTransport;
+/// use azure_iothub_service::transport::HyperTransport;
+/// use azure_iothub_service::IoTHubService;
+///
+/// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+/// let recording = RecordingTransport::new(HyperTransport::new());
+/// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?
+///     .with_transport(recording.clone());
+/// iothub.twin_manager().get_device_twin("some-device").await?;
+/// recording.write_to_file("resources/test/cassettes/twin.json")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct RecordingTransport {
+    inner: Arc<dyn Transport>,
+    interactions: Arc<Mutex<Vec<Interaction>>>,
+}
+
+impl RecordingTransport {
+    /// Create a RecordingTransport that sends its requests with the given transport
+    pub fn new<T>(inner: T) -> Self
+    where
+        T: Transport + 'static,
+    {
+        RecordingTransport {
+            inner: Arc::new(inner),
+            interactions: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Get a cassette with the interactions that were recorded so far
+    pub fn cassette(&self) -> Cassette {
+        Cassette {
+            interactions: self.lock().clone(),
+        }
+    }
+
+    /// Write the interactions that were recorded so far to a cassette file
+    pub fn write_to_file<P>(&self, path: P) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        self.cassette().write_to_file(path)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Interaction>> {
+        self.interactions
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send(&self, request: HttpRequest) -> TransportFuture<'_> {
+        let method = request.method().to_string();
+        let uri = path_and_query(&request);
+        let request_body = if request.body().is_empty() {
+            None
+        } else {
+            Some(scrub_secrets(&String::from_utf8_lossy(request.body())))
+        };
+
+        Box::pin(async move {
+            let response = self.inner.send(request).await?;
+            let response_headers = response
+                .headers()
+                .iter()
+                .filter(|(name, _)| !SKIPPED_HEADERS.contains(name))
+                .map(|(name, value)| {
+                    (
+                        name.to_string(),
+                        scrub_secrets(&String::from_utf8_lossy(value.as_bytes())),
+                    )
+                })
+                .collect();
+            self.lock().push(Interaction {
+                method,
+                uri,
+                request_body,
+                status: response.status().as_u16(),
+                response_headers,
+                response_body: scrub_secrets(&String::from_utf8_lossy(response.body())),
+            });
+            Ok(response)
+        })
+    }
+}
+
+/// A transport that answers requests with the interactions of a cassette
+///
+/// A request is answered with the first interaction that was not replayed yet and
+/// has the same method, path and query. Requests without such an interaction fail.
+pub struct ReplayTransport {
+    interactions: Mutex<Vec<Option<Interaction>>>,
+}
+
+impl ReplayTransport {
+    /// Create a ReplayTransport that replays the interactions of the cassette
+    pub fn new(cassette: Cassette) -> Self {
+        ReplayTransport {
+            interactions: Mutex::new(cassette.interactions.into_iter().map(Some).collect()),
+        }
+    }
+
+    /// Create a ReplayTransport that replays the interactions of a cassette file
+    pub fn from_file<P>(path: P) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        Ok(Self::new(Cassette::read_from_file(path)?))
+    }
+
+    /// Get the number of interactions that were not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.lock().iter().filter(|entry| entry.is_some()).count()
+    }
+
+    /// Take the first interaction matching the request
+    fn take_interaction(&self, request: &HttpRequest) -> Option<Interaction> {
+        let method = request.method().as_str();
+        let uri = path_and_query(request);
+        self.lock()
+            .iter_mut()
+            .find(|entry| match entry {
+                Some(interaction) => interaction.method == method && interaction.uri == uri,
+                None => false,
+            })
+            .and_then(Option::take)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Option<Interaction>>> {
+        self.interactions
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&self, request: HttpRequest) -> TransportFuture<'_> {
+        let interaction = self.take_interaction(&request);
+        Box::pin(async move {
+            let interaction = interaction.ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!(
+                        "No recorded interaction for {} {}",
+                        request.method(),
+                        path_and_query(&request)
+                    ),
+                )
+            })?;
+
+            let mut response = http::Response::builder().status(interaction.status);
+            for (name, value) in &interaction.response_headers {
+                response = response.header(name.as_str(), value.as_str());
+            }
+            let response: HttpResponse = response.body(interaction.response_body.into_bytes())?;
+            Ok(response)
+        })
+    }
+}
+
+fn path_and_query(request: &HttpRequest) -> String {
+    request
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.to_string())
+        .unwrap_or_else(|| request.uri().path().to_string())
+}
+
+/// Replace the secrets in a recorded body or header value
+fn scrub_secrets(text: &str) -> String {
+    let text = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(mut value) if value.is_object() || value.is_array() => {
+            if scrub_secret_fields(&mut value) {
+                serde_json::to_string(&value).unwrap_or_else(|_| text.to_string())
+            } else {
+                text.to_string()
+            }
+        }
+        _ => text.to_string(),
+    };
+
+    let is_end_of_token = |c: char| c == '"' || c == '\'' || c == ',' || c.is_whitespace();
+    let text = redact_after(&text, "SharedAccessSignature sr=", is_end_of_token);
+    let text = redact_after(&text, "sig=", |c| {
+        c == '&' || c == ';' || is_end_of_token(c)
+    });
+    redact_after(&text, "SharedAccessKey=", |c| {
+        c == ';' || is_end_of_token(c)
+    })
+}
+
+/// Replace the values of secret fields in a JSON value, returning whether any field was replaced
+fn scrub_secret_fields(value: &mut serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Object(object) => {
+            let mut scrubbed = false;
+            for (key, value) in object.iter_mut() {
+                if SECRET_FIELDS.contains(&key.as_str()) && value.is_string() {
+                    *value = serde_json::Value::String(REDACTED.to_string());
+                    scrubbed = true;
+                } else {
+                    scrubbed |= scrub_secret_fields(value);
+                }
+            }
+            scrubbed
+        }
+        serde_json::Value::Array(values) => values.iter_mut().fold(false, |scrubbed, value| {
+            scrub_secret_fields(value) | scrubbed
+        }),
+        _ => false,
+    }
+}
+
+/// Replace everything that follows a marker until the end of the secret
+fn redact_after<F>(text: &str, marker: &str, is_end: F) -> String
+where
+    F: Fn(char) -> bool,
+{
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find(marker) {
+        let (before, after) = rest.split_at(index + marker.len());
+        redacted.push_str(before);
+        redacted.push_str(REDACTED);
+        rest = &after[after.find(&is_end).unwrap_or(after.len())..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_json::json;
+
+    use crate::redact::REDACTED;
+    use crate::testing::mocktransport::{error_response, json_response, MockTransport};
+    use crate::testing::recording::scrub_secrets;
+    use crate::testing::{Cassette, RecordingTransport, ReplayTransport};
+    use crate::IoTHubService;
+
+    fn load_json_file(file_name: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/");
+        d.push(file_name);
+
+        Ok(std::fs::read_to_string(d)?)
+    }
+
+    #[test]
+    fn recording_should_replay_scrubbed_interactions(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // The mock transport stands in for a live IoT Hub
+        let twin = load_json_file("twin/devicetwin_deserialization.json")?;
+        let hub = MockTransport::new(move |request, _| match request.uri().path() {
+            "/twins/some-device" => Ok(http::Response::builder()
+                .header("x-ms-request-id", "some-request-id")
+                .body(twin.as_bytes().to_vec())?),
+            "/twins/some-device/methods" => json_response(&json!({
+                "status": 200,
+                "payload": {
+                    "connectionString": "HostName=test.azure-devices.net;DeviceId=some-device;SharedAccessKey=c2VjcmV0"
+                }
+            })),
+            _ => error_response(404, "DeviceNotFound", "The device does not exist"),
+        });
+        let recording = RecordingTransport::new(hub);
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(recording.clone());
+
+        let recorded_twin =
+            futures::executor::block_on(iothub.twin_manager().get_device_twin("some-device"))?
+                .into_inner();
+        futures::executor::block_on(
+            iothub
+                .create_device_method("some-device", "getConnectionString", 30, 30)?
+                .invoke_raw(json!({})),
+        )?;
+        assert!(
+            futures::executor::block_on(iothub.twin_manager().get_device_twin("other-device"))
+                .is_err()
+        );
+
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "azure-iothub-service-cassette-{}.json",
+            std::process::id()
+        ));
+        recording.write_to_file(&path)?;
+        let cassette = Cassette::read_from_file(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(cassette, recording.cassette());
+        assert_eq!(cassette.interactions.len(), 3);
+        assert_eq!(cassette.interactions[2].status, 404);
+        let method_response = &cassette.interactions[1].response_body;
+        assert!(!method_response.contains("c2VjcmV0"));
+        assert!(method_response.contains(&format!("SharedAccessKey={}", REDACTED)));
+
+        let replay = ReplayTransport::new(cassette);
+        let iothub =
+            IoTHubService::from_sas_token("other-hub", "other-token").with_transport(replay);
+        let replayed_twin =
+            futures::executor::block_on(iothub.twin_manager().get_device_twin("some-device"))?;
+        assert_eq!(
+            replayed_twin.metadata().request_id().as_deref(),
+            Some("some-request-id")
+        );
+        assert_eq!(replayed_twin.into_inner(), recorded_twin);
+
+        let result =
+            futures::executor::block_on(iothub.twin_manager().get_device_twin("some-device"));
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn scrub_secrets_should_redact_tokens_and_keys() {
+        assert_eq!(
+            scrub_secrets(
+                "SharedAccessSignature sr=test.azure-devices.net&sig=abc%3D&se=1&skn=iothubowner"
+            ),
+            format!("SharedAccessSignature sr={}", REDACTED)
+        );
+        assert_eq!(
+            scrub_secrets("https://test.blob.core.windows.net/c?sv=2020&sig=abc%3D&se=1"),
+            format!(
+                "https://test.blob.core.windows.net/c?sv=2020&sig={}&se=1",
+                REDACTED
+            )
+        );
+
+        let identity = scrub_secrets(
+            &json!({
+                "deviceId": "some-device",
+                "authentication": {
+                    "symmetricKey": { "primaryKey": "cHJpbWFyeQ==", "secondaryKey": "c2Vjb25kYXJ5" }
+                }
+            })
+            .to_string(),
+        );
+        let identity: serde_json::Value = serde_json::from_str(&identity).unwrap();
+        assert_eq!(
+            identity["authentication"]["symmetricKey"],
+            json!({ "primaryKey": REDACTED, "secondaryKey": REDACTED })
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! The tier module describes the pricing tiers of an IoT Hub and the limits that
+//! follow from them, so requests can be sized to what the IoT Hub accepts.
+//!
+//! The tier of an IoT Hub is not available through the service API, so it is either
+//! configured with [`IoTHubService::with_tier`] or read through the management plane.
+//!
+//! [`IoTHubService::with_tier`]: crate::IoTHubService::with_tier
+use serde::de;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The maximum number of devices in a single bulk registry operation
+pub const MAX_BULK_REGISTRY_OPERATIONS: usize = 100;
+
+/// The maximum number of items in a single page of query results
+pub const MAX_QUERY_PAGE_SIZE: usize = 100;
+
+/// The pricing tier of an IoT Hub
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HubTier {
+    Free,
+    Basic1,
+    Basic2,
+    Basic3,
+    Standard1,
+    Standard2,
+    Standard3,
+}
+
+impl HubTier {
+    /// Get the name of the tier as used by the IoT Hub, such as `S1`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HubTier::Free => "F1",
+            HubTier::Basic1 => "B1",
+            HubTier::Basic2 => "B2",
+            HubTier::Basic3 => "B3",
+            HubTier::Standard1 => "S1",
+            HubTier::Standard2 => "S2",
+            HubTier::Standard3 => "S3",
+        }
+    }
+
+    /// Check whether the tier supports twins, direct methods and queries,
+    /// which are not available in the basic tiers
+    pub fn supports_twins(&self) -> bool {
+        !matches!(self, HubTier::Basic1 | HubTier::Basic2 | HubTier::Basic3)
+    }
+
+    /// Get the limits of an IoT Hub of this tier with a number of units
+    ///
+    /// The throttling limits scale with the number of units, except for the free tier,
+    /// which always has a single unit.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::tier::HubTier;
+    ///
+    /// let limits = HubTier::Standard1.limits(4);
+    /// assert_eq!(limits.registry_operations_per_minute(), 400);
+    /// assert_eq!(limits.twin_operations_per_second(), 10);
+    /// ```
+    pub fn limits(&self, units: u32) -> HubLimits {
+        let units = match self {
+            HubTier::Free => 1,
+            _ => units.max(1),
+        };
+        let (registry_operations_per_minute, twin_operations_per_second, queries_per_minute) =
+            match self {
+                HubTier::Free => (100, 10, 20),
+                HubTier::Basic1 | HubTier::Basic2 => (100 * units, 0, 0),
+                HubTier::Basic3 => (5000 * units, 0, 0),
+                HubTier::Standard1 => (100 * units, units.max(10), 20 * units),
+                HubTier::Standard2 => (100 * units, 120 * units, 20 * units),
+                HubTier::Standard3 => (5000 * units, 600 * units, 1000 * units),
+            };
+
+        HubLimits {
+            tier: *self,
+            units,
+            registry_operations_per_minute,
+            twin_operations_per_second,
+            queries_per_minute,
+        }
+    }
+}
+
+impl std::fmt::Display for HubTier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::str::FromStr for HubTier {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "F1" => Ok(HubTier::Free),
+            "B1" => Ok(HubTier::Basic1),
+            "B2" => Ok(HubTier::Basic2),
+            "B3" => Ok(HubTier::Basic3),
+            "S1" => Ok(HubTier::Standard1),
+            "S2" => Ok(HubTier::Standard2),
+            "S3" => Ok(HubTier::Standard3),
+            _ => Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown IoT Hub tier {}", s),
+            ))),
+        }
+    }
+}
+
+impl Serialize for HubTier {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for HubTier {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|_| {
+            de::Error::custom(format!(
+                "Expected the tier to be F1, B1, B2, B3, S1, S2 or S3 but received: {}",
+                s
+            ))
+        })
+    }
+}
+
+/// The limits of an IoT Hub, which follow from its tier and number of units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HubLimits {
+    tier: HubTier,
+    units: u32,
+    registry_operations_per_minute: u32,
+    twin_operations_per_second: u32,
+    queries_per_minute: u32,
+}
+
+impl HubLimits {
+    /// Get the tier of the IoT Hub
+    pub fn tier(&self) -> HubTier {
+        self.tier
+    }
+
+    /// Get the number of units of the IoT Hub
+    pub fn units(&self) -> u32 {
+        self.units
+    }
+
+    /// Get the number of device and module identity operations per minute
+    pub fn registry_operations_per_minute(&self) -> u32 {
+        self.registry_operations_per_minute
+    }
+
+    /// Get the number of twin reads or updates per second, which is 0 when twins are not supported
+    pub fn twin_operations_per_second(&self) -> u32 {
+        self.twin_operations_per_second
+    }
+
+    /// Get the number of queries per minute, which is 0 when queries are not supported
+    pub fn queries_per_minute(&self) -> u32 {
+        self.queries_per_minute
+    }
+
+    /// Get the number of devices to send in a single bulk registry operation
+    pub fn bulk_registry_chunk_size(&self) -> usize {
+        MAX_BULK_REGISTRY_OPERATIONS
+    }
+
+    /// Get the number of items to request in a single page of query results
+    pub fn query_page_size(&self) -> usize {
+        MAX_QUERY_PAGE_SIZE
+    }
+
+    /// Get the number of twin requests that can be in flight at the same time
+    /// without being throttled, for use as the concurrency of bulk twin operations
+    ///
+    /// A twin request takes well below a second, so at most the number of twin
+    /// operations per second are sent at the same time.
+    pub fn max_twin_requests_in_flight(&self) -> usize {
+        (self.twin_operations_per_second as usize).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HubTier;
+
+    #[test]
+    fn limits_should_scale_with_units() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let free = HubTier::Free.limits(10);
+        assert_eq!(free.units(), 1);
+        assert_eq!(free.queries_per_minute(), 20);
+
+        let standard = "s3".parse::<HubTier>()?.limits(2);
+        assert_eq!(standard.tier(), HubTier::Standard3);
+        assert_eq!(standard.registry_operations_per_minute(), 10000);
+        assert_eq!(standard.twin_operations_per_second(), 1200);
+        assert_eq!(standard.max_twin_requests_in_flight(), 1200);
+
+        let basic = HubTier::Basic2.limits(1);
+        assert!(!basic.tier().supports_twins());
+        assert_eq!(basic.max_twin_requests_in_flight(), 1);
+        assert_eq!(serde_json::to_string(&HubTier::Standard1)?, "\"S1\"");
+        assert!("P1".parse::<HubTier>().is_err());
+        Ok(())
+    }
+}
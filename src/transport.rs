@@ -0,0 +1,197 @@
+//! The transport module contains the abstraction over the HTTP client
+//! that is used for sending requests to the IoT Hub.
+//!
+//! By default the `hyper-transport` feature is enabled, which sends requests
+//! with hyper on the tokio runtime. Applications using another async runtime,
+//! such as async-std or smol, can disable the default features and provide
+//! their own implementation of the [`Transport`] trait.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// The request sent by a Transport
+pub type HttpRequest = http::Request<Vec<u8>>;
+
+/// The response received by a Transport
+pub type HttpResponse = http::Response<Vec<u8>>;
+
+/// The future returned by a Transport when sending a request
+pub type TransportFuture<'a> = Pin<
+    Box<
+        dyn Future<Output = Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>>>
+            + Send
+            + 'a,
+    >,
+>;
+
+/// The Transport trait is used for sending requests to the IoT Hub
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::IoTHubService;
+/// use azure_iothub_service::transport::{HttpRequest, Transport, TransportFuture};
+///
+/// struct EmptyTransport;
+///
+/// impl Transport for EmptyTransport {
+///     fn send(&self, _request: HttpRequest) -> TransportFuture<'_> {
+///         Box::pin(async { Ok(http::Response::new(b"{}".to_vec())) })
+///     }
+/// }
+///
+/// let iothub = IoTHubService::from_sas_token("cool-iot-hub", "<a generated sas token>")
+///     .with_transport(EmptyTransport);
+/// ```
+pub trait Transport: Send + Sync {
+    /// Send the request and return the complete response
+    fn send(&self, request: HttpRequest) -> TransportFuture<'_>;
+}
+
+/// Get the transport that is used when no transport is set explicitly
+pub(crate) fn default_transport() -> Arc<dyn Transport> {
+    #[cfg(feature = "hyper-transport")]
+    {
+        Arc::new(HyperTransport::new())
+    }
+
+    #[cfg(not(feature = "hyper-transport"))]
+    {
+        Arc::new(MissingTransport)
+    }
+}
+
+/// The transport used when the `hyper-transport` feature is disabled and
+/// no transport was set on the IoTHubService.
+#[cfg(not(feature = "hyper-transport"))]
+struct MissingTransport;
+
+#[cfg(not(feature = "hyper-transport"))]
+impl Transport for MissingTransport {
+    fn send(&self, _request: HttpRequest) -> TransportFuture<'_> {
+        Box::pin(async {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "No transport is set, enable the hyper-transport feature or use IoTHubService::with_transport",
+            )) as Box<dyn std::error::Error + Send + Sync>)
+        })
+    }
+}
+
+#[cfg(feature = "hyper-transport")]
+pub use self::hyper_transport::HyperTransport;
+
+#[cfg(feature = "hyper-transport")]
+mod hyper_transport {
+    use hyper::body::HttpBody;
+    use hyper::client::HttpConnector;
+    use hyper::{Body, Client};
+    use hyper_proxy::ProxyConnector;
+    use hyper_tls::HttpsConnector;
+
+    use super::{HttpRequest, Transport, TransportFuture};
+    use crate::proxy::ProxySettings;
+
+    type HttpClient = Client<ProxyConnector<HttpsConnector<HttpConnector>>>;
+
+    /// The largest buffer allocated up front for a response body, larger bodies grow the buffer
+    const MAX_PREALLOCATED_BODY_SIZE: u64 = 1 << 20;
+
+    /// The HyperTransport sends requests with hyper on the tokio runtime
+    #[derive(Debug, Clone, Default)]
+    pub struct HyperTransport {
+        proxy: Option<ProxySettings>,
+    }
+
+    impl HyperTransport {
+        /// Create a new HyperTransport
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Create a new HyperTransport that sends all requests through the given proxy
+        pub fn with_proxy(proxy: ProxySettings) -> Self {
+            Self { proxy: Some(proxy) }
+        }
+
+        /// Get the proxy settings of the HyperTransport
+        pub fn proxy(&self) -> &Option<ProxySettings> {
+            &self.proxy
+        }
+
+        /// Create the HTTP client used for sending the requests
+        fn client(&self) -> Result<HttpClient, Box<dyn std::error::Error + Send + Sync>> {
+            let https = HttpsConnector::new();
+            let connector = match &self.proxy {
+                Some(proxy) => ProxyConnector::from_proxy(https, proxy.to_proxy()?)?,
+                None => ProxyConnector::new(https)?,
+            };
+            Ok(Client::builder().build::<_, Body>(connector))
+        }
+    }
+
+    impl Transport for HyperTransport {
+        fn send(&self, request: HttpRequest) -> TransportFuture<'_> {
+            let client = self.client();
+            Box::pin(async move {
+                let client =
+                    client.map_err(|err| err as Box<dyn std::error::Error + Send + Sync>)?;
+                let (parts, body) = request.into_parts();
+                let response = client
+                    .request(hyper::Request::from_parts(parts, Body::from(body)))
+                    .await?;
+
+                // Read the body chunk by chunk into a single buffer, instead of collecting
+                // the chunks and copying them into a vector afterwards. The length sent by
+                // the server is only trusted up to a limit for allocating the buffer.
+                let (parts, mut body) = response.into_parts();
+                let capacity = body.size_hint().lower().min(MAX_PREALLOCATED_BODY_SIZE);
+                let mut buffer = Vec::with_capacity(capacity as usize);
+                while let Some(chunk) = body.data().await {
+                    buffer.extend_from_slice(&chunk?);
+                }
+                Ok(http::Response::from_parts(parts, buffer))
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::mocktransport::MockTransport;
+    use crate::IoTHubService;
+
+    #[test]
+    fn iothub_service_should_send_requests_with_custom_transport(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let transport = MockTransport::new(|_, _| {
+            Ok(http::Response::builder()
+                .header("x-ms-request-id", "some-request-id")
+                .body(b"[]".to_vec())?)
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let query = iothub.build_query().select("*").from("devices").build()?;
+        let result = futures::executor::block_on(query.execute())?;
+
+        assert_eq!(*result, serde_json::json!([]));
+        assert_eq!(result.item_count(), 0);
+        assert_eq!(
+            result.metadata().request_id().as_deref(),
+            Some("some-request-id")
+        );
+        let uris: Vec<String> = transport
+            .requests()
+            .iter()
+            .map(|request| request.uri().to_string())
+            .collect();
+        assert_eq!(
+            uris,
+            vec![format!(
+                "https://test.azure-devices.net/devices/query?api-version={}",
+                iothub.api_version()
+            )]
+        );
+        Ok(())
+    }
+}
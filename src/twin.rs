@@ -1,35 +1,29 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
-use bytes::buf::BufExt as _;
-use hyper::{Body, Client, Method, Request};
-use hyper_tls::HttpsConnector;
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use http::{Method, Request};
 use serde::de::{self};
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::json;
 
-use crate::{error::IoTHubError, IoTHubService, API_VERSION};
-
-#[derive(Deserialize, Debug)]
-pub struct TwinError {
-    #[serde(rename = "Message")]
-    message: String,
-    #[serde(rename = "ExceptionMessage")]
-    exception_message: String
-}
-
-impl std::fmt::Display for TwinError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{{ message: {}, exception_message: {} }}",
-            self.message, self.exception_message
-        )
-    }
-}
+use crate::concurrent::{for_each_bounded, unique};
+use crate::configuration::{EdgeAgentReported, EdgeHubReported, ReportedModule, RouteReport};
+use crate::delay::delay_for;
+use crate::error::IoTHubError;
+use crate::query::{quote, Query, TwinItem};
+use crate::response::{Response, ResponseMetadata};
+use crate::tagimport::{changed_tags, TagChange, TagImport};
+use crate::twinsize::TwinSizeEstimate;
+use crate::watch::{ConnectionStateWatch, TwinWatch};
+use crate::IoTHubService;
 
-impl std::error::Error for TwinError {}
+/// The error returned by the IoT Hub when a twin operation failed
+pub type TwinError = IoTHubError;
 
 /// AuthenticationType of a module or device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AuthenticationType {
     Certificate,
     Authority,
@@ -38,29 +32,68 @@ pub enum AuthenticationType {
     SelfSigned,
 }
 
+impl AuthenticationType {
+    /// Get the value of the AuthenticationType as used by the IoT Hub
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthenticationType::Certificate => "certificate",
+            AuthenticationType::Authority => "certificateAuthority",
+            AuthenticationType::None => "none",
+            AuthenticationType::SAS => "sas",
+            AuthenticationType::SelfSigned => "selfSigned",
+        }
+    }
+}
+
+impl std::fmt::Display for AuthenticationType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Serialize for AuthenticationType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
 impl<'de> Deserialize<'de> for AuthenticationType {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        match s.as_str() {
+        match s.to_ascii_lowercase().as_str() {
             "certificate" => Ok(AuthenticationType::Certificate),
             "sas" => Ok(AuthenticationType::SAS),
-            "Authority" => Ok(AuthenticationType::Authority),
-            "selfSigned" => Ok(AuthenticationType::SelfSigned),
+            "authority" | "certificateauthority" => Ok(AuthenticationType::Authority),
+            "selfsigned" => Ok(AuthenticationType::SelfSigned),
             "none" => Ok(AuthenticationType::None),
-            _ => Err(de::Error::custom(format!("Expected status to be 'certificate','sas','Authority','selfSigned' or 'none' but received: {}", s))),
+            _ => Err(de::Error::custom(format!("Expected authentication type to be 'certificate','sas','certificateAuthority','selfSigned' or 'none' but received: {}", s))),
         }
     }
 }
 
 /// The connection state of a module or device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConnectionState {
     Connected,
     Disconnected,
 }
 
+impl ConnectionState {
+    /// Get the value of the ConnectionState as used by the IoT Hub
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Connected => "Connected",
+            ConnectionState::Disconnected => "Disconnected",
+        }
+    }
+}
+
 impl std::fmt::Display for ConnectionState {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -70,15 +103,24 @@ impl std::fmt::Display for ConnectionState {
     }
 }
 
+impl Serialize for ConnectionState {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
 impl<'de> Deserialize<'de> for ConnectionState {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        match s.as_str() {
-            "Connected" => Ok(ConnectionState::Connected),
-            "Disconnected" => Ok(ConnectionState::Disconnected),
+        match s.to_ascii_lowercase().as_str() {
+            "connected" => Ok(ConnectionState::Connected),
+            "disconnected" => Ok(ConnectionState::Disconnected),
             _ => Err(de::Error::custom(format!(
                 "Expected status to be 'Connected' or 'Disconnected' but received: {}",
                 s
@@ -88,18 +130,44 @@ impl<'de> Deserialize<'de> for ConnectionState {
 }
 
 /// Device or module status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
     Disabled,
     Enabled,
 }
 
+impl Status {
+    /// Get the value of the Status as used by the IoT Hub
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Disabled => "disabled",
+            Status::Enabled => "enabled",
+        }
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Serialize for Status {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
 impl<'de> Deserialize<'de> for Status {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        match s.as_str() {
+        match s.to_ascii_lowercase().as_str() {
             "disabled" => Ok(Status::Disabled),
             "enabled" => Ok(Status::Enabled),
             _ => Err(de::Error::custom(format!(
@@ -110,84 +178,446 @@ impl<'de> Deserialize<'de> for Status {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct DeviceCapabilities {
     #[serde(rename = "iotEdge")]
     pub iotedge: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct X509ThumbPrint {
     pub primary_thumbprint: Option<String>,
     pub secondary_thumbprint: Option<String>,
 }
 
-#[derive(Deserialize)]
-pub struct TwinProperties {
-    pub desired: serde_json::Value,
-    pub reported: serde_json::Value,
+/// The number of hexadecimal digits of a SHA-1 thumbprint
+const SHA1_THUMBPRINT_LENGTH: usize = 40;
+
+/// The number of hexadecimal digits of a SHA-256 thumbprint
+const SHA256_THUMBPRINT_LENGTH: usize = 64;
+
+impl X509ThumbPrint {
+    /// Create the thumbprints of the certificates of a device
+    ///
+    /// The thumbprints must be the hexadecimal SHA-1 or SHA-256 hash of the certificates.
+    /// Colons and spaces, as shown by tools like `openssl x509 -fingerprint`, are removed
+    /// and the digits are converted to upper case.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::twin::X509ThumbPrint;
+    ///
+    /// let thumbprints = X509ThumbPrint::new(
+    ///     "2f:d4:c5:1a:2c:3e:09:e1:30:b3:1c:51:b6:c0:1f:4c:0e:7a:99:e0",
+    ///     None,
+    /// )
+    /// .expect("Invalid thumbprint");
+    /// assert_eq!(
+    ///     thumbprints.primary_thumbprint.as_deref(),
+    ///     Some("2FD4C51A2C3E09E130B31C51B6C01F4C0E7A99E0")
+    /// );
+    /// assert!(X509ThumbPrint::new("not-a-thumbprint", None).is_err());
+    /// ```
+    pub fn new<S>(
+        primary_thumbprint: S,
+        secondary_thumbprint: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: AsRef<str>,
+    {
+        Ok(X509ThumbPrint {
+            primary_thumbprint: Some(normalize_thumbprint(primary_thumbprint.as_ref())?),
+            secondary_thumbprint: secondary_thumbprint.map(normalize_thumbprint).transpose()?,
+        })
+    }
 }
 
-#[derive(Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct DeviceTwin {
-    pub authentication_type: AuthenticationType,
+/// Validate a thumbprint and convert it to the format used by the IoT Hub
+fn normalize_thumbprint(
+    thumbprint: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let normalized: String = thumbprint
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect::<String>()
+        .to_ascii_uppercase();
+
+    let valid_length =
+        normalized.len() == SHA1_THUMBPRINT_LENGTH || normalized.len() == SHA256_THUMBPRINT_LENGTH;
+    if !valid_length || !normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid thumbprint {}, expected a hexadecimal SHA-1 or SHA-256 hash",
+                thumbprint
+            ),
+        )));
+    }
+    Ok(normalized)
+}
+
+/// The desired and reported properties of a twin
+///
+/// The properties are JSON values unless other types are given, which must implement
+/// `Default` for twins without properties. The properties maintained by the IoT Hub,
+/// such as `$metadata` and `$version`, are part of both sections.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(bound(
+    deserialize = "D: Deserialize<'de> + Default, R: Deserialize<'de> + Default",
+    serialize = "D: Serialize, R: Serialize"
+))]
+pub struct TwinProperties<D = serde_json::Value, R = serde_json::Value> {
+    #[serde(default)]
+    pub desired: D,
+    #[serde(default)]
+    pub reported: R,
+}
+
+/// The twin of a device
+///
+/// Fields that are not returned by every api-version are optional or have
+/// a default value, and fields that are unknown to this crate are collected in `extra`.
+/// The desired and reported properties are JSON values unless other types are given,
+/// see [`TwinManager::get_typed_device_twin`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(
+    rename_all = "camelCase",
+    bound(
+        deserialize = "D: Deserialize<'de> + Default, R: Deserialize<'de> + Default",
+        serialize = "D: Serialize, R: Serialize"
+    )
+)]
+pub struct DeviceTwin<D = serde_json::Value, R = serde_json::Value> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authentication_type: Option<AuthenticationType>,
+    #[serde(default)]
     pub capabilities: DeviceCapabilities,
+    #[serde(default)]
     pub cloud_to_device_message_count: i64,
     pub connection_state: ConnectionState,
+    #[serde(default)]
     pub device_etag: String,
     pub device_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub device_scope: Option<String>,
     pub etag: String,
+    #[serde(default)]
     pub last_activity_time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub parent_scopes: Option<Vec<String>>,
-    pub properties: TwinProperties,
+    #[serde(default)]
+    pub properties: TwinProperties<D, R>,
     pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub status_reason: Option<String>,
+    #[serde(default)]
     pub status_update_time: String,
-    pub tags: HashMap<String, String>,
+    #[serde(default)]
+    pub tags: HashMap<String, serde_json::Value>,
+    #[serde(default)]
     pub version: i64,
+    #[serde(default)]
     pub x509_thumbprint: X509ThumbPrint,
+    /// The fields of the twin that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct ModuleTwin {
-    pub authentication_type: AuthenticationType,
+/// The twin of a module
+///
+/// Fields that are not returned by every api-version are optional or have
+/// a default value, and fields that are unknown to this crate are collected in `extra`.
+/// The desired and reported properties are JSON values unless other types are given,
+/// see [`TwinManager::get_typed_module_twin`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(
+    rename_all = "camelCase",
+    bound(
+        deserialize = "D: Deserialize<'de> + Default, R: Deserialize<'de> + Default",
+        serialize = "D: Serialize, R: Serialize"
+    )
+)]
+pub struct ModuleTwin<D = serde_json::Value, R = serde_json::Value> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authentication_type: Option<AuthenticationType>,
+    #[serde(default)]
     pub cloud_to_device_message_count: i64,
     pub connection_state: ConnectionState,
+    #[serde(default)]
     pub device_etag: String,
     pub device_id: String,
     pub etag: String,
+    #[serde(default)]
     pub last_activity_time: String,
     pub module_id: String,
-    pub properties: TwinProperties,
+    #[serde(default)]
+    pub properties: TwinProperties<D, R>,
     pub status: Status,
+    #[serde(default)]
     pub status_update_time: String,
+    #[serde(default)]
+    pub tags: HashMap<String, serde_json::Value>,
+    #[serde(default)]
     pub version: i64,
+    #[serde(default)]
     pub x509_thumbprint: X509ThumbPrint,
+    /// The fields of the twin that are unknown to this crate
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
+/// The path of the time the reported properties of a twin were last updated
+const LAST_UPDATED_PATH: &str = "properties.reported.$metadata.$lastUpdated";
+
+/// A device whose reported properties were not updated recently
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaleDevice {
+    device_id: String,
+    last_updated: DateTime<Utc>,
+}
+
+impl StaleDevice {
+    /// Get the id of the device
+    pub fn device_id(&self) -> &String {
+        &self.device_id
+    }
+
+    /// Get the time the reported properties of the device were last updated
+    pub fn last_updated(&self) -> &DateTime<Utc> {
+        &self.last_updated
+    }
+}
+
+/// The id of the module that reports the modules of an edge device
+const EDGE_AGENT_MODULE_ID: &str = "$edgeAgent";
+
+/// A module of an edge device that was found by `find_modules_by_image` or `find_modules_by_version`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleMatch {
+    device_id: String,
+    module_name: String,
+    image: Option<String>,
+    version: Option<String>,
+}
+
+impl ModuleMatch {
+    /// Get the id of the edge device
+    pub fn device_id(&self) -> &String {
+        &self.device_id
+    }
+
+    /// Get the name of the module, such as `$edgeHub` or `SimulatedTemperatureSensor`
+    pub fn module_name(&self) -> &String {
+        &self.module_name
+    }
+
+    /// Get the image of the module, as reported by the edge agent
+    pub fn image(&self) -> &Option<String> {
+        &self.image
+    }
+
+    /// Get the version of the module, as reported by the edge agent
+    pub fn version(&self) -> &Option<String> {
+        &self.version
+    }
+}
+
+/// The modules reported by the edge agent of a device, as selected by `find_modules`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReportedModules {
+    device_id: String,
+    #[serde(default)]
+    system_modules: HashMap<String, ReportedModule>,
+    #[serde(default)]
+    modules: HashMap<String, ReportedModule>,
+}
+
+/// Check whether a value matches a pattern, in which `*` matches any number of characters
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // The pattern contains no wildcard, so the value must match exactly
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct DesiredTwin {
     contents: serde_json::Value,
 }
 
+impl DesiredTwin {
+    /// Create the minimal patch that changes the desired properties of a twin into the target
+    ///
+    /// Only the properties that differ are part of the patch, and properties that are not
+    /// part of the target are removed by setting them to `null`. Nested objects are compared
+    /// property by property, while arrays are replaced as a whole. The metadata properties
+    /// starting with `$`, such as `$version`, are ignored. The target must be a JSON object.
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::twin::{DesiredTwin, DeviceTwin};
+    /// use serde_json::json;
+    ///
+    /// let device_twin: DeviceTwin = serde_json::from_value(json!({
+    ///     "deviceId": "some-device",
+    ///     "etag": "AAAAAAAAAAE=",
+    ///     "status": "enabled",
+    ///     "connectionState": "Connected",
+    ///     "properties": {
+    ///         "desired": { "interval": 30, "logging": { "level": "info", "target": "file" }, "$version": 4 }
+    ///     }
+    /// }))
+    /// .unwrap();
+    ///
+    /// let desired_twin = DesiredTwin::diff(&device_twin, &json!({
+    ///     "logging": { "level": "debug", "target": "file" },
+    ///     "mode": "eco"
+    /// }))
+    /// .unwrap();
+    /// assert_eq!(
+    ///     desired_twin.desired_properties(),
+    ///     &json!({ "interval": null, "logging": { "level": "debug" }, "mode": "eco" })
+    /// );
+    /// ```
+    pub fn diff(
+        current: &DeviceTwin,
+        target: &serde_json::Value,
+    ) -> Result<DesiredTwin, Box<dyn std::error::Error + Send + Sync>> {
+        let target = match target {
+            serde_json::Value::Object(target) => target,
+            _ => {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "The target desired properties must be a JSON object",
+                )))
+            }
+        };
+        let empty = serde_json::Map::new();
+        let current = current.properties.desired.as_object().unwrap_or(&empty);
+
+        Ok(DesiredTwinBuilder::new()
+            .properties(serde_json::Value::Object(diff_objects(current, target)))
+            .build())
+    }
+
+    /// Get the tags the patch sets
+    pub fn tags(&self) -> &serde_json::Value {
+        &self.contents["tags"]
+    }
+
+    /// Get the desired properties the patch sets
+    pub fn desired_properties(&self) -> &serde_json::Value {
+        &self.contents["properties"]["desired"]
+    }
+
+    /// Check whether the patch changes no tags or desired properties
+    pub fn is_empty(&self) -> bool {
+        let is_empty =
+            |value: &serde_json::Value| value.as_object().map_or(true, |object| object.is_empty());
+        is_empty(self.tags()) && is_empty(self.desired_properties())
+    }
+}
+
+/// Get the properties that change the current object into the target object,
+/// where removed properties are set to `null`
+fn diff_objects(
+    current: &serde_json::Map<String, serde_json::Value>,
+    target: &serde_json::Map<String, serde_json::Value>,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut patch = serde_json::Map::new();
+    for (name, current_value) in current {
+        if !name.starts_with('$')
+            && !current_value.is_null()
+            && target.get(name).map_or(true, serde_json::Value::is_null)
+        {
+            patch.insert(name.clone(), serde_json::Value::Null);
+        }
+    }
+
+    for (name, target_value) in target {
+        if name.starts_with('$') || target_value.is_null() {
+            continue;
+        }
+        match (current.get(name), target_value) {
+            (
+                Some(serde_json::Value::Object(current_value)),
+                serde_json::Value::Object(target_value),
+            ) => {
+                let nested = diff_objects(current_value, target_value);
+                if !nested.is_empty() {
+                    patch.insert(name.clone(), serde_json::Value::Object(nested));
+                }
+            }
+            (Some(current_value), target_value) if current_value == target_value => {}
+            (_, target_value) => {
+                patch.insert(name.clone(), target_value.clone());
+            }
+        }
+    }
+    patch
+}
+
+/// The DesiredTwinBuilder builds the patches that update the tags and desired properties of twins
+///
+/// A partially filled builder can be cloned to build similar patches for many twins.
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::twin::DesiredTwinBuilder;
+/// use serde_json::json;
+///
+/// let template = DesiredTwinBuilder::new().add_tag("environment", "production");
+/// let north = template.clone().add_tag("site", "north").build();
+/// let south = template.add_tag("site", "south").build();
+/// assert_eq!(north.tags()["environment"], json!("production"));
+/// assert_eq!(south.tags()["site"], json!("south"));
+/// ```
+#[derive(Debug, Clone, Default)]
 pub struct DesiredTwinBuilder {
     desired_properties: Option<serde_json::Value>,
-    desired_tags: HashMap<String, String>,
+    desired_tags: HashMap<String, serde_json::Value>,
 }
 
 impl DesiredTwinBuilder {
+    /// Create a new DesiredTwinBuilder
     pub fn new() -> Self {
-        DesiredTwinBuilder {
-            desired_properties: None,
-            desired_tags: HashMap::new(),
-        }
+        Self::default()
     }
 
-    pub fn add_tag<T>(mut self, tag_name: T, tag_value: T) -> Self
+    /// Add a tag to the desired twin, the value can be any JSON value
+    ///
+    /// # Example
+    /// ```
+    /// use azure_iothub_service::twin::DesiredTwinBuilder;
+    /// use serde_json::json;
+    ///
+    /// let desired_twin = DesiredTwinBuilder::new()
+    ///     .add_tag("environment", "production")
+    ///     .add_tag("location", json!({ "building": "43", "floor": 3 }))
+    ///     .build();
+    /// ```
+    pub fn add_tag<S, T>(mut self, tag_name: S, tag_value: T) -> Self
     where
-        T: Into<String>,
+        S: Into<String>,
+        T: Into<serde_json::Value>,
     {
         self.desired_tags.insert(tag_name.into(), tag_value.into());
         self
@@ -210,174 +640,2501 @@ impl DesiredTwinBuilder {
     }
 }
 
-pub struct TwinManager<'a> {
-    iothub_service: &'a IoTHubService,
+/// The complete content of a twin, used for replacing a twin
+///
+/// Replacing a twin overwrites all tags and desired properties, so unlike a DesiredTwin
+/// patch the FullTwinContent always contains both. Start from an existing twin to keep
+/// the tags and desired properties that should not change.
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use azure_iothub_service::twin::FullTwinContent;
+/// use serde_json::json;
+///
+/// let mut tags = HashMap::new();
+/// tags.insert("environment".to_string(), json!("production"));
+/// let twin_content = FullTwinContent::new(tags, json!({ "telemetryInterval": 30 }));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct FullTwinContent {
+    tags: HashMap<String, serde_json::Value>,
+    desired_properties: serde_json::Map<String, serde_json::Value>,
+}
+
+impl FullTwinContent {
+    /// Create the content of a twin from all of its tags and desired properties
+    ///
+    /// Desired properties that are not a JSON object are replaced by an empty object, and the
+    /// properties maintained by the IoT Hub such as `$metadata` and `$version` are left out.
+    pub fn new(
+        tags: HashMap<String, serde_json::Value>,
+        desired_properties: serde_json::Value,
+    ) -> Self {
+        let desired_properties = match desired_properties {
+            serde_json::Value::Object(desired_properties) => desired_properties,
+            _ => serde_json::Map::new(),
+        };
+        FullTwinContent {
+            tags,
+            desired_properties: without_metadata(desired_properties),
+        }
+    }
+
+    /// Get the tags of the twin
+    pub fn tags(&self) -> &HashMap<String, serde_json::Value> {
+        &self.tags
+    }
+
+    /// Get a mutable reference to the tags of the twin
+    pub fn tags_mut(&mut self) -> &mut HashMap<String, serde_json::Value> {
+        &mut self.tags
+    }
+
+    /// Get the desired properties of the twin
+    pub fn desired_properties(&self) -> &serde_json::Map<String, serde_json::Value> {
+        &self.desired_properties
+    }
+
+    /// Get a mutable reference to the desired properties of the twin
+    pub fn desired_properties_mut(&mut self) -> &mut serde_json::Map<String, serde_json::Value> {
+        &mut self.desired_properties
+    }
+
+    /// Get the JSON body of a request replacing the twin
+    fn contents(&self) -> serde_json::Value {
+        json!({
+            "properties": {
+                "desired": self.desired_properties
+            },
+            "tags": self.tags
+        })
+    }
+}
+
+impl From<&DeviceTwin> for FullTwinContent {
+    fn from(device_twin: &DeviceTwin) -> Self {
+        FullTwinContent::new(
+            device_twin.tags.clone(),
+            device_twin.properties.desired.clone(),
+        )
+    }
+}
+
+impl From<&ModuleTwin> for FullTwinContent {
+    fn from(module_twin: &ModuleTwin) -> Self {
+        FullTwinContent::new(
+            module_twin.tags.clone(),
+            module_twin.properties.desired.clone(),
+        )
+    }
+}
+
+/// Remove the properties maintained by the IoT Hub, such as `$metadata` and `$version`
+fn without_metadata(
+    properties: serde_json::Map<String, serde_json::Value>,
+) -> serde_json::Map<String, serde_json::Value> {
+    properties
+        .into_iter()
+        .filter(|(name, _)| !name.starts_with('$'))
+        .collect()
+}
+
+/// The tags and desired properties of a single twin in a TwinSnapshot
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TwinSnapshotEntry {
+    pub device_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module_id: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    pub desired_properties: serde_json::Map<String, serde_json::Value>,
+}
+
+impl TwinSnapshotEntry {
+    /// Get the id of the twin, which is `{device_id}/{module_id}` for the twin of a module
+    pub fn twin_id(&self) -> String {
+        match &self.module_id {
+            Some(module_id) => format!("{}/{}", self.device_id, module_id),
+            None => self.device_id.clone(),
+        }
+    }
+
+    /// Get the content the twin is replaced with when the snapshot is restored
+    pub fn content(&self) -> FullTwinContent {
+        FullTwinContent::new(
+            self.tags.clone(),
+            serde_json::Value::Object(self.desired_properties.clone()),
+        )
+    }
+}
+
+/// A snapshot of the tags and desired properties of twins
+///
+/// The snapshot can be serialized, such as to a file with [`TwinSnapshot::write_to_file`],
+/// and restored to the same or another IoT Hub with [`TwinManager::restore`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TwinSnapshot {
+    pub created_time_utc: String,
+    pub twins: Vec<TwinSnapshotEntry>,
+}
+
+impl TwinSnapshot {
+    /// Write the snapshot to a file as JSON
+    pub fn write_to_file<P>(&self, path: P) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// Read a snapshot from a JSON file
+    pub fn read_from_file<P>(path: P) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+}
+
+/// The interval at which the twin is polled by `wait_for_reported`
+pub const REPORTED_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The number of times a throttled request is retried by `get_device_twins`
+pub const THROTTLING_RETRIES: u32 = 5;
+
+/// The time to wait before retrying a throttled request, when the IoT Hub returned no `Retry-After`
+const THROTTLING_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum time to wait before retrying a throttled request
+const MAX_THROTTLING_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct TwinManager {
+    iothub_service: IoTHubService,
 }
 
-impl<'a> TwinManager<'a> {
-    pub fn new(iothub_service: &'a IoTHubService) -> Self {
-        TwinManager { iothub_service }
+impl TwinManager {
+    pub fn new(iothub_service: &IoTHubService) -> Self {
+        TwinManager {
+            iothub_service: iothub_service.clone(),
+        }
     }
 
-    async fn get_twin<T>(&self, uri: String) -> Result<T, Box<dyn std::error::Error>>
+    async fn get_twin<T>(
+        &self,
+        operation: &'static str,
+        device_id: &str,
+        uri: String,
+    ) -> Result<Response<T>, Box<dyn std::error::Error + Send + Sync>>
     where
         for<'de> T: Deserialize<'de>,
     {
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, hyper::Body>(https);
         let request = Request::builder()
             .uri(uri)
             .method(Method::GET)
-            .header("Authorization", &self.iothub_service.sas_token)
+            .header("Authorization", self.iothub_service.authorization())
             .header("Content-Type", "application/json")
-            .body(Body::empty())?;
+            .body(Vec::new())?;
+
+        let response = self
+            .iothub_service
+            .send_compressible(operation, Some(device_id), request)
+            .await?;
+        if !response.status().is_success() {
+            return Err(Box::new(IoTHubError::from_response(&response)));
+        }
 
-        let response = client.request(request).await?;
-        let body = hyper::body::aggregate(response).await?;
-        Ok(serde_json::from_reader(body.reader())?)
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            serde_json::from_slice(response.body())?,
+        ))
     }
 
     async fn update_twin<T>(
         &self,
+        operation: &'static str,
+        device_id: &str,
         uri: String,
         method: Method,
-        desired_twin: DesiredTwin,
-    ) -> Result<T, Box<dyn std::error::Error>>
+        contents: serde_json::Value,
+        etag: Option<&str>,
+    ) -> Result<Response<T>, Box<dyn std::error::Error + Send + Sync>>
     where
         for<'de> T: Deserialize<'de>,
     {
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, hyper::Body>(https);
-        let request = Request::builder()
+        #[cfg(feature = "tracing")]
+        {
+            let estimate =
+                TwinSizeEstimate::new(&contents["tags"], &contents["properties"]["desired"]);
+            for section in estimate.exceeded_sections() {
+                tracing::warn!(
+                    device_id,
+                    operation,
+                    "twin update exceeds the size limit of the {}",
+                    section
+                );
+            }
+        }
+
+        let mut request = Request::builder()
             .uri(uri)
             .method(method)
-            .header("Authorization", &self.iothub_service.sas_token)
-            .header("Content-Type", "application/json")
-            .body(Body::from(serde_json::to_string(&desired_twin.contents)?))?;
+            .header("Authorization", self.iothub_service.authorization())
+            .header("Content-Type", "application/json");
+        if let Some(etag) = etag {
+            request = request.header("If-Match", format!("\"{}\"", etag.trim_matches('"')));
+        }
+        let request = request.body(serde_json::to_vec(&contents)?)?;
 
-        let response = client.request(request).await?;
+        let response = self
+            .iothub_service
+            .send_compressible(operation, Some(device_id), request)
+            .await?;
         if !response.status().is_success() {
-            let body = hyper::body::to_bytes(response).await?;
-            let twin_error: TwinError = serde_json::from_slice(&body)?;
-            return Err(Box::new(twin_error));
+            return Err(Box::new(IoTHubError::from_response(&response)));
         }
 
-        let body = hyper::body::to_bytes(response).await?;
-        Ok(serde_json::from_slice(&body)?)
+        Ok(Response::new(
+            ResponseMetadata::from_response(&response),
+            serde_json::from_slice(response.body())?,
+        ))
+    }
+
+    /// Read, modify and write a twin, retrying when the twin changed in between
+    async fn modify_twin<T, F>(
+        &self,
+        operation: &'static str,
+        device_id: &str,
+        uri: String,
+        max_retries: u32,
+        mut modify: F,
+    ) -> Result<Response<T>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        for<'de> T: Deserialize<'de>,
+        T: TwinEtag,
+        F: FnMut(&T) -> DesiredTwin,
+    {
+        let mut retries = 0;
+        loop {
+            let twin: Response<T> = self.get_twin(operation, device_id, uri.clone()).await?;
+            let desired_twin = modify(&twin);
+            let result = self
+                .update_twin(
+                    operation,
+                    device_id,
+                    uri.clone(),
+                    Method::PATCH,
+                    desired_twin.contents,
+                    Some(twin.etag()),
+                )
+                .await;
+
+            match result {
+                Err(err) if retries < max_retries && is_precondition_failed(&*err) => {
+                    self.iothub_service.metrics().request_retried(operation);
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
     }
 
     pub async fn get_device_twin<T>(
         self,
         device_id: T,
-    ) -> Result<DeviceTwin, Box<dyn std::error::Error>>
+    ) -> Result<Response<DeviceTwin>, Box<dyn std::error::Error + Send + Sync>>
     where
         T: Into<String>,
     {
+        let device_id = device_id.into();
         let uri = format!(
-            "https://{}.azure-devices.net/twins/{}?api-version={}",
-            self.iothub_service.iothub_name,
-            device_id.into(),
-            API_VERSION
+            "https://{}/twins/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            self.iothub_service.api_version()
         );
 
-        self.get_twin(uri).await
+        self.get_twin("get_device_twin", &device_id, uri).await
     }
 
     pub async fn get_module_twin<S, T>(
         &self,
         device_id: S,
         module_id: T,
-    ) -> Result<ModuleTwin, Box<dyn std::error::Error>>
+    ) -> Result<Response<ModuleTwin>, Box<dyn std::error::Error + Send + Sync>>
     where
         S: Into<String>,
         T: Into<String>,
     {
+        let device_id = device_id.into();
         let uri = format!(
-            "https://{}.azure-devices.net/twins/{}/modules/{}?api-version={}",
-            self.iothub_service.iothub_name,
-            device_id.into(),
+            "https://{}/twins/{}/modules/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
             module_id.into(),
-            API_VERSION
+            self.iothub_service.api_version()
         );
 
-        self.get_twin(uri).await
+        self.get_twin("get_module_twin", &device_id, uri).await
     }
 
-    pub async fn update_device_twin<T>(
+    /// Get the twin of a device with its desired and reported properties deserialized
+    /// into the given types
+    ///
+    /// Either section can stay a JSON value by using `serde_json::Value` as its type.
+    /// Properties missing from the twin deserialize to the default of the type, and
+    /// properties the types do not declare, such as `$metadata`, are ignored unless the
+    /// types deny unknown fields.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::response::Response;
+    /// use azure_iothub_service::twin::DeviceTwin;
+    /// use azure_iothub_service::IoTHubService;
+    /// use serde::Deserialize;
+    ///
+    /// #[derive(Deserialize, Default)]
+    /// #[serde(rename_all = "camelCase")]
+    /// struct Settings {
+    ///     telemetry_interval: Option<u64>,
+    /// }
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let device_twin: Response<DeviceTwin<Settings>> = iothub
+    ///     .twin_manager()
+    ///     .get_typed_device_twin("some-device")
+    ///     .await?;
+    /// println!("{:?}", device_twin.properties.desired.telemetry_interval);
+    /// println!("{}", device_twin.properties.reported["$version"]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_typed_device_twin<D, R, S>(
         &self,
-        device_id: T,
-        desired_twin: DesiredTwin,
-    ) -> Result<DeviceTwin, Box<dyn std::error::Error>>
+        device_id: S,
+    ) -> Result<Response<DeviceTwin<D, R>>, Box<dyn std::error::Error + Send + Sync>>
     where
-        T: Into<String>,
+        for<'de> D: Deserialize<'de> + Default,
+        for<'de> R: Deserialize<'de> + Default,
+        S: Into<String>,
     {
-        let uri = format!(
-            "https://{}.azure-devices.net/twins/{}?api-version={}",
-            self.iothub_service.iothub_name,
-            device_id.into(),
-            API_VERSION
-        );
-
-        self.update_twin(uri, Method::PATCH, desired_twin).await
+        let device_id = device_id.into();
+        let uri = self.device_twin_uri(&device_id);
+        self.get_twin("get_typed_device_twin", &device_id, uri)
+            .await
     }
 
-    pub async fn update_module_twin<S, T>(
+    /// Get the twin of a module with its desired and reported properties deserialized
+    /// into the given types, see [`TwinManager::get_typed_device_twin`]
+    pub async fn get_typed_module_twin<D, R, S, T>(
         &self,
         device_id: S,
         module_id: T,
-        desired_twin: DesiredTwin,
-    ) -> Result<ModuleTwin, Box<dyn std::error::Error>>
+    ) -> Result<Response<ModuleTwin<D, R>>, Box<dyn std::error::Error + Send + Sync>>
     where
+        for<'de> D: Deserialize<'de> + Default,
+        for<'de> R: Deserialize<'de> + Default,
         S: Into<String>,
         T: Into<String>,
     {
+        let device_id = device_id.into();
         let uri = format!(
-            "https://{}.azure-devices.net/twins/{}/modules/{}?api-version={}",
-            self.iothub_service.iothub_name,
-            device_id.into(),
+            "https://{}/twins/{}/modules/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
             module_id.into(),
-            API_VERSION
+            self.iothub_service.api_version()
         );
 
-        self.update_twin(uri, Method::PATCH, desired_twin).await
+        self.get_twin("get_typed_module_twin", &device_id, uri)
+            .await
     }
 
-    pub async fn replace_device_twin<T>(
-        self,
-        device_id: T,
-        desired_twin: DesiredTwin,
-    ) -> Result<DeviceTwin, Box<dyn std::error::Error>>
+    /// Watch the twin of a device, which emits the twin whenever its version changes
+    ///
+    /// The twin is requested once every interval, see [`TwinWatch`] for an example.
+    pub fn watch_device_twin<S>(&self, device_id: S, interval: Duration) -> TwinWatch<DeviceTwin>
     where
-        T: Into<String>,
+        S: Into<String>,
     {
-        let uri = format!(
-            "https://{}.azure-devices.net/twins/{}?api-version={}",
-            self.iothub_service.iothub_name,
-            device_id.into(),
-            API_VERSION
-        );
+        let twin_manager = self.clone();
+        let device_id = device_id.into();
+        TwinWatch::new(
+            interval,
+            |device_twin: &DeviceTwin| device_twin.version,
+            move || {
+                let twin_manager = twin_manager.clone();
+                let device_id = device_id.clone();
+                Box::pin(async move { twin_manager.get_device_twin(device_id).await })
+            },
+        )
+    }
 
-        self.update_twin(uri, Method::PUT, desired_twin).await
+    /// Monitor the connection states of the devices, which emits a change whenever a device
+    /// connects or disconnects
+    ///
+    /// The devices are selected with a condition of the query language, such as
+    /// `tags.site = 'north'`, an empty condition monitors all devices. The connection states
+    /// are queried once every interval, see [`ConnectionStateWatch`] for an example.
+    pub fn monitor_connection_states<S>(
+        &self,
+        condition: S,
+        interval: Duration,
+    ) -> ConnectionStateWatch
+    where
+        S: Into<String>,
+    {
+        let iothub_service = self.iothub_service.clone();
+        let condition = condition.into();
+        ConnectionStateWatch::new(interval, move || {
+            let mut query = iothub_service
+                .build_query()
+                .select("deviceId, connectionState")
+                .from("devices");
+            if !condition.trim().is_empty() {
+                query = query.and_where(condition.clone());
+            }
+            Box::pin(async move { Ok(query.build()?.execute_all_pages().await?.into_inner()) })
+        })
     }
 
-    pub async fn replace_module_twin<S, T>(
+    /// Watch the twin of a module, which emits the twin whenever its version changes
+    ///
+    /// The twin is requested once every interval, see [`TwinWatch`] for an example.
+    pub fn watch_module_twin<S, T>(
         &self,
         device_id: S,
         module_id: T,
-        desired_twin: DesiredTwin,
-    ) -> Result<ModuleTwin, Box<dyn std::error::Error>>
+        interval: Duration,
+    ) -> TwinWatch<ModuleTwin>
     where
         S: Into<String>,
         T: Into<String>,
     {
+        let twin_manager = self.clone();
+        let device_id = device_id.into();
+        let module_id = module_id.into();
+        TwinWatch::new(
+            interval,
+            |module_twin: &ModuleTwin| module_twin.version,
+            move || {
+                let twin_manager = twin_manager.clone();
+                let device_id = device_id.clone();
+                let module_id = module_id.clone();
+                Box::pin(async move { twin_manager.get_module_twin(device_id, module_id).await })
+            },
+        )
+    }
+
+    /// Wait until a reported property of a device has the expected value
+    ///
+    /// The property is selected with a JSON pointer into the reported properties, such as
+    /// `/firmware/version`. The twin is polled every `REPORTED_POLL_INTERVAL` until the
+    /// property matches, and the matching twin is returned. When the property still does not
+    /// match after the timeout, the wait fails with a `TimedOut` error that contains the last
+    /// reported value.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use std::time::Duration;
+    /// use azure_iothub_service::IoTHubService;
+    /// use serde_json::json;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// iothub
+    ///     .create_device_method_with_defaults("some-device", "firmwareUpdate")?
+    ///     .invoke_raw(json!({ "version": "2.1.0" }))
+    ///     .await?;
+    /// iothub
+    ///     .twin_manager()
+    ///     .wait_for_reported("some-device", "/firmware/version", json!("2.1.0"), Duration::from_secs(600))
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn wait_for_reported<S, P>(
+        &self,
+        device_id: S,
+        pointer: P,
+        expected_value: serde_json::Value,
+        timeout: Duration,
+    ) -> Result<Response<DeviceTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        P: Into<String>,
+    {
+        let device_id = device_id.into();
+        let pointer = pointer.into();
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{:?} is not a JSON pointer, which starts with /", pointer),
+            )));
+        }
+
+        let started = std::time::Instant::now();
+        loop {
+            let device_twin = self.clone().get_device_twin(device_id.as_str()).await?;
+            let reported_value = device_twin.properties.reported.pointer(&pointer);
+            if reported_value == Some(&expected_value) {
+                return Ok(device_twin);
+            }
+
+            let elapsed = started.elapsed();
+            if elapsed >= timeout {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!(
+                        "The reported property {} of {} is {} instead of {} after {:?}",
+                        pointer,
+                        device_id,
+                        reported_value.unwrap_or(&serde_json::Value::Null),
+                        expected_value,
+                        elapsed
+                    ),
+                )));
+            }
+            delay_for(REPORTED_POLL_INTERVAL.min(timeout - elapsed)).await;
+        }
+    }
+
+    /// Get the reported properties of the `$edgeAgent` module of an edge device
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let reported = iothub
+    ///     .twin_manager()
+    ///     .get_edge_agent_reported("some-edge-device")
+    ///     .await?;
+    /// for module_name in reported.modules_not_running() {
+    ///     println!("{} is not running", module_name);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_edge_agent_reported<S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<EdgeAgentReported>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let (metadata, module_twin) = self
+            .get_runtime_module_twin("get_edge_agent_reported", device_id.into(), "$edgeAgent")
+            .await?
+            .into_parts();
+        let reported = serde_json::from_value(module_twin.properties.reported)?;
+        Ok(Response::new(metadata, reported))
+    }
+
+    /// Get the reported properties of the `$edgeHub` module of an edge device,
+    /// which contain the clients that are connected to the edge hub
+    pub async fn get_edge_hub_reported<S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<EdgeHubReported>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let (metadata, module_twin) = self
+            .get_runtime_module_twin("get_edge_hub_reported", device_id.into(), "$edgeHub")
+            .await?
+            .into_parts();
+        let reported = serde_json::from_value(module_twin.properties.reported)?;
+        Ok(Response::new(metadata, reported))
+    }
+
+    /// Get the report of the routes of an edge device, to verify that the edge hub applied
+    /// the routes of a deployment and that the modules that send into the routes are connected
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let report = iothub
+    ///     .twin_manager()
+    ///     .get_route_report("some-edge-device")
+    ///     .await?;
+    /// if !report.is_applied() {
+    ///     println!("Routes are not applied: {:?}", report.reported_status_description());
+    /// }
+    /// for route in report.routes_without_connected_source() {
+    ///     println!("{} receives no messages", route.route_name());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_route_report<S>(
+        &self,
+        device_id: S,
+    ) -> Result<Response<RouteReport>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let (metadata, module_twin) = self
+            .get_runtime_module_twin("get_route_report", device_id.into(), "$edgeHub")
+            .await?
+            .into_parts();
+        let report = RouteReport::from_edge_hub_twin(&module_twin)?;
+        Ok(Response::new(metadata, report))
+    }
+
+    /// Get the twin of a runtime module of an edge device, such as `$edgeAgent` or `$edgeHub`
+    async fn get_runtime_module_twin(
+        &self,
+        operation: &'static str,
+        device_id: String,
+        module_id: &str,
+    ) -> Result<Response<ModuleTwin>, Box<dyn std::error::Error + Send + Sync>> {
         let uri = format!(
-            "https://{}.azure-devices.net/twins/{}/modules/{}?api-version={}",
-            self.iothub_service.iothub_name,
-            device_id.into(),
-            module_id.into(),
-            API_VERSION
+            "https://{}/twins/{}/modules/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            module_id,
+            self.iothub_service.api_version()
         );
 
-        self.update_twin(uri, Method::PUT, desired_twin).await
+        self.get_twin(operation, &device_id, uri).await
+    }
+
+    pub async fn update_device_twin<T>(
+        &self,
+        device_id: T,
+        desired_twin: DesiredTwin,
+    ) -> Result<Response<DeviceTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: Into<String>,
+    {
+        let device_id = device_id.into();
+        let uri = format!(
+            "https://{}/twins/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            self.iothub_service.api_version()
+        );
+
+        self.update_twin(
+            "update_device_twin",
+            &device_id,
+            uri,
+            Method::PATCH,
+            desired_twin.contents,
+            None,
+        )
+        .await
+    }
+
+    pub async fn update_module_twin<S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+        desired_twin: DesiredTwin,
+    ) -> Result<Response<ModuleTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let device_id = device_id.into();
+        let uri = format!(
+            "https://{}/twins/{}/modules/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            module_id.into(),
+            self.iothub_service.api_version()
+        );
+
+        self.update_twin(
+            "update_module_twin",
+            &device_id,
+            uri,
+            Method::PATCH,
+            desired_twin.contents,
+            None,
+        )
+        .await
+    }
+
+    /// Update the desired properties of the twin of a module
+    ///
+    /// The properties are wrapped in the `properties.desired` envelope of the twin,
+    /// so only the properties of the module have to be given. The properties must
+    /// serialize to a JSON object, and properties that are not given are left untouched.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use serde_json::json;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// iothub
+    ///     .twin_manager()
+    ///     .set_module_desired_properties(
+    ///         "some-device",
+    ///         "some-module",
+    ///         json!({ "telemetryInterval": 60 }),
+    ///     )
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn set_module_desired_properties<S, T, P>(
+        &self,
+        device_id: S,
+        module_id: T,
+        properties: P,
+    ) -> Result<Response<ModuleTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+        P: Serialize,
+    {
+        let properties = serde_json::to_value(properties)?;
+        if !properties.is_object() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The desired properties must be a JSON object",
+            )));
+        }
+
+        let device_id = device_id.into();
+        let uri = format!(
+            "https://{}/twins/{}/modules/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            module_id.into(),
+            self.iothub_service.api_version()
+        );
+
+        self.update_twin(
+            "set_module_desired_properties",
+            &device_id,
+            uri,
+            Method::PATCH,
+            json!({ "properties": { "desired": properties } }),
+            None,
+        )
+        .await
+    }
+
+    /// Change the image of a single module of an edge device
+    ///
+    /// Instead of applying a whole deployment manifest, only the image in the desired
+    /// properties of the `$edgeAgent` module twin is patched, so the other modules are
+    /// left untouched. The module must already be part of the deployment of the device,
+    /// and the patch is applied with the etag of the read twin, so it fails when the
+    /// twin was changed in the meantime. The system modules are named `$edgeAgent` and `$edgeHub`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// iothub
+    ///     .twin_manager()
+    ///     .set_module_image("some-edge-device", "sensor", "someacr.azurecr.io/sensor:1.1")
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn set_module_image<S, T, U>(
+        &self,
+        device_id: S,
+        module_name: T,
+        image: U,
+    ) -> Result<Response<ModuleTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+        U: Into<String>,
+    {
+        let device_id = device_id.into();
+        let module_name = module_name.into();
+        let image = image.into();
+        if image.trim().is_empty() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The image cannot be empty",
+            )));
+        }
+
+        let uri = format!(
+            "https://{}/twins/{}/modules/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            EDGE_AGENT_MODULE_ID,
+            self.iothub_service.api_version()
+        );
+        let edge_agent: Response<ModuleTwin> = self
+            .get_twin("set_module_image", &device_id, uri.clone())
+            .await?;
+        let patch = module_image_patch(&edge_agent.properties.desired, &module_name, &image)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "The module {} is not deployed to the device {}",
+                        module_name, device_id
+                    ),
+                )
+            })?;
+
+        self.update_twin(
+            "set_module_image",
+            &device_id,
+            uri,
+            Method::PATCH,
+            json!({ "properties": { "desired": patch } }),
+            Some(&edge_agent.etag),
+        )
+        .await
+    }
+
+    /// Replace the tags and desired properties of the twin of a device
+    pub async fn replace_device_twin<T>(
+        &self,
+        device_id: T,
+        twin_content: &FullTwinContent,
+    ) -> Result<Response<DeviceTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: Into<String>,
+    {
+        let device_id = device_id.into();
+        let uri = format!(
+            "https://{}/twins/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            self.iothub_service.api_version()
+        );
+
+        self.update_twin(
+            "replace_device_twin",
+            &device_id,
+            uri,
+            Method::PUT,
+            twin_content.contents(),
+            None,
+        )
+        .await
+    }
+
+    /// Replace the tags and desired properties of the twin of a module
+    pub async fn replace_module_twin<S, T>(
+        &self,
+        device_id: S,
+        module_id: T,
+        twin_content: &FullTwinContent,
+    ) -> Result<Response<ModuleTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let device_id = device_id.into();
+        let uri = format!(
+            "https://{}/twins/{}/modules/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            module_id.into(),
+            self.iothub_service.api_version()
+        );
+
+        self.update_twin(
+            "replace_module_twin",
+            &device_id,
+            uri,
+            Method::PUT,
+            twin_content.contents(),
+            None,
+        )
+        .await
+    }
+
+    /// Modify the twin of a device without overwriting concurrent changes
+    ///
+    /// The twin is read and passed to the closure, which returns the patch for the twin.
+    /// The patch is applied with the etag of the read twin, so the update fails when the
+    /// twin was changed in the meantime. In that case the twin is read again and the closure
+    /// is called again, up to `max_retries` times.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    /// use azure_iothub_service::twin::DesiredTwinBuilder;
+    /// use serde_json::json;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let device_twin = iothub
+    ///     .twin_manager()
+    ///     .modify_device_twin("some-device", 3, |twin| {
+    ///         let interval = twin.properties.desired["telemetryInterval"].as_u64().unwrap_or(30);
+    ///         DesiredTwinBuilder::new()
+    ///             .properties(json!({ "telemetryInterval": interval * 2 }))
+    ///             .build()
+    ///     })
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn modify_device_twin<S, F>(
+        &self,
+        device_id: S,
+        max_retries: u32,
+        modify: F,
+    ) -> Result<Response<DeviceTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        F: FnMut(&DeviceTwin) -> DesiredTwin,
+    {
+        let device_id = device_id.into();
+        let uri = format!(
+            "https://{}/twins/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            self.iothub_service.api_version()
+        );
+
+        self.modify_twin("modify_device_twin", &device_id, uri, max_retries, modify)
+            .await
+    }
+
+    /// Modify the twin of a module without overwriting concurrent changes
+    ///
+    /// See [`TwinManager::modify_device_twin`] for more information.
+    pub async fn modify_module_twin<S, T, F>(
+        &self,
+        device_id: S,
+        module_id: T,
+        max_retries: u32,
+        modify: F,
+    ) -> Result<Response<ModuleTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: Into<String>,
+        F: FnMut(&ModuleTwin) -> DesiredTwin,
+    {
+        let device_id = device_id.into();
+        let uri = format!(
+            "https://{}/twins/{}/modules/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            module_id.into(),
+            self.iothub_service.api_version()
+        );
+
+        self.modify_twin("modify_module_twin", &device_id, uri, max_retries, modify)
+            .await
+    }
+
+    /// Find the devices whose reported properties were not updated for longer than `max_age`
+    ///
+    /// The devices are found with the `$metadata.$lastUpdated` time of the reported properties,
+    /// which is the standard way of finding devices that stopped reporting.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use std::time::Duration;
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let stale_devices = iothub
+    ///     .twin_manager()
+    ///     .find_stale_devices(Duration::from_secs(24 * 60 * 60))
+    ///     .await?;
+    /// for device in stale_devices.iter() {
+    ///     println!("{} last reported at {}", device.device_id(), device.last_updated());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn find_stale_devices(
+        &self,
+        max_age: Duration,
+    ) -> Result<Response<Vec<StaleDevice>>, Box<dyn std::error::Error + Send + Sync>> {
+        let cutoff = Utc::now() - chrono::Duration::from_std(max_age)?;
+        let (metadata, items) = self
+            .iothub_service
+            .build_query()
+            .select(format!("deviceId, {} AS lastUpdated", LAST_UPDATED_PATH))
+            .from("devices")
+            .and_where(format!(
+                "{} < {}",
+                LAST_UPDATED_PATH,
+                quote(cutoff.to_rfc3339_opts(SecondsFormat::Millis, true))
+            ))
+            .build()?
+            .execute_all_pages::<serde_json::Value>()
+            .await?
+            .into_parts();
+
+        let mut stale_devices = Vec::with_capacity(items.len());
+        for item in items {
+            let device_id = match item["deviceId"].as_str() {
+                Some(device_id) => device_id.to_string(),
+                None => {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "Expected a device id in the query result but received: {}",
+                            item
+                        ),
+                    )))
+                }
+            };
+            let last_updated = match item["lastUpdated"].as_str() {
+                Some(last_updated) => DateTime::parse_from_rfc3339(last_updated)?,
+                None => continue,
+            };
+            stale_devices.push(StaleDevice {
+                device_id,
+                last_updated: last_updated.with_timezone(&Utc),
+            });
+        }
+        Ok(Response::new(metadata, stale_devices))
+    }
+
+    /// Estimate the size of the twin of a device after a patch is applied to it
+    ///
+    /// The twin is read and the patch is merged into it, so a patch that would make the
+    /// tags or desired properties larger than the IoT Hub accepts can be found before it
+    /// is sent. The estimate lists the largest properties of each section.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::twin::DesiredTwinBuilder;
+    /// use azure_iothub_service::IoTHubService;
+    /// use serde_json::json;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let twin_manager = iothub.twin_manager();
+    /// let patch = DesiredTwinBuilder::new()
+    ///     .properties(json!({ "schedule": [1, 2, 3] }))
+    ///     .build();
+    /// let estimate = twin_manager
+    ///     .check_device_twin_patch("some-device", &patch)
+    ///     .await?;
+    /// if estimate.is_within_limits() {
+    ///     twin_manager.update_device_twin("some-device", patch).await?;
+    /// } else {
+    ///     println!("{}", estimate.into_inner());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn check_device_twin_patch<S>(
+        &self,
+        device_id: S,
+        patch: &DesiredTwin,
+    ) -> Result<Response<TwinSizeEstimate>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let device_id = device_id.into();
+        let uri = self.device_twin_uri(&device_id);
+        let (metadata, device_twin) = self
+            .get_twin::<DeviceTwin>("check_device_twin_patch", &device_id, uri)
+            .await?
+            .into_parts();
+        Ok(Response::new(
+            metadata,
+            TwinSizeEstimate::after_patch(&device_twin, patch),
+        ))
+    }
+
+    /// Get the uri of the twin of a device
+    fn device_twin_uri(&self, device_id: &str) -> String {
+        format!(
+            "https://{}/twins/{}?api-version={}",
+            self.iothub_service.hostname(),
+            device_id,
+            self.iothub_service.api_version()
+        )
+    }
+
+    /// Find the modules of edge devices whose reported image matches a pattern
+    ///
+    /// In the pattern `*` matches any number of characters, such as in
+    /// `someacr.azurecr.io/sensor:2.*`. The modules are read from the reported properties
+    /// of the `$edgeAgent` modules, so modules that are not yet deployed are not found.
+    /// The matches are ordered by device id and module name.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let outdated = iothub
+    ///     .twin_manager()
+    ///     .find_modules_by_image("someacr.azurecr.io/sensor:1.*")
+    ///     .await?;
+    /// for module in outdated.iter() {
+    ///     println!("{} still runs {:?}", module.device_id(), module.image());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn find_modules_by_image<S>(
+        &self,
+        pattern: S,
+    ) -> Result<Response<Vec<ModuleMatch>>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        self.find_modules(pattern.into(), ReportedModule::image)
+            .await
+    }
+
+    /// Find the modules of edge devices whose reported version matches a pattern
+    ///
+    /// The pattern is matched like in `find_modules_by_image`, against the version
+    /// of the module in the deployment, such as `1.2`.
+    pub async fn find_modules_by_version<S>(
+        &self,
+        pattern: S,
+    ) -> Result<Response<Vec<ModuleMatch>>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        self.find_modules(pattern.into(), ReportedModule::version)
+            .await
+    }
+
+    /// Find the modules reported by the edge agents whose field matches a pattern
+    async fn find_modules(
+        &self,
+        pattern: String,
+        field: fn(&ReportedModule) -> Option<&str>,
+    ) -> Result<Response<Vec<ModuleMatch>>, Box<dyn std::error::Error + Send + Sync>> {
+        let (metadata, devices) = self
+            .iothub_service
+            .build_query()
+            .select("deviceId, properties.reported.systemModules AS systemModules, properties.reported.modules AS modules")
+            .from("devices.modules")
+            .and_where(format!("moduleId = {}", quote(EDGE_AGENT_MODULE_ID)))
+            .build()?
+            .execute_all_pages::<ReportedModules>()
+            .await?
+            .into_parts();
+
+        let mut matches = Vec::new();
+        for device in devices {
+            for (module_name, module) in device.system_modules.iter().chain(device.modules.iter()) {
+                if field(module).map_or(false, |value| matches_pattern(&pattern, value)) {
+                    matches.push(ModuleMatch {
+                        device_id: device.device_id.clone(),
+                        module_name: module_name.clone(),
+                        image: module.image().map(String::from),
+                        version: module.version().map(String::from),
+                    });
+                }
+            }
+        }
+        matches.sort_by(|a, b| (&a.device_id, &a.module_name).cmp(&(&b.device_id, &b.module_name)));
+        Ok(Response::new(metadata, matches))
+    }
+
+    /// Get the tags of a device
+    pub async fn get_tags<S>(
+        &self,
+        device_id: S,
+    ) -> Result<
+        Response<HashMap<String, serde_json::Value>>,
+        Box<dyn std::error::Error + Send + Sync>,
+    >
+    where
+        S: Into<String>,
+    {
+        let device_id = device_id.into();
+        let uri = self.device_twin_uri(&device_id);
+        let (metadata, device_twin) = self
+            .get_twin::<DeviceTwin>("get_tags", &device_id, uri)
+            .await?
+            .into_parts();
+        Ok(Response::new(metadata, device_twin.tags))
+    }
+
+    /// Add tags to a device, or replace the tags with the same name
+    ///
+    /// Only the given tags are sent, so other tags of the device are left untouched.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use std::collections::HashMap;
+    ///
+    /// use azure_iothub_service::IoTHubService;
+    /// use serde_json::json;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let mut tags = HashMap::new();
+    /// tags.insert("environment".to_string(), json!("production"));
+    /// tags.insert("location".to_string(), json!({ "building": "43" }));
+    /// iothub.twin_manager().add_tags("some-device", tags).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn add_tags<S>(
+        &self,
+        device_id: S,
+        tags: HashMap<String, serde_json::Value>,
+    ) -> Result<Response<DeviceTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+    {
+        let device_id = device_id.into();
+        let uri = self.device_twin_uri(&device_id);
+        self.update_twin(
+            "add_tags",
+            &device_id,
+            uri,
+            Method::PATCH,
+            json!({ "tags": tags }),
+            None,
+        )
+        .await
+    }
+
+    /// Remove a tag from a device
+    ///
+    /// The path of the tag is separated by dots, so `location.building` only removes
+    /// the `building` of the `location` tag.
+    pub async fn remove_tag<S, T>(
+        &self,
+        device_id: S,
+        path: T,
+    ) -> Result<Response<DeviceTwin>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Into<String>,
+        T: AsRef<str>,
+    {
+        let path = path.as_ref();
+        if path.is_empty() || path.split('.').any(str::is_empty) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid tag path {:?}", path),
+            )));
+        }
+
+        let tags = path.rsplit('.').fold(
+            serde_json::Value::Null,
+            |value, name| json!({ name: value }),
+        );
+        let device_id = device_id.into();
+        let uri = self.device_twin_uri(&device_id);
+        self.update_twin(
+            "remove_tag",
+            &device_id,
+            uri,
+            Method::PATCH,
+            json!({ "tags": tags }),
+            None,
+        )
+        .await
+    }
+
+    /// Take a snapshot of the tags and desired properties of the twins returned by a query
+    ///
+    /// The query must return whole twins, such as `SELECT * FROM devices` or
+    /// `SELECT * FROM devices.modules WHERE moduleId = 'sensor'`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let query = iothub
+    ///     .build_query()
+    ///     .select("*")
+    ///     .from("devices")
+    ///     .and_where("tags.environment = 'production'")
+    ///     .build()?;
+    /// let snapshot = iothub.twin_manager().snapshot(query).await?;
+    /// snapshot.write_to_file("production-twins.json")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn snapshot(
+        &self,
+        query: Query,
+    ) -> Result<Response<TwinSnapshot>, Box<dyn std::error::Error + Send + Sync>> {
+        let created_time_utc = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let (metadata, items) = query
+            .execute_all_pages::<serde_json::Value>()
+            .await?
+            .into_parts();
+
+        let mut twins = Vec::with_capacity(items.len());
+        for item in items {
+            let (device_id, module_id, content) = match TwinItem::from_value(item)? {
+                TwinItem::Device(device_twin) => {
+                    let content = FullTwinContent::from(&device_twin);
+                    (device_twin.device_id, None, content)
+                }
+                TwinItem::Module(module_twin) => {
+                    let content = FullTwinContent::from(&module_twin);
+                    (module_twin.device_id, Some(module_twin.module_id), content)
+                }
+            };
+            twins.push(TwinSnapshotEntry {
+                device_id,
+                module_id,
+                tags: content.tags,
+                desired_properties: content.desired_properties,
+            });
+        }
+
+        Ok(Response::new(
+            metadata,
+            TwinSnapshot {
+                created_time_utc,
+                twins,
+            },
+        ))
+    }
+
+    /// Get the twins of many devices, such as for refreshing a dashboard
+    ///
+    /// At most `max_in_flight` twins are requested at the same time. A request that is
+    /// throttled is retried after the `Retry-After` duration returned by the IoT Hub, or
+    /// after an exponential backoff when it is missing, up to `THROTTLING_RETRIES` times.
+    /// The results are returned per device id, so a failing twin does not stop the others.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let twins = iothub
+    ///     .twin_manager()
+    ///     .get_device_twins(vec!["some-device", "other-device"], 10)
+    ///     .await;
+    /// for (device_id, result) in twins {
+    ///     match result {
+    ///         Ok(device_twin) => println!("{}: {}", device_id, device_twin.properties.reported),
+    ///         Err(err) => println!("{}: {}", device_id, err),
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_device_twins<I, S>(
+        &self,
+        device_ids: I,
+        max_in_flight: usize,
+    ) -> HashMap<String, Result<DeviceTwin, Box<dyn std::error::Error + Send + Sync>>>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let get = |device_id: String| async move {
+            let mut backoff = THROTTLING_BACKOFF;
+            let mut retries = 0;
+            let result = loop {
+                let uri = self.device_twin_uri(&device_id);
+                match self
+                    .get_twin::<DeviceTwin>("get_device_twins", &device_id, uri)
+                    .await
+                {
+                    Ok(device_twin) => break Ok(device_twin.into_inner()),
+                    Err(err) if retries < THROTTLING_RETRIES => {
+                        match throttling_delay(err.as_ref(), backoff) {
+                            Some(delay) => {
+                                self.iothub_service
+                                    .metrics()
+                                    .request_retried("get_device_twins");
+                                delay_for(delay).await;
+                                backoff = (backoff * 2).min(MAX_THROTTLING_BACKOFF);
+                                retries += 1;
+                            }
+                            None => break Err(err),
+                        }
+                    }
+                    Err(err) => break Err(err),
+                }
+            };
+            (device_id, result)
+        };
+
+        let device_ids = unique(device_ids.into_iter().map(Into::into));
+        for_each_bounded(device_ids, max_in_flight, get)
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    /// Restore the twins of a snapshot by replacing their tags and desired properties
+    ///
+    /// At most `max_concurrency` twins are replaced at the same time. The results are
+    /// returned per twin id, so a failing twin does not stop the others.
+    pub async fn restore(
+        &self,
+        snapshot: &TwinSnapshot,
+        max_concurrency: usize,
+    ) -> HashMap<String, Result<(), Box<dyn std::error::Error + Send + Sync>>> {
+        let replace = |entry: &TwinSnapshotEntry| {
+            let content = entry.content();
+            let twin_id = entry.twin_id();
+            let device_id = entry.device_id.clone();
+            let module_id = entry.module_id.clone();
+            async move {
+                let result = match module_id {
+                    Some(module_id) => self
+                        .replace_module_twin(device_id, module_id, &content)
+                        .await
+                        .map(|_| ()),
+                    None => self
+                        .replace_device_twin(device_id, &content)
+                        .await
+                        .map(|_| ()),
+                };
+                (twin_id, result)
+            }
+        };
+
+        // A twin that is in the snapshot twice is restored from its first entry
+        let mut twin_ids = HashSet::new();
+        let entries: Vec<&TwinSnapshotEntry> = snapshot
+            .twins
+            .iter()
+            .filter(|entry| twin_ids.insert(entry.twin_id()))
+            .collect();
+        for_each_bounded(entries, max_concurrency, replace)
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    /// Apply the tags of an import to the device twins
+    ///
+    /// The current tags of every device are compared with the import, and only the
+    /// tags that are added or changed are sent, so other tags are left untouched and
+    /// devices whose tags already match are not updated. With `dry_run` nothing is
+    /// updated, so the report tells what would change. At most `max_in_flight` devices
+    /// are handled at the same time and the results are returned per device id.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// use azure_iothub_service::tagimport::TagImport;
+    /// use azure_iothub_service::IoTHubService;
+    ///
+    /// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+    /// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+    /// let import = TagImport::from_csv(std::fs::File::open("devices.csv")?)?;
+    /// let report = iothub.twin_manager().import_tags(&import, 10, true).await;
+    /// for (device_id, change) in report {
+    ///     println!("{}: {:?}", device_id, change?.changed_tags());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn import_tags(
+        &self,
+        import: &TagImport,
+        max_in_flight: usize,
+        dry_run: bool,
+    ) -> HashMap<String, Result<TagChange, Box<dyn std::error::Error + Send + Sync>>> {
+        let apply = |device_id: &String| {
+            let device_id = device_id.clone();
+            let imported = import.tags(&device_id).cloned().unwrap_or_default();
+            async move {
+                let result = async {
+                    let uri = self.device_twin_uri(&device_id);
+                    let device_twin = self
+                        .get_twin::<DeviceTwin>("import_tags", &device_id, uri.clone())
+                        .await?
+                        .into_inner();
+                    let current = device_twin.tags.into_iter().collect();
+                    let changed = changed_tags(&current, &imported);
+                    let changed_names = changed.keys().cloned().collect();
+                    if dry_run || changed.is_empty() {
+                        return Ok(TagChange::new(changed_names, false));
+                    }
+
+                    self.update_twin::<DeviceTwin>(
+                        "import_tags",
+                        &device_id,
+                        uri,
+                        Method::PATCH,
+                        json!({ "tags": changed }),
+                        None,
+                    )
+                    .await?;
+                    Ok(TagChange::new(changed_names, true))
+                }
+                .await;
+                (device_id, result)
+            }
+        };
+
+        for_each_bounded(import.device_ids(), max_in_flight, apply)
+            .await
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Get the time to wait before retrying a request that was throttled, or `None`
+/// when the error was not caused by throttling
+fn throttling_delay(
+    err: &(dyn std::error::Error + 'static),
+    backoff: Duration,
+) -> Option<Duration> {
+    let error = err.downcast_ref::<IoTHubError>()?;
+    if !error.is_throttling() {
+        return None;
+    }
+    Some(error.metadata().retry_after().unwrap_or(backoff))
+}
+
+/// Get the patch of the desired properties of the `$edgeAgent` module that changes the
+/// image of a module, or `None` when the module is not part of the desired properties
+fn module_image_patch(
+    edge_agent_desired: &serde_json::Value,
+    module_name: &str,
+    image: &str,
+) -> Option<serde_json::Value> {
+    let (section, name) = match module_name {
+        "$edgeAgent" => ("systemModules", "edgeAgent"),
+        "$edgeHub" => ("systemModules", "edgeHub"),
+        module_name => ("modules", module_name),
+    };
+    edge_agent_desired.get(section)?.get(name)?;
+    Some(json!({ section: { name: { "settings": { "image": image } } } }))
+}
+
+/// Check whether an error was caused by a mismatching etag
+fn is_precondition_failed(err: &(dyn std::error::Error + 'static)) -> bool {
+    match err.downcast_ref::<IoTHubError>() {
+        Some(error) => error.is_precondition_failed(),
+        None => false,
+    }
+}
+
+/// Access to the etag of a twin
+trait TwinEtag {
+    fn etag(&self) -> &str;
+}
+
+impl<D, R> TwinEtag for DeviceTwin<D, R> {
+    fn etag(&self) -> &str {
+        &self.etag
+    }
+}
+
+impl<D, R> TwinEtag for ModuleTwin<D, R> {
+    fn etag(&self) -> &str {
+        &self.etag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::testing::mocktransport::{error_response, json_response, MockTransport};
+    use crate::twin::{
+        AuthenticationType, ConnectionState, DesiredTwin, DesiredTwinBuilder, DeviceTwin,
+        FullTwinContent, ModuleTwin, Status, TwinSnapshot,
+    };
+    use crate::IoTHubService;
+
+    fn load_json_file(file_name: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/");
+        d.push(file_name);
+
+        Ok(std::fs::read_to_string(d)?)
+    }
+
+    #[test]
+    fn device_twin_should_deserialize_nested_tags(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let device_twin: DeviceTwin =
+            serde_json::from_str(&load_json_file("twin/devicetwin_deserialization.json")?)?;
+
+        assert_eq!(device_twin.device_id, "some-device");
+        assert_eq!(device_twin.tags["environment"], json!("production"));
+        assert_eq!(device_twin.tags["floor"], json!(3));
+        assert_eq!(device_twin.tags["location"]["building"], json!("43"));
+        Ok(())
+    }
+
+    #[test]
+    fn device_twin_should_collect_unknown_fields(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let device_twin: DeviceTwin =
+            serde_json::from_str(&load_json_file("twin/devicetwin_deserialization.json")?)?;
+
+        assert_eq!(
+            device_twin.extra["modelId"],
+            json!("dtmi:com:example:Thermostat;1")
+        );
+        assert!(!device_twin.extra.contains_key("deviceId"));
+        Ok(())
+    }
+
+    #[test]
+    fn module_twin_should_deserialize_without_optional_fields(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let module_twin: ModuleTwin = serde_json::from_value(json!({
+            "deviceId": "some-device",
+            "moduleId": "some-module",
+            "etag": "AAAAAAAAAAE=",
+            "status": "enabled",
+            "connectionState": "Disconnected"
+        }))?;
+
+        assert_eq!(module_twin.module_id, "some-module");
+        assert!(module_twin.authentication_type.is_none());
+        assert!(module_twin.x509_thumbprint.primary_thumbprint.is_none());
+        assert_eq!(module_twin.properties.desired, serde_json::Value::Null);
+        assert_eq!(module_twin.version, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn device_twin_should_roundtrip() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let device_twin: DeviceTwin =
+            serde_json::from_str(&load_json_file("twin/devicetwin_deserialization.json")?)?;
+        let serialized = serde_json::to_value(&device_twin)?;
+
+        assert_eq!(serialized["authenticationType"], json!("sas"));
+        assert_eq!(serialized["connectionState"], json!("Connected"));
+        assert_eq!(serialized["status"], json!("enabled"));
+        assert_eq!(
+            serialized["modelId"],
+            json!("dtmi:com:example:Thermostat;1")
+        );
+        assert_eq!(
+            serde_json::from_value::<DeviceTwin>(serialized)?,
+            device_twin
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn twin_enums_should_deserialize_case_insensitive(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        assert_eq!(
+            serde_json::from_value::<AuthenticationType>(json!("Authority"))?,
+            AuthenticationType::Authority
+        );
+        assert_eq!(
+            serde_json::from_value::<AuthenticationType>(json!("certificateAuthority"))?,
+            AuthenticationType::Authority
+        );
+        assert_eq!(
+            serde_json::from_value::<AuthenticationType>(json!("SelfSigned"))?,
+            AuthenticationType::SelfSigned
+        );
+        assert_eq!(
+            serde_json::from_value::<ConnectionState>(json!("connected"))?,
+            ConnectionState::Connected
+        );
+        assert_eq!(
+            serde_json::from_value::<Status>(json!("Enabled"))?,
+            Status::Enabled
+        );
+        Ok(())
+    }
+
+    /// Get the If-Match headers of the updates sent through the transport
+    fn update_etags(transport: &MockTransport) -> Vec<String> {
+        transport
+            .requests()
+            .iter()
+            .filter(|request| request.method() == http::Method::PATCH)
+            .map(|request| request.headers()["If-Match"].to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn modify_device_twin_should_retry_on_precondition_failed(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Every other update of the twin fails with a mismatching etag
+        let twin = load_json_file("twin/devicetwin_deserialization.json")?;
+        let transport = MockTransport::new(move |request, earlier| {
+            let updates = earlier
+                .iter()
+                .filter(|request| request.method() == http::Method::PATCH)
+                .count();
+            if request.method() == http::Method::PATCH && updates % 2 == 0 {
+                return error_response(412, "PreconditionFailed", "Precondition failed");
+            }
+            Ok(http::Response::new(twin.as_bytes().to_vec()))
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let mut calls = 0;
+        let device_twin = futures::executor::block_on(iothub.twin_manager().modify_device_twin(
+            "some-device",
+            1,
+            |twin| {
+                calls += 1;
+                DesiredTwinBuilder::new()
+                    .properties(json!({ "telemetryInterval": twin.properties.desired["telemetryInterval"] }))
+                    .build()
+            },
+        ))?;
+
+        assert_eq!(device_twin.device_id, "some-device");
+        assert_eq!(calls, 2);
+        assert_eq!(
+            update_etags(&transport),
+            vec![
+                "\"AAAAAAAAAAE=\"".to_string(),
+                "\"AAAAAAAAAAE=\"".to_string()
+            ]
+        );
+
+        let result = futures::executor::block_on(iothub.twin_manager().modify_device_twin(
+            "some-device",
+            0,
+            |_| DesiredTwinBuilder::new().build(),
+        ));
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    /// Create a transport that answers the query with two pages of devices, or with the
+    /// `malformed` body when it is set
+    fn stale_devices_transport(malformed: Option<serde_json::Value>) -> MockTransport {
+        MockTransport::new(move |request, _| {
+            if let Some(malformed) = &malformed {
+                return json_response(malformed);
+            }
+            if request.headers().get("x-ms-continuation").is_none() {
+                Ok(http::Response::builder()
+                    .header("x-ms-continuation", "page-2")
+                    .body(serde_json::to_vec(&json!([
+                        { "deviceId": "some-device", "lastUpdated": "2020-06-01T12:30:00.1234567Z" },
+                        { "deviceId": "other-device" }
+                    ]))?)?)
+            } else {
+                json_response(&json!([
+                    { "deviceId": "third-device", "lastUpdated": "2020-06-01T11:00:00Z" }
+                ]))
+            }
+        })
+    }
+
+    #[test]
+    fn find_stale_devices_should_query_last_updated(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let transport = stale_devices_transport(None);
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let stale_devices = futures::executor::block_on(
+            iothub
+                .twin_manager()
+                .find_stale_devices(Duration::from_secs(60 * 60)),
+        )?;
+
+        let body: serde_json::Value = serde_json::from_slice(transport.requests()[0].body())?;
+        assert!(body["query"].as_str().unwrap().starts_with(
+            "SELECT deviceId, properties.reported.$metadata.$lastUpdated AS lastUpdated FROM devices \
+             WHERE properties.reported.$metadata.$lastUpdated < '"
+        ));
+        assert_eq!(stale_devices.len(), 2);
+        assert_eq!(stale_devices[0].device_id(), "some-device");
+        assert_eq!(
+            stale_devices[0].last_updated().to_rfc3339(),
+            "2020-06-01T12:30:00.123456700+00:00"
+        );
+        assert_eq!(stale_devices[1].device_id(), "third-device");
+
+        for malformed in &[
+            json!("unexpected"),
+            json!([{ "lastUpdated": "2020-06-01T11:00:00Z" }]),
+        ] {
+            let iothub = IoTHubService::from_sas_token("test", "test")
+                .with_transport(stale_devices_transport(Some(malformed.clone())));
+            assert!(futures::executor::block_on(
+                iothub
+                    .twin_manager()
+                    .find_stale_devices(Duration::from_secs(60 * 60)),
+            )
+            .is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn replace_device_twin_should_send_full_twin_content(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let twin = load_json_file("twin/devicetwin_deserialization.json")?;
+        let response = twin.clone();
+        let transport =
+            MockTransport::new(move |_, _| Ok(http::Response::new(response.as_bytes().to_vec())));
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let device_twin: DeviceTwin = serde_json::from_str(&twin)?;
+        let mut twin_content = FullTwinContent::from(&device_twin);
+        twin_content
+            .desired_properties_mut()
+            .insert("telemetryInterval".to_string(), json!(60));
+        futures::executor::block_on(
+            iothub
+                .twin_manager()
+                .replace_device_twin("some-device", &twin_content),
+        )?;
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].method(), http::Method::PUT);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(requests[0].body())?,
+            json!({
+                "properties": {
+                    "desired": { "telemetryInterval": 60 }
+                },
+                "tags": {
+                    "environment": "production",
+                    "floor": 3,
+                    "location": { "building": "43", "region": "westeurope" }
+                }
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_should_restore_tags_and_desired_properties(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // The queries are answered with a device and a module twin, each on its own page
+        let device_twin: serde_json::Value =
+            serde_json::from_str(&load_json_file("twin/devicetwin_deserialization.json")?)?;
+        let module_twin = json!({
+            "deviceId": "some-device",
+            "moduleId": "some-module",
+            "etag": "AAAAAAAAAAE=",
+            "status": "enabled",
+            "connectionState": "Disconnected",
+            "properties": {
+                "desired": { "threshold": 5, "$version": 2 }
+            }
+        });
+        let transport = MockTransport::new(move |request, _| {
+            if request.method() == http::Method::POST {
+                match request.headers().get("x-ms-continuation") {
+                    None => Ok(http::Response::builder()
+                        .header("x-ms-continuation", "page-2")
+                        .body(serde_json::to_vec(&json!([device_twin]))?)?),
+                    Some(_) => json_response(&json!([module_twin])),
+                }
+            } else if request.uri().path().contains("/modules/") {
+                json_response(&module_twin)
+            } else {
+                json_response(&device_twin)
+            }
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let query = iothub.build_query().select("*").from("devices").build()?;
+        let snapshot = futures::executor::block_on(iothub.twin_manager().snapshot(query))?;
+        assert_eq!(snapshot.twins.len(), 2);
+        assert_eq!(snapshot.twins[0].twin_id(), "some-device");
+        assert_eq!(snapshot.twins[1].twin_id(), "some-device/some-module");
+
+        let snapshot: TwinSnapshot = serde_json::from_value(serde_json::to_value(&*snapshot)?)?;
+        let results = futures::executor::block_on(iothub.twin_manager().restore(&snapshot, 1));
+        assert_eq!(results.len(), 2);
+        assert!(results.values().all(|result| result.is_ok()));
+
+        let replaced = transport
+            .requests()
+            .iter()
+            .filter(|request| request.method() == http::Method::PUT)
+            .map(|request| {
+                let body: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+                (request.uri().path().to_string(), body)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(replaced[0].0, "/twins/some-device");
+        assert_eq!(
+            replaced[0].1["properties"]["desired"],
+            json!({ "telemetryInterval": 30 })
+        );
+        assert_eq!(replaced[0].1["tags"]["floor"], json!(3));
+        assert_eq!(replaced[1].0, "/twins/some-device/modules/some-module");
+        assert_eq!(
+            replaced[1].1,
+            json!({ "properties": { "desired": { "threshold": 5 } }, "tags": {} })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn desired_twin_diff_should_only_contain_changes(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = crate::testing::MockHub::new();
+        hub.add_device("some-device");
+        let twin_manager = hub.service().twin_manager();
+        let desired = json!({
+            "interval": 30,
+            "limits": [1, 2],
+            "logging": { "level": "info", "sinks": { "file": true, "syslog": false } }
+        });
+        futures::executor::block_on(
+            twin_manager.update_device_twin(
+                "some-device",
+                DesiredTwinBuilder::new()
+                    .properties(desired.clone())
+                    .build(),
+            ),
+        )?;
+        let device_twin =
+            futures::executor::block_on(twin_manager.clone().get_device_twin("some-device"))?;
+
+        assert!(DesiredTwin::diff(&device_twin, &desired)?.is_empty());
+        assert!(DesiredTwin::diff(&device_twin, &json!([1])).is_err());
+
+        let target = json!({
+            "limits": [1, 2, 3],
+            "logging": { "level": "info", "sinks": { "file": true } },
+            "mode": "eco"
+        });
+        let desired_twin = DesiredTwin::diff(&device_twin, &target)?;
+        assert_eq!(
+            desired_twin.desired_properties(),
+            &json!({
+                "interval": null,
+                "limits": [1, 2, 3],
+                "logging": { "sinks": { "syslog": null } },
+                "mode": "eco"
+            })
+        );
+
+        let device_twin = futures::executor::block_on(
+            twin_manager.update_device_twin("some-device", desired_twin),
+        )?;
+        assert!(DesiredTwin::diff(&device_twin, &target)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn typed_twins_should_deserialize_the_properties(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        #[derive(serde::Deserialize, Debug, Default, PartialEq)]
+        #[serde(rename_all = "camelCase")]
+        struct Settings {
+            telemetry_interval: Option<u64>,
+        }
+
+        let hub = crate::testing::MockHub::new();
+        hub.add_device("some-device");
+        hub.add_module("some-device", "some-module");
+        let twin_manager = hub.service().twin_manager();
+        futures::executor::block_on(
+            twin_manager.update_device_twin(
+                "some-device",
+                DesiredTwinBuilder::new()
+                    .properties(json!({ "telemetryInterval": 30 }))
+                    .build(),
+            ),
+        )?;
+
+        let device_twin: crate::response::Response<DeviceTwin<Settings>> =
+            futures::executor::block_on(twin_manager.get_typed_device_twin("some-device"))?;
+        assert_eq!(device_twin.properties.desired.telemetry_interval, Some(30));
+        assert!(device_twin.properties.reported.is_object());
+
+        let module_twin: crate::response::Response<ModuleTwin<Settings, Settings>> =
+            futures::executor::block_on(
+                twin_manager.get_typed_module_twin("some-device", "some-module"),
+            )?;
+        assert_eq!(module_twin.properties.desired, Settings::default());
+        Ok(())
+    }
+
+    #[test]
+    fn check_device_twin_patch_should_merge_the_patch(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = crate::testing::MockHub::new();
+        hub.add_device("some-device");
+        let twin_manager = hub.service().twin_manager();
+        futures::executor::block_on(
+            twin_manager.update_device_twin(
+                "some-device",
+                DesiredTwinBuilder::new()
+                    .properties(json!({ "blob": "x".repeat(30 * 1024) }))
+                    .build(),
+            ),
+        )?;
+
+        let patch = DesiredTwinBuilder::new()
+            .properties(json!({ "extra": "y".repeat(4 * 1024) }))
+            .build();
+        let estimate = futures::executor::block_on(
+            twin_manager.check_device_twin_patch("some-device", &patch),
+        )?;
+        assert!(!estimate.is_within_limits());
+        assert_eq!(estimate.desired_properties().largest_keys()[0].0, "blob");
+        Ok(())
+    }
+
+    #[test]
+    fn tag_helpers_should_send_minimal_patches(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = crate::testing::MockHub::new();
+        hub.add_device("some-device");
+        let twin_manager = hub.service().twin_manager();
+
+        let mut tags = std::collections::HashMap::new();
+        tags.insert("environment".to_string(), json!("production"));
+        tags.insert(
+            "location".to_string(),
+            json!({ "building": "43", "floor": 3 }),
+        );
+        futures::executor::block_on(twin_manager.add_tags("some-device", tags))?;
+        futures::executor::block_on(twin_manager.remove_tag("some-device", "location.floor"))?;
+
+        let tags = futures::executor::block_on(twin_manager.get_tags("some-device"))?;
+        assert_eq!(tags["environment"], "production");
+        assert_eq!(tags["location"], json!({ "building": "43" }));
+        assert!(
+            futures::executor::block_on(twin_manager.remove_tag("some-device", "location."))
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn set_module_desired_properties_should_wrap_the_properties(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct SensorSettings {
+            telemetry_interval: u32,
+        }
+
+        let hub = crate::testing::MockHub::new();
+        hub.add_module("some-device", "sensor");
+        let twin_manager = hub.service().twin_manager();
+
+        let module_twin = futures::executor::block_on(twin_manager.set_module_desired_properties(
+            "some-device",
+            "sensor",
+            SensorSettings {
+                telemetry_interval: 60,
+            },
+        ))?;
+        assert_eq!(module_twin.properties.desired["telemetryInterval"], 60);
+        assert_eq!(module_twin.properties.desired["$version"], 2);
+        assert!(
+            futures::executor::block_on(twin_manager.set_module_desired_properties(
+                "some-device",
+                "sensor",
+                vec![1, 2, 3]
+            ))
+            .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn set_module_image_should_only_patch_the_image(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = crate::testing::MockHub::new();
+        hub.add_module("edge-device", "$edgeAgent");
+        let twin_manager = hub.service().twin_manager();
+        futures::executor::block_on(twin_manager.set_module_desired_properties(
+            "edge-device",
+            "$edgeAgent",
+            json!({
+                "systemModules": {
+                    "edgeHub": { "settings": { "image": "mcr.microsoft.com/azureiotedge-hub:1.1" } }
+                },
+                "modules": {
+                    "sensor": {
+                        "status": "running",
+                        "settings": { "image": "someacr.azurecr.io/sensor:1.0", "createOptions": "{}" }
+                    }
+                }
+            }),
+        ))?;
+
+        futures::executor::block_on(twin_manager.set_module_image(
+            "edge-device",
+            "sensor",
+            "someacr.azurecr.io/sensor:1.1",
+        ))?;
+        let module_twin = futures::executor::block_on(twin_manager.set_module_image(
+            "edge-device",
+            "$edgeHub",
+            "mcr.microsoft.com/azureiotedge-hub:1.2",
+        ))?;
+
+        let desired = &module_twin.properties.desired;
+        assert_eq!(
+            desired["modules"]["sensor"],
+            json!({
+                "status": "running",
+                "settings": { "image": "someacr.azurecr.io/sensor:1.1", "createOptions": "{}" }
+            })
+        );
+        assert_eq!(
+            desired["systemModules"]["edgeHub"]["settings"]["image"],
+            "mcr.microsoft.com/azureiotedge-hub:1.2"
+        );
+        assert!(futures::executor::block_on(twin_manager.set_module_image(
+            "edge-device",
+            "filter",
+            "someacr.azurecr.io/filter:1.0",
+        ))
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_reported_should_poll_until_the_property_matches(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = crate::testing::MockHub::new();
+        hub.add_device("some-device");
+        hub.report_device_properties("some-device", json!({ "firmware": { "version": "2.0.0" } }));
+        let twin_manager = hub.service().twin_manager();
+
+        let reporter = {
+            let hub = hub.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(100));
+                hub.report_device_properties(
+                    "some-device",
+                    json!({ "firmware": { "version": "2.1.0" } }),
+                );
+            })
+        };
+        let device_twin = futures::executor::block_on(twin_manager.wait_for_reported(
+            "some-device",
+            "/firmware/version",
+            json!("2.1.0"),
+            Duration::from_secs(10),
+        ))?;
+        reporter.join().unwrap();
+        assert_eq!(
+            device_twin.properties.reported["firmware"]["version"],
+            "2.1.0"
+        );
+
+        let err = futures::executor::block_on(twin_manager.wait_for_reported(
+            "some-device",
+            "/firmware/version",
+            json!("3.0.0"),
+            Duration::from_secs(0),
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains(r#"is "2.1.0" instead of "3.0.0""#));
+        assert!(futures::executor::block_on(twin_manager.wait_for_reported(
+            "some-device",
+            "firmware",
+            json!("3.0.0"),
+            Duration::from_secs(0),
+        ))
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pattern_should_match_wildcards() {
+        use crate::twin::matches_pattern;
+        assert!(matches_pattern("sensor:1.0", "sensor:1.0"));
+        assert!(!matches_pattern("sensor:1.0", "sensor:1.0.1"));
+        assert!(matches_pattern("sensor:1.*", "sensor:1.0.1"));
+        assert!(matches_pattern(
+            "*/sensor:*",
+            "someacr.azurecr.io/sensor:2.0"
+        ));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("a*b*c", "abc"));
+        assert!(!matches_pattern("a*bc", "abc-bc-b"));
+        assert!(!matches_pattern("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn find_modules_by_image_should_match_reported_modules(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let devices = json!([
+            {
+                "deviceId": "gateway-2",
+                "systemModules": {
+                    "edgeHub": { "runtimeStatus": "running", "settings": { "image": "mcr.microsoft.com/azureiotedge-hub:1.2" } }
+                },
+                "modules": {
+                    "sensor": { "runtimeStatus": "running", "version": "1.1", "settings": { "image": "someacr.azurecr.io/sensor:1.1.0" } }
+                }
+            },
+            {
+                "deviceId": "gateway-1",
+                "modules": {
+                    "sensor": { "runtimeStatus": "running", "version": "2.0", "settings": { "image": "someacr.azurecr.io/sensor:2.0.0" } },
+                    "filter": { "runtimeStatus": "running", "version": "1.0", "settings": { "image": "someacr.azurecr.io/filter:1.0.0" } }
+                }
+            }
+        ]);
+        // The queries are answered with the reported modules of the devices, each on its own page
+        let transport = MockTransport::new(move |request, _| {
+            match request.headers().get("x-ms-continuation") {
+                None => Ok(http::Response::builder()
+                    .header("x-ms-continuation", "page-2")
+                    .body(serde_json::to_vec(&json!([devices[0]]))?)?),
+                Some(_) => json_response(&json!([devices[1]])),
+            }
+        });
+        let twin_manager = IoTHubService::from_sas_token("test", "test")
+            .with_transport(transport)
+            .twin_manager();
+
+        let found = futures::executor::block_on(
+            twin_manager.find_modules_by_image("someacr.azurecr.io/sensor:*"),
+        )?;
+        let found: Vec<(&str, &str, Option<&str>)> = found
+            .iter()
+            .map(|module| {
+                (
+                    module.device_id().as_str(),
+                    module.module_name().as_str(),
+                    module.version().as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("gateway-1", "sensor", Some("2.0")),
+                ("gateway-2", "sensor", Some("1.1"))
+            ]
+        );
+
+        let found = futures::executor::block_on(twin_manager.find_modules_by_version("1.*"))?;
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].module_name(), "filter");
+        assert_eq!(
+            found[1].image().as_deref(),
+            Some("someacr.azurecr.io/sensor:1.1.0")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn import_tags_should_only_send_changed_tags(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = crate::testing::MockHub::new();
+        hub.add_device("sensor-1");
+        hub.add_device("sensor-2");
+        let twin_manager = hub.service().twin_manager();
+        let mut tags = std::collections::HashMap::new();
+        tags.insert("environment".to_string(), json!("production"));
+        futures::executor::block_on(twin_manager.add_tags("sensor-1", tags))?;
+
+        let import = crate::tagimport::TagImport::from_csv(
+            "deviceId,environment,location.building\nsensor-1,production,\nsensor-2,test,43\nunknown,test,\n"
+                .as_bytes(),
+        )?;
+
+        let report = futures::executor::block_on(twin_manager.import_tags(&import, 2, true));
+        assert!(report["sensor-1"].as_ref().unwrap().is_unchanged());
+        let change = report["sensor-2"].as_ref().unwrap();
+        assert_eq!(change.changed_tags(), &vec!["environment", "location"]);
+        assert!(!change.is_applied());
+        assert!(report["unknown"].is_err());
+        assert!(hub.device_twin("sensor-2").unwrap().tags.is_empty());
+
+        let report = futures::executor::block_on(twin_manager.import_tags(&import, 2, false));
+        assert!(report["sensor-2"].as_ref().unwrap().is_applied());
+        let device_twin = hub.device_twin("sensor-2").unwrap();
+        assert_eq!(device_twin.tags["environment"], "test");
+        assert_eq!(device_twin.tags["location"], json!({ "building": "43" }));
+        assert_eq!(hub.device_twin("sensor-1").unwrap().version, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn get_edge_agent_reported_should_return_typed_modules(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = crate::testing::MockHub::new();
+        hub.add_module("some-device", "$edgeAgent");
+        hub.report_module_properties(
+            "some-device",
+            "$edgeAgent",
+            json!({
+                "lastDesiredVersion": 2,
+                "systemModules": {
+                    "edgeHub": { "runtimeStatus": "running", "restartCount": 0 }
+                },
+                "modules": {
+                    "sensor": { "runtimeStatus": "failed", "exitCode": 1, "restartCount": 3 }
+                }
+            }),
+        );
+
+        let reported = futures::executor::block_on(
+            hub.service()
+                .twin_manager()
+                .get_edge_agent_reported("some-device"),
+        )?;
+        assert_eq!(reported.last_desired_version, Some(2));
+        let sensor = reported.module("sensor").unwrap();
+        assert_eq!(
+            sensor.runtime_status,
+            crate::configuration::RuntimeStatus::Failed
+        );
+        assert_eq!(sensor.exit_code, Some(1));
+        assert_eq!(sensor.restart_count, Some(3));
+        assert_eq!(reported.modules_not_running(), vec!["sensor"]);
+        Ok(())
+    }
+
+    #[test]
+    fn get_route_report_should_validate_routes(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hub = crate::testing::MockHub::new();
+        hub.add_module("some-device", "$edgeHub");
+        let twin_manager = hub.service().twin_manager();
+        futures::executor::block_on(twin_manager.update_module_twin(
+            "some-device",
+            "$edgeHub",
+            DesiredTwinBuilder::new()
+                .properties(json!({
+                    "routes": {
+                        "sensorToUpstream": "FROM /messages/modules/sensor/outputs/* INTO $upstream",
+                        "debuggerToUpstream": {
+                            "route": "FROM /messages/modules/debugger/* INTO $upstream",
+                            "priority": 1
+                        },
+                        "allToUpstream": "FROM /messages/* INTO $upstream"
+                    }
+                }))
+                .build(),
+        ))?;
+        hub.report_module_properties(
+            "some-device",
+            "$edgeHub",
+            json!({
+                "lastDesiredVersion": 2,
+                "lastDesiredStatus": { "code": 200 },
+                "clients": {
+                    "some-device/sensor": { "status": "Connected" },
+                    "some-device/debugger": { "status": "Disconnected" }
+                }
+            }),
+        );
+
+        let report = futures::executor::block_on(twin_manager.get_route_report("some-device"))?;
+        assert_eq!(report.desired_version(), Some(2));
+        assert!(report.is_applied());
+        assert!(!report.is_healthy());
+        assert_eq!(report.routes().len(), 3);
+        let unconnected = report.routes_without_connected_source();
+        assert_eq!(unconnected.len(), 1);
+        assert_eq!(unconnected[0].route_name(), "debuggerToUpstream");
+        assert_eq!(unconnected[0].source_module().as_deref(), Some("debugger"));
+
+        let reported =
+            futures::executor::block_on(twin_manager.get_edge_hub_reported("some-device"))?;
+        assert_eq!(reported.connected_clients(), vec!["some-device/sensor"]);
+        Ok(())
+    }
+
+    #[test]
+    fn get_device_twins_should_retry_throttled_requests(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // The first request for every device is throttled, and `unknown-device` does not exist
+        let transport = MockTransport::new(|request, earlier| {
+            let path = request.uri().path();
+            let device_id = path.trim_start_matches("/twins/");
+            if device_id == "unknown-device" {
+                return error_response(404, "DeviceNotFound", "Device not found");
+            }
+            if !earlier.iter().any(|earlier| earlier.uri().path() == path) {
+                let mut response = error_response(429, "ThrottlingException", "Too many requests")?;
+                response
+                    .headers_mut()
+                    .insert("Retry-After", http::HeaderValue::from_static("0"));
+                return Ok(response);
+            }
+            json_response(&json!({
+                "deviceId": device_id,
+                "etag": "AAAAAAAAAAE=",
+                "status": "enabled",
+                "connectionState": "Connected"
+            }))
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+
+        let twins = futures::executor::block_on(
+            iothub
+                .twin_manager()
+                .get_device_twins(vec!["some-device", "other-device", "unknown-device"], 2),
+        );
+        assert_eq!(twins.len(), 3);
+        assert_eq!(
+            twins["some-device"].as_ref().unwrap().device_id,
+            "some-device"
+        );
+        assert_eq!(
+            twins["other-device"].as_ref().unwrap().device_id,
+            "other-device"
+        );
+        assert!(twins["unknown-device"].is_err());
+        assert_eq!(transport.requests().len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn desired_twin_builder_should_add_json_tags() {
+        let desired_twin = DesiredTwinBuilder::new()
+            .add_tag("environment", "production")
+            .add_tag("location", json!({ "building": "43" }))
+            .build();
+
+        assert_eq!(
+            desired_twin.contents["tags"],
+            json!({
+                "environment": "production",
+                "location": { "building": "43" }
+            })
+        );
     }
 }
@@ -0,0 +1,305 @@
+//! The twinsize module estimates the size of the tags and desired properties of a
+//! twin, so patches that would exceed the limits of the IoT Hub can be found before
+//! they are sent.
+//!
+//! The IoT Hub measures a section of a twin by its JSON representation without
+//! whitespace and without the metadata properties, such as `$version`, which is
+//! what the estimate is based on.
+use std::fmt;
+
+use crate::twin::{DesiredTwin, DeviceTwin};
+
+/// The maximum size in bytes of the tags of a twin
+pub const MAX_TAGS_SIZE: usize = 8 * 1024;
+
+/// The maximum size in bytes of the desired properties of a twin
+pub const MAX_DESIRED_PROPERTIES_SIZE: usize = 32 * 1024;
+
+/// The number of largest properties listed in the breakdown of a section
+const LARGEST_KEYS: usize = 5;
+
+/// A section of a twin that has a size limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TwinSection {
+    Tags,
+    DesiredProperties,
+}
+
+impl TwinSection {
+    /// Get the maximum size in bytes of the section
+    pub fn limit(&self) -> usize {
+        match self {
+            TwinSection::Tags => MAX_TAGS_SIZE,
+            TwinSection::DesiredProperties => MAX_DESIRED_PROPERTIES_SIZE,
+        }
+    }
+}
+
+impl fmt::Display for TwinSection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TwinSection::Tags => write!(f, "tags"),
+            TwinSection::DesiredProperties => write!(f, "desired properties"),
+        }
+    }
+}
+
+/// The estimated size of a section of a twin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionSize {
+    section: TwinSection,
+    size: usize,
+    largest_keys: Vec<(String, usize)>,
+}
+
+impl SectionSize {
+    /// Estimate the size of a section from its JSON object
+    fn new(section: TwinSection, value: &serde_json::Value) -> Self {
+        let empty = serde_json::Map::new();
+        let object = value.as_object().unwrap_or(&empty);
+
+        let mut sizes: Vec<(String, usize)> = object
+            .iter()
+            .filter(|(name, _)| !name.starts_with('$'))
+            .map(|(name, value)| (name.clone(), property_size(name, value)))
+            .collect();
+        // The properties are separated by commas and enclosed in braces
+        let size =
+            sizes.iter().map(|(_, size)| size).sum::<usize>() + sizes.len().saturating_sub(1) + 2;
+
+        sizes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        sizes.truncate(LARGEST_KEYS);
+        SectionSize {
+            section,
+            size,
+            largest_keys: sizes,
+        }
+    }
+
+    /// Get the section
+    pub fn section(&self) -> TwinSection {
+        self.section
+    }
+
+    /// Get the estimated size in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Get the maximum size in bytes
+    pub fn limit(&self) -> usize {
+        self.section.limit()
+    }
+
+    /// Get the largest top-level properties with their size in bytes, largest first
+    pub fn largest_keys(&self) -> &Vec<(String, usize)> {
+        &self.largest_keys
+    }
+
+    /// Check whether the section is larger than the IoT Hub accepts
+    pub fn exceeds_limit(&self) -> bool {
+        self.size > self.limit()
+    }
+}
+
+impl fmt::Display for SectionSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} of {} bytes",
+            self.section,
+            self.size,
+            self.limit()
+        )?;
+        for (index, (name, size)) in self.largest_keys.iter().enumerate() {
+            let separator = if index == 0 { ", largest: " } else { ", " };
+            write!(f, "{}{} ({} bytes)", separator, name, size)?;
+        }
+        Ok(())
+    }
+}
+
+/// The estimated size of the tags and desired properties of a twin
+///
+/// # Example
+/// ```
+/// use azure_iothub_service::twin::DesiredTwinBuilder;
+/// use azure_iothub_service::twinsize::TwinSizeEstimate;
+/// use serde_json::json;
+///
+/// let patch = DesiredTwinBuilder::new()
+///     .add_tag("site", "north")
+///     .properties(json!({ "firmware": "x".repeat(40 * 1024) }))
+///     .build();
+/// let estimate = TwinSizeEstimate::of_patch(&patch);
+/// assert!(!estimate.is_within_limits());
+/// assert_eq!(estimate.desired_properties().largest_keys()[0].0, "firmware");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TwinSizeEstimate {
+    tags: SectionSize,
+    desired_properties: SectionSize,
+}
+
+impl TwinSizeEstimate {
+    /// Estimate the size of the given tags and desired properties, which are JSON objects
+    pub fn new(tags: &serde_json::Value, desired_properties: &serde_json::Value) -> Self {
+        TwinSizeEstimate {
+            tags: SectionSize::new(TwinSection::Tags, tags),
+            desired_properties: SectionSize::new(
+                TwinSection::DesiredProperties,
+                desired_properties,
+            ),
+        }
+    }
+
+    /// Estimate the size of the tags and desired properties of a device twin
+    pub fn of_device_twin(device_twin: &DeviceTwin) -> Self {
+        Self::new(&tags_value(device_twin), &device_twin.properties.desired)
+    }
+
+    /// Estimate the size of the tags and desired properties set by a patch
+    ///
+    /// The IoT Hub merges the patch into the twin, so a patch within the limits can
+    /// still result in a twin that exceeds them, see [`TwinSizeEstimate::after_patch`].
+    pub fn of_patch(patch: &DesiredTwin) -> Self {
+        Self::new(patch.tags(), patch.desired_properties())
+    }
+
+    /// Estimate the size of a device twin after a patch is applied to it
+    pub fn after_patch(device_twin: &DeviceTwin, patch: &DesiredTwin) -> Self {
+        let mut tags = tags_value(device_twin);
+        merge_patch(&mut tags, patch.tags());
+        let mut desired_properties = device_twin.properties.desired.clone();
+        merge_patch(&mut desired_properties, patch.desired_properties());
+        Self::new(&tags, &desired_properties)
+    }
+
+    /// Get the estimated size of the tags
+    pub fn tags(&self) -> &SectionSize {
+        &self.tags
+    }
+
+    /// Get the estimated size of the desired properties
+    pub fn desired_properties(&self) -> &SectionSize {
+        &self.desired_properties
+    }
+
+    /// Get the sections that are larger than the IoT Hub accepts
+    pub fn exceeded_sections(&self) -> Vec<&SectionSize> {
+        vec![&self.tags, &self.desired_properties]
+            .into_iter()
+            .filter(|section| section.exceeds_limit())
+            .collect()
+    }
+
+    /// Check whether all sections are within the limits of the IoT Hub
+    pub fn is_within_limits(&self) -> bool {
+        self.exceeded_sections().is_empty()
+    }
+}
+
+impl fmt::Display for TwinSizeEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.tags)?;
+        write!(f, "{}", self.desired_properties)
+    }
+}
+
+/// Get the size of a property within an object, which is its quoted name, a colon and its value
+fn property_size(name: &str, value: &serde_json::Value) -> usize {
+    serde_json::Value::String(name.to_string())
+        .to_string()
+        .len()
+        + 1
+        + value.to_string().len()
+}
+
+/// Get the tags of a device twin as a JSON object
+fn tags_value(device_twin: &DeviceTwin) -> serde_json::Value {
+    serde_json::Value::Object(
+        device_twin
+            .tags
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+    )
+}
+
+/// Merge a patch into a JSON value like the IoT Hub does, where `null` removes a property
+fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    match patch {
+        serde_json::Value::Object(patch) => {
+            if !target.is_object() {
+                *target = serde_json::Value::Object(serde_json::Map::new());
+            }
+            if let Some(target) = target.as_object_mut() {
+                for (name, value) in patch {
+                    if value.is_null() {
+                        target.remove(name);
+                    } else {
+                        merge_patch(
+                            target
+                                .entry(name.clone())
+                                .or_insert(serde_json::Value::Null),
+                            value,
+                        );
+                    }
+                }
+            }
+        }
+        patch => *target = patch.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{SectionSize, TwinSection, TwinSizeEstimate};
+    use crate::twin::{DesiredTwinBuilder, DeviceTwin};
+
+    #[test]
+    fn section_size_should_match_compact_json() {
+        let value = json!({ "interval": 30, "logging": { "level": "debug" }, "$version": 4 });
+        let size = SectionSize::new(TwinSection::DesiredProperties, &value);
+        assert_eq!(
+            size.size(),
+            r#"{"interval":30,"logging":{"level":"debug"}}"#.len()
+        );
+        assert_eq!(size.largest_keys()[0].0, "logging");
+        assert_eq!(SectionSize::new(TwinSection::Tags, &json!({})).size(), 2);
+    }
+
+    #[test]
+    fn after_patch_should_include_the_current_twin(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let device_twin: DeviceTwin = serde_json::from_value(json!({
+            "deviceId": "some-device",
+            "etag": "AAAAAAAAAAE=",
+            "status": "enabled",
+            "connectionState": "Connected",
+            "tags": { "notes": "x".repeat(6 * 1024) },
+            "properties": { "desired": { "blob": "y".repeat(20 * 1024) } }
+        }))?;
+        let patch = DesiredTwinBuilder::new()
+            .add_tag("history", "z".repeat(3 * 1024))
+            .properties(json!({ "blob": null, "small": 1 }))
+            .build();
+
+        assert!(TwinSizeEstimate::of_patch(&patch).is_within_limits());
+        let estimate = TwinSizeEstimate::after_patch(&device_twin, &patch);
+        let exceeded = estimate.exceeded_sections();
+        assert_eq!(exceeded.len(), 1);
+        assert_eq!(exceeded[0].section(), TwinSection::Tags);
+        assert_eq!(estimate.desired_properties().size(), r#"{"small":1}"#.len());
+        let tags_size = json!({ "notes": "x".repeat(6 * 1024), "history": "z".repeat(3 * 1024) })
+            .to_string()
+            .len();
+        assert!(estimate.to_string().starts_with(&format!(
+            "tags: {} of 8192 bytes, largest: notes (6154 bytes), history (3084 bytes)",
+            tags_size
+        )));
+        Ok(())
+    }
+}
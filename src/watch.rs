@@ -0,0 +1,487 @@
+//! The watch module contains streams that poll the IoT Hub for changes, which
+//! are used until change notifications can be received from the IoT Hub.
+//!
+//! The streams implement [`futures_core::Stream`] and do not depend on a
+//! specific async runtime.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures_core::Stream;
+
+use crate::delay::delay_for;
+use crate::response::Response;
+use crate::twin::ConnectionState;
+
+/// The future that requests the twin once
+type FetchFuture<T> = Pin<
+    Box<dyn Future<Output = Result<Response<T>, Box<dyn std::error::Error + Send + Sync>>> + Send>,
+>;
+
+/// A future that completes with the next item of a stream
+struct Next<'a, S> {
+    stream: &'a mut S,
+}
+
+impl<S> Future for Next<'_, S>
+where
+    S: Stream + Unpin,
+{
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+/// A stream that emits a twin whenever its version changes
+///
+/// The twin is requested once every interval and emitted when its version differs
+/// from the version that was emitted last, so the first twin is always emitted.
+/// Failed requests are emitted as errors and the stream continues polling afterwards,
+/// the stream never ends.
+///
+/// # Example
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// use std::time::Duration;
+///
+/// use azure_iothub_service::IoTHubService;
+///
+/// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+/// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+/// let mut watch = iothub
+///     .twin_manager()
+///     .watch_device_twin("some-device", Duration::from_secs(30));
+/// while let Some(device_twin) = watch.next().await {
+///     println!("Reported: {}", device_twin?.properties.reported);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct TwinWatch<T> {
+    fetch: Arc<dyn Fn() -> FetchFuture<T> + Send + Sync>,
+    version: fn(&T) -> i64,
+    interval: Duration,
+    last_version: Option<i64>,
+    polled: bool,
+    polling: Option<FetchFuture<T>>,
+}
+
+impl<T> TwinWatch<T>
+where
+    T: 'static,
+{
+    /// Create a TwinWatch that requests the twin with `fetch` and compares the versions with `version`
+    pub(crate) fn new<F>(interval: Duration, version: fn(&T) -> i64, fetch: F) -> Self
+    where
+        F: Fn() -> FetchFuture<T> + Send + Sync + 'static,
+    {
+        TwinWatch {
+            fetch: Arc::new(fetch),
+            version,
+            interval,
+            last_version: None,
+            polled: false,
+            polling: None,
+        }
+    }
+
+    /// Wait for the next change of the twin
+    ///
+    /// This is a shorthand for using the stream, when no stream combinators are needed.
+    pub async fn next(
+        &mut self,
+    ) -> Option<Result<Response<T>, Box<dyn std::error::Error + Send + Sync>>> {
+        Next { stream: self }.await
+    }
+}
+
+impl<T> Stream for TwinWatch<T>
+where
+    T: 'static,
+{
+    type Item = Result<Response<T>, Box<dyn std::error::Error + Send + Sync>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.polling.is_none() {
+                // The first request is sent right away, the others after the interval
+                let interval = if self.polled {
+                    self.interval
+                } else {
+                    Duration::from_secs(0)
+                };
+                self.polled = true;
+                let fetch = (self.fetch)();
+                self.polling = Some(Box::pin(async move {
+                    delay_for(interval).await;
+                    fetch.await
+                }));
+            }
+
+            let result = match self.polling.as_mut() {
+                Some(polling) => match polling.as_mut().poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return Poll::Pending,
+                },
+                None => return Poll::Pending,
+            };
+            self.polling = None;
+
+            match result {
+                Ok(twin) => {
+                    let version = (self.version)(&twin);
+                    if self.last_version != Some(version) {
+                        self.last_version = Some(version);
+                        return Poll::Ready(Some(Ok(twin)));
+                    }
+                }
+                Err(err) => return Poll::Ready(Some(Err(err))),
+            }
+        }
+    }
+}
+
+/// The connection state of a device as returned by a query
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DeviceConnectionState {
+    device_id: String,
+    connection_state: ConnectionState,
+}
+
+/// The future that requests the connection states of the devices once
+type StatesFuture = Pin<
+    Box<
+        dyn Future<
+                Output = Result<
+                    Vec<DeviceConnectionState>,
+                    Box<dyn std::error::Error + Send + Sync>,
+                >,
+            > + Send,
+    >,
+>;
+
+/// A transition of the connection state of a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStateChange {
+    device_id: String,
+    previous: ConnectionState,
+    current: ConnectionState,
+}
+
+impl ConnectionStateChange {
+    /// Get the id of the device
+    pub fn device_id(&self) -> &String {
+        &self.device_id
+    }
+
+    /// Get the connection state before the transition
+    pub fn previous(&self) -> ConnectionState {
+        self.previous
+    }
+
+    /// Get the connection state after the transition
+    pub fn current(&self) -> ConnectionState {
+        self.current
+    }
+
+    /// Check whether the device disconnected
+    pub fn is_disconnect(&self) -> bool {
+        self.current == ConnectionState::Disconnected
+    }
+}
+
+/// A stream that emits the devices whose connection state changed
+///
+/// The connection states of the devices are queried once every interval. The first
+/// query records the current states, and every following query emits a change for
+/// each device whose state differs from its recorded state. Devices that are added
+/// later are recorded without emitting a change. Failed requests are emitted as
+/// errors and the stream continues polling afterwards, the stream never ends.
+///
+/// With [`ConnectionStateWatch::with_debounce`], a new state must be observed for the
+/// debounce duration before the change is emitted, so devices that reconnect quickly
+/// are not reported.
+///
+/// # Example
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// use std::time::Duration;
+///
+/// use azure_iothub_service::IoTHubService;
+///
+/// # let connection_string = "HostName=cool-iot-hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=YSB2ZXJ5IHNlY3VyZSBrZXkgaXMgaW1wb3J0YW50Cg==";
+/// let iothub = IoTHubService::from_connection_string(connection_string, 3600)?;
+/// let mut watch = iothub
+///     .twin_manager()
+///     .monitor_connection_states("tags.site = 'north'", Duration::from_secs(60))
+///     .with_debounce(Duration::from_secs(300));
+/// while let Some(change) = watch.next().await {
+///     let change = change?;
+///     if change.is_disconnect() {
+///         println!("{} disconnected", change.device_id());
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct ConnectionStateWatch {
+    fetch: Arc<dyn Fn() -> StatesFuture + Send + Sync>,
+    interval: Duration,
+    debounce: Duration,
+    states: Option<HashMap<String, ConnectionState>>,
+    pending: HashMap<String, (ConnectionState, Instant)>,
+    changes: VecDeque<ConnectionStateChange>,
+    polled: bool,
+    polling: Option<StatesFuture>,
+}
+
+impl ConnectionStateWatch {
+    /// Create a ConnectionStateWatch that requests the connection states with `fetch`
+    pub(crate) fn new<F>(interval: Duration, fetch: F) -> Self
+    where
+        F: Fn() -> StatesFuture + Send + Sync + 'static,
+    {
+        ConnectionStateWatch {
+            fetch: Arc::new(fetch),
+            interval,
+            debounce: Duration::from_secs(0),
+            states: None,
+            pending: HashMap::new(),
+            changes: VecDeque::new(),
+            polled: false,
+            polling: None,
+        }
+    }
+
+    /// Only emit a change once the new state was observed for at least the debounce duration
+    ///
+    /// The states are only observed once every interval, so a change is emitted by the first
+    /// query after the debounce duration has passed. When the device returns to its recorded
+    /// state in the meantime, no change is emitted.
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Wait for the next change of a connection state
+    ///
+    /// This is a shorthand for using the stream, when no stream combinators are needed.
+    pub async fn next(
+        &mut self,
+    ) -> Option<Result<ConnectionStateChange, Box<dyn std::error::Error + Send + Sync>>> {
+        Next { stream: self }.await
+    }
+
+    /// Compare the queried states with the recorded states and queue the changes
+    fn update(&mut self, devices: Vec<DeviceConnectionState>) {
+        let now = Instant::now();
+        let states = match self.states.as_mut() {
+            Some(states) => states,
+            None => {
+                self.states = Some(
+                    devices
+                        .into_iter()
+                        .map(|device| (device.device_id, device.connection_state))
+                        .collect(),
+                );
+                return;
+            }
+        };
+
+        let mut queried = HashSet::with_capacity(devices.len());
+        let mut changes = Vec::new();
+        for device in devices {
+            let current = device.connection_state;
+            match states.get(&device.device_id).copied() {
+                Some(previous) if previous != current => {
+                    let since = match self.pending.get(&device.device_id) {
+                        Some((state, since)) if *state == current => *since,
+                        _ => now,
+                    };
+                    if now.duration_since(since) >= self.debounce {
+                        self.pending.remove(&device.device_id);
+                        states.insert(device.device_id.clone(), current);
+                        changes.push(ConnectionStateChange {
+                            device_id: device.device_id.clone(),
+                            previous,
+                            current,
+                        });
+                    } else {
+                        self.pending
+                            .insert(device.device_id.clone(), (current, since));
+                    }
+                }
+                Some(_) => {
+                    self.pending.remove(&device.device_id);
+                }
+                None => {
+                    states.insert(device.device_id.clone(), current);
+                }
+            }
+            queried.insert(device.device_id);
+        }
+
+        // Forget the devices that are deleted or no longer match the query
+        states.retain(|device_id, _| queried.contains(device_id));
+        self.pending
+            .retain(|device_id, _| queried.contains(device_id));
+
+        changes.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        self.changes.extend(changes);
+    }
+}
+
+impl Stream for ConnectionStateWatch {
+    type Item = Result<ConnectionStateChange, Box<dyn std::error::Error + Send + Sync>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(change) = self.changes.pop_front() {
+                return Poll::Ready(Some(Ok(change)));
+            }
+
+            if self.polling.is_none() {
+                // The first request is sent right away, the others after the interval
+                let interval = if self.polled {
+                    self.interval
+                } else {
+                    Duration::from_secs(0)
+                };
+                self.polled = true;
+                let fetch = (self.fetch)();
+                self.polling = Some(Box::pin(async move {
+                    delay_for(interval).await;
+                    fetch.await
+                }));
+            }
+
+            let result = match self.polling.as_mut() {
+                Some(polling) => match polling.as_mut().poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return Poll::Pending,
+                },
+                None => return Poll::Pending,
+            };
+            self.polling = None;
+
+            match result {
+                Ok(devices) => self.update(devices),
+                Err(err) => return Poll::Ready(Some(Err(err))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::testing::mocktransport::{json_response, MockTransport};
+    use crate::twin::ConnectionState;
+    use crate::IoTHubService;
+
+    #[test]
+    fn watch_device_twin_should_emit_changed_versions(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // The version of the twin changes after the third request
+        let transport = MockTransport::new(|request, earlier| {
+            assert_eq!(request.uri().path(), "/twins/some-device");
+            let version = if earlier.len() < 3 { 1 } else { 2 };
+            json_response(&serde_json::json!({
+                "deviceId": "some-device",
+                "etag": "AAAAAAAAAAE=",
+                "status": "enabled",
+                "connectionState": "Connected",
+                "version": version
+            }))
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let mut watch = iothub
+            .twin_manager()
+            .watch_device_twin("some-device", Duration::from_millis(10));
+
+        let device_twin = futures::executor::block_on(watch.next()).unwrap()?;
+        assert_eq!(device_twin.version, 1);
+        assert_eq!(transport.requests().len(), 1);
+
+        let device_twin = futures::executor::block_on(watch.next()).unwrap()?;
+        assert_eq!(device_twin.version, 2);
+        assert_eq!(transport.requests().len(), 4);
+        Ok(())
+    }
+
+    /// Create a transport that returns the connection states of the nth query
+    fn states_transport(states: fn(usize) -> serde_json::Value) -> MockTransport {
+        MockTransport::new(move |request, earlier| {
+            let query: serde_json::Value = serde_json::from_slice(request.body())?;
+            assert_eq!(
+                query["query"],
+                "SELECT deviceId, connectionState FROM devices WHERE tags.site = 'north'"
+            );
+            json_response(&states(earlier.len()))
+        })
+    }
+
+    #[test]
+    fn monitor_connection_states_should_emit_transitions(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let iothub = IoTHubService::from_sas_token("test", "test").with_transport(
+            states_transport(|request| {
+                let (a, b) = match request {
+                    0 => ("Connected", "Connected"),
+                    1 => ("Disconnected", "Connected"),
+                    _ => ("Disconnected", "Disconnected"),
+                };
+                serde_json::json!([
+                    { "deviceId": "a", "connectionState": a },
+                    { "deviceId": "b", "connectionState": b },
+                    { "deviceId": "c", "connectionState": "Connected" }
+                ])
+            }),
+        );
+        let mut watch = iothub
+            .twin_manager()
+            .monitor_connection_states("tags.site = 'north'", Duration::from_millis(10));
+
+        let change = futures::executor::block_on(watch.next()).unwrap()?;
+        assert_eq!(change.device_id(), "a");
+        assert_eq!(change.previous(), ConnectionState::Connected);
+        assert!(change.is_disconnect());
+        let change = futures::executor::block_on(watch.next()).unwrap()?;
+        assert_eq!(change.device_id(), "b");
+        Ok(())
+    }
+
+    #[test]
+    fn monitor_connection_states_should_debounce_transitions(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let transport = states_transport(|request| {
+            // The device briefly disconnects on the second query and disconnects for good from the fourth
+            let state = if request == 1 || request >= 3 {
+                "Disconnected"
+            } else {
+                "Connected"
+            };
+            serde_json::json!([{ "deviceId": "a", "connectionState": state }])
+        });
+        let iothub =
+            IoTHubService::from_sas_token("test", "test").with_transport(transport.clone());
+        let mut watch = iothub
+            .twin_manager()
+            .monitor_connection_states("tags.site = 'north'", Duration::from_millis(10))
+            .with_debounce(Duration::from_millis(30));
+
+        let change = futures::executor::block_on(watch.next()).unwrap()?;
+        assert_eq!(change.current(), ConnectionState::Disconnected);
+        // The disconnect is first seen by the fourth query and must last 30ms, at 10ms per query
+        assert!(transport.requests().len() >= 7);
+        Ok(())
+    }
+}